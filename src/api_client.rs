@@ -0,0 +1,361 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::RwLock;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::time::{sleep, Duration};
+use wikibase::mediawiki::api::Api;
+use wikibase::mediawiki::media_wiki_error::MediaWikiError;
+
+/// The MediaWiki API calls made while updating a page, and the Wikibase calls made for its lists.
+/// Tests can swap the live wikis for recorded responses, see `MockApiClient`.
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))] // Browser fetch futures are not Send
+pub trait ApiClient: std::fmt::Debug + Send + Sync {
+    async fn get_query_api_json(&self, params: &HashMap<String, String>) -> Result<Value>;
+    async fn post_query_api_json(&self, params: &HashMap<String, String>) -> Result<Value>;
+    async fn get_edit_token(&self) -> Result<String>;
+    /// Body of an external URL, e.g. a tool on Toolforge
+    async fn get_url(&self, url: &str) -> Result<String>;
+    /// JSON results of a SPARQL query
+    async fn sparql_query(&self, endpoint: &str, sparql: &str) -> Result<Value>;
+
+    /// The SPARQL service of a wikibase, from its site info
+    async fn sparql_endpoint(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Talks to the actual wiki
+#[derive(Debug, Clone)]
+pub struct LiveApiClient {
+    api: Arc<RwLock<Api>>,
+}
+
+impl LiveApiClient {
+    pub fn new(api: Arc<RwLock<Api>>) -> Self {
+        Self { api }
+    }
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+impl ApiClient for LiveApiClient {
+    async fn get_query_api_json(&self, params: &HashMap<String, String>) -> Result<Value> {
+        Ok(self.api.read().await.get_query_api_json(params).await?)
+    }
+
+    async fn post_query_api_json(&self, params: &HashMap<String, String>) -> Result<Value> {
+        Ok(self.api.read().await.post_query_api_json(params).await?)
+    }
+
+    async fn get_edit_token(&self) -> Result<String> {
+        Ok(self.api.write().await.get_edit_token().await?)
+    }
+
+    async fn get_url(&self, url: &str) -> Result<String> {
+        let api = self.api.read().await;
+        Ok(api.query_raw(url, &api.no_params(), "GET").await?)
+    }
+
+    async fn sparql_query(&self, endpoint: &str, sparql: &str) -> Result<Value> {
+        // SPARQL might need some retries sometimes, bad server or somesuch
+        let mut attempts_left = 10;
+        loop {
+            let ret = self.api.read().await.sparql_query_endpoint(sparql, endpoint).await;
+            match ret {
+                Ok(ret) => return Ok(ret),
+                Err(MediaWikiError::String(s))
+                    if attempts_left > 0 && s == "error decoding response body: expected value at line 1 column 1" =>
+                {
+                    #[cfg(not(target_arch = "wasm32"))] // No timers in the browser runtime
+                    sleep(Duration::from_millis(500)).await;
+                    attempts_left -= 1;
+                }
+                Err(e) => return Err(anyhow!("{e}")),
+            }
+        }
+    }
+
+    async fn sparql_endpoint(&self) -> Option<String> {
+        let api = self.api.read().await;
+        api.get_site_info_string("general", "wikibase-sparql").ok().map(|s| s.to_string())
+    }
+}
+
+/// Serves canned responses instead of asking a wiki.
+/// A request matches a response if it has all the parameters given for that response; the first match wins.
+/// `wbgetentities` calls without a matching response are answered from the entities added with `add_entity`.
+/// A SPARQL query gets the results added for the first query text it contains.
+/// Requests without a match go to the fallback client if there is one, and fail otherwise.
+#[derive(Debug, Default)]
+pub struct MockApiClient {
+    responses: Vec<(HashMap<String, String>, Value)>,
+    urls: HashMap<String, String>,
+    entities: HashMap<String, Value>,
+    sparql: Vec<(String, Value)>,
+    requests: Mutex<Vec<HashMap<String, String>>>,
+    fallback: Option<Arc<dyn ApiClient>>,
+}
+
+impl MockApiClient {
+    /// Reads responses from JSON lines, as in the MOCK_API section of test fixtures:
+    /// `{"params":{"action":"parse"},"response":{...}}` for API calls, `{"url":"...","body":"..."}` for URLs
+    pub fn new_from_lines(text: &str) -> Result<Self> {
+        let mut ret = Self::default();
+        for line in text.lines().map(|line| line.trim()).filter(|line| !line.is_empty()) {
+            let j: Value = serde_json::from_str(line)?;
+            if let Some(url) = j["url"].as_str() {
+                let body = j["body"].as_str().ok_or_else(|| anyhow!("No body for mock URL {url}"))?;
+                ret.add_url(url, body);
+                continue;
+            }
+            let params = j["params"]
+                .as_object()
+                .ok_or_else(|| anyhow!("Mock response without params: {line}"))?
+                .iter()
+                .map(|(k, v)| (k.to_owned(), v.as_str().map(|s| s.to_string()).unwrap_or_else(|| v.to_string())))
+                .collect();
+            ret.responses.push((params, j["response"].to_owned()));
+        }
+        Ok(ret)
+    }
+
+    pub fn with_fallback(mut self, fallback: Arc<dyn ApiClient>) -> Self {
+        self.fallback = Some(fallback);
+        self
+    }
+
+    pub fn add_response(&mut self, params: &[(&str, &str)], response: Value) {
+        let params = params.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        self.responses.push((params, response));
+    }
+
+    pub fn add_url(&mut self, url: &str, body: &str) {
+        self.urls.insert(url.to_string(), body.to_string());
+    }
+
+    /// An entity as `wbgetentities` returns it
+    pub fn add_entity(&mut self, entity: Value) -> Result<()> {
+        let id = entity["id"].as_str().ok_or_else(|| anyhow!("Mock entity without id"))?;
+        self.entities.insert(id.to_string(), entity);
+        Ok(())
+    }
+
+    /// Adds entities from JSON lines, one entity per line, as in the MOCK_ENTITIES section of test fixtures
+    pub fn add_entities_from_lines(&mut self, text: &str) -> Result<()> {
+        for line in text.lines().map(|line| line.trim()).filter(|line| !line.is_empty()) {
+            self.add_entity(serde_json::from_str(line)?)?;
+        }
+        Ok(())
+    }
+
+    /// Results for SPARQL queries that contain `sparql`
+    pub fn add_sparql_results(&mut self, sparql: &str, results: Value) {
+        self.sparql.push((sparql.to_string(), results));
+    }
+
+    /// Reads SPARQL results from JSON lines, as in the MOCK_SPARQL section of test fixtures:
+    /// `{"sparql":"SELECT ...","results":{...}}`
+    pub fn add_sparql_results_from_lines(&mut self, text: &str) -> Result<()> {
+        for line in text.lines().map(|line| line.trim()).filter(|line| !line.is_empty()) {
+            let j: Value = serde_json::from_str(line)?;
+            let sparql = j["sparql"].as_str().ok_or_else(|| anyhow!("Mock SPARQL results without query: {line}"))?;
+            self.add_sparql_results(sparql, j["results"].to_owned());
+        }
+        Ok(())
+    }
+
+    /// All API requests made so far, in order
+    pub fn requests(&self) -> Vec<HashMap<String, String>> {
+        self.requests.lock().map(|r| r.clone()).unwrap_or_default()
+    }
+
+    fn find_response(&self, params: &HashMap<String, String>) -> Option<Value> {
+        if let Ok(mut requests) = self.requests.lock() {
+            requests.push(params.to_owned());
+        }
+        self.responses
+            .iter()
+            .find(|(expected, _)| expected.iter().all(|(k, v)| params.get(k) == Some(v)))
+            .map(|(_, response)| response.to_owned())
+            .or_else(|| self.entities_response(params))
+    }
+
+    /// A `wbgetentities` result for the requested IDs; unknown ones are missing, as on a wiki
+    fn entities_response(&self, params: &HashMap<String, String>) -> Option<Value> {
+        if self.entities.is_empty() || params.get("action").map(|s| s.as_str()) != Some("wbgetentities") {
+            return None;
+        }
+        let entities: serde_json::Map<String, Value> = params
+            .get("ids")?
+            .split('|')
+            .map(|id| {
+                let entity = self.entities.get(id).cloned().unwrap_or_else(|| json!({"id": id, "missing": ""}));
+                (id.to_string(), entity)
+            })
+            .collect();
+        Some(json!({"entities": entities}))
+    }
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+impl ApiClient for MockApiClient {
+    async fn get_query_api_json(&self, params: &HashMap<String, String>) -> Result<Value> {
+        match (self.find_response(params), &self.fallback) {
+            (Some(response), _) => Ok(response),
+            (None, Some(fallback)) => fallback.get_query_api_json(params).await,
+            (None, None) => Err(anyhow!("No mock response for {params:?}")),
+        }
+    }
+
+    async fn post_query_api_json(&self, params: &HashMap<String, String>) -> Result<Value> {
+        match (self.find_response(params), &self.fallback) {
+            (Some(response), _) => Ok(response),
+            (None, Some(fallback)) => fallback.post_query_api_json(params).await,
+            (None, None) => Err(anyhow!("No mock response for {params:?}")),
+        }
+    }
+
+    async fn get_edit_token(&self) -> Result<String> {
+        Ok("+\\".to_string())
+    }
+
+    async fn get_url(&self, url: &str) -> Result<String> {
+        match (self.urls.get(url), &self.fallback) {
+            (Some(body), _) => Ok(body.to_owned()),
+            (None, Some(fallback)) => fallback.get_url(url).await,
+            (None, None) => Err(anyhow!("No mock response for {url}")),
+        }
+    }
+
+    async fn sparql_query(&self, endpoint: &str, sparql: &str) -> Result<Value> {
+        let results = self.sparql.iter().find(|(query, _)| sparql.contains(query.as_str()));
+        match (results, &self.fallback) {
+            (Some((_, results)), _) => Ok(results.to_owned()),
+            (None, Some(fallback)) => fallback.sparql_query(endpoint, sparql).await,
+            (None, None) => Err(anyhow!("No mock response for SPARQL query at {endpoint}")),
+        }
+    }
+}
+
+/// Passes calls on to another client, and keeps the responses for writing test fixtures
+#[derive(Debug)]
+pub struct RecordingApiClient {
+    inner: Arc<dyn ApiClient>,
+    responses: Mutex<Vec<(HashMap<String, String>, Value)>>,
+    urls: Mutex<Vec<(String, String)>>,
+    sparql: Mutex<Vec<(String, Value)>>,
+}
+
+impl RecordingApiClient {
+    pub fn new(inner: Arc<dyn ApiClient>) -> Self {
+        Self {
+            inner,
+            responses: Mutex::new(vec![]),
+            urls: Mutex::new(vec![]),
+            sparql: Mutex::new(vec![]),
+        }
+    }
+
+    /// Recorded API calls, as (params, response)
+    pub fn responses(&self) -> Vec<(HashMap<String, String>, Value)> {
+        self.responses.lock().map(|r| r.clone()).unwrap_or_default()
+    }
+
+    /// Recorded URL calls, as (url, body)
+    pub fn urls(&self) -> Vec<(String, String)> {
+        self.urls.lock().map(|r| r.clone()).unwrap_or_default()
+    }
+
+    /// Recorded SPARQL queries, as (query, results)
+    pub fn sparql_results(&self) -> Vec<(String, Value)> {
+        self.sparql.lock().map(|r| r.clone()).unwrap_or_default()
+    }
+
+    fn record(&self, params: &HashMap<String, String>, response: &Value) {
+        if let Ok(mut responses) = self.responses.lock() {
+            responses.push((params.to_owned(), response.to_owned()));
+        }
+    }
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+impl ApiClient for RecordingApiClient {
+    async fn get_query_api_json(&self, params: &HashMap<String, String>) -> Result<Value> {
+        let ret = self.inner.get_query_api_json(params).await?;
+        self.record(params, &ret);
+        Ok(ret)
+    }
+
+    async fn post_query_api_json(&self, params: &HashMap<String, String>) -> Result<Value> {
+        let ret = self.inner.post_query_api_json(params).await?;
+        self.record(params, &ret);
+        Ok(ret)
+    }
+
+    async fn get_edit_token(&self) -> Result<String> {
+        self.inner.get_edit_token().await
+    }
+
+    async fn get_url(&self, url: &str) -> Result<String> {
+        let ret = self.inner.get_url(url).await?;
+        if let Ok(mut urls) = self.urls.lock() {
+            urls.push((url.to_string(), ret.to_owned()));
+        }
+        Ok(ret)
+    }
+
+    async fn sparql_query(&self, endpoint: &str, sparql: &str) -> Result<Value> {
+        let ret = self.inner.sparql_query(endpoint, sparql).await?;
+        if let Ok(mut queries) = self.sparql.lock() {
+            queries.push((sparql.to_string(), ret.to_owned()));
+        }
+        Ok(ret)
+    }
+
+    async fn sparql_endpoint(&self) -> Option<String> {
+        self.inner.sparql_endpoint().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mock_api_client() {
+        let lines = r#"{"params":{"action":"parse","prop":"wikitext"},"response":{"parse":{"wikitext":{"*":"Foo"}}}}
+            {"url":"https://example.org/","body":"Bar"}"#;
+        let client = MockApiClient::new_from_lines(lines).unwrap();
+        let params: HashMap<String, String> = [("action", "parse"), ("prop", "wikitext"), ("page", "X")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let j = client.post_query_api_json(&params).await.unwrap();
+        assert_eq!(j["parse"]["wikitext"]["*"], json!("Foo"));
+        assert_eq!(client.get_url("https://example.org/").await.unwrap(), "Bar");
+        let mut params = params;
+        params.insert("action".to_string(), "query".to_string());
+        assert!(client.get_query_api_json(&params).await.is_err());
+        assert_eq!(client.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_mock_api_client_entities() {
+        let mut client = MockApiClient::default();
+        client.add_entities_from_lines(r#"{"type":"item","id":"Q1","labels":{"en":{"language":"en","value":"universe"}}}"#).unwrap();
+        let params: HashMap<String, String> = [("action", "wbgetentities"), ("ids", "Q1|Q2")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let j = client.get_query_api_json(&params).await.unwrap();
+        assert_eq!(j["entities"]["Q1"]["labels"]["en"]["value"], json!("universe"));
+        assert_eq!(j["entities"]["Q2"], json!({"id": "Q2", "missing": ""}));
+        assert!(client.add_entity(json!({"type": "item"})).is_err());
+    }
+}
@@ -4,6 +4,8 @@ extern crate serde_json;
 use anyhow::Result;
 use std::sync::Arc;
 use tokio::sync::Mutex;
+use listeria::configuration::Configuration;
+use listeria::event_stream::EventStreamListener;
 use listeria::listeria_bot::ListeriaBot;
 use tokio::time::{sleep, Duration};
 use std::env;
@@ -25,15 +27,25 @@ toolforge-jobs run --image tf-php74 --mem 2500Mi --continuous --command '/data/p
 
 const DEFAULT_THREADS: usize = 8;
 
-async fn run_singles(threads: usize) {
+async fn run_singles(threads: usize, config_overrides: &[String]) {
     let running_counter = Arc::new(Mutex::new(0 as usize));
-    let bot = ListeriaBot::new("config.json").await.unwrap();
+    let bot = ListeriaBot::new_with_overrides("config.json", config_overrides).await.unwrap();
     let _ = bot.reset_running().await;
+    let config = bot.config().await;
+    if config.event_stream_enabled() {
+        let mut listener = EventStreamListener::new(&config, bot.queue().to_owned());
+        tokio::spawn(async move { listener.run().await });
+    }
     let bot = Arc::new(bot);
     loop {
         while *running_counter.lock().await>=threads {
             sleep(Duration::from_millis(100)).await;
         }
+        match bot.reload_config_if_changed().await {
+            Ok(true) => println!("Configuration reloaded"),
+            Ok(false) => {}
+            Err(e) => eprintln!("Could not reload configuration, keeping the old one: {e}"),
+        }
         let page = match bot.prepare_next_single_page().await {
             Ok(page) => page,
             Err(e) => {
@@ -58,7 +70,8 @@ async fn run_singles(threads: usize) {
 //#[tokio::main(flavor = "multi_thread", worker_threads = 4)]
 #[tokio::main]
 async fn main() -> Result<()> {
-    let argv: Vec<_> = env::args_os().collect();
+    // `--set key.path=value` overrides config.json values
+    let (argv, config_overrides) = Configuration::split_override_args(env::args_os())?;
     let threads = match argv.get(1) {
         Some(t) => t.to_owned().into_string().unwrap_or("".into()).parse::<usize>().unwrap_or(DEFAULT_THREADS),
         None => DEFAULT_THREADS
@@ -75,6 +88,6 @@ async fn main() -> Result<()> {
     // threaded_rt.block_on(async move {
     //     run_singles(threads).await;
     // });
-    run_singles(threads).await;
+    run_singles(threads, &config_overrides).await;
     Ok(())
 }
@@ -7,23 +7,152 @@ use listeria::configuration::Configuration;
 use listeria::listeria_page::ListeriaPage;
 use std::env;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
+use wikibase::mediawiki::api::Api;
 
-async fn update_page(_settings: &Config, page_title: &str, api_url: &str) -> Result<String> {
-    let config = Arc::new(Configuration::new_from_file("config.json").await.unwrap());
-    let mut mw_api = wikibase::mediawiki::api::Api::new(api_url).await?;
-    // let token = settings.get_string("user.token").expect("No oauth2 user.token");
-    // mw_api.set_oauth2(&token);
-    mw_api.set_oauth2(config.oauth2_token());
+/*
+USAGE
+main [--purge|--null-edit] [--pages-from FILE] [--timeout SECONDS] [--set key.path=value] WIKI_SERVER [PAGE...]
+main lint WIKI_SERVER [PAGE...]
+main --explain WIKI_SERVER [PAGE...]
+main [--wikitext-file FILE] [--sparql-results-file FILE] WIKI_SERVER PAGE
+eg: main en.wikipedia.org "User:Magnus Manske/listeria test"
+--pages-from reads one page title per line
+--timeout gives up on a page after that many seconds, leaving it unchanged; default from page_timeout in the config
+lint reports problems with the list definitions, without rendering or editing
+--explain prints the query, columns, sorting and sections of each list as JSON, without editing
+--wikitext-file and --sparql-results-file simulate the page with the wikitext and/or the saved JSON output
+  of the SPARQL service instead of loading them, and print the new wikitext without editing
+*/
 
-    let mw_api = Arc::new(RwLock::new(mw_api));
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Update,
+    Purge,
+    NullEdit,
+    Lint,
+    Explain,
+}
+
+async fn update_page(
+    _settings: &Config,
+    page_title: &str,
+    config: Arc<Configuration>,
+    mw_api: Arc<RwLock<Api>>,
+    options: &Options,
+) -> Result<String> {
+    let mode = options.mode;
     let mut page = ListeriaPage::new(config, mw_api, page_title.into()).await?;
+    if options.is_simulation() {
+        let read = |file: &Option<String>| -> Result<Option<String>> {
+            match file {
+                Some(file) => Ok(Some(
+                    std::fs::read_to_string(file).map_err(|e| anyhow!("Cannot read '{file}': {e}"))?,
+                )),
+                None => Ok(None),
+            }
+        };
+        page.do_simulate(read(&options.wikitext_file)?, read(&options.sparql_results_file)?, None);
+    }
+    if let Some(timeout) = options.timeout {
+        page.set_timeout(timeout)?;
+    }
+    match mode {
+        Mode::Purge => {
+            page.purge_page().await?;
+            return Ok(format!("{page_title} purged"));
+        }
+        Mode::NullEdit => {
+            page.null_edit().await?;
+            return Ok(format!("{page_title} null-edited"));
+        }
+        Mode::Lint => {
+            let problems = page.lint().await.map_err(|e|anyhow!("{e:?}"))?;
+            return Ok(match problems.is_empty() {
+                true => format!("{page_title}: no problems found"),
+                false => format!("{page_title}:\n{}", problems.join("\n")),
+            });
+        }
+        Mode::Update | Mode::Explain => {}
+    }
     page.run().await.map_err(|e|anyhow!("{e:?}"))?;
+    if mode == Mode::Explain {
+        return Ok(format!("{page_title}:\n{}", serde_json::to_string_pretty(&page.explain())?));
+    }
+    if options.is_simulation() {
+        let mut ret = format!("{page_title} simulated:\n{}", page.new_wikitext()?);
+        for message in page.list_errors().into_iter().chain(page.list_warnings()) {
+            ret += &format!("\n{message}");
+        }
+        return Ok(ret);
+    }
 
-    Ok(match page.update_source_page().await.map_err(|e|anyhow!("{e:?}"))? {
-        true => format!("{page_title} edited"),
-        false => format!("{page_title} not edited"),
-    })
+    let edit_result = page.update_source_page().await.map_err(|e|anyhow!("{e:?}"))?;
+    let mut ret = format!("{page_title} {}", edit_result.describe());
+    for message in page.list_errors().into_iter().chain(page.list_warnings()) {
+        ret += &format!("\n{message}");
+    }
+    Ok(ret)
+}
+
+/// The flags that are not config overrides
+struct Options {
+    mode: Mode,
+    pages_from: Option<String>,
+    timeout: Option<Duration>,
+    wikitext_file: Option<String>,
+    sparql_results_file: Option<String>,
+}
+
+impl Options {
+    /// Simulated pages are rendered from local files, and not saved
+    fn is_simulation(&self) -> bool {
+        self.wikitext_file.is_some() || self.sparql_results_file.is_some()
+    }
+}
+
+/// Takes the mode flags, the `--pages-from` file, the `--timeout` and the simulation files off the positional arguments
+fn split_mode_args(args: Vec<String>) -> Result<(Options, Vec<String>)> {
+    let mut mode = Mode::Update;
+    let mut pages_from = None;
+    let mut timeout = None;
+    let mut wikitext_file = None;
+    let mut sparql_results_file = None;
+    let mut positional = vec![];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--purge" => mode = Mode::Purge,
+            "--null-edit" => mode = Mode::NullEdit,
+            "--explain" => mode = Mode::Explain,
+            "lint" if positional.len() == 1 => mode = Mode::Lint, // Subcommand, right after the program name
+            "--pages-from" => {
+                pages_from = Some(args.next().ok_or_else(|| anyhow!("--pages-from needs a file name"))?)
+            }
+            "--timeout" => {
+                let seconds = args.next().and_then(|s| s.parse::<u64>().ok());
+                let seconds = seconds.ok_or_else(|| anyhow!("--timeout needs a number of seconds"))?;
+                timeout = Some(Duration::from_secs(seconds))
+            }
+            "--wikitext-file" => {
+                wikitext_file = Some(args.next().ok_or_else(|| anyhow!("--wikitext-file needs a file name"))?)
+            }
+            "--sparql-results-file" => {
+                let file = args.next().ok_or_else(|| anyhow!("--sparql-results-file needs a file name"))?;
+                sparql_results_file = Some(file)
+            }
+            _ => positional.push(arg),
+        }
+    }
+    let options = Options {
+        mode,
+        pages_from,
+        timeout,
+        wikitext_file,
+        sparql_results_file,
+    };
+    Ok((options, positional))
 }
 
 #[tokio::main]
@@ -35,17 +164,66 @@ async fn main() -> Result<()> {
         .build()
         .unwrap_or_else(|_| panic!("INI file '{}' can't be opened", ini_file));
 
-    let args: Vec<String> = env::args().collect();
+    let (args, config_overrides) = Configuration::split_override_args(env::args_os())?;
+    let args: Vec<String> = args.into_iter().map(|arg| arg.to_string_lossy().into_owned()).collect();
+    let (mut options, args) = split_mode_args(args)?;
     let wiki_server = args
         .get(1)
         .ok_or_else(|| anyhow!("No wiki server argument"))?;
-    let page = args.get(2).ok_or_else(|| anyhow!("No page argument"))?;
+    let mut pages: Vec<String> = args.iter().skip(2).cloned().collect();
+    if let Some(file) = &options.pages_from {
+        let text = std::fs::read_to_string(file)?;
+        pages.extend(
+            text.lines()
+                .map(|line| line.trim().to_string())
+                .filter(|line| !line.is_empty()),
+        );
+    }
+    if pages.is_empty() {
+        return Err(anyhow!("No page argument"));
+    }
 
+    let config = Configuration::new_from_file_with_overrides("config.json", &config_overrides).await?;
+    let config = Arc::new(config);
     let wiki_api = format!("https://{}/w/api.php", &wiki_server);
-    let message = match update_page(&settings, &page, &wiki_api).await {
-        Ok(m) => format!("OK: {}", m),
-        Err(e) => format!("ERROR: {}", e),
-    };
-    println!("{}", message);
+    let mw_api = config.client_manager().api(&wiki_api).await?;
+    options.timeout = options.timeout.or_else(|| config.page_timeout());
+
+    for page in &pages {
+        let message = match update_page(&settings, page, config.clone(), mw_api.clone(), &options).await {
+            Ok(m) => format!("OK: {}", m),
+            Err(e) => format!("ERROR: {}", e),
+        };
+        println!("{}", message);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(a: &[&str]) -> Vec<String> {
+        a.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_split_mode_args_simulation() {
+        let (options, positional) = split_mode_args(args(&[
+            "main", "--wikitext-file", "page.wiki", "--sparql-results-file", "results.json", "en.wikipedia.org", "Foo",
+        ]))
+        .unwrap();
+        assert_eq!(positional, args(&["main", "en.wikipedia.org", "Foo"]));
+        assert_eq!(options.wikitext_file.as_deref(), Some("page.wiki"));
+        assert_eq!(options.sparql_results_file.as_deref(), Some("results.json"));
+        assert!(options.is_simulation());
+
+        let (options, _) = split_mode_args(args(&["main", "--sparql-results-file", "results.json", "x", "Foo"])).unwrap();
+        assert!(options.wikitext_file.is_none());
+        assert!(options.is_simulation());
+
+        let (options, _) = split_mode_args(args(&["main", "en.wikipedia.org", "Foo"])).unwrap();
+        assert!(!options.is_simulation());
+        assert!(split_mode_args(args(&["main", "en.wikipedia.org", "Foo", "--wikitext-file"])).is_err());
+    }
+}
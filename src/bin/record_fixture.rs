@@ -0,0 +1,116 @@
+use anyhow::{anyhow, Result};
+use listeria::api_client::{LiveApiClient, RecordingApiClient};
+use listeria::client_manager::ClientManager;
+use listeria::configuration::Configuration;
+use listeria::listeria_page::ListeriaPage;
+use listeria::wiki_login::WikiLogin;
+use serde_json::{json, Value};
+use std::env;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use wikibase::mediawiki::api::Api;
+
+/*
+Runs a page live, and writes a test fixture with its current wikitext, SPARQL results, rendered lists,
+API responses, and the Wikidata entities and queries of its lists.
+USAGE: record_fixture WIKI_SERVER PAGE [OUTPUT_FILE]
+eg: record_fixture en.wikipedia.org "User:Magnus Manske/listeria test" test_data/my_test.fixture
+Check the EXPECTED section before committing a new fixture!
+*/
+
+fn mock_api_lines(recorder: &RecordingApiClient) -> Vec<String> {
+    let mut ret = vec![];
+    for (params, response) in recorder.responses() {
+        if params.get("action").map(|a| a.as_str()) == Some("parse") {
+            continue; // Simulated runs use the WIKITEXT section instead
+        }
+        ret.push(json!({"params": params, "response": response}).to_string());
+    }
+    for (url, body) in recorder.urls() {
+        ret.push(json!({"url": url, "body": body}).to_string());
+    }
+    let mut seen = std::collections::HashSet::new();
+    ret.retain(|line| seen.insert(line.to_owned()));
+    ret
+}
+
+/// The entities loaded through the Wikibase API, one per line
+fn mock_entity_lines(recorder: &RecordingApiClient) -> Vec<String> {
+    let mut ret = vec![];
+    for (params, response) in recorder.responses() {
+        if params.get("action").map(|a| a.as_str()) != Some("wbgetentities") {
+            continue;
+        }
+        let entities = response["entities"].as_object().into_iter().flat_map(|entities| entities.values());
+        ret.extend(entities.filter(|entity| entity.get("missing").is_none()).map(|entity| entity.to_string()));
+    }
+    ret.sort();
+    ret.dedup();
+    ret
+}
+
+/// The SPARQL queries run by the lists, with their results
+fn mock_sparql_lines(recorder: &RecordingApiClient) -> Vec<String> {
+    recorder
+        .sparql_results()
+        .iter()
+        .map(|(sparql, results)| json!({"sparql": sparql, "results": results}).to_string())
+        .collect()
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args: Vec<String> = env::args().collect();
+    let wiki_server = args.get(1).ok_or_else(|| anyhow!("No wiki server argument"))?;
+    let page_title = args.get(2).ok_or_else(|| anyhow!("No page argument"))?;
+
+    let j = Configuration::read_json_file("config.json", &[])?;
+    let client_manager = ClientManager::new(&j["http"], WikiLogin::new_from_json(&j["wiki_login"]));
+    let default_api = j["default_api"].as_str().unwrap_or("wikidata");
+    let wb_api_url = j["apis"][default_api]
+        .as_str()
+        .or_else(|| j["apis"][default_api]["api"].as_str())
+        .ok_or_else(|| anyhow!("No API '{default_api}' in config.json"))?
+        .to_string();
+    let wb_recorder = Arc::new(RecordingApiClient::new(client_manager.api_client(&wb_api_url).await?));
+    client_manager.register_api_client(&wb_api_url, wb_recorder.clone());
+    let config = Arc::new(Configuration::new_from_json_with_client_manager(j, client_manager).await?);
+    let wiki_api = format!("https://{}/w/api.php", &wiki_server);
+    let mut mw_api = Api::new(&wiki_api).await?;
+    mw_api.set_oauth2(&config.oauth2_token());
+    let mw_api = Arc::new(RwLock::new(mw_api));
+
+    let recorder = Arc::new(RecordingApiClient::new(Arc::new(LiveApiClient::new(mw_api.clone()))));
+    let mut page = ListeriaPage::new(config, mw_api, page_title.to_owned()).await?;
+    page.set_api_client(recorder.clone())?;
+    let wikitext = page.load_page_as("wikitext").await.map_err(|e| anyhow!("{e:?}"))?;
+    page.run().await.map_err(|e| anyhow!("{e:?}"))?;
+    let expected = page.as_wikitext()?.join("\n\n----\n\n");
+    let sparql_results = page
+        .elements()
+        .iter()
+        .find_map(|element| element.sparql_results())
+        .map(Value::to_string)
+        .ok_or_else(|| anyhow!("No list with SPARQL results on this page"))?;
+
+    let sections = [
+        ("API", wiki_api),
+        ("PAGETITLE", page_title.to_owned()),
+        ("WIKITEXT", wikitext),
+        ("EXPECTED", expected.trim().to_string()),
+        ("SPARQL_RESULTS", sparql_results),
+        ("MOCK_API", mock_api_lines(&recorder).join("\n")),
+        ("MOCK_ENTITIES", mock_entity_lines(&wb_recorder).join("\n")),
+        ("MOCK_SPARQL", mock_sparql_lines(&wb_recorder).join("\n")),
+    ];
+    let fixture: Vec<String> = sections
+        .iter()
+        .map(|(key, value)| format!("$$$$ {key}\n\n{value}\n"))
+        .collect();
+    let fixture = fixture.join("\n");
+    match args.get(3) {
+        Some(path) => std::fs::write(path, fixture)?,
+        None => print!("{fixture}"),
+    }
+    Ok(())
+}
@@ -0,0 +1,21 @@
+use anyhow::Result;
+use listeria::configuration::Configuration;
+use listeria::listeria_bot::ListeriaBot;
+use listeria::update_server::UpdateServer;
+use std::env;
+use std::sync::Arc;
+
+/*
+Serves on-demand updates; port, tokens etc. are in the "server" section of config.json
+curl -X POST -H 'Authorization: Bearer TOKEN' 'http://localhost:8000/update?wiki=enwiki&page=User:Magnus_Manske/listeria_test'
+*/
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // `--set key.path=value` overrides config.json values
+    let (_, config_overrides) = Configuration::split_override_args(env::args_os())?;
+
+    let bot = ListeriaBot::new_with_overrides("config.json", &config_overrides).await?;
+    let server = UpdateServer::new(Arc::new(bot)).await?;
+    server.run().await
+}
@@ -0,0 +1,17 @@
+use crate::listeria_list::ListeriaList;
+use crate::result_cell_part::ResultCellPart;
+
+/// Overrides how parts of table cells are rendered, for library users who need bespoke formatting.
+/// Register with `ListeriaList::add_cell_formatter` or `ListeriaPage::add_cell_formatter`.
+pub trait CellFormatter: std::fmt::Debug + Send + Sync {
+    /// Wikitext for the part, or None to fall through to the next formatter and finally the default rendering.
+    /// The column is available as `list.column(colnum)`.
+    fn format_part(
+        &self,
+        list: &ListeriaList,
+        part: &ResultCellPart,
+        rownum: usize,
+        colnum: usize,
+        partnum: usize,
+    ) -> Option<String>;
+}
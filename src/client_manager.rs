@@ -0,0 +1,347 @@
+use crate::api_client::{ApiClient, LiveApiClient};
+use crate::site_info::SiteInfo;
+use crate::wiki_login::WikiLogin;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{Mutex, OwnedSemaphorePermit, RwLock, Semaphore};
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::time::{sleep_until, Duration, Instant};
+use wikibase::mediawiki::api::Api;
+
+const DEFAULT_MAX_CONCURRENT_PER_HOST: usize = 4;
+
+/// Creates and shares the wiki API clients, from the "http" section of the config file.
+/// There is one `Api` per API URL, so its HTTP connections are kept alive and reused between pages.
+/// Requests through `api_client` wait their turn per host: at most `max_concurrent_per_host`
+/// at a time, at least `min_request_interval_ms` apart. This includes SPARQL queries, by the host of the endpoint.
+/// The clients use the current token of the bot login, also after it is renewed.
+#[derive(Debug)]
+pub struct ClientManager {
+    apis: Mutex<HashMap<String, Arc<RwLock<Api>>>>,
+    registered: std::sync::Mutex<HashMap<String, Arc<dyn ApiClient>>>, // By API URL, used instead of live clients
+    limiters: Arc<HostLimiters>,
+    site_info: Mutex<HashMap<String, Arc<SiteInfo>>>, // By wiki
+    wiki_login: WikiLogin,
+}
+
+impl Default for ClientManager {
+    fn default() -> Self {
+        Self::new(&Value::Null, WikiLogin::default())
+    }
+}
+
+impl ClientManager {
+    pub fn new(j: &Value, wiki_login: WikiLogin) -> Self {
+        Self {
+            apis: Mutex::new(HashMap::new()),
+            registered: std::sync::Mutex::new(HashMap::new()),
+            limiters: Arc::new(HostLimiters {
+                hosts: std::sync::Mutex::new(HashMap::new()),
+                max_concurrent_per_host: j["max_concurrent_per_host"]
+                    .as_u64()
+                    .map(|n| n.max(1) as usize)
+                    .unwrap_or(DEFAULT_MAX_CONCURRENT_PER_HOST),
+                min_request_interval_ms: j["min_request_interval_ms"].as_u64().unwrap_or(0),
+            }),
+            site_info: Mutex::new(HashMap::new()),
+            wiki_login,
+        }
+    }
+
+    /// The bot login the API clients use; shares its tokens with the login it was created with
+    pub fn wiki_login(&self) -> &WikiLogin {
+        &self.wiki_login
+    }
+
+    /// A new, unshared API client, logged in with the bot token
+    pub async fn create_api(&self, api_url: &str) -> Result<Api> {
+        let mut api = Api::new(api_url).await?;
+        let oauth2_token = self.wiki_login.token();
+        if !oauth2_token.is_empty() {
+            api.set_oauth2(&oauth2_token);
+        }
+        Ok(api)
+    }
+
+    /// The shared API client for an API URL; created on first use
+    pub async fn api(&self, api_url: &str) -> Result<Arc<RwLock<Api>>> {
+        if let Some(api) = self.apis.lock().await.get(api_url) {
+            return Ok(api.clone());
+        }
+        // Not locked while the client loads the site info, so other wikis do not wait for it;
+        // if another task created one in the meantime, that one is kept
+        let api = Arc::new(RwLock::new(self.create_api(api_url).await?));
+        Ok(self.apis.lock().await.entry(api_url.to_string()).or_insert(api).clone())
+    }
+
+    /// Calls through `mw_api`, paced by the limits of its host, with the current bot token
+    pub async fn api_client_for(&self, mw_api: Arc<RwLock<Api>>) -> Result<Arc<dyn ApiClient>> {
+        let host = Self::host(mw_api.read().await.api_url())?;
+        Ok(Arc::new(PacedApiClient {
+            inner: Arc::new(LiveApiClient::new(mw_api.clone())),
+            host,
+            limiters: self.limiters.clone(),
+            login: Some(LoginSync {
+                api: mw_api,
+                wiki_login: self.wiki_login.clone(),
+                oauth2_token: Mutex::new(String::new()),
+            }),
+        }))
+    }
+
+    /// Makes `api_client` return `client` for `api_url`, eg a `MockApiClient` in tests
+    pub fn register_api_client(&self, api_url: &str, client: Arc<dyn ApiClient>) {
+        let mut registered = self.registered.lock().unwrap_or_else(|e| e.into_inner());
+        registered.insert(api_url.to_string(), client);
+    }
+
+    /// Like `api`, with the calls paced; or the client registered for `api_url`
+    pub async fn api_client(&self, api_url: &str) -> Result<Arc<dyn ApiClient>> {
+        let registered = self.registered.lock().unwrap_or_else(|e| e.into_inner()).get(api_url).cloned();
+        if let Some(client) = registered {
+            return Ok(client);
+        }
+        let mw_api = self.api(api_url).await?;
+        self.api_client_for(mw_api).await
+    }
+
+    /// Namespaces of a wiki, asked for once per wiki
+    pub async fn site_info(&self, wiki: &str, api_client: &dyn ApiClient) -> Result<Arc<SiteInfo>> {
+        if let Some(site_info) = self.site_info.lock().await.get(wiki) {
+            return Ok(site_info.clone());
+        }
+        let params: HashMap<String, String> = [
+            ("action", "query"),
+            ("meta", "siteinfo"),
+            ("siprop", "general|namespaces|namespacealiases"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let j = api_client.get_query_api_json(&params).await?;
+        if !j["query"]["namespaces"].is_object() {
+            return Err(anyhow!("No site info for {wiki}"));
+        }
+        Ok(self.site_info_from_json(wiki, &j["query"]).await)
+    }
+
+    /// Like `site_info`, from the "query" part of a siteinfo result that is already there, eg in an `Api`
+    pub async fn site_info_from_json(&self, wiki: &str, j: &Value) -> Arc<SiteInfo> {
+        self.site_info
+            .lock()
+            .await
+            .entry(wiki.to_string())
+            .or_insert_with(|| Arc::new(SiteInfo::new(j)))
+            .clone()
+    }
+
+    /// "https://de.wikipedia.org/w/api.php" => "de.wikipedia.org"
+    fn host(url: &str) -> Result<String> {
+        let rest = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+        let host = rest.split(['/', '?', '#']).next().unwrap_or_default().to_lowercase();
+        match host.is_empty() {
+            true => Err(anyhow!("No host in API URL '{url}'")),
+            false => Ok(host),
+        }
+    }
+}
+
+/// Request limits per host
+#[derive(Debug)]
+struct HostLimiters {
+    hosts: std::sync::Mutex<HashMap<String, Arc<HostLimiter>>>,
+    max_concurrent_per_host: usize,
+    min_request_interval_ms: u64,
+}
+
+impl HostLimiters {
+    fn limiter(&self, host: &str) -> Arc<HostLimiter> {
+        let mut hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+        hosts
+            .entry(host.to_string())
+            .or_insert_with(|| {
+                Arc::new(HostLimiter::new(
+                    self.max_concurrent_per_host,
+                    self.min_request_interval_ms,
+                ))
+            })
+            .clone()
+    }
+
+    /// Waits for the limits of the host of `url`
+    async fn acquire(&self, url: &str) -> Result<OwnedSemaphorePermit> {
+        self.limiter(&ClientManager::host(url)?).acquire().await
+    }
+}
+
+/// Request limits of one host
+#[derive(Debug)]
+struct HostLimiter {
+    semaphore: Arc<Semaphore>,
+    #[cfg(not(target_arch = "wasm32"))]
+    next_request: Mutex<Option<Instant>>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    min_request_interval_ms: u64,
+}
+
+impl HostLimiter {
+    fn new(max_concurrent: usize, min_request_interval_ms: u64) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max_concurrent)),
+            #[cfg(not(target_arch = "wasm32"))]
+            next_request: Mutex::new(None),
+            min_request_interval_ms,
+        }
+    }
+
+    /// Waits for a free slot, and for the interval since the previous request
+    async fn acquire(&self) -> Result<OwnedSemaphorePermit> {
+        let permit = self.semaphore.clone().acquire_owned().await?;
+        #[cfg(not(target_arch = "wasm32"))] // No timers in the browser runtime
+        if self.min_request_interval_ms > 0 {
+            let start = {
+                let mut next_request = self.next_request.lock().await;
+                let start = next_request.map_or_else(Instant::now, |next| next.max(Instant::now()));
+                *next_request = Some(start + Duration::from_millis(self.min_request_interval_ms));
+                start
+            };
+            sleep_until(start).await;
+        }
+        Ok(permit)
+    }
+}
+
+/// Sets the current bot token on a shared `Api` when the login has renewed it
+#[derive(Debug)]
+struct LoginSync {
+    api: Arc<RwLock<Api>>,
+    wiki_login: WikiLogin,
+    oauth2_token: Mutex<String>, // Last token set on `api`
+}
+
+impl LoginSync {
+    async fn sync(&self) {
+        let current = self.wiki_login.token();
+        let mut oauth2_token = self.oauth2_token.lock().await;
+        if current.is_empty() || *oauth2_token == current {
+            return;
+        }
+        self.api.write().await.set_oauth2(&current);
+        *oauth2_token = current;
+    }
+}
+
+/// An API client that waits for the limits of the host of each request; the wiki API is on `host`
+#[derive(Debug)]
+struct PacedApiClient {
+    inner: Arc<dyn ApiClient>,
+    host: String,
+    limiters: Arc<HostLimiters>,
+    login: Option<LoginSync>,
+}
+
+impl PacedApiClient {
+    /// Waits for the limits of the wiki API host, and brings the token up to date
+    async fn acquire(&self) -> Result<OwnedSemaphorePermit> {
+        let permit = self.limiters.limiter(&self.host).acquire().await?;
+        if let Some(login) = &self.login {
+            login.sync().await;
+        }
+        Ok(permit)
+    }
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+impl ApiClient for PacedApiClient {
+    async fn get_query_api_json(&self, params: &HashMap<String, String>) -> Result<Value> {
+        let _permit = self.acquire().await?;
+        self.inner.get_query_api_json(params).await
+    }
+
+    async fn post_query_api_json(&self, params: &HashMap<String, String>) -> Result<Value> {
+        let _permit = self.acquire().await?;
+        self.inner.post_query_api_json(params).await
+    }
+
+    async fn get_edit_token(&self) -> Result<String> {
+        let _permit = self.acquire().await?;
+        self.inner.get_edit_token().await
+    }
+
+    async fn get_url(&self, url: &str) -> Result<String> {
+        let _permit = self.limiters.acquire(url).await?; // Other hosts, eg Toolforge
+        self.inner.get_url(url).await
+    }
+
+    async fn sparql_query(&self, endpoint: &str, sparql: &str) -> Result<Value> {
+        let _permit = self.limiters.acquire(endpoint).await?;
+        self.inner.sparql_query(endpoint, sparql).await
+    }
+
+    async fn sparql_endpoint(&self) -> Option<String> {
+        self.inner.sparql_endpoint().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_client::MockApiClient;
+
+    #[test]
+    fn test_host() {
+        assert_eq!(
+            ClientManager::host("https://de.wikipedia.org/w/api.php").unwrap(),
+            "de.wikipedia.org"
+        );
+        assert_eq!(ClientManager::host("http://localhost:8080?x").unwrap(), "localhost:8080");
+        assert!(ClientManager::host("https:///w/api.php").is_err());
+    }
+
+    #[test]
+    fn test_new() {
+        let manager = ClientManager::new(
+            &json!({"max_concurrent_per_host":0,"min_request_interval_ms":200}),
+            WikiLogin::default(),
+        );
+        assert_eq!(manager.limiters.max_concurrent_per_host, 1);
+        assert_eq!(manager.limiters.min_request_interval_ms, 200);
+        assert_eq!(
+            ClientManager::default().limiters.max_concurrent_per_host,
+            DEFAULT_MAX_CONCURRENT_PER_HOST
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pacing() {
+        let manager = ClientManager::new(&json!({"min_request_interval_ms":50}), WikiLogin::default());
+        let mut mock = MockApiClient::default();
+        mock.add_response(&[("action", "query")], json!({"batchcomplete":""}));
+        mock.add_sparql_results("SELECT", json!({"results":{"bindings":[]}}));
+        let client = PacedApiClient {
+            inner: Arc::new(mock),
+            host: "www.wikidata.org".to_string(),
+            limiters: manager.limiters.clone(),
+            login: None,
+        };
+        let params: HashMap<String, String> = [("action".to_string(), "query".to_string())].into();
+
+        // Three requests to the wiki API, and three SPARQL queries to another host, each 50ms apart
+        let start = Instant::now();
+        let (api, sparql) = futures::join!(
+            futures::future::try_join_all((0..3).map(|_| client.get_query_api_json(&params))),
+            futures::future::try_join_all(
+                (0..3).map(|_| client.sparql_query("https://query.wikidata.org/sparql", "SELECT ?item {}"))
+            ),
+        );
+        api.unwrap();
+        sparql.unwrap();
+        // The clock only moves when every task waits, so the pacing is all there is to the time taken
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+        assert_eq!(manager.limiters.hosts.lock().unwrap().len(), 2);
+    }
+}
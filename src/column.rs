@@ -1,8 +1,11 @@
+use crate::expression::Expression;
 use crate::listeria_list::ListeriaList;
 
 use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use wikibase::entity::EntityTrait;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ColumnType {
     Number,
     Label,
@@ -10,11 +13,23 @@ pub enum ColumnType {
     AliasLang(String),
     Description,
     Item,
+    ItemLabelQid, // Label, and the QID linked to Wikidata
+    ItemTemplate, // The QID in the local Q template of the wiki, see `Configuration::get_item_template`
     Qid,
+    Modified,
+    StatementCount,
+    IdentifierCount,
+    HasProperty(String),
+    Sitelink(String), // The page on another wiki, "wiki:frwiki"
+    Age((String, Option<String>)), // Years between two date properties, or from one until today
     Property(String),
+    PropertyFallback(Vec<String>),    // First property with a value, "P569|P571"; "P569{{!}}P571" in the template
+    PropertyCombination(Vec<String>), // All properties in one cell, "P735+P734"
+    PropertyPath(Vec<String>),        // Follows item values to another item, "path/P50/P569" for the author's birth date
     PropertyQualifier((String, String)),
     PropertyQualifierValue((String, String, String)),
     Field(String),
+    Expression(String), // Evaluated over the SPARQL fields of the row, "?pop / ?area", see `Expression`
     Unknown,
 }
 
@@ -29,19 +44,45 @@ impl ColumnType {
                 .case_insensitive(true)
                 .build()
                 .expect("RE_ALIAS_LANG does not parse");
+            static ref RE_HAS_PROPERTY: Regex = RegexBuilder::new(r#"^has/([Pp]\d+)$"#)
+                .case_insensitive(true)
+                .build()
+                .expect("RE_HAS_PROPERTY does not parse");
             static ref RE_PROPERTY: Regex = Regex::new(r#"^([Pp]\d+)$"#).expect("RE_PROPERTY does not parse");
             static ref RE_PROP_QUAL: Regex =
                 Regex::new(r#"^\s*([Pp]\d+)\s*/\s*([Pp]\d+)\s*$"#).expect("RE_PROP_QUAL does not parse");
             static ref RE_PROP_QUAL_VAL: Regex =
                 Regex::new(r#"^\s*([Pp]\d+)\s*/\s*([Qq]\d+)\s*/\s*([Pp]\d+)\s*$"#).expect("RE_PROP_QUAL_VAL does not parse");
+            static ref RE_PROP_FALLBACK: Regex =
+                Regex::new(r#"^\s*[Pp]\d+(\s*\|\s*[Pp]\d+)+\s*$"#).expect("RE_PROP_FALLBACK does not parse");
+            static ref RE_PROP_COMBINATION: Regex =
+                Regex::new(r#"^\s*[Pp]\d+(\s*\+\s*[Pp]\d+)+\s*$"#).expect("RE_PROP_COMBINATION does not parse");
+            static ref RE_AGE: Regex = RegexBuilder::new(r#"^\s*age\s*/\s*([Pp]\d+)\s*(?:/\s*([Pp]\d+)\s*)?$"#)
+                .case_insensitive(true)
+                .build()
+                .expect("RE_AGE does not parse");
+            static ref RE_PROP_PATH: Regex = RegexBuilder::new(r#"^\s*path(\s*/\s*[Pp]\d+){2,}\s*$"#)
+                .case_insensitive(true)
+                .build()
+                .expect("RE_PROP_PATH does not parse");
+            static ref RE_SITELINK: Regex = RegexBuilder::new(r#"^\s*wiki\s*:\s*([a-z_]+)\s*$"#)
+                .case_insensitive(true)
+                .build()
+                .expect("RE_SITELINK does not parse");
             static ref RE_FIELD: Regex = Regex::new(r#"^\?(.+)$"#).expect("RE_FIELD does not parse");
+            static ref RE_PLAIN_FIELD: Regex = Regex::new(r#"^\s*\?\w+\s*$"#).expect("RE_PLAIN_FIELD does not parse");
         }
         match s.to_lowercase().as_str() {
             "number" => return ColumnType::Number,
             "label" => return ColumnType::Label,
             "description" => return ColumnType::Description,
-            "item" => return ColumnType::Item,
-            "qid" => return ColumnType::Qid,
+            "item" | "item/wikidata" => return ColumnType::Item,
+            "item/label" => return ColumnType::ItemLabelQid,
+            "item/template" => return ColumnType::ItemTemplate,
+            "qid" | "item/bare" => return ColumnType::Qid,
+            "modified" => return ColumnType::Modified,
+            "statements" => return ColumnType::StatementCount,
+            "identifiers" => return ColumnType::IdentifierCount,
             _ => {}
         }
         if let Some(caps) = RE_LABEL_LANG.captures(&s) {
@@ -52,6 +93,23 @@ impl ColumnType {
             let ret = caps.get(1).map(|s|s.as_str().to_lowercase()).unwrap_or_default();
             return ColumnType::AliasLang(ret);
         }
+        if let Some(caps) = RE_HAS_PROPERTY.captures(s) {
+            let ret = caps.get(1).map(|s|s.as_str().to_uppercase()).unwrap_or_default();
+            return ColumnType::HasProperty(ret);
+        }
+        if let Some(caps) = RE_SITELINK.captures(s) {
+            let ret = caps.get(1).map(|s|s.as_str().to_lowercase()).unwrap_or_default();
+            return ColumnType::Sitelink(ret);
+        }
+        if RE_PROP_PATH.is_match(s) {
+            return ColumnType::PropertyPath(s.split('/').skip(1).map(|p| p.trim().to_uppercase()).collect());
+        }
+        if let Some(caps) = RE_AGE.captures(s) {
+            return ColumnType::Age((
+                caps.get(1).map(|s|s.as_str().to_uppercase()).unwrap_or_default(),
+                caps.get(2).map(|s|s.as_str().to_uppercase()),
+            ));
+        }
         if let Some(caps) = RE_PROPERTY.captures(&s) {
             let ret = caps.get(1).map(|s|s.as_str().to_uppercase()).unwrap_or_default();
             return ColumnType::Property(ret);
@@ -69,6 +127,20 @@ impl ColumnType {
                 caps.get(3).map(|s|s.as_str().to_uppercase()).unwrap_or_default(),
             ));
         }
+        let properties = |separator: char| {
+            s.split(separator)
+                .map(|p| p.trim().to_uppercase())
+                .collect::<Vec<String>>()
+        };
+        if RE_PROP_FALLBACK.is_match(s) {
+            return ColumnType::PropertyFallback(properties('|'));
+        }
+        if RE_PROP_COMBINATION.is_match(s) {
+            return ColumnType::PropertyCombination(properties('+'));
+        }
+        if s.contains('?') && !RE_PLAIN_FIELD.is_match(s) && Expression::new(s).is_ok() {
+            return ColumnType::Expression(s.trim().to_string());
+        }
         if let Some(caps) = RE_FIELD.captures(&s) {
             let ret = caps.get(1).map(|s|s.as_str().to_uppercase()).unwrap_or_default();
             return ColumnType::Field(ret);
@@ -82,43 +154,349 @@ impl ColumnType {
             Self::Label => "label".to_string(),
             Self::Description => "desc".to_string(),
             Self::Item => "item".to_string(),
+            Self::ItemLabelQid => "item_label".to_string(),
+            Self::ItemTemplate => "item_template".to_string(),
             Self::Qid => "qid".to_string(),
+            Self::Modified => "modified".to_string(),
+            Self::StatementCount => "statements".to_string(),
+            Self::IdentifierCount => "identifiers".to_string(),
             Self::LabelLang(l) => format!("language:{}", l),
             Self::AliasLang(l) => format!("alias:{}", l),
+            Self::HasProperty(p) => format!("has_{}", p.to_lowercase()),
+            Self::Sitelink(site) => format!("sitelink_{site}"),
+            Self::Age((start, end)) => match end {
+                Some(end) => format!("age_{}_{}", start.to_lowercase(), end.to_lowercase()),
+                None => format!("age_{}", start.to_lowercase()),
+            },
             Self::Property(p) => p.to_lowercase(),
+            Self::PropertyFallback(v) => v.join("_or_").to_lowercase(),
+            Self::PropertyCombination(v) => v.join("_and_").to_lowercase(),
+            Self::PropertyPath(v) => format!("path_{}", v.join("_").to_lowercase()),
             Self::PropertyQualifier((p, q)) => p.to_lowercase() + "_" + &q.to_lowercase(),
             Self::PropertyQualifierValue((p, q, v)) => {
                 p.to_lowercase() + "_" + &q.to_lowercase() + "_" + &v.to_lowercase()
             }
             Self::Field(f) => f.to_lowercase(),
+            Self::Expression(_) => "expression".to_string(),
             Self::Unknown => "unknown".to_string(),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+impl ColumnType {
+    /// The value of an item metadata column (last modification, statement or identifier count)
+    pub fn entity_metadata(&self, entity: &wikibase::entity::Entity) -> Option<String> {
+        match self {
+            Self::Modified => entity.to_json()["modified"].as_str().map(|s| s.to_string()),
+            Self::StatementCount => Some(entity.claims().len().to_string()),
+            Self::IdentifierCount => Some(
+                entity
+                    .claims()
+                    .iter()
+                    .filter(|statement| {
+                        *statement.main_snak().datatype() == wikibase::SnakDataType::ExternalId
+                    })
+                    .count()
+                    .to_string(),
+            ),
+            _ => None,
+        }
+    }
+}
+
+/// A constraint on the statements shown in a property column, eg `P2048[unit=Q11573]`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum StatementFilter {
+    Unit(String),
+    Qualifier((String, String)), // Property, value
+}
+
+impl StatementFilter {
+    /// Splits "P106[P31=Q5,P642=Q1]" into "P106" and its filters
+    pub fn split_column(s: &str) -> (String, Vec<Self>) {
+        lazy_static! {
+            static ref RE_FILTERS: Regex = Regex::new(r#"^(.+?)\s*\[(.*)\]\s*$"#).expect("RE_FILTERS does not parse");
+        }
+        match RE_FILTERS.captures(s) {
+            Some(caps) => (
+                caps[1].to_string(),
+                caps[2].split(',').filter_map(Self::new).collect(),
+            ),
+            None => (s.to_string(), vec![]),
+        }
+    }
+
+    pub fn new(s: &str) -> Option<Self> {
+        let (key, value) = s.split_once('=')?;
+        let key = key.trim().to_uppercase();
+        let value = value.trim().to_string();
+        if key == "UNIT" {
+            return Some(Self::Unit(value.to_uppercase()));
+        }
+        let is_property =
+            key.starts_with('P') && key.len() > 1 && key[1..].chars().all(|c| c.is_ascii_digit());
+        if !is_property {
+            return None;
+        }
+        let is_item = value.len() > 1
+            && value.chars().next().is_some_and(|c| c.eq_ignore_ascii_case(&'Q'))
+            && value[1..].chars().all(|c| c.is_ascii_digit());
+        let value = if is_item { value.to_uppercase() } else { value };
+        Some(Self::Qualifier((key, value)))
+    }
+
+    pub fn matches(&self, statement: &wikibase::statement::Statement) -> bool {
+        match self {
+            Self::Unit(unit) => match statement.main_snak().data_value() {
+                Some(dv) => match dv.value() {
+                    // Units are entity URLs, or "1" for none
+                    wikibase::Value::Quantity(q) => {
+                        q.unit().rsplit('/').next().unwrap_or_default().to_uppercase() == *unit
+                    }
+                    _ => false,
+                },
+                None => false,
+            },
+            Self::Qualifier((property, value)) => statement
+                .qualifiers()
+                .iter()
+                .filter(|snak| *snak.property() == *property)
+                .filter_map(|snak| snak.data_value().to_owned())
+                .any(|dv| match dv.value() {
+                    wikibase::Value::Entity(e) => e.id() == value,
+                    wikibase::Value::StringValue(s) => s == value,
+                    _ => false,
+                }),
+        }
+    }
+}
+
+/// A sed-style substitution for the displayed text of a column, eg `P1476:regex(s/^The //)`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegexTransform {
+    pattern: String,
+    replacement: String,
+    global: bool,
+    case_insensitive: bool,
+    #[serde(skip)]
+    regex: Option<Regex>,
+}
+
+impl PartialEq for RegexTransform {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern == other.pattern
+            && self.replacement == other.replacement
+            && self.global == other.global
+            && self.case_insensitive == other.case_insensitive
+    }
+}
+
+impl RegexTransform {
+    /// Parses "regex(s/PATTERN/REPLACEMENT/FLAGS)" at the start of `s`, with flags g and i.
+    /// Returns the transform and the rest of `s`.
+    pub fn new_from_prefix(s: &str) -> Option<(Self, &str)> {
+        let s = s.trim_start();
+        if !s.get(..6).is_some_and(|p| p.eq_ignore_ascii_case("regex(")) {
+            return None;
+        }
+        let mut chars = s[6..].char_indices().map(|(pos, c)| (pos + 6, c));
+        if chars.next()?.1 != 's' {
+            return None;
+        }
+        let delimiter = chars.next()?.1;
+        let mut parts = vec![String::new()];
+        let mut end = None;
+        while let Some((pos, c)) = chars.next() {
+            if parts.len() == 3 {
+                if c == ')' {
+                    end = Some(pos + 1);
+                    break;
+                }
+                parts[2].push(c);
+            } else if c == '\\' {
+                let (_, next) = chars.next()?;
+                if next != delimiter {
+                    parts.last_mut()?.push('\\');
+                }
+                parts.last_mut()?.push(next);
+            } else if c == delimiter {
+                parts.push(String::new());
+            } else {
+                parts.last_mut()?.push(c);
+            }
+        }
+        let rest = &s[end?..];
+        let flags = &parts[2];
+        let replacement = parts[1].replace('$', "$$");
+        let replacement = RE_SED_GROUP.replace_all(&replacement, "$${${1}}").to_string();
+        let mut ret = Self {
+            pattern: parts[0].to_owned(),
+            replacement,
+            global: flags.contains('g'),
+            case_insensitive: flags.contains('i'),
+            regex: None,
+        };
+        ret.regex = Some(ret.compile()?);
+        Some((ret, rest))
+    }
+
+    fn compile(&self) -> Option<Regex> {
+        RegexBuilder::new(&self.pattern)
+            .case_insensitive(self.case_insensitive)
+            .build()
+            .ok()
+    }
+
+    pub fn apply(&self, s: &str) -> String {
+        let compiled;
+        let regex = match &self.regex {
+            Some(regex) => regex,
+            None => match self.compile() {
+                Some(regex) => {
+                    compiled = regex;
+                    &compiled
+                }
+                None => return s.to_string(),
+            },
+        };
+        if self.global {
+            regex.replace_all(s, self.replacement.as_str()).to_string()
+        } else {
+            regex.replace(s, self.replacement.as_str()).to_string()
+        }
+    }
+}
+
+lazy_static! {
+    // Turns sed-style "\1" into "${1}"; literal "$" is escaped first
+    static ref RE_SED_GROUP: Regex = Regex::new(r"\\(\d+)").expect("RE_SED_GROUP does not parse");
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Column {
     pub obj: ColumnType,
     pub label: String,
     has_label: bool,
+    #[serde(default)]
+    filters: Vec<StatementFilter>,
+    #[serde(default)]
+    transform: Option<RegexTransform>,
 }
 
 impl Column {
+    /// Splits the columns parameter at commas, except those in `[...]` statement filters
+    /// and in `regex(...)` transforms
+    pub fn split_columns(s: &str) -> Vec<&str> {
+        let mut ret = vec![];
+        let mut depth: usize = 0;
+        let mut start = 0;
+        let mut pos = 0;
+        while let Some(c) = s[pos..].chars().next() {
+            if depth == 0 && (c == 'r' || c == 'R') {
+                if let Some((_, rest)) = RegexTransform::new_from_prefix(&s[pos..]) {
+                    pos = s.len() - rest.len();
+                    continue;
+                }
+            }
+            match c {
+                '[' => depth += 1,
+                ']' => depth = depth.saturating_sub(1),
+                ',' if depth == 0 => {
+                    ret.push(&s[start..pos]);
+                    start = pos + 1;
+                }
+                _ => {}
+            }
+            pos += c.len_utf8();
+        }
+        ret.push(&s[start..]);
+        ret
+    }
+
     pub fn new(s: &str) -> Self {
         lazy_static! {
             static ref RE_COLUMN_LABEL: Regex = Regex::new(r#"^\s*(.+?)\s*:\s*(.+?)\s*$"#).expect("RE_COLUMN_LABEL does not parse");
+            // "wiki:frwiki" has a colon of its own, so the label is after the second one
+            static ref RE_SITELINK_LABEL: Regex = RegexBuilder::new(r#"^\s*(wiki\s*:\s*[a-z_]+)\s*(?::\s*(.*?)\s*)?$"#)
+                .case_insensitive(true)
+                .build()
+                .expect("RE_SITELINK_LABEL does not parse");
+        }
+        if let Some(caps) = RE_SITELINK_LABEL.captures(s) {
+            let obj = caps.get(1).unwrap().as_str();
+            let label = caps.get(2).map(|m| m.as_str()).unwrap_or_default();
+            return Self {
+                obj: ColumnType::new(obj),
+                label: if label.is_empty() { obj.to_owned() } else { label.to_string() },
+                has_label: !label.is_empty(),
+                filters: vec![],
+                transform: None,
+            };
         }
         match RE_COLUMN_LABEL.captures(&s) {
-            Some(caps) => Self {
-                obj: ColumnType::new(&caps.get(1).unwrap().as_str().to_string()),
-                label: caps.get(2).unwrap().as_str().to_string(),
-                has_label: !caps.get(2).unwrap().as_str().is_empty(),
-            },
-            None => Self {
-                obj: ColumnType::new(&s.trim().to_string()),
-                label: s.trim().to_string(),
-                has_label: false,
-            },
+            Some(caps) => {
+                let (obj, filters) = StatementFilter::split_column(caps.get(1).unwrap().as_str());
+                let mut label = caps.get(2).unwrap().as_str();
+                let transform = match RegexTransform::new_from_prefix(label) {
+                    Some((transform, rest)) => {
+                        label = rest.trim().trim_start_matches(':').trim_start();
+                        Some(transform)
+                    }
+                    None => None,
+                };
+                Self {
+                    obj: ColumnType::new(&obj),
+                    label: if label.is_empty() { obj.to_owned() } else { label.to_string() },
+                    has_label: !label.is_empty(),
+                    filters,
+                    transform,
+                }
+            }
+            None => {
+                let (obj, filters) = StatementFilter::split_column(s.trim());
+                Self {
+                    obj: ColumnType::new(&obj),
+                    label: s.trim().to_string(),
+                    has_label: false,
+                    filters,
+                    transform: None,
+                }
+            }
+        }
+    }
+
+    /// Constraints on which statements to show, from the `[...]` column suffix
+    pub fn filters(&self) -> &Vec<StatementFilter> {
+        &self.filters
+    }
+
+    /// Applies the column's `regex(...)` transform, if any, to displayed text
+    pub fn transform_text(&self, s: &str) -> String {
+        match &self.transform {
+            Some(transform) => transform.apply(s),
+            None => s.to_string(),
+        }
+    }
+
+    /// True if the label was given in the column definition
+    pub fn has_label(&self) -> bool {
+        self.has_label
+    }
+
+    /// The (first) property a generated label is made from
+    pub fn header_property(&self) -> Option<&String> {
+        if self.has_label {
+            return None;
+        }
+        match &self.obj {
+            ColumnType::Property(prop)
+            | ColumnType::HasProperty(prop)
+            | ColumnType::PropertyQualifier((prop, _))
+            | ColumnType::PropertyQualifierValue((prop, _, _)) => Some(prop),
+            ColumnType::PropertyFallback(props)
+            | ColumnType::PropertyCombination(props)
+            | ColumnType::PropertyPath(props) => props.first(),
+            _ => None,
         }
     }
 
@@ -126,19 +504,160 @@ impl Column {
         if self.has_label {
             return;
         }
-        self.label = match &self.obj {
-            ColumnType::Property(prop) => list.get_label_with_fallback(prop, None),
+        self.label = self.label_in_language(list, None);
+    }
+
+    /// The label generated from property labels in a language (default: the list language);
+    /// a label given in the column definition is used as is
+    pub fn label_in_language(&self, list: &ListeriaList, language: Option<&str>) -> String {
+        if self.has_label {
+            return self.label.to_owned();
+        }
+        match &self.obj {
+            ColumnType::Property(prop) | ColumnType::HasProperty(prop) => {
+                list.get_label_with_fallback(prop, language)
+            }
+            ColumnType::PropertyFallback(props) => props
+                .iter()
+                .map(|prop| list.get_label_with_fallback(prop, language))
+                .collect::<Vec<String>>()
+                .join(" / "),
+            ColumnType::PropertyCombination(props) => props
+                .iter()
+                .map(|prop| list.get_label_with_fallback(prop, language))
+                .collect::<Vec<String>>()
+                .join(list.combine_separator()),
+            ColumnType::PropertyPath(props) => props
+                .iter()
+                .map(|prop| list.get_label_with_fallback(prop, language))
+                .collect::<Vec<String>>()
+                .join("/"),
             ColumnType::PropertyQualifier((prop, qual)) => {
-                list.get_label_with_fallback(&prop, None)
+                list.get_label_with_fallback(prop, language)
                     + "/"
-                    + &list.get_label_with_fallback(&qual, None)
+                    + &list.get_label_with_fallback(qual, language)
             }
             ColumnType::PropertyQualifierValue((prop1, _qual, prop2)) => {
-                list.get_label_with_fallback(&prop1, None)
+                list.get_label_with_fallback(prop1, language)
                     + "/"
-                    + &list.get_label_with_fallback(&prop2, None)
+                    + &list.get_label_with_fallback(prop2, language)
             }
             _ => self.label.to_owned(), // Fallback
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_column_filters() {
+        let col = Column::new("P2048[unit=q11573]:Height");
+        assert_eq!(col.obj, ColumnType::Property("P2048".to_string()));
+        assert_eq!(col.label, "Height");
+        assert_eq!(col.filters(), &vec![StatementFilter::Unit("Q11573".to_string())]);
+
+        let col = Column::new("P106[P31=q5, p642=foo]");
+        assert_eq!(col.obj, ColumnType::Property("P106".to_string()));
+        assert_eq!(
+            col.filters(),
+            &vec![
+                StatementFilter::Qualifier(("P31".to_string(), "Q5".to_string())),
+                StatementFilter::Qualifier(("P642".to_string(), "foo".to_string())),
+            ]
+        );
+
+        assert!(Column::new("P31").filters().is_empty());
+        assert_eq!(
+            StatementFilter::new("P1448=Édith"),
+            Some(StatementFilter::Qualifier(("P1448".to_string(), "Édith".to_string())))
+        );
+        assert_eq!(
+            Column::split_columns("label,P106[P31=Q5,P642=Q1]:Job,P569"),
+            vec!["label", "P106[P31=Q5,P642=Q1]:Job", "P569"]
+        );
+        assert_eq!(
+            Column::split_columns("P1476:regex(s/^The, //),P217:regex(s/\\d{2,3}//g):No.,P569"),
+            vec!["P1476:regex(s/^The, //)", "P217:regex(s/\\d{2,3}//g):No.", "P569"]
+        );
+        assert_eq!(Column::new("item/bare").obj, ColumnType::Qid);
+        assert_eq!(
+            Column::new("age/P569/p570:Age").obj,
+            ColumnType::Age(("P569".to_string(), Some("P570".to_string())))
+        );
+        assert_eq!(Column::new("Age/P569").obj, ColumnType::Age(("P569".to_string(), None)));
+        assert_eq!(
+            Column::new("path/P50/p569:Born").obj,
+            ColumnType::PropertyPath(vec!["P50".to_string(), "P569".to_string()])
+        );
+        assert_eq!(Column::new("path/P50/P569").header_property(), Some(&"P50".to_string()));
+        assert_eq!(Column::new("P569:Born").header_property(), None);
+        assert_eq!(Column::new("Item/Label").obj, ColumnType::ItemLabelQid);
+        assert_eq!(Column::new("item/template:Item").obj, ColumnType::ItemTemplate);
+        let col = Column::new("?pop / ?area : Density");
+        assert_eq!(col.obj, ColumnType::Expression("?pop / ?area".to_string()));
+        assert_eq!(col.label, "Density");
+        assert_eq!(Column::new("?pop").obj, ColumnType::Field("POP".to_string()));
+        assert_eq!(Column::new("wiki:FRWIKI").obj, ColumnType::Sitelink("frwiki".to_string()));
+        let col = Column::new("wiki:dewiki:German article");
+        assert_eq!(col.obj, ColumnType::Sitelink("dewiki".to_string()));
+        assert_eq!(col.label, "German article");
+        assert_eq!(Column::new("Has/p18").obj, ColumnType::HasProperty("P18".to_string()));
+        assert_eq!(
+            Column::new("P569 | p571").obj,
+            ColumnType::PropertyFallback(vec!["P569".to_string(), "P571".to_string()])
+        );
+        assert_eq!(
+            Column::new("P735+P734:Name").obj,
+            ColumnType::PropertyCombination(vec!["P735".to_string(), "P734".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_column_transform() {
+        let col = Column::new("P1476:regex(s/^The //)");
+        assert_eq!(col.obj, ColumnType::Property("P1476".to_string()));
+        assert!(!col.has_label());
+        assert_eq!(col.transform_text("The Title"), "Title");
+
+        let col = Column::new("P217:regex(s#(\\d+)/(\\d+)#\\2-\\1#g): Inventory no.");
+        assert_eq!(col.label, "Inventory no.");
+        assert_eq!(col.transform_text("12/2020, 3/2021"), "2020-12, 2021-3");
+
+        let col = Column::new("P1476:regex(s/the/A/i)");
+        assert_eq!(col.transform_text("The the"), "A the");
+
+        assert_eq!(Column::new("P1476").transform_text("The Title"), "The Title");
+
+        // Multibyte characters across the length of the "regex(" prefix
+        let col = Column::new("P31:Geschäft");
+        assert_eq!(col.label, "Geschäft");
+        assert_eq!(col.transform_text("Laden"), "Laden");
+        assert_eq!(Column::new("P569:Jahr äquiv").label, "Jahr äquiv");
+    }
+
+    #[test]
+    fn test_entity_metadata() {
+        let snak = |property: &str, datatype: &str| {
+            serde_json::json!([{"mainsnak": {"snaktype": "value", "property": property, "datatype": datatype,
+                "datavalue": {"type": "string", "value": "x"}}, "type": "statement", "rank": "normal", "id": "Q1$1"}])
         };
+        let j = serde_json::json!({"type": "item", "id": "Q1", "modified": "2024-05-06T07:08:09Z",
+            "labels": {}, "descriptions": {}, "aliases": {}, "sitelinks": {},
+            "claims": {"P1476": snak("P1476", "string"), "P214": snak("P214", "external-id")}});
+        let entity = wikibase::entity::Entity::new_from_json(&j).unwrap();
+        assert_eq!(ColumnType::Modified.entity_metadata(&entity), Some("2024-05-06T07:08:09Z".to_string()));
+        assert_eq!(ColumnType::StatementCount.entity_metadata(&entity), Some("2".to_string()));
+        assert_eq!(ColumnType::IdentifierCount.entity_metadata(&entity), Some("1".to_string()));
+        assert_eq!(ColumnType::Item.entity_metadata(&entity), None);
+
+        // Without a modification time, or any statements
+        let j = serde_json::json!({"type": "item", "id": "Q2", "labels": {}, "descriptions": {}, "aliases": {},
+            "sitelinks": {}, "claims": {}});
+        let entity = wikibase::entity::Entity::new_from_json(&j).unwrap();
+        assert_eq!(ColumnType::Modified.entity_metadata(&entity), None);
+        assert_eq!(ColumnType::StatementCount.entity_metadata(&entity), Some("0".to_string()));
+        assert_eq!(ColumnType::IdentifierCount.entity_metadata(&entity), Some("0".to_string()));
     }
 }
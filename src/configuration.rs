@@ -1,48 +1,269 @@
 use crate::*;
+use crate::api_client::ApiClient;
+use crate::client_manager::ClientManager;
+use crate::entity_container_wrapper::EntityContainerWrapper;
+use crate::site_info::SiteInfo;
+use crate::wiki_login::WikiLogin;
+use std::ffi::OsString;
 use std::path::Path;
 use anyhow::{Result,anyhow};
 
+/// Environment variables with this prefix override config.json values.
+/// Nested keys are separated by a double underscore, eg `LISTERIA_WIKI_LOGIN__TOKEN`.
+const ENV_OVERRIDE_PREFIX: &str = "LISTERIA_";
+const MODULE_NAMESPACE: i64 = 828;
+
+/// A namespace, or a kind of namespaces, in namespace_blocks
+#[derive(Debug, Clone, PartialEq)]
+pub enum NamespaceRef {
+    Id(i64),
+    Name(String), // Local or canonical name, or alias; resolved with the site info of the wiki
+    Talk,         // All talk namespaces
+    Subject,      // All namespaces that are not talk namespaces
+}
+
+impl NamespaceRef {
+    pub fn new_from_json(v: &Value) -> Option<Self> {
+        if let Some(id) = v.as_i64() {
+            return Some(Self::Id(id));
+        }
+        match v.as_str()?.trim() {
+            "" => None,
+            s if s.eq_ignore_ascii_case("talk") => Some(Self::Talk),
+            s if s.eq_ignore_ascii_case("subject") => Some(Self::Subject),
+            s => match s.parse::<i64>() {
+                Ok(id) => Some(Self::Id(id)),
+                Err(_) => Some(Self::Name(s.to_string())),
+            },
+        }
+    }
+
+    fn matches(&self, nsid: i64, site_info: Option<&SiteInfo>) -> bool {
+        match self {
+            Self::Id(id) => *id == nsid,
+            Self::Name(name) => site_info.and_then(|si| si.namespace_by_name(name)) == Some(nsid),
+            Self::Talk => nsid % 2 == 1,
+            Self::Subject => nsid % 2 == 0,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum NamespaceGroup {
-    All,            // All namespaces forbidden
-    List(Vec<i64>), // List of forbidden namespaces
+    All,                     // All namespaces forbidden
+    List(Vec<NamespaceRef>), // List of forbidden namespaces
+    Allow(Vec<NamespaceRef>), // Only these namespaces allowed
 }
 
 impl NamespaceGroup {
-    pub fn can_edit_namespace(&self, nsid: i64) -> bool {
+    /// From "*", a list of forbidden namespaces, or {"allow":[...]} or {"block":[...]}
+    pub fn new_from_json(v: &Value) -> Option<Self> {
+        let refs = |v: &Value| -> Option<Vec<NamespaceRef>> {
+            Some(v.as_array()?.iter().filter_map(NamespaceRef::new_from_json).collect())
+        };
+        match v {
+            Value::String(s) if s == "*" => Some(Self::All),
+            Value::Array(_) => Some(Self::List(refs(v)?)),
+            Value::Object(o) if o.contains_key("allow") => Some(Self::Allow(refs(&v["allow"])?)),
+            Value::Object(o) if o.contains_key("block") => Some(Self::List(refs(&v["block"])?)),
+            _ => None,
+        }
+    }
+
+    /// Namespace names can only be told without site info if they are given as numbers
+    pub fn can_edit_namespace(&self, nsid: i64, site_info: Option<&SiteInfo>) -> bool {
+        if nsid < 0 {
+            return false; // Special, Media
+        }
         match self {
             Self::All => false,
-            Self::List(list) => nsid >= 0 && !list.contains(&nsid),
+            Self::List(list) => !list.iter().any(|ns| ns.matches(nsid, site_info)),
+            Self::Allow(list) => list.iter().any(|ns| ns.matches(nsid, site_info)),
         }
     }
 }
 
+/// What to do with a list whose table was edited by hand since the bot last wrote it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ManualEditPolicy {
+    #[default]
+    Ignore, // No detection
+    Overwrite, // Warn, then replace the table as usual
+    Skip, // Warn, and leave the list as it is
+}
+
+impl ManualEditPolicy {
+    pub fn new_from_str(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "ignore" => Some(Self::Ignore),
+            "overwrite" => Some(Self::Overwrite),
+            "skip" => Some(Self::Skip),
+            _ => None,
+        }
+    }
+}
+
+/// Names of the start and end templates
+pub type TemplateNames = (Vec<String>, Vec<String>);
+
+/// Full local titles of the start and end templates on a wiki, if it has them
+pub type TemplateTitles = (Option<String>, Option<String>);
+
 #[derive(Debug, Clone, Default)]
 pub struct Configuration {
-    wb_apis: HashMap<String, Arc<Api>>,
+    wb_apis: HashMap<String, Arc<dyn ApiClient>>,
+    sparql_endpoints: HashMap<String, String>, // Per API name, instead of the one in the site info
+    concept_bases: Vec<(String, String)>,      // Entity URI prefix, API name
+    client_manager: Arc<ClientManager>,
     namespace_blocks: HashMap<String, NamespaceGroup>,
     default_api: String,
     prefer_preferred: bool,
     default_language: String,
+    wiki_languages: HashMap<String, String>,
+    link_sites: HashMap<String, Vec<String>>,
     template_start_sites: HashMap<String, String>,
     template_end_sites: HashMap<String, String>,
+    template_items: HashMap<String, (String, String)>, // Per API name, items of the start and end templates
+    local_template_titles: Arc<std::sync::RwLock<HashMap<String, TemplateTitles>>>,
+    template_aliases: HashMap<String, TemplateNames>,
+    template_redirects: Arc<std::sync::RwLock<HashMap<String, TemplateNames>>>,
     location_templates: HashMap<String, String>,
+    bidi_templates: HashMap<String, String>,
+    row_markers: HashMap<String, bool>,
+    sparql_prefixes: HashMap<String, Vec<(String, String)>>,
+    data_module_invocations: HashMap<String, String>,
+    data_module_prefixes: HashMap<String, String>, // Per wiki; data_module pages must be under Module:<prefix>
+    reference_templates: HashMap<String, HashMap<String, String>>,
+    date_range_formats: HashMap<String, HashMap<String, String>>,
+    boolean_indicators: HashMap<String, (String, String)>,
+    item_templates: HashMap<String, String>,
+    toc_limit_templates: HashMap<String, String>,
+    footer_templates: HashMap<String, String>,
+    list_error_templates: HashMap<String, String>,
+    list_warning_templates: HashMap<String, String>,
+    list_error_categories: HashMap<String, String>,
+    manual_edit_policy: ManualEditPolicy,
     shadow_images_check: Vec<String>,
     default_thumbnail_size: Option<u64>,
+    thumbnail_sizes: HashMap<String, u64>, // Per wiki, instead of default_thumbnail_size
+    tabbed_data_max_cell_length: Option<usize>,
+    tabbed_data_languages: Vec<String>,
     location_regions: Vec<String>,
+    family_name_sources: Vec<String>,
+    sort_articles: HashMap<String, Vec<String>>, // Per language
     mysql: Option<Value>,
-    oauth2_token: String,
+    job_queue: Option<Value>,
+    server: Option<Value>,
+    event_stream: Option<Value>,
+    wiki_login: WikiLogin,
+    protected_page_talk_note: bool,
+    keep_row_order: bool,
+    page_timeout: Option<u64>,
+    run_report_page: Option<(String, String)>, // wiki, page
+    run_report_pages_per_cycle: usize,
 }
 
 impl Configuration {
     pub async fn new_from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::new_from_file_with_overrides(path, &[]).await
+    }
+
+    /// Loads the config file, then applies environment variable overrides,
+    /// then the given `key.path=value` overrides (eg from the command line).
+    pub async fn new_from_file_with_overrides<P: AsRef<Path>>(
+        path: P,
+        overrides: &[String],
+    ) -> Result<Self> {
+        let j = Self::read_json_file(path, overrides)?;
+        Self::new_from_json(j).await
+    }
+
+    /// The JSON of the config file, with the overrides of `new_from_file_with_overrides` applied
+    pub fn read_json_file<P: AsRef<Path>>(path: P, overrides: &[String]) -> Result<Value> {
         let file = File::open(path)?;
         let reader = BufReader::new(file);
-        let j = serde_json::from_reader(reader)?;
-        Self::new_from_json(j).await
+        let mut j = serde_json::from_reader(reader)?;
+        Self::apply_env_overrides(&mut j);
+        for o in overrides {
+            Self::apply_override_string(&mut j, o)?;
+        }
+        Ok(j)
+    }
+
+    fn apply_env_overrides(j: &mut Value) {
+        for (key, value) in std::env::vars() {
+            if let Some(key) = key.strip_prefix(ENV_OVERRIDE_PREFIX) {
+                let path: Vec<String> = key.to_lowercase().split("__").map(|s| s.to_string()).collect();
+                Self::apply_override(j, &path, &value);
+            }
+        }
+    }
+
+    /// Applies an override of the form `wiki_login.token=XYZ`
+    pub fn apply_override_string(j: &mut Value, s: &str) -> Result<()> {
+        let (path, value) = Self::parse_override_string(s)?;
+        Self::apply_override(j, &path, value);
+        Ok(())
+    }
+
+    /// The key path and value of an override of the form `wiki_login.token=XYZ`
+    fn parse_override_string(s: &str) -> Result<(Vec<String>, &str)> {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Config override '{s}' is not of the form key=value"))?;
+        let path: Vec<String> = key.trim().split('.').map(|s| s.to_string()).collect();
+        if path.iter().any(|k| k.is_empty()) {
+            return Err(anyhow!("Bad config override key '{key}'"));
+        }
+        Ok((path, value))
+    }
+
+    /// Separates `--set key.path=value` and `--set=key.path=value` overrides from the other command line arguments.
+    /// A `--set` without a valid override is an error, rather than running with the config unchanged.
+    pub fn split_override_args<I: IntoIterator<Item = OsString>>(args: I) -> Result<(Vec<OsString>, Vec<String>)> {
+        let mut rest = vec![];
+        let mut overrides = vec![];
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let o = match arg.to_str() {
+                Some("--set") => args.next().ok_or_else(|| anyhow!("--set needs a key=value override"))?,
+                Some(s) if s.starts_with("--set=") => OsString::from(&s["--set=".len()..]),
+                _ => {
+                    rest.push(arg);
+                    continue;
+                }
+            };
+            let o = o
+                .into_string()
+                .map_err(|o| anyhow!("Config override {o:?} is not valid UTF-8"))?;
+            Self::parse_override_string(&o)?;
+            overrides.push(o);
+        }
+        Ok((rest, overrides))
+    }
+
+    fn apply_override(j: &mut Value, path: &[String], value: &str) {
+        let mut target = j;
+        for key in path {
+            if !target.is_object() {
+                *target = json!({});
+            }
+            target = &mut target[key.as_str()];
+        }
+        // Keep strings as strings (passwords can look like numbers), otherwise try JSON
+        *target = match target {
+            Value::String(_) => Value::String(value.to_string()),
+            _ => serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string())),
+        };
     }
 
     pub async fn new_from_json(j: Value) -> Result<Self> {
+        let client_manager = ClientManager::new(&j["http"], WikiLogin::new_from_json(&j["wiki_login"]));
+        Self::new_from_json_with_client_manager(j, client_manager).await
+    }
+
+    /// Like `new_from_json`, with API clients from `client_manager`; tests register mock clients there
+    pub async fn new_from_json_with_client_manager(j: Value, client_manager: ClientManager) -> Result<Self> {
         let mut ret: Self = Default::default();
 
         if let Some(s) = j["default_api"].as_str() {
@@ -51,37 +272,105 @@ impl Configuration {
         if let Some(s) = j["default_language"].as_str() {
             ret.default_language = s.to_string()
         }
+        // Content language per wiki, where the site info does not fit, eg commonswiki or specieswiki
+        if let Some(o) = j["wiki_languages"].as_object() {
+            for (k, v) in o.iter() {
+                if let Some(v) = v.as_str() {
+                    ret.wiki_languages.insert(k.to_string(), v.trim().to_lowercase());
+                }
+            }
+        }
+        // Sitelinks to use for item links per wiki, in order, eg Wikisource first, then Wikipedia
+        if let Some(o) = j["link_sites"].as_object() {
+            for (k, v) in o.iter() {
+                if let Some(v) = v.as_array() {
+                    let sites = v.iter().filter_map(|site| site.as_str()).map(|site| site.trim().to_string());
+                    ret.link_sites.insert(k.to_string(), sites.collect());
+                }
+            }
+        }
         if let Some(b) = j["prefer_preferred"].as_bool() {
             ret.prefer_preferred = b
         }
+        if let (Some(wiki), Some(page)) = (j["run_report"]["wiki"].as_str(), j["run_report"]["page"].as_str()) {
+            ret.run_report_page = Some((wiki.to_string(), page.to_string()));
+        }
+        ret.run_report_pages_per_cycle = j["run_report"]["pages_per_cycle"].as_u64().unwrap_or(1000) as usize;
+        if let Some(b) = j["protected_page_talk_note"].as_bool() {
+            ret.protected_page_talk_note = b
+        }
+        if let Some(b) = j["keep_row_order"].as_bool() {
+            ret.keep_row_order = b
+        }
+        if let Some(i) = j["page_timeout"].as_u64() {
+            ret.page_timeout = Some(i)
+        }
         if let Some(i) = j["default_thumbnail_size"].as_u64() {
             ret.default_thumbnail_size = Some(i)
         }
+        if let Some(o) = j["thumbnail_sizes"].as_object() {
+            ret.thumbnail_sizes = o.iter().filter_map(|(k, v)| Some((k.to_string(), v.as_u64()?))).collect();
+        }
+        if let Some(i) = j["tabbed_data_max_cell_length"].as_u64() {
+            ret.tabbed_data_max_cell_length = Some(i as usize)
+        }
+        if let Some(languages) = j["tabbed_data_languages"].as_array() {
+            ret.tabbed_data_languages = languages
+                .iter()
+                .filter_map(|language| language.as_str())
+                .map(|language| language.to_string())
+                .collect();
+        }
         if let Some(sic) = j["shadow_images_check"].as_array() {
-            ret.shadow_images_check = sic
+            ret.shadow_images_check = sic.iter().filter_map(|s| s.as_str()).map(|s| s.to_string()).collect()
+        }
+        if let Some(a) = j["family_name_sources"].as_array() {
+            ret.family_name_sources = a.iter().filter_map(|s| s.as_str()).map(|s| s.to_string()).collect();
+        }
+        if let Some(o) = j["sort_articles"].as_object() {
+            ret.sort_articles = o
                 .iter()
-                .map(|s| s.as_str().expect("shadow_images_check needs to be a string").to_string())
-                .collect()
+                .filter_map(|(k, v)| {
+                    let articles = v.as_array()?.iter().filter_map(|s| s.as_str()).map(|s| s.to_string()).collect();
+                    Some((k.to_string(), articles))
+                })
+                .collect();
         }
         if let Some(lr) = j["location_regions"].as_array() {
-            ret.location_regions = lr.iter().map(|s| s.as_str().expect("location_regions needs to be a string").to_string()).collect()
-        }
-        if let Some(s) = j["wiki_login"]["token"].as_str() {
-            ret.oauth2_token = s.to_string()
+            ret.location_regions = lr.iter().filter_map(|s| s.as_str()).map(|s| s.to_string()).collect()
         }
+        ret.wiki_login = client_manager.wiki_login().clone(); // Shares renewed tokens with the API clients
         if j["mysql"].is_object() {
             ret.mysql = Some(j["mysql"].to_owned());
         }
+        if j["job_queue"].is_object() {
+            ret.job_queue = Some(j["job_queue"].to_owned());
+        }
+        if j["server"].is_object() {
+            ret.server = Some(j["server"].to_owned());
+        }
+        if j["event_stream"].is_object() {
+            ret.event_stream = Some(j["event_stream"].to_owned());
+        }
 
         // valid WikiBase APIs
-        let oauth2_token = ret.oauth2_token.to_owned();
+        ret.client_manager = Arc::new(client_manager);
+        // Either the API URL, or {"api":URL,"sparql":endpoint,"concept_base":entity URI prefix,
+        // "template_start_q":item,"template_end_q":item}
         if let Some(o) = j["apis"].as_object() {
             for (k, v) in o.iter() {
-                if let (name, Some(url)) = (k.as_str(), v.as_str()) {
-                    let mut api = wikibase::mediawiki::api::Api::new(&url)
-                        .await?;
-                    api.set_oauth2(&oauth2_token);
-                    ret.wb_apis.insert(name.to_string(), Arc::new(api));
+                if let (name, Some(url)) = (k.as_str(), v.as_str().or_else(|| v["api"].as_str())) {
+                    let api = ret.client_manager.api_client(url).await?;
+                    ret.wb_apis.insert(name.to_string(), api);
+                    if let Some(endpoint) = v["sparql"].as_str() {
+                        ret.sparql_endpoints.insert(name.to_string(), endpoint.to_string());
+                    }
+                    if let Some(concept_base) = v["concept_base"].as_str() {
+                        ret.concept_bases.push((concept_base.to_string(), name.to_string()));
+                    }
+                    if let (Some(q_start), Some(q_end)) = (v["template_start_q"].as_str(), v["template_end_q"].as_str()) {
+                        ret.template_items.insert(name.to_string(), (q_start.to_string(), q_end.to_string()));
+                    }
                 }
             }
         }
@@ -95,56 +384,203 @@ impl Configuration {
             }
         }
 
-        // Namespace blocks on wikis
-        if let Some(o) = j["namespace_blocks"].as_object() {
+        // Wrappers for text whose direction differs from the wiki language
+        if let Some(o) = j["bidi_templates"].as_object() {
             for (k, v) in o.iter() {
-                // Check for string value ("*")
-                if let Some(s) = v.as_str() {
-                    if s == "*" {
-                        // All namespaces
-                        ret.namespace_blocks
-                            .insert(k.to_string(), NamespaceGroup::All);
-                    } else {
-                        return Err(anyhow!("Unrecognized string value for namespace_blocks[{k}]:{v}"));
-                    }
+                if let (k, Some(v)) = (k.as_str(), v.as_str()) {
+                    ret.bidi_templates.insert(k.to_string(), v.to_string());
                 }
+            }
+        }
 
-                // Check for array of integers
-                if let Some(a) = v.as_array() {
-                    let nsids: Vec<i64> = a
+        // Page text for lists rendered as a data module, per wiki
+        if let Some(o) = j["data_module_invocations"].as_object() {
+            for (k, v) in o.iter() {
+                if let (k, Some(v)) = (k.as_str(), v.as_str()) {
+                    ret.data_module_invocations.insert(k.to_string(), v.to_string());
+                }
+            }
+        }
+
+        // Where lists may put their data modules, per wiki
+        if let Some(o) = j["data_module_prefixes"].as_object() {
+            for (k, v) in o.iter() {
+                if let (k, Some(v)) = (k.as_str(), v.as_str()) {
+                    ret.data_module_prefixes.insert(k.to_string(), v.replace('_', " "));
+                }
+            }
+        }
+
+        // PREFIX declarations added to queries, per wikibase
+        if let Some(o) = j["sparql_prefixes"].as_object() {
+            for (k, v) in o.iter() {
+                let prefixes: Vec<(String, String)> = v
+                    .as_object()
+                    .map(|o| {
+                        o.iter()
+                            .filter_map(|(prefix, iri)| Some((prefix.to_string(), iri.as_str()?.to_string())))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                ret.sparql_prefixes.insert(k.to_lowercase(), prefixes);
+            }
+        }
+
+        // Per-row item comments, per wiki
+        if let Some(o) = j["row_markers"].as_object() {
+            for (k, v) in o.iter() {
+                if let (k, Some(v)) = (k.as_str(), v.as_bool()) {
+                    ret.row_markers.insert(k.to_string(), v);
+                }
+            }
+        }
+
+        // Patterns for start/end time qualifiers, per wiki and kind ("range", "since" or "until")
+        if let Some(o) = j["date_range_formats"].as_object() {
+            for (wiki, kinds) in o.iter() {
+                if let Some(kinds) = kinds.as_object() {
+                    let kinds = kinds
                         .iter()
-                        .filter_map(|v| v.as_u64())
-                        .map(|x| x as i64)
+                        .filter_map(|(k, v)| Some((k.to_string(), v.as_str()?.to_string())))
                         .collect();
-                    ret.namespace_blocks
-                        .insert(k.to_string(), NamespaceGroup::List(nsids));
+                    ret.date_range_formats.insert(wiki.to_string(), kinds);
                 }
             }
         }
 
-        // Start/end template site/page mappings
-        let api = ret.get_default_wbapi()?;
-        let q_start = match j["template_start_q"].as_str() {
-            Some(q) => q.to_string(),
-            None => return Err(anyhow!("No template_start_q in config")),
-        };
-        let q_end = match j["template_end_q"].as_str() {
-            Some(q) => q.to_string(), //ret.template_end_sites = ret.get_template(q)?,
-            None => return Err(anyhow!("No template_end_q in config")),
-        };
-        let entities = wikibase::entity_container::EntityContainer::new();
+        // Citation template patterns for references, per wiki and kind ("web" or "item")
+        if let Some(o) = j["reference_templates"].as_object() {
+            for (wiki, kinds) in o.iter() {
+                if let Some(kinds) = kinds.as_object() {
+                    let kinds = kinds
+                        .iter()
+                        .filter_map(|(k, v)| Some((k.to_string(), v.as_str()?.to_string())))
+                        .collect();
+                    ret.reference_templates.insert(wiki.to_string(), kinds);
+                }
+            }
+        }
+
+        // What to show in has/Pxx columns, per wiki
+        if let Some(o) = j["boolean_indicators"].as_object() {
+            for (wiki, v) in o.iter() {
+                if let (Some(yes), Some(no)) = (v["yes"].as_str(), v["no"].as_str()) {
+                    ret.boolean_indicators.insert(wiki.to_string(), (yes.to_string(), no.to_string()));
+                }
+            }
+        }
+
+        // Template for item/template columns, per wiki, eg "Q" for {{Q|Q42}}
+        if let Some(o) = j["item_templates"].as_object() {
+            for (k, v) in o.iter() {
+                if let (k, Some(v)) = (k.as_str(), v.as_str()) {
+                    ret.item_templates.insert(k.to_string(), v.trim().to_string());
+                }
+            }
+        }
+
+        // Template for toc=<level>, per wiki, eg "TOC limit" for {{TOC limit|2}}
+        if let Some(o) = j["toc_limit_templates"].as_object() {
+            for (k, v) in o.iter() {
+                if let (k, Some(v)) = (k.as_str(), v.as_str()) {
+                    ret.toc_limit_templates.insert(k.to_string(), v.trim().to_string());
+                }
+            }
+        }
+
+        // Template for footer=template, per wiki; gets rows= and timestamp=
+        if let Some(o) = j["footer_templates"].as_object() {
+            for (k, v) in o.iter() {
+                if let (k, Some(v)) = (k.as_str(), v.as_str()) {
+                    ret.footer_templates.insert(k.to_string(), v.trim().to_string());
+                }
+            }
+        }
+
+        // Templates to show list errors with; HTML comment otherwise
+        if let Some(o) = j["list_error_templates"].as_object() {
+            for (k, v) in o.iter() {
+                if let (k, Some(v)) = (k.as_str(), v.as_str()) {
+                    ret.list_error_templates.insert(k.to_string(), v.to_string());
+                }
+            }
+        }
+
+        // Templates to list unknown template parameters with, per wiki; named parameters are the unknown ones,
+        // with the known one they are probably a misspelling of as value. HTML comment otherwise
+        if let Some(o) = j["list_warning_templates"].as_object() {
+            for (k, v) in o.iter() {
+                if let (k, Some(v)) = (k.as_str(), v.as_str()) {
+                    ret.list_warning_templates.insert(k.to_string(), v.to_string());
+                }
+            }
+        }
+
+        // Tracking categories for pages with list errors
+        if let Some(o) = j["list_error_categories"].as_object() {
+            for (k, v) in o.iter() {
+                if let (k, Some(v)) = (k.as_str(), v.as_str()) {
+                    ret.list_error_categories.insert(k.to_string(), v.to_string());
+                }
+            }
+        }
+
+        if let Some(s) = j["manual_edit_policy"].as_str() {
+            ret.manual_edit_policy = ManualEditPolicy::new_from_str(s)
+                .ok_or_else(|| anyhow!("Unknown manual_edit_policy '{s}'"))?;
+        }
+
+        // Alternate names of the start/end templates, per wiki
+        if let Some(o) = j["template_aliases"].as_object() {
+            let names = |v: &Value| -> Vec<String> {
+                v.as_array()
+                    .map(|a| a.iter().filter_map(|s| s.as_str()).map(|s| s.to_string()).collect())
+                    .unwrap_or_default()
+            };
+            for (wiki, v) in o.iter() {
+                ret.template_aliases
+                    .insert(wiki.to_string(), (names(&v["start"]), names(&v["end"])));
+            }
+        }
+
+        // Namespace blocks on wikis
+        if let Some(o) = j["namespace_blocks"].as_object() {
+            for (k, v) in o.iter() {
+                let group = NamespaceGroup::new_from_json(v)
+                    .ok_or_else(|| anyhow!("Unrecognized value for namespace_blocks[{k}]:{v}"))?;
+                ret.namespace_blocks.insert(k.to_string(), group);
+            }
+        }
+
+        // Start/end template site/page mappings; the top-level items are for the default API
+        let default_api = ret.default_api().to_string();
+        if let (Some(q_start), Some(q_end)) = (j["template_start_q"].as_str(), j["template_end_q"].as_str()) {
+            ret.template_items
+                .entry(default_api.to_owned())
+                .or_insert((q_start.to_string(), q_end.to_string()));
+        }
+        let (q_start, q_end) = ret
+            .template_items
+            .get(&default_api)
+            .cloned()
+            .ok_or_else(|| anyhow!("No template_start_q and template_end_q in config"))?;
+        let api = ret.get_default_wbapi()?.clone();
+        let mut entities = EntityContainerWrapper::new();
         entities
-            .load_entities(&api, &vec![q_start.clone(), q_end.clone()])
-            .await
-            .map_err(|e|anyhow!("{e}"))?;
+            .load_entities(api.as_ref(), &[q_start.clone(), q_end.clone()])
+            .await?;
         ret.template_start_sites = ret.get_sitelink_mapping(&entities, &q_start)?;
         ret.template_end_sites = ret.get_sitelink_mapping(&entities, &q_end)?;
 
         Ok(ret)
     }
 
-    pub fn oauth2_token(&self) -> &String {
-        &self.oauth2_token
+    pub fn oauth2_token(&self) -> String {
+        self.wiki_login.token()
+    }
+
+    pub fn wiki_login(&self) -> &WikiLogin {
+        &self.wiki_login
     }
 
     pub fn mysql(&self, key: &str) -> Value {
@@ -154,13 +590,38 @@ impl Configuration {
         }
     }
 
+    pub fn job_queue(&self, key: &str) -> Value {
+        match &self.job_queue {
+            Some(job_queue) => job_queue[key].to_owned(),
+            None => Value::Null,
+        }
+    }
+
+    pub fn server(&self, key: &str) -> Value {
+        match &self.server {
+            Some(server) => server[key].to_owned(),
+            None => Value::Null,
+        }
+    }
+
+    pub fn event_stream(&self, key: &str) -> Value {
+        match &self.event_stream {
+            Some(event_stream) => event_stream[key].to_owned(),
+            None => Value::Null,
+        }
+    }
+
+    pub fn event_stream_enabled(&self) -> bool {
+        self.event_stream("enabled").as_bool().unwrap_or(false)
+    }
+
     fn get_sitelink_mapping(
         &self,
-        entities: &wikibase::entity_container::EntityContainer,
+        entities: &EntityContainerWrapper,
         q: &str,
     ) -> Result<HashMap<String, String>> {
         let entity = entities
-            .get_entity(q.to_owned())
+            .get_entity(q)
             .ok_or(anyhow!("Entity {q} not found"))?;
         match entity.sitelinks() {
             Some(sl) => Ok(sl
@@ -175,33 +636,59 @@ impl Configuration {
         self.shadow_images_check.contains(wiki)
     }
 
-    pub fn get_local_template_title_start(&self, wiki: &str) -> Result<String> {
-        let ret = self
-            .template_start_sites
-            .get(wiki)
-            .map(|s| s.to_string())
-            .ok_or_else(|| anyhow!("Cannot find local start template"))?;
-        match ret.split(':').last() {
-            Some(x) => Ok(x.to_string()),
-            None => Err(anyhow!("get_local_template_title_start: no match")),
+    /// Full local titles (with namespace) of the start and end templates, from the sitelinks of their items.
+    /// Wikis without sitelinks from the items of the default API are looked up in the other APIs; cached per wiki.
+    pub async fn get_local_template_full_titles(&self, wiki: &str) -> TemplateTitles {
+        if let Some(titles) = self.local_template_titles.read().ok().and_then(|cache| cache.get(wiki).cloned()) {
+            return titles;
+        }
+        let mut ret = (self.template_start_sites.get(wiki).cloned(), self.template_end_sites.get(wiki).cloned());
+        let mut failed = false;
+        for name in self.wbapi_names() {
+            if ret.0.is_some() || ret.1.is_some() {
+                break;
+            }
+            let (Some(api), Some((q_start, q_end))) = (self.wb_apis.get(name), self.template_items.get(name)) else {
+                continue;
+            };
+            if name == self.default_api() {
+                continue; // Loaded already
+            }
+            let mut entities = EntityContainerWrapper::new();
+            if entities.load_entities(api.as_ref(), &[q_start.clone(), q_end.clone()]).await.is_err() {
+                failed = true;
+                continue;
+            }
+            let title = |q: &str| self.get_sitelink_mapping(&entities, q).ok()?.remove(wiki);
+            ret = (title(q_start), title(q_end));
         }
+        if !failed {
+            if let Ok(mut cache) = self.local_template_titles.write() {
+                cache.insert(wiki.to_string(), ret.clone());
+            }
+        }
+        ret
     }
 
-    pub fn get_local_template_title_end(&self, wiki: &str) -> Result<String> {
-        let ret = self
-            .template_end_sites
-            .get(wiki)
-            .map(|s| s.to_string())
-            .ok_or_else(|| anyhow!("Cannot find local end template"))?;
-        match ret.split(':').last() {
-            Some(x) => Ok(x.to_string()),
-            None => Err(anyhow!("get_local_template_title_end: no match")),
+    /// Configured alternate names (without namespace) of the start and end templates
+    pub fn get_template_aliases(&self, wiki: &str) -> TemplateNames {
+        self.template_aliases.get(wiki).cloned().unwrap_or_default()
+    }
+
+    /// Previously looked-up redirects to the start and end templates
+    pub fn cached_template_redirects(&self, wiki: &str) -> Option<TemplateNames> {
+        self.template_redirects.read().ok()?.get(wiki).cloned()
+    }
+
+    pub fn cache_template_redirects(&self, wiki: &str, redirects: TemplateNames) {
+        if let Ok(mut cache) = self.template_redirects.write() {
+            cache.insert(wiki.to_string(), redirects);
         }
     }
 
-    pub fn can_edit_namespace(&self, wiki: &str, nsid: i64) -> bool {
+    pub fn can_edit_namespace(&self, wiki: &str, nsid: i64, site_info: Option<&SiteInfo>) -> bool {
         match self.namespace_blocks.get(wiki) {
-            Some(nsg) => nsg.can_edit_namespace(nsid),
+            Some(nsg) => nsg.can_edit_namespace(nsid, site_info),
             None => true, // Default
         }
     }
@@ -209,44 +696,394 @@ impl Configuration {
     pub fn get_location_template(&self, wiki: &str) -> String {
         self.location_templates
             .get(wiki)
-            .unwrap_or_else(|| self.location_templates.get(&"default".to_string()).unwrap())
-            .to_string()
+            .or_else(|| self.location_templates.get("default"))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Pattern to wrap mixed-direction text in, with $TEXT$, $LANG$, and $DIR$ placeholders.
+    /// An empty pattern for a wiki turns wrapping off there.
+    pub fn get_bidi_template(&self, wiki: &str) -> Option<&String> {
+        self.bidi_templates
+            .get(wiki)
+            .or_else(|| self.bidi_templates.get("default"))
+            .filter(|s| !s.is_empty())
+    }
+
+    /// What a list with a data module shows on its page; $DATA$ is the title of the data module
+    pub fn get_data_module_invocation(&self, wiki: &str) -> Option<&String> {
+        self.data_module_invocations
+            .get(wiki)
+            .or_else(|| self.data_module_invocations.get("default"))
+            .filter(|s| !s.is_empty())
+    }
+
+    /// Page name prefix in the Module namespace for data modules; none means no data modules on the wiki
+    pub fn get_data_module_prefix(&self, wiki: &str) -> Option<&String> {
+        self.data_module_prefixes
+            .get(wiki)
+            .or_else(|| self.data_module_prefixes.get("default"))
+            .filter(|s| !s.trim().is_empty())
+    }
+
+    /// Errors unless the bot may write a data module to this title: a page in the Module namespace,
+    /// under the prefix configured for the wiki, where namespace_blocks allow edits
+    pub fn check_data_module_title(&self, wiki: &str, title: &str, site_info: Option<&SiteInfo>) -> Result<()> {
+        let prefix = self
+            .get_data_module_prefix(wiki)
+            .ok_or_else(|| anyhow!("Data modules are not enabled on {wiki}"))?;
+        let full_title = title.trim().trim_start_matches(':');
+        let (namespace, name) = full_title.split_once(':').unwrap_or(("", full_title));
+        let namespace_id = match site_info {
+            Some(site_info) => site_info.namespace_by_name(namespace),
+            None if namespace.trim().eq_ignore_ascii_case("Module") => Some(MODULE_NAMESPACE),
+            None => None,
+        };
+        if namespace_id != Some(MODULE_NAMESPACE) {
+            return Err(anyhow!("data_module '{title}' is not in the Module namespace"));
+        }
+        if !self.can_edit_namespace(wiki, MODULE_NAMESPACE, site_info) {
+            return Err(anyhow!("data_module '{title}': the Module namespace is not allowed for edit on {wiki}"));
+        }
+        let name = name.replace('_', " ");
+        let name = name.trim();
+        let invalid = name.contains(['#', '<', '>', '[', ']', '|', '{', '}'])
+            || name.split('/').any(|part| part == "." || part == "..");
+        let mut chars = name.chars();
+        let name = match chars.next() {
+            Some(first) => first.to_uppercase().collect::<String>() + chars.as_str(),
+            None => String::new(),
+        };
+        match name.strip_prefix(prefix.as_str()) {
+            Some(rest) if !invalid && !rest.trim().is_empty() => Ok(()),
+            _ => Err(anyhow!("data_module '{title}' must be a page under Module:{prefix}")),
+        }
+    }
+
+    /// Prefix and IRI of the PREFIX declarations added to queries on a wikibase; these add to, and override, the default ones
+    pub fn sparql_prefixes(&self, wikibase: &str) -> Vec<(String, String)> {
+        let mut ret = self.sparql_prefixes.get("default").cloned().unwrap_or_default();
+        for (prefix, iri) in self.sparql_prefixes.get(&wikibase.to_lowercase()).into_iter().flatten() {
+            ret.retain(|(p, _)| p != prefix);
+            ret.push((prefix.to_owned(), iri.to_owned()));
+        }
+        ret
+    }
+
+    /// If each row starts with an invisible comment naming its item, eg "<!-- Listeria row: Q42 -->"
+    pub fn row_markers(&self, wiki: &str) -> bool {
+        self.row_markers
+            .get(wiki)
+            .or_else(|| self.row_markers.get("default"))
+            .copied()
+            .unwrap_or(false)
+    }
+
+    /// Pattern for the start and end time qualifiers of a value, with $START$ and $END$ placeholders.
+    /// Kinds are "range", "since" (no end) and "until" (no start); English unless configured.
+    pub fn get_date_range_format(&self, wiki: &str, kind: &str) -> String {
+        let configured = self
+            .date_range_formats
+            .get(wiki)
+            .and_then(|kinds| kinds.get(kind))
+            .or_else(|| self.date_range_formats.get("default")?.get(kind));
+        match (configured, kind) {
+            (Some(format), _) => format.to_owned(),
+            (None, "since") => "since $START$".to_string(),
+            (None, "until") => "until $END$".to_string(),
+            (None, _) => "$START$\u{2013}$END$".to_string(),
+        }
+    }
+
+    /// Citation pattern for a kind of reference ("web" or "item"), with $URL$, $TITLE$, $STATED_IN$,
+    /// $STATED_IN_ITEM$, and $DATE$ placeholders. An empty pattern for a wiki turns it off there.
+    pub fn get_reference_template(&self, wiki: &str, kind: &str) -> Option<&String> {
+        self.reference_templates
+            .get(wiki)
+            .and_then(|kinds| kinds.get(kind))
+            .or_else(|| self.reference_templates.get("default")?.get(kind))
+            .filter(|s| !s.is_empty())
+    }
+
+    pub fn get_item_template(&self, wiki: &str) -> Option<&String> {
+        self.item_templates
+            .get(wiki)
+            .or_else(|| self.item_templates.get("default"))
+            .filter(|s| !s.is_empty())
+    }
+
+    pub fn get_toc_limit_template(&self, wiki: &str) -> Option<&String> {
+        self.toc_limit_templates
+            .get(wiki)
+            .or_else(|| self.toc_limit_templates.get("default"))
+            .filter(|s| !s.is_empty())
+    }
+
+    pub fn get_footer_template(&self, wiki: &str) -> Option<&String> {
+        self.footer_templates
+            .get(wiki)
+            .or_else(|| self.footer_templates.get("default"))
+            .filter(|s| !s.is_empty())
+    }
+
+    /// Wikitext for a yes/no value in a has/Pxx column; ✓/✗ unless configured
+    pub fn get_boolean_indicator(&self, wiki: &str, value: bool) -> String {
+        match self
+            .boolean_indicators
+            .get(wiki)
+            .or_else(|| self.boolean_indicators.get("default"))
+        {
+            Some((yes, no)) => if value { yes } else { no }.to_string(),
+            None => if value { "✓" } else { "✗" }.to_string(),
+        }
+    }
+
+    pub fn get_list_error_template(&self, wiki: &str) -> Option<&String> {
+        self.list_error_templates.get(wiki)
+    }
+
+    pub fn get_list_warning_template(&self, wiki: &str) -> Option<&String> {
+        self.list_warning_templates.get(wiki).filter(|s| !s.is_empty())
+    }
+
+    /// Category page title for pages where a list could not be generated, if configured for the wiki
+    pub fn get_list_error_category(&self, wiki: &str) -> Option<String> {
+        let category = self.list_error_categories.get(wiki)?.trim();
+        if category.is_empty() {
+            None
+        } else if category.contains(':') {
+            Some(category.to_string())
+        } else {
+            Some(format!("Category:{category}"))
+        }
+    }
+
+    pub fn manual_edit_policy(&self) -> ManualEditPolicy {
+        self.manual_edit_policy
     }
 
     pub fn prefer_preferred(&self) -> bool {
         self.prefer_preferred
     }
 
+    /// The configured content language of a wiki, if any
+    pub fn wiki_language(&self, wiki: &str) -> Option<&String> {
+        self.wiki_languages.get(wiki).filter(|s| !s.is_empty())
+    }
+
+    /// The sites whose sitelinks are used for item links on a wiki, in order; by default only the wiki itself
+    pub fn link_sites(&self, wiki: &str) -> Vec<String> {
+        match self.link_sites.get(wiki) {
+            Some(sites) if !sites.is_empty() => sites.to_owned(),
+            _ => vec![wiki.to_string()],
+        }
+    }
+
     pub fn default_language(&self) -> &str {
         &self.default_language
     }
 
-    pub fn default_thumbnail_size(&self) -> u64 {
-        self.default_thumbnail_size.unwrap_or(128)
+    /// Leave a note on the talk page if a page can't be updated because it is protected
+    pub fn protected_page_talk_note(&self) -> bool {
+        self.protected_page_talk_note
+    }
+
+    /// Unsorted lists keep the row order of the previous bot edit, so an update only touches the rows that changed
+    pub fn keep_row_order(&self) -> bool {
+        self.keep_row_order
+    }
+
+    /// How long the bot may work on one page, if limited
+    pub fn page_timeout(&self) -> Option<std::time::Duration> {
+        self.page_timeout.map(std::time::Duration::from_secs)
+    }
+
+    /// Wiki and page to write the bot status report to
+    pub fn run_report_page(&self) -> Option<&(String, String)> {
+        self.run_report_page.as_ref()
+    }
+
+    pub fn run_report_pages_per_cycle(&self) -> usize {
+        self.run_report_pages_per_cycle
+    }
+
+    pub fn default_thumbnail_size(&self, wiki: &str) -> u64 {
+        match self.thumbnail_sizes.get(wiki) {
+            Some(size) => *size,
+            None => self.default_thumbnail_size.unwrap_or(128),
+        }
+    }
+
+    /// Longest string in a tabular data cell, in characters; Commons allows 400
+    pub fn tabbed_data_max_cell_length(&self) -> usize {
+        self.tabbed_data_max_cell_length.unwrap_or(400)
+    }
+
+    /// Languages for tabular data column headers, besides the language of the list
+    pub fn tabbed_data_languages(&self) -> &[String] {
+        &self.tabbed_data_languages
+    }
+
+    /// Where sort=family_name looks for a family name, in order:
+    /// a property like "P734" (its value's label), or "label" (the last word of the item label)
+    pub fn family_name_sources(&self) -> Vec<String> {
+        if self.family_name_sources.is_empty() {
+            vec!["P734".to_string(), "label".to_string()]
+        } else {
+            self.family_name_sources.to_owned()
+        }
+    }
+
+    /// Leading articles that sort_ignore_articles skips for a language; "L'" style articles need no space after them
+    pub fn sort_articles(&self, language: &str) -> Vec<String> {
+        if let Some(articles) = self.sort_articles.get(language) {
+            return articles.to_owned();
+        }
+        let articles: &[&str] = match language {
+            "en" => &["The", "A", "An"],
+            "de" => &["Der", "Die", "Das"],
+            "fr" => &["Le", "La", "Les", "L'"],
+            _ => &[],
+        };
+        articles.iter().map(|s| s.to_string()).collect()
     }
 
     pub fn location_regions(&self) -> &Vec<String> {
         &self.location_regions
     }
 
-    pub async fn wbapi_login(&mut self, key: &str) -> bool {
-        let oauth2_token = self.oauth2_token().to_owned();
-        match self.wb_apis.get_mut(key) {
-            Some(mut api) => {
-                if let Some(api) = Arc::get_mut(&mut api) {api.set_oauth2(&oauth2_token);}
-                true
-            }
-            None => false,
-        }
+    /// Creates and shares the API clients for wikis, see `ClientManager`
+    pub fn client_manager(&self) -> &Arc<ClientManager> {
+        &self.client_manager
     }
 
-    pub fn get_wbapi(&self, key: &str) -> Option<&Arc<Api>> {
+    pub fn get_wbapi(&self, key: &str) -> Option<&Arc<dyn ApiClient>> {
         self.wb_apis.get(key)
     }
 
-    pub fn get_default_wbapi(&self) -> Result<&Arc<Api>> {
+    /// The configured SPARQL endpoint of an API, if any
+    pub fn get_sparql_endpoint(&self, key: &str) -> Option<&String> {
+        self.sparql_endpoints.get(key)
+    }
+
+    /// The API whose concept base an entity URI is in, for federated queries; http and https are the same
+    pub fn get_api_for_entity_uri(&self, uri: &str) -> Option<&String> {
+        let strip_scheme = |s: &str| s.split_once("://").map(|(_, rest)| rest.to_string()).unwrap_or_default();
+        let uri = strip_scheme(uri);
+        self.concept_bases
+            .iter()
+            .find(|(concept_base, _)| uri.starts_with(&strip_scheme(concept_base)))
+            .map(|(_, name)| name)
+    }
+
+    /// The names of the configured APIs, sorted
+    pub fn wbapi_names(&self) -> Vec<&String> {
+        let mut ret: Vec<&String> = self.wb_apis.keys().collect();
+        ret.sort();
+        ret
+    }
+
+    /// The name of the API for lists without api= parameter
+    pub fn default_api(&self) -> &str {
+        match self.default_api.is_empty() {
+            true => "wikidata",
+            false => &self.default_api,
+        }
+    }
+
+    pub fn get_default_wbapi(&self) -> Result<&Arc<dyn ApiClient>> {
         self.wb_apis
-            .get(&self.default_api)
+            .get(self.default_api())
             .ok_or_else(|| anyhow!("No default API set in config file"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_namespace_group() {
+        let site_info = SiteInfo::new(&json!({"namespaces":{
+            "4": {"id": 4, "canonical": "Project", "*": "Wikipedia"},
+            "10": {"id": 10, "canonical": "Template", "*": "Vorlage"}
+        }}));
+        let allow = NamespaceGroup::new_from_json(&json!({"allow":[0, "Wikipedia"]})).unwrap();
+        assert!(allow.can_edit_namespace(0, None));
+        assert!(allow.can_edit_namespace(4, Some(&site_info)));
+        assert!(!allow.can_edit_namespace(4, None));
+        assert!(!allow.can_edit_namespace(10, Some(&site_info)));
+        let block = NamespaceGroup::new_from_json(&json!(["talk", "Template"])).unwrap();
+        assert!(!block.can_edit_namespace(1, None));
+        assert!(!block.can_edit_namespace(10, Some(&site_info)));
+        assert!(block.can_edit_namespace(0, Some(&site_info)));
+        assert!(!block.can_edit_namespace(-1, None));
+        assert!(!NamespaceGroup::new_from_json(&json!("*")).unwrap().can_edit_namespace(0, None));
+        assert!(NamespaceGroup::new_from_json(&json!("x")).is_none());
+    }
+
+    #[test]
+    fn test_api_for_entity_uri() {
+        let config = Configuration {
+            concept_bases: vec![
+                ("http://www.wikidata.org/entity/".to_string(), "wikidata".to_string()),
+                ("https://example.wikibase.cloud/entity/".to_string(), "example".to_string()),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(config.get_api_for_entity_uri("https://www.wikidata.org/entity/Q42").unwrap(), "wikidata");
+        assert_eq!(config.get_api_for_entity_uri("http://example.wikibase.cloud/entity/Q1").unwrap(), "example");
+        assert!(config.get_api_for_entity_uri("http://www.wikidata.org/wiki/Q42").is_none());
+    }
+
+    #[test]
+    fn test_apply_override_string() {
+        let mut j = json!({"wiki_login":{"token":"old"},"mysql":{"password":"x","port":3306}});
+        Configuration::apply_override_string(&mut j, "wiki_login.token=new").unwrap();
+        Configuration::apply_override_string(&mut j, "mysql.password=1234").unwrap();
+        Configuration::apply_override_string(&mut j, "mysql.port=3307").unwrap();
+        Configuration::apply_override_string(&mut j, "default_thumbnail_size=200").unwrap();
+        assert_eq!(j["wiki_login"]["token"], json!("new"));
+        assert_eq!(j["mysql"]["password"], json!("1234"));
+        assert_eq!(j["mysql"]["port"], json!(3307));
+        assert_eq!(j["default_thumbnail_size"], json!(200));
+        assert!(Configuration::apply_override_string(&mut j, "no_value").is_err());
+    }
+
+    #[test]
+    fn test_split_override_args() {
+        let args = |a: &[&str]| a.iter().map(OsString::from).collect::<Vec<OsString>>();
+        let (rest, overrides) = Configuration::split_override_args(args(&[
+            "bot", "--set", "wiki_login.token=a", "4", "--set=mysql.port=3307",
+        ]))
+        .unwrap();
+        assert_eq!(rest, args(&["bot", "4"]));
+        assert_eq!(overrides, vec!["wiki_login.token=a", "mysql.port=3307"]);
+        assert!(Configuration::split_override_args(args(&["bot", "--set"])).is_err());
+        assert!(Configuration::split_override_args(args(&["bot", "--set", "4"])).is_err());
+        assert!(Configuration::split_override_args(args(&["bot", "--set=.token=a"])).is_err());
+    }
+
+    #[test]
+    fn test_check_data_module_title() {
+        let mut config = Configuration::default();
+        assert!(config.check_data_module_title("enwiki", "Module:Listeria/Foo", None).is_err());
+        config.data_module_prefixes.insert("default".to_string(), "Listeria/".to_string());
+        assert!(config.check_data_module_title("enwiki", "Module:Listeria/Foo", None).is_ok());
+        assert!(config.check_data_module_title("enwiki", "Module:listeria/Foo_bar", None).is_ok());
+        assert!(config.check_data_module_title("enwiki", "Module:Listeria/", None).is_err());
+        assert!(config.check_data_module_title("enwiki", "Module:Other", None).is_err());
+        assert!(config.check_data_module_title("enwiki", "Module:Listeria/../Other", None).is_err());
+        assert!(config.check_data_module_title("enwiki", "Main Page", None).is_err());
+        assert!(config.check_data_module_title("enwiki", "Template:Listeria/Foo", None).is_err());
+        assert!(config.check_data_module_title("enwiki", "Listeria/Foo", None).is_err());
+
+        let site_info = SiteInfo::new(&json!({"namespaces":{
+            "828": {"id": 828, "canonical": "Module", "*": "Modul"}
+        }}));
+        assert!(config.check_data_module_title("dewiki", "Modul:Listeria/Foo", Some(&site_info)).is_ok());
+        assert!(config.check_data_module_title("dewiki", "Vorlage:Listeria/Foo", Some(&site_info)).is_err());
+        config.namespace_blocks.insert("dewiki".to_string(), NamespaceGroup::new_from_json(&json!([828])).unwrap());
+        assert!(config.check_data_module_title("dewiki", "Modul:Listeria/Foo", Some(&site_info)).is_err());
+    }
+}
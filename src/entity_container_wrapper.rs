@@ -1,94 +1,150 @@
+use crate::api_client::ApiClient;
 use crate::listeria_list::ListeriaList;
 use crate::result_cell_part::PartWithReference;
 use crate::result_cell_part::ResultCellPart;
 use crate::result_row::ResultRow;
 use crate::{LinksType, SparqlValue};
 use anyhow::{Result,anyhow};
+#[cfg(not(target_arch = "wasm32"))]
 use tempfile::NamedTempFile;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 //use std::sync::Mutex;
 use wikibase::entity::*;
-use wikibase::entity_container::EntityContainer;
-use wikibase::mediawiki::api::Api;
+#[cfg(not(target_arch = "wasm32"))]
 use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
 use wikibase::snak::SnakDataType;
 //use std::io::{SeekFrom, Write};
 //use std::io::prelude::*;
 
 const MAX_LOCAL_CACHED_ENTITIES: usize = 50000;//usize::MAX; //100;
+const ENTITIES_PER_REQUEST: usize = 50; // Limit of wbgetentities
 
+/// Entities loaded for a list, shared between its clones
 #[derive(Clone)]
 pub struct EntityContainerWrapper {
-    entities: EntityContainer,
+    entities: Arc<RwLock<HashMap<String, Entity>>>,
+    #[cfg(not(target_arch = "wasm32"))]
     pickledb: Option<Arc<PickleDb>>,
+    #[cfg(not(target_arch = "wasm32"))]
     pickledb_filename: Option<Arc<NamedTempFile>>,
 }
 
 impl std::fmt::Debug for EntityContainerWrapper {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("EntityContainerWrapper")
-         .field("entities", &self.entities)
-         .field("pickledb_filename", &self.pickledb_filename)
-         .finish()
+        let mut ret = f.debug_struct("EntityContainerWrapper");
+        ret.field("entities", &self.len());
+        #[cfg(not(target_arch = "wasm32"))]
+        ret.field("pickledb_filename", &self.pickledb_filename);
+        ret.finish()
     }
 }
 
 impl EntityContainerWrapper {
     pub fn new() -> Self {
         Self {
-            entities: EntityContainer::new(),
+            entities: Arc::new(RwLock::new(HashMap::new())),
+            #[cfg(not(target_arch = "wasm32"))]
             pickledb: None,
+            #[cfg(not(target_arch = "wasm32"))]
             pickledb_filename: None,
         }
     }
 
-    pub async fn load_entities(&mut self, api: &Api, ids: &Vec<String>) -> Result<()> {
+    pub async fn load_entities(&mut self, api: &dyn ApiClient, ids: &[String]) -> Result<()> {
         self.load_entities_max_size(api, ids, MAX_LOCAL_CACHED_ENTITIES)
             .await
     }
 
-    pub async fn load_entities_max_size(&mut self, api: &Api, ids: &Vec<String>, max_entities: usize) -> Result<()> {
-        let ids = self.entities.unique_shuffle_entity_ids(ids).unwrap();
+    pub async fn load_entities_max_size(&mut self, api: &dyn ApiClient, ids: &[String], max_entities: usize) -> Result<()> {
+        let mut ids = ids.to_vec();
+        ids.sort();
+        ids.dedup();
+        ids.retain(|id| !self.has_entity(id));
+        #[cfg(not(target_arch = "wasm32"))]
         if ids.len()>max_entities { // Use pickledb disk cache
-            self.pickledb_filename = Some(Arc::new(NamedTempFile::new()?));
-            let temp_filename = self.pickledb_filename.as_ref().unwrap().path().to_str().unwrap();
+            let temp_file = Arc::new(NamedTempFile::new()?);
+            let temp_filename = temp_file
+                .path()
+                .to_str()
+                .ok_or_else(|| anyhow!("Entity cache file name is not valid UTF-8"))?
+                .to_string();
+            self.pickledb_filename = Some(temp_file);
             let mut db = PickleDb::new(
-                temp_filename,
+                &temp_filename,
                 PickleDbDumpPolicy::AutoDump,
                 SerializationMethod::Json,
             );
             let chunks = ids.chunks(max_entities) ;
             for chunk in chunks {
-                if let Err(e) = self.entities.load_entities(api, &chunk.into()).await {
-                    return Err(anyhow!("Error loading entities: {e}"))
+                for entity in Self::fetch_entities(api, chunk).await? {
+                    let json = entity.to_json();
+                    //let _ = self.hashfile_add_entity(&entity.id(), json);
+                    db.set(entity.id(), &json)?;
                 }
-                for entity_id in chunk {
-                    if let Some(entity) = self.entities.get_entity(entity_id) {
-                        let json = entity.to_json();
-                        //let _ = self.hashfile_add_entity(&entity.id(), json);
-                        db.set(&entity.id(), &json).unwrap();
-                    }
-                }
-                self.entities.clear();
             }
             self.pickledb = Some(Arc::new(db));
-            Ok(())
-        } else {
-            match self.entities.load_entities(api, &ids).await {
-                Ok(_) => Ok(()),
-                Err(e) => Err(anyhow!("Error loading entities: {e}")),
-            }
+            return Ok(());
+        }
+        #[cfg(target_arch = "wasm32")]
+        let _ = max_entities; // No disk to cache on, keep everything in memory
+        let entities = Self::fetch_entities(api, &ids).await?;
+        let mut loaded = self.entities.write().map_err(|e| anyhow!("{e}"))?;
+        for entity in entities {
+            loaded.insert(entity.id().to_string(), entity);
         }
+        Ok(())
+    }
+
+    /// Loads entities with `wbgetentities`, several requests at a time; missing entities are skipped
+    async fn fetch_entities(api: &dyn ApiClient, ids: &[String]) -> Result<Vec<Entity>> {
+        let requests = ids.chunks(ENTITIES_PER_REQUEST).map(|chunk| async move {
+            let params: HashMap<String, String> = [("action", "wbgetentities"), ("ids", chunk.join("|").as_str())]
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            let j = api.get_query_api_json(&params).await?;
+            if let Some(info) = j["error"]["info"].as_str() {
+                return Err(anyhow!("Error loading entities: {info}"));
+            }
+            let entities: Vec<Entity> = j["entities"]
+                .as_object()
+                .into_iter()
+                .flat_map(|entities| entities.values())
+                .filter(|entity| entity.get("missing").is_none())
+                .filter_map(|entity| Entity::new_from_json(entity).ok())
+                .collect();
+            Ok(entities)
+        });
+        let ret = futures::future::try_join_all(requests).await?;
+        Ok(ret.into_iter().flatten().collect())
+    }
+
+    fn has_entity(&self, entity_id: &str) -> bool {
+        self.entities.read().map(|entities| entities.contains_key(entity_id)).unwrap_or(false)
+    }
+
+    /// Number of entities held in memory
+    pub fn len(&self) -> usize {
+        self.entities.read().map(|entities| entities.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
     pub fn get_entity(&self, entity_id: &str) -> Option<Entity> {
-        if let Some(entity) = self.entities.get_entity(entity_id) {
-            return Some(entity)
+        if let Some(entity) = self.entities.read().ok()?.get(entity_id) {
+            return Some(entity.to_owned())
         }
         // self.hashfile_get_entity(entity_id)
-        let json = self.pickledb.as_ref()?.get::<serde_json::Value>(entity_id)?;
-        Entity::new_from_json(&json).ok()
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let json = self.pickledb.as_ref()?.get::<serde_json::Value>(entity_id)?;
+            Entity::new_from_json(&json).ok()
+        }
+        #[cfg(target_arch = "wasm32")]
+        None
     }
 
     pub fn get_local_entity_label(&self, entity_id: &str, language: &str) -> Option<String> {
@@ -97,24 +153,31 @@ impl EntityContainerWrapper {
             .map(|s| s.to_string())
     }
 
+    /// The page of the entity on the first of the sites that has one, with an interproject prefix
+    /// like "w:" or "s:fr:" if that is not the wiki itself
+    pub fn local_page(entity: &Entity, wiki: &str, sites: &[String]) -> Option<String> {
+        let sitelinks = entity.sitelinks().as_ref()?;
+        sites.iter().find_map(|site| {
+            let title = sitelinks.iter().find(|s| s.site() == site)?.title();
+            if site == wiki {
+                return Some(title.to_string());
+            }
+            Some(interproject_prefix(wiki, site)? + title)
+        })
+    }
+
     pub fn entity_to_local_link(
         &self,
         item: &str,
         wiki: &str,
+        sites: &[String],
         language: &str,
     ) -> Option<ResultCellPart> {
         let entity = match self.get_entity(item) {
             Some(e) => e,
             None => return None,
         };
-        let page = match entity.sitelinks() {
-            Some(sl) => sl
-                .iter()
-                .filter(|s| *s.site() == wiki)
-                .map(|s| s.title().to_string())
-                .next(),
-            None => None,
-        }?;
+        let page = Self::local_page(&entity, wiki, sites)?;
         //let title = wikibase::mediawiki::title::Title::new_from_full(page,&mw_api);
         let label = self
             .get_local_entity_label(item, language)
@@ -206,17 +269,83 @@ impl EntityContainerWrapper {
 
 }
 
+/// Splits a site ID like "frwikisource" into language and project family; multilingual wikis have no language
+fn split_site(site: &str) -> Option<(Option<String>, &'static str)> {
+    match site {
+        "commonswiki" => return Some((None, "commons")),
+        "specieswiki" => return Some((None, "species")),
+        "wikidatawiki" => return Some((None, "wikidata")),
+        "metawiki" => return Some((None, "meta")),
+        "mediawikiwiki" => return Some((None, "mediawiki")),
+        _ => {}
+    }
+    let families = [
+        "wikisource",
+        "wikivoyage",
+        "wikiquote",
+        "wikibooks",
+        "wikinews",
+        "wikiversity",
+        "wiktionary",
+        "wiki",
+    ];
+    families.iter().find_map(|family| {
+        let language = site.strip_suffix(family).filter(|l| !l.is_empty())?;
+        Some((Some(language.replace('_', "-")), *family))
+    })
+}
+
+/// The interwiki prefix for links from one wiki to another, like "w:" or "s:fr:"; None if unknown
+pub fn interproject_prefix(from_wiki: &str, to_wiki: &str) -> Option<String> {
+    let (from_language, from_family) = split_site(from_wiki)?;
+    let (to_language, to_family) = split_site(to_wiki)?;
+    let family_prefix = match to_family {
+        "wiki" => "w:",
+        "wikisource" => "s:",
+        "wikivoyage" => "voy:",
+        "wikiquote" => "q:",
+        "wikibooks" => "b:",
+        "wikinews" => "n:",
+        "wikiversity" => "v:",
+        "wiktionary" => "wikt:",
+        "commons" => "c:",
+        "species" => "species:",
+        "wikidata" => "d:",
+        "meta" => "m:",
+        _ => "mw:",
+    };
+    let family_prefix = if from_family == to_family { "" } else { family_prefix };
+    let language_prefix = match to_language {
+        Some(language) if from_language.as_ref() != Some(&language) => format!("{language}:"),
+        _ => String::new(),
+    };
+    Some(format!(":{family_prefix}{language_prefix}"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_interproject_prefix() {
+        assert_eq!(interproject_prefix("enwikisource", "enwiki"), Some(":w:".to_string()));
+        assert_eq!(interproject_prefix("dewikivoyage", "enwikivoyage"), Some(":en:".to_string()));
+        assert_eq!(interproject_prefix("specieswiki", "enwiki"), Some(":w:en:".to_string()));
+        assert_eq!(interproject_prefix("enwiki", "zh_yuewikisource"), Some(":s:zh-yue:".to_string()));
+        assert_eq!(interproject_prefix("enwiki", "commonswiki"), Some(":c:".to_string()));
+        assert_eq!(interproject_prefix("enwiki", "foo"), None);
+    }
+
     #[tokio::test]
     async fn test_pickledb() {
         let mut ecw = EntityContainerWrapper::new();
-        let api = wikibase::mediawiki::api::Api::new("https://www.wikidata.org/w/api.php").await.unwrap();
-        let ids = ["Q1","Q2","Q3","Q4","Q5"].iter().map(|s|s.to_string()).collect();
+        let mut api = crate::api_client::MockApiClient::default();
+        let ids: Vec<String> = ["Q1","Q2","Q3","Q4","Q5"].iter().map(|s|s.to_string()).collect();
+        for id in &ids {
+            api.add_entity(json!({"type":"item","id":id,"labels":{},"descriptions":{},"aliases":{},"claims":{},"sitelinks":{}})).unwrap();
+        }
         ecw.load_entities_max_size(&api, &ids, 2).await.unwrap();
-        assert_eq!(ecw.entities.len(),0);
+        assert_eq!(ecw.len(),0);
 
         let path = ecw.pickledb_filename.as_ref().unwrap().path();
         let len = std::fs::metadata(path).unwrap().len();
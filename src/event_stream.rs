@@ -0,0 +1,192 @@
+use crate::configuration::Configuration;
+use crate::page_queue::PageQueue;
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use std::collections::HashSet;
+use tokio::time::{sleep, Duration};
+
+const DEFAULT_URL: &str = "https://stream.wikimedia.org/v2/stream/recentchange";
+const DEFAULT_PRIORITY: i32 = 1;
+const RECONNECT_DELAY_SECONDS: u64 = 10;
+
+/// One server-sent event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SseEvent {
+    pub id: Option<String>,
+    pub data: String,
+}
+
+/// Splits a server-sent event stream, arriving in arbitrary chunks, into events
+#[derive(Debug, Default)]
+pub struct SseParser {
+    buffer: Vec<u8>,
+}
+
+impl SseParser {
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend(chunk.iter().filter(|b| **b != b'\r'));
+        let mut ret = vec![];
+        while let Some(pos) = self.buffer.windows(2).position(|w| w == b"\n\n") {
+            let block: Vec<u8> = self.buffer.drain(..pos + 2).collect();
+            let block = String::from_utf8_lossy(&block);
+            let mut id = None;
+            let mut data = vec![];
+            for line in block.lines() {
+                if let Some(value) = line.strip_prefix("data:") {
+                    data.push(value.trim_start().to_string());
+                } else if let Some(value) = line.strip_prefix("id:") {
+                    id = Some(value.trim_start().to_string());
+                }
+            }
+            if !data.is_empty() {
+                ret.push(SseEvent {
+                    id,
+                    data: data.join("\n"),
+                });
+            }
+        }
+        ret
+    }
+}
+
+/// Watches Wikimedia EventStreams recent changes, and raises the queue priority of
+/// pages that were edited, or that show a Wikidata item that was edited.
+/// Item tracking relies on the bot storing the items of each page it updates.
+#[derive(Debug, Clone)]
+pub struct EventStreamListener {
+    queue: PageQueue,
+    url: String,
+    ignore_users: HashSet<String>,
+    priority: i32,
+    wikis: HashSet<String>,
+    last_event_id: Option<String>,
+}
+
+impl EventStreamListener {
+    pub fn new(config: &Configuration, queue: PageQueue) -> Self {
+        let ignore_users = config
+            .event_stream("ignore_users")
+            .as_array()
+            .map(|a| a.iter().filter_map(|u| u.as_str()).map(|u| u.to_string()).collect())
+            .unwrap_or_default();
+        Self {
+            queue,
+            url: config
+                .event_stream("url")
+                .as_str()
+                .unwrap_or(DEFAULT_URL)
+                .to_string(),
+            ignore_users,
+            priority: config
+                .event_stream("priority")
+                .as_i64()
+                .map(|p| p as i32)
+                .unwrap_or(DEFAULT_PRIORITY),
+            wikis: HashSet::new(),
+            last_event_id: None,
+        }
+    }
+
+    /// Listens forever, reconnecting (and resuming from the last event) when the stream drops
+    pub async fn run(&mut self) {
+        loop {
+            if let Err(e) = self.listen().await {
+                eprintln!("EventStreams: {e}");
+            }
+            sleep(Duration::from_secs(RECONNECT_DELAY_SECONDS)).await;
+        }
+    }
+
+    async fn listen(&mut self) -> Result<()> {
+        self.wikis = self.queue.active_wikis().await?.into_iter().collect();
+        let client = reqwest::Client::builder()
+            .user_agent("Listeria (https://github.com/magnusmanske/listeria_rs)")
+            .build()?;
+        let mut request = client.get(&self.url).header("Accept", "text/event-stream");
+        if let Some(id) = &self.last_event_id {
+            request = request.header("Last-Event-ID", id.as_str());
+        }
+        let mut response = request.send().await?.error_for_status()?;
+        let mut parser = SseParser::default();
+        while let Some(chunk) = response.chunk().await? {
+            for event in parser.push(&chunk) {
+                if let Err(e) = self.handle_event(&event).await {
+                    eprintln!("EventStreams: {e}");
+                }
+                if event.id.is_some() {
+                    self.last_event_id = event.id;
+                }
+            }
+        }
+        Err(anyhow!("stream closed"))
+    }
+
+    async fn handle_event(&self, event: &SseEvent) -> Result<()> {
+        let j: Value = serde_json::from_str(&event.data)?;
+        match j["type"].as_str() {
+            Some("edit") | Some("new") => {}
+            _ => return Ok(()),
+        }
+        if let Some(user) = j["user"].as_str() {
+            if self.ignore_users.contains(user) {
+                return Ok(());
+            }
+        }
+        let (wiki, title) = match (j["wiki"].as_str(), j["title"].as_str()) {
+            (Some(wiki), Some(title)) => (wiki, title),
+            _ => return Ok(()),
+        };
+        if let Some(item) = Self::wikidata_entity(wiki, j["namespace"].as_i64(), title) {
+            self.queue.raise_priority_for_item(&item, self.priority).await?;
+        }
+        if self.wikis.contains(wiki) {
+            self.queue.raise_priority(wiki, title, self.priority).await?;
+        }
+        Ok(())
+    }
+
+    /// The entity ID, if this is an item or property edit on Wikidata
+    fn wikidata_entity(wiki: &str, namespace: Option<i64>, title: &str) -> Option<String> {
+        if wiki != "wikidatawiki" {
+            return None;
+        }
+        match namespace {
+            Some(0) => Some(title.to_string()),
+            Some(120) => title.strip_prefix("Property:").map(|p| p.to_string()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sse_parser() {
+        let mut parser = SseParser::default();
+        assert!(parser.push(b"event: message\nid: [{\"offset\":1}]\ndata: {\"a\"").is_empty());
+        let events = parser.push(b":1}\n\n:ok\n\ndata: {}\r\n\r\n");
+        assert_eq!(
+            events,
+            vec![
+                SseEvent {
+                    id: Some("[{\"offset\":1}]".to_string()),
+                    data: "{\"a\":1}".to_string()
+                },
+                SseEvent {
+                    id: None,
+                    data: "{}".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_wikidata_entity() {
+        assert_eq!(EventStreamListener::wikidata_entity("wikidatawiki", Some(0), "Q42"), Some("Q42".to_string()));
+        assert_eq!(EventStreamListener::wikidata_entity("wikidatawiki", Some(120), "Property:P31"), Some("P31".to_string()));
+        assert_eq!(EventStreamListener::wikidata_entity("wikidatawiki", Some(1), "Talk:Q42"), None);
+        assert_eq!(EventStreamListener::wikidata_entity("enwiki", Some(0), "Q42"), None);
+    }
+}
@@ -0,0 +1,299 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+/// A small expression over SPARQL variables, for expression columns like "?pop / ?area : Density".
+/// Numbers, "strings", ?variables, + - * /, & to join text, comparisons (= != < <= > >=), parentheses,
+/// and the functions if(condition, then, else) and round(value, digits).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Number(f64),
+    Text(String),
+    Variable(String), // Lower case, without "?"
+    Negate(Box<Expression>),
+    Binary((Box<Expression>, String, Box<Expression>)), // Left, operator, right
+    Function((String, Vec<Expression>)),
+}
+
+/// The value of an expression; missing variables and invalid arithmetic give Empty
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExpressionValue {
+    Empty,
+    Number(f64),
+    Text(String),
+    Bool(bool),
+}
+
+impl ExpressionValue {
+    fn as_number(&self) -> Option<f64> {
+        match self {
+            Self::Number(n) => Some(*n),
+            Self::Text(s) => s.trim().parse::<f64>().ok(),
+            _ => None,
+        }
+    }
+
+    fn is_true(&self) -> bool {
+        match self {
+            Self::Empty => false,
+            Self::Number(n) => *n != 0.0,
+            Self::Text(s) => !s.is_empty(),
+            Self::Bool(b) => *b,
+        }
+    }
+
+    /// Wikitext for the cell; numbers with at most two decimals, unless rounded otherwise
+    pub fn as_text(&self) -> String {
+        match self {
+            Self::Empty => String::new(),
+            Self::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => format!("{}", *n as i64),
+            Self::Number(n) => {
+                let s = format!("{n:.2}");
+                s.trim_end_matches('0').trim_end_matches('.').to_string()
+            }
+            Self::Text(s) => s.to_owned(),
+            Self::Bool(b) => b.to_string(),
+        }
+    }
+}
+
+const OPERATORS: &[&str] = &["!=", "<=", ">=", "=", "<", ">", "&", "+", "-", "*", "/"];
+
+impl Expression {
+    pub fn new(s: &str) -> Result<Self> {
+        let tokens = Self::tokenize(s)?;
+        let mut pos = 0;
+        let ret = Self::parse_comparison(&tokens, &mut pos)?;
+        match tokens.get(pos) {
+            Some(token) => Err(anyhow!("Unexpected '{token}' in expression '{s}'")),
+            None => Ok(ret),
+        }
+    }
+
+    /// The SPARQL variables used, lower case and without "?"
+    pub fn variables(&self) -> Vec<String> {
+        match self {
+            Self::Variable(name) => vec![name.to_owned()],
+            Self::Negate(e) => e.variables(),
+            Self::Binary((left, _, right)) => [left.variables(), right.variables()].concat(),
+            Self::Function((_, args)) => args.iter().flat_map(|arg| arg.variables()).collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Evaluates with values by lower case variable name
+    pub fn evaluate(&self, values: &HashMap<String, String>) -> ExpressionValue {
+        match self {
+            Self::Number(n) => ExpressionValue::Number(*n),
+            Self::Text(s) => ExpressionValue::Text(s.to_owned()),
+            Self::Variable(name) => match values.get(name) {
+                Some(value) => ExpressionValue::Text(value.to_owned()),
+                None => ExpressionValue::Empty,
+            },
+            Self::Negate(e) => match e.evaluate(values).as_number() {
+                Some(n) => ExpressionValue::Number(-n),
+                None => ExpressionValue::Empty,
+            },
+            Self::Binary((left, operator, right)) => {
+                Self::evaluate_binary(&left.evaluate(values), operator, &right.evaluate(values))
+            }
+            Self::Function((name, args)) => {
+                let args: Vec<ExpressionValue> = args.iter().map(|arg| arg.evaluate(values)).collect();
+                match (name.as_str(), args.as_slice()) {
+                    ("if", [condition, then, otherwise]) => match condition.is_true() {
+                        true => then.to_owned(),
+                        false => otherwise.to_owned(),
+                    },
+                    ("round", [value, digits]) => match (value.as_number(), digits.as_number()) {
+                        (Some(value), Some(digits)) if (0.0..=10.0).contains(&digits) => {
+                            ExpressionValue::Text(format!("{value:.*}", digits as usize))
+                        }
+                        _ => ExpressionValue::Empty,
+                    },
+                    _ => ExpressionValue::Empty,
+                }
+            }
+        }
+    }
+
+    fn evaluate_binary(left: &ExpressionValue, operator: &str, right: &ExpressionValue) -> ExpressionValue {
+        if operator == "&" {
+            return ExpressionValue::Text(left.as_text() + &right.as_text());
+        }
+        if *left == ExpressionValue::Empty || *right == ExpressionValue::Empty {
+            return ExpressionValue::Empty;
+        }
+        let numbers = left.as_number().zip(right.as_number());
+        let ordering = match numbers {
+            Some((l, r)) => l.partial_cmp(&r),
+            None => Some(left.as_text().cmp(&right.as_text())),
+        };
+        let compare = |accepts: fn(std::cmp::Ordering) -> bool| match ordering {
+            Some(ordering) => ExpressionValue::Bool(accepts(ordering)),
+            None => ExpressionValue::Empty,
+        };
+        match (operator, numbers) {
+            ("=", _) => compare(|o| o.is_eq()),
+            ("!=", _) => compare(|o| o.is_ne()),
+            ("<", _) => compare(|o| o.is_lt()),
+            ("<=", _) => compare(|o| o.is_le()),
+            (">", _) => compare(|o| o.is_gt()),
+            (">=", _) => compare(|o| o.is_ge()),
+            ("+", Some((l, r))) => ExpressionValue::Number(l + r),
+            ("-", Some((l, r))) => ExpressionValue::Number(l - r),
+            ("*", Some((l, r))) => ExpressionValue::Number(l * r),
+            ("/", Some((l, r))) if r != 0.0 => ExpressionValue::Number(l / r),
+            _ => ExpressionValue::Empty,
+        }
+    }
+
+    fn tokenize(s: &str) -> Result<Vec<String>> {
+        let chars: Vec<char> = s.chars().collect();
+        let mut ret = vec![];
+        let mut pos = 0;
+        while pos < chars.len() {
+            let c = chars[pos];
+            if c.is_whitespace() {
+                pos += 1;
+            } else if c == '"' || c == '\'' {
+                let end = chars[pos + 1..]
+                    .iter()
+                    .position(|d| *d == c)
+                    .ok_or_else(|| anyhow!("Unclosed string in expression '{s}'"))?;
+                ret.push(chars[pos..pos + end + 2].iter().collect());
+                pos += end + 2;
+            } else if c == '?' || c.is_alphanumeric() || c == '.' || c == '_' {
+                let start = pos;
+                pos += 1;
+                while pos < chars.len() && (chars[pos].is_alphanumeric() || chars[pos] == '_' || chars[pos] == '.') {
+                    pos += 1;
+                }
+                ret.push(chars[start..pos].iter().collect());
+            } else if c == '(' || c == ')' || c == ',' {
+                ret.push(c.to_string());
+                pos += 1;
+            } else {
+                let rest: String = chars[pos..].iter().take(2).collect();
+                let operator = OPERATORS
+                    .iter()
+                    .find(|operator| rest.starts_with(*operator))
+                    .ok_or_else(|| anyhow!("Unexpected '{c}' in expression '{s}'"))?;
+                ret.push(operator.to_string());
+                pos += operator.chars().count();
+            }
+        }
+        Ok(ret)
+    }
+
+    fn parse_binary(
+        tokens: &[String],
+        pos: &mut usize,
+        operators: &[&str],
+        next: fn(&[String], &mut usize) -> Result<Self>,
+    ) -> Result<Self> {
+        let mut left = next(tokens, pos)?;
+        while let Some(operator) = tokens.get(*pos).filter(|token| operators.contains(&token.as_str())) {
+            *pos += 1;
+            let right = next(tokens, pos)?;
+            left = Self::Binary((Box::new(left), operator.to_owned(), Box::new(right)));
+        }
+        Ok(left)
+    }
+
+    fn parse_comparison(tokens: &[String], pos: &mut usize) -> Result<Self> {
+        Self::parse_binary(tokens, pos, &["=", "!=", "<", "<=", ">", ">="], Self::parse_concat)
+    }
+
+    fn parse_concat(tokens: &[String], pos: &mut usize) -> Result<Self> {
+        Self::parse_binary(tokens, pos, &["&"], Self::parse_sum)
+    }
+
+    fn parse_sum(tokens: &[String], pos: &mut usize) -> Result<Self> {
+        Self::parse_binary(tokens, pos, &["+", "-"], Self::parse_product)
+    }
+
+    fn parse_product(tokens: &[String], pos: &mut usize) -> Result<Self> {
+        Self::parse_binary(tokens, pos, &["*", "/"], Self::parse_unary)
+    }
+
+    fn parse_unary(tokens: &[String], pos: &mut usize) -> Result<Self> {
+        if tokens.get(*pos).map(|t| t.as_str()) == Some("-") {
+            *pos += 1;
+            return Ok(Self::Negate(Box::new(Self::parse_unary(tokens, pos)?)));
+        }
+        Self::parse_primary(tokens, pos)
+    }
+
+    fn expect(tokens: &[String], pos: &mut usize, expected: &str) -> Result<()> {
+        match tokens.get(*pos) {
+            Some(token) if token == expected => {
+                *pos += 1;
+                Ok(())
+            }
+            Some(token) => Err(anyhow!("Expected '{expected}' instead of '{token}' in expression")),
+            None => Err(anyhow!("Expected '{expected}' at the end of expression")),
+        }
+    }
+
+    fn parse_primary(tokens: &[String], pos: &mut usize) -> Result<Self> {
+        let token = tokens
+            .get(*pos)
+            .ok_or_else(|| anyhow!("Unexpected end of expression"))?
+            .to_owned();
+        *pos += 1;
+        if token == "(" {
+            let ret = Self::parse_comparison(tokens, pos)?;
+            Self::expect(tokens, pos, ")")?;
+            return Ok(ret);
+        }
+        if let Some(name) = token.strip_prefix('?') {
+            if name.is_empty() {
+                return Err(anyhow!("Variable without a name in expression"));
+            }
+            return Ok(Self::Variable(name.to_lowercase()));
+        }
+        if token.starts_with('"') || token.starts_with('\'') {
+            return Ok(Self::Text(token[1..token.len() - 1].to_string()));
+        }
+        if let Ok(n) = token.parse::<f64>() {
+            return Ok(Self::Number(n));
+        }
+        let name = token.to_lowercase();
+        if !matches!(name.as_str(), "if" | "round") {
+            return Err(anyhow!("Unknown function or value '{token}' in expression"));
+        }
+        Self::expect(tokens, pos, "(")?;
+        let mut args = vec![Self::parse_comparison(tokens, pos)?];
+        while tokens.get(*pos).map(|t| t.as_str()) == Some(",") {
+            *pos += 1;
+            args.push(Self::parse_comparison(tokens, pos)?);
+        }
+        Self::expect(tokens, pos, ")")?;
+        Ok(Self::Function((name, args)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expression() {
+        let values: HashMap<String, String> = [("pop", "1000"), ("area", "8"), ("name", "X")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let eval = |s: &str| Expression::new(s).unwrap().evaluate(&values).as_text();
+        assert_eq!(eval("?pop / ?area"), "125");
+        assert_eq!(eval("?pop / 3"), "333.33");
+        assert_eq!(eval("round(?pop / 3, 0)"), "333");
+        assert_eq!(eval("-(?area + 2) * 2"), "-20");
+        assert_eq!(eval("?name & ' (' & ?AREA & ')'"), "X (8)");
+        assert_eq!(eval("if(?pop >= 1000, \"big\", \"small\")"), "big");
+        assert_eq!(eval("?pop / ?missing"), "");
+        assert_eq!(eval("?pop / 0"), "");
+        assert_eq!(Expression::new("?pop / ?Area").unwrap().variables(), vec!["pop", "area"]);
+        assert!(Expression::new("?pop /").is_err());
+        assert!(Expression::new("foo(?pop)").is_err());
+        assert!(Expression::new("(?pop").is_err());
+    }
+}
@@ -0,0 +1,243 @@
+use crate::api_client::ApiClient;
+use crate::lint;
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Titles per wbgetentities request when mapping pages to items
+const SITELINKS_BATCH_SIZE: usize = 50;
+
+lazy_static! {
+    static ref RE_ENTITY_ID: Regex =
+        Regex::new(r"^(?:[A-Za-z]+:)?([QPL]\d+)$").expect("RE_ENTITY_ID does not parse");
+    static ref RE_ITEM_IN_TEXT: Regex =
+        Regex::new(r"\b[Qq](\d+)\b").expect("RE_ITEM_IN_TEXT does not parse");
+    static ref RE_ITEM_LIST: Regex =
+        Regex::new(r"^\s*[Qq]\d+(\s*[,;\s]\s*[Qq]\d+)*\s*$").expect("RE_ITEM_LIST does not parse");
+}
+
+/// The entity ID in a Wikidata page title like "Q42" or "Property:P31"
+pub fn entity_id_from_title(title: &str) -> Option<String> {
+    RE_ENTITY_ID
+        .captures(title.trim())
+        .and_then(|caps| caps.get(1))
+        .map(|id| id.as_str().to_string())
+}
+
+/// True if the text is a plain list of items, like "Q1, Q2 Q3"
+pub fn is_item_list(text: &str) -> bool {
+    RE_ITEM_LIST.is_match(text)
+}
+
+/// All items mentioned in a text, in order, without duplicates
+pub fn items_in_text(text: &str) -> Vec<String> {
+    let mut ret: Vec<String> = vec![];
+    for caps in RE_ITEM_IN_TEXT.captures_iter(text) {
+        let item = format!("Q{}", &caps[1]);
+        if !ret.contains(&item) {
+            ret.push(item);
+        }
+    }
+    ret
+}
+
+/// Restricts a SPARQL query to the given items, with a VALUES clause for ?item at the start of the WHERE block
+pub fn restrict_sparql_to_items(sparql: &str, items: &[String]) -> Result<String> {
+    lazy_static! {
+        static ref RE_WHERE: Regex = Regex::new(r"(?i)(?:^|[^\w?$:])where\s*\{").expect("RE_WHERE does not parse");
+    }
+    if items.is_empty() {
+        return Err(anyhow!("No items to restrict the SPARQL query to"));
+    }
+    // Strings, IRIs and comments are blanked out, at the same byte positions as in the query
+    let code = lint::blank_literals(sparql);
+    let pos = match RE_WHERE.find(&code) {
+        Some(m) => m.end() - 1,
+        None => code.find('{').ok_or_else(|| anyhow!("Can not find the WHERE clause in the SPARQL query"))?,
+    };
+    let values: Vec<String> = items.iter().map(|q| format!("wd:{q}")).collect();
+    Ok(format!(
+        "{} VALUES ?item {{ {} }} {}",
+        &sparql[..=pos],
+        values.join(" "),
+        &sparql[pos + 1..]
+    ))
+}
+
+/// SPARQL JSON results with one ?item row per item, to use a plain item list instead of a query
+pub fn items_as_sparql_results(items: &[String], concept_base_uri: &str) -> Value {
+    let bindings: Vec<Value> = items
+        .iter()
+        .map(|q| json!({"item": {"type": "uri", "value": format!("{concept_base_uri}{q}")}}))
+        .collect();
+    json!({"head": {"vars": ["item"]}, "results": {"bindings": bindings}})
+}
+
+/// Maps page titles on a wiki to their Wikidata items; pages without an item are skipped
+pub async fn items_for_pages(wb_api: &dyn ApiClient, wiki: &str, titles: &[String]) -> Result<Vec<String>> {
+    let mut ret = vec![];
+    for chunk in titles.chunks(SITELINKS_BATCH_SIZE) {
+        let titles = chunk.join("|");
+        let params: HashMap<String, String> = [
+            ("action", "wbgetentities"),
+            ("sites", wiki),
+            ("titles", titles.as_str()),
+            ("props", "info"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let j = wb_api.get_query_api_json(&params).await?;
+        if let Some(entities) = j["entities"].as_object() {
+            ret.extend(
+                entities
+                    .keys()
+                    .filter(|id| entity_id_from_title(id).is_some())
+                    .cloned(),
+            );
+        }
+    }
+    Ok(ret)
+}
+
+/// Items of a PagePile. Piles from wikis other than Wikidata are mapped to items via sitelinks.
+pub async fn pagepile_items(wb_api: &dyn ApiClient, pagepile_id: &str) -> Result<Vec<String>> {
+    let pagepile_id = pagepile_id.trim();
+    if pagepile_id.is_empty() || !pagepile_id.chars().all(|c| c.is_ascii_digit()) {
+        return Err(anyhow!("Bad PagePile ID '{pagepile_id}'"));
+    }
+    let url = format!(
+        "https://pagepile.toolforge.org/api.php?id={pagepile_id}&action=get_data&format=json&doit"
+    );
+    let body = wb_api.get_url(&url).await?;
+    let j: Value = serde_json::from_str(&body)
+        .map_err(|_| anyhow!("PagePile {pagepile_id} could not be loaded"))?;
+    let wiki = j["wiki"]
+        .as_str()
+        .ok_or_else(|| anyhow!("PagePile {pagepile_id} does not exist"))?;
+    let pages: Vec<String> = j["pages"]
+        .as_array()
+        .ok_or_else(|| anyhow!("PagePile {pagepile_id} has no pages"))?
+        .iter()
+        .filter_map(|page| page.as_str())
+        .map(|page| page.replace('_', " "))
+        .collect();
+    if wiki == "wikidatawiki" {
+        Ok(pages.iter().filter_map(|page| entity_id_from_title(page)).collect())
+    } else {
+        items_for_pages(wb_api, wiki, &pages).await
+    }
+}
+
+/// Items in the results of a stored PetScan query.
+/// Pages are taken from their Wikidata item (`q`), or are items themselves if PetScan ran on Wikidata.
+pub async fn petscan_items(wb_api: &dyn ApiClient, psid: &str) -> Result<Vec<String>> {
+    let psid = psid.trim();
+    if psid.is_empty() || !psid.chars().all(|c| c.is_ascii_digit()) {
+        return Err(anyhow!("Bad PetScan ID '{psid}'"));
+    }
+    let url = format!("https://petscan.wmflabs.org/?psid={psid}&format=json&doit=1&wikidata_item=with");
+    let body = wb_api.get_url(&url).await?;
+    let j: Value = serde_json::from_str(&body)
+        .map_err(|_| anyhow!("PetScan query {psid} could not be run"))?;
+    if let Some(error) = j["error"].as_str() {
+        return Err(anyhow!("PetScan query {psid}: {error}"));
+    }
+    Ok(petscan_result_items(&j))
+}
+
+fn petscan_result_items(j: &Value) -> Vec<String> {
+    let wiki = j["a"]["wiki"].as_str().unwrap_or_default();
+    let mut ret = vec![];
+    for combination in j["*"].as_array().into_iter().flatten() {
+        for page in combination["a"]["*"].as_array().into_iter().flatten() {
+            let item = match page["q"].as_str() {
+                Some(q) => entity_id_from_title(q),
+                None if wiki == "wikidatawiki" => page["title"].as_str().and_then(entity_id_from_title),
+                None => None,
+            };
+            if let Some(item) = item {
+                if !ret.contains(&item) {
+                    ret.push(item);
+                }
+            }
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entity_id_from_title() {
+        assert_eq!(entity_id_from_title("Q42"), Some("Q42".to_string()));
+        assert_eq!(entity_id_from_title("Property:P31"), Some("P31".to_string()));
+        assert_eq!(entity_id_from_title("Douglas Adams"), None);
+    }
+
+    #[test]
+    fn test_items_in_text() {
+        assert!(is_item_list("Q1, q2 ;Q3"));
+        assert!(!is_item_list("User:Foo/Items"));
+        assert_eq!(
+            items_in_text("* [[d:Q1|one]]\n* Q2 (Q1)\n* Q3x"),
+            vec!["Q1".to_string(), "Q2".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_restrict_sparql_to_items() {
+        let items = vec!["Q1".to_string(), "Q2".to_string()];
+        let sparql = "SELECT ?item ?itemLabel WHERE { ?item wdt:P31 wd:Q5 }";
+        assert_eq!(
+            restrict_sparql_to_items(sparql, &items).unwrap(),
+            "SELECT ?item ?itemLabel WHERE { VALUES ?item { wd:Q1 wd:Q2 }  ?item wdt:P31 wd:Q5 }"
+        );
+        assert!(restrict_sparql_to_items("SELECT ?item", &items).is_err());
+        assert!(restrict_sparql_to_items(sparql, &[]).is_err());
+
+        // "where" in a prefix IRI, a comment or a variable is not the WHERE clause
+        let sparql = "PREFIX ex: <http://example.org/where{/>\n# Somewhere {\nSELECT ?item ?where { ?item ex:p ?where }";
+        assert_eq!(
+            restrict_sparql_to_items(sparql, &items).unwrap(),
+            "PREFIX ex: <http://example.org/where{/>\n# Somewhere {\nSELECT ?item ?where { VALUES ?item { wd:Q1 wd:Q2 }  ?item ex:p ?where }"
+        );
+
+        // Lowercasing "İ" changes its length in bytes
+        let sparql = "SELECT ?item wHeRe { ?item rdfs:label \"İstanbul\"@tr }";
+        assert_eq!(
+            restrict_sparql_to_items(sparql, &items).unwrap(),
+            "SELECT ?item wHeRe { VALUES ?item { wd:Q1 wd:Q2 }  ?item rdfs:label \"İstanbul\"@tr }"
+        );
+        let sparql = "SELECT ?item (\"İİİİ\" AS ?x) WHERE { ?item wdt:P31 wd:Q5 }";
+        assert_eq!(
+            restrict_sparql_to_items(sparql, &items).unwrap(),
+            "SELECT ?item (\"İİİİ\" AS ?x) WHERE { VALUES ?item { wd:Q1 wd:Q2 }  ?item wdt:P31 wd:Q5 }"
+        );
+    }
+
+    #[test]
+    fn test_petscan_result_items() {
+        let j = json!({"n":"result","a":{"wiki":"enwiki"},"*":[{"n":"combination","a":{"type":"subset","*":[
+            {"title":"Douglas_Adams","q":"Q42","namespace":0},
+            {"title":"No_item","namespace":0},
+            {"title":"Douglas_Adams","q":"Q42","namespace":0}
+        ]}}]});
+        assert_eq!(petscan_result_items(&j), vec!["Q42".to_string()]);
+        let j = json!({"a":{"wiki":"wikidatawiki"},"*":[{"a":{"*":[{"title":"Q1","namespace":0}]}}]});
+        assert_eq!(petscan_result_items(&j), vec!["Q1".to_string()]);
+    }
+
+    #[test]
+    fn test_items_as_sparql_results() {
+        let j = items_as_sparql_results(&["Q42".to_string()], "http://www.wikidata.org/entity/");
+        assert_eq!(j["head"]["vars"], json!(["item"]));
+        assert_eq!(
+            j["results"]["bindings"][0]["item"]["value"],
+            json!("http://www.wikidata.org/entity/Q42")
+        );
+    }
+}
@@ -3,33 +3,57 @@ extern crate lazy_static;
 #[macro_use]
 extern crate serde_json;
 
+pub mod api_client;
+pub mod cell_formatter;
+pub mod client_manager;
 pub mod column;
 pub mod configuration;
 pub mod entity_container_wrapper;
+#[cfg(feature = "bot")]
+pub mod event_stream;
+pub mod expression;
+pub mod item_source;
 pub mod listeria_list;
 pub mod listeria_page;
+pub mod lint;
+#[cfg(feature = "bot")]
 pub mod listeria_bot;
 pub mod reference;
+pub mod render_map;
+pub mod render_scribunto;
 pub mod render_tabbed_data;
 pub mod render_wikitext;
 pub mod result_cell;
 pub mod result_cell_part;
 pub mod result_row;
+pub mod row_filter;
+#[cfg(feature = "bot")]
+pub mod page_queue;
+#[cfg(feature = "python")]
+mod python;
+pub mod run_report;
+pub mod site_info;
+#[cfg(feature = "bot")]
+pub mod update_server;
+pub mod wiki_login;
 
+use crate::api_client::ApiClient;
+use crate::site_info::SiteInfo;
 use crate::column::*;
-use crate::configuration::Configuration;
-use crate::listeria_list::ListeriaList;
+use crate::configuration::{Configuration, ManualEditPolicy};
+use crate::listeria_list::{ListeriaList, RowChanges};
 use crate::listeria_page::ListeriaPage;
 use crate::render_wikitext::RendererWikitext;
 use anyhow::{Result,anyhow};
 use regex::Regex;
 use regex::RegexBuilder;
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::BufReader;
 use std::sync::Arc;
 use tokio::sync::RwLock;
+use unicode_normalization::UnicodeNormalization;
 use wikibase::entity::EntityTrait;
 use wikibase::mediawiki::api::Api;
 
@@ -38,14 +62,18 @@ pub struct PageParams {
     language: String,
     wiki: String,
     page: String,
-    mw_api: Arc<RwLock<Api>>,
-    wb_api: Arc<Api>,
+    mw_api: Option<Arc<RwLock<Api>>>,
+    api_client: Arc<dyn ApiClient>,
+    wb_api: Arc<dyn ApiClient>,
     simulate: bool,
     simulated_text: Option<String>,
     simulated_sparql_results: Option<String>,
     simulated_autodesc: Option<Vec<String>>,
     config: Arc<Configuration>,
     local_file_namespace_prefix: String,
+    site_info: Option<Arc<SiteInfo>>,
+    #[cfg(not(target_arch = "wasm32"))] // No clock for tokio in the browser, so no timeouts
+    deadline: Option<tokio::time::Instant>, // Processing stops after this, see `ListeriaPage::set_timeout`
 }
 
 impl PageParams {
@@ -54,29 +82,193 @@ impl PageParams {
         mw_api: Arc<RwLock<Api>>,
         page: String,
     ) -> Result<Self> {
-        let api = mw_api.read().await;
-        let ret = Self {
-            wiki: api.get_site_info_string("general", "wikiid")?.to_string(),
-            page,
-            language: api.get_site_info_string("general", "lang")?.to_string(),
-            mw_api: mw_api.clone(),
-            wb_api: config.get_default_wbapi()?.clone(),
-            simulate: false,
-            simulated_text: None,
-            simulated_sparql_results: None,
-            simulated_autodesc: None,
-            config: config.clone(),
-            local_file_namespace_prefix: api
-                .get_local_namespace_name(6)
-                .unwrap_or("File")
-                .to_string(),
-        };
-        Ok(ret)
+        PageParamsBuilder::new(config, &page).mw_api(mw_api).build().await
     }
 
     pub fn local_file_namespace_prefix(&self) -> &String {
         &self.local_file_namespace_prefix
     }
+
+    /// When processing the page has to be done by, if there is a timeout
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn deadline(&self) -> Option<tokio::time::Instant> {
+        self.deadline
+    }
+
+    /// An error once the deadline has passed; called between the steps of processing a list
+    pub fn check_deadline(&self) -> Result<()> {
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(deadline) = self.deadline {
+            if tokio::time::Instant::now() >= deadline {
+                return Err(anyhow!("Timed out"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Namespaces of this wiki, if known
+    pub fn site_info(&self) -> Option<&Arc<SiteInfo>> {
+        self.site_info.as_ref()
+    }
+
+    /// The namespace of a title on this wiki.
+    /// Without site info or a wiki API, only the main namespace and "Category:" are told apart.
+    pub async fn namespace_id(&self, title: &str) -> i64 {
+        if let Some(site_info) = &self.site_info {
+            return site_info.namespace_id(title);
+        }
+        match &self.mw_api {
+            Some(mw_api) => {
+                let api = mw_api.read().await;
+                wikibase::mediawiki::title::Title::new_from_full(title, &api).namespace_id()
+            }
+            None if title.starts_with("Category:") => 14,
+            None => 0,
+        }
+    }
+}
+
+/// Assembles `PageParams` from parts; unlike `PageParams::new`, nothing has to come from a live wiki.
+/// Wiki, language and file namespace default to the site info of the wiki API, if one is given;
+/// the language of a wiki in `wiki_languages` of the configuration comes before the site info.
+#[derive(Debug, Clone, Default)]
+pub struct PageParamsBuilder {
+    config: Option<Arc<Configuration>>,
+    page: String,
+    wiki: Option<String>,
+    language: Option<String>,
+    local_file_namespace_prefix: Option<String>,
+    mw_api: Option<Arc<RwLock<Api>>>,
+    api_client: Option<Arc<dyn ApiClient>>,
+    wb_api: Option<Arc<dyn ApiClient>>,
+    simulate: bool,
+}
+
+impl PageParamsBuilder {
+    pub fn new(config: Arc<Configuration>, page: &str) -> Self {
+        Self {
+            config: Some(config),
+            page: page.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn wiki(mut self, wiki: &str) -> Self {
+        self.wiki = Some(wiki.to_string());
+        self
+    }
+
+    pub fn language(mut self, language: &str) -> Self {
+        self.language = Some(language.to_string());
+        self
+    }
+
+    pub fn local_file_namespace_prefix(mut self, prefix: &str) -> Self {
+        self.local_file_namespace_prefix = Some(prefix.to_string());
+        self
+    }
+
+    /// The wiki the page is on; also used for API calls unless `api_client` is set
+    pub fn mw_api(mut self, mw_api: Arc<RwLock<Api>>) -> Self {
+        self.mw_api = Some(mw_api);
+        self
+    }
+
+    pub fn api_client(mut self, api_client: Arc<dyn ApiClient>) -> Self {
+        self.api_client = Some(api_client);
+        self
+    }
+
+    /// The Wikibase API for entities; defaults to the configured default one
+    pub fn wb_api(mut self, wb_api: Arc<dyn ApiClient>) -> Self {
+        self.wb_api = Some(wb_api);
+        self
+    }
+
+    pub fn simulate(mut self, simulate: bool) -> Self {
+        self.simulate = simulate;
+        self
+    }
+
+    pub async fn build(self) -> Result<PageParams> {
+        let config = self.config.ok_or_else(|| anyhow!("No configuration for page parameters"))?;
+        let (mut wiki, mut language, mut prefix) = (self.wiki, self.language, self.local_file_namespace_prefix);
+        if let Some(mw_api) = &self.mw_api {
+            let api = mw_api.read().await;
+            if wiki.is_none() {
+                wiki = Some(api.get_site_info_string("general", "wikiid")?.to_string());
+            }
+            if language.is_none() {
+                language = wiki.as_ref().and_then(|wiki| config.wiki_language(wiki)).cloned();
+            }
+            if language.is_none() {
+                language = Some(api.get_site_info_string("general", "lang")?.to_string());
+            }
+            if prefix.is_none() {
+                prefix = api.get_local_namespace_name(6).map(|s| s.to_string());
+            }
+        }
+        if language.is_none() {
+            language = wiki.as_ref().and_then(|wiki| config.wiki_language(wiki)).cloned();
+        }
+        let api_client: Arc<dyn ApiClient> = match (self.api_client, &self.mw_api) {
+            (Some(api_client), _) => api_client,
+            (None, Some(mw_api)) => config.client_manager().api_client_for(mw_api.clone()).await?,
+            (None, None) => return Err(anyhow!("Page parameters need a wiki API or an API client")),
+        };
+        // Once per wiki; from the wiki API if there is one, or through the API client
+        let site_info = match (&self.mw_api, &wiki) {
+            (Some(mw_api), Some(wiki)) => {
+                let api = mw_api.read().await;
+                let j = &api.get_site_info()["query"];
+                match j["namespaces"].is_object() {
+                    true => Some(config.client_manager().site_info_from_json(wiki, j).await),
+                    false => None,
+                }
+            }
+            (None, Some(wiki)) => config.client_manager().site_info(wiki, api_client.as_ref()).await.ok(),
+            _ => None,
+        };
+        if prefix.is_none() {
+            prefix = site_info
+                .as_ref()
+                .and_then(|site_info| site_info.local_namespace_name(6))
+                .cloned();
+        }
+        let wb_api = match self.wb_api {
+            Some(wb_api) => wb_api,
+            None => config.get_default_wbapi()?.clone(),
+        };
+        Ok(PageParams {
+            wiki: wiki.ok_or_else(|| anyhow!("No wiki for page parameters"))?,
+            page: self.page,
+            language: language.ok_or_else(|| anyhow!("No language for page parameters"))?,
+            mw_api: self.mw_api,
+            api_client,
+            wb_api,
+            simulate: self.simulate,
+            simulated_text: None,
+            simulated_sparql_results: None,
+            simulated_autodesc: None,
+            config,
+            local_file_namespace_prefix: prefix.unwrap_or_else(|| "File".to_string()),
+            site_info,
+            #[cfg(not(target_arch = "wasm32"))]
+            deadline: None,
+        })
+    }
+}
+
+/// Languages written right-to-left, by their Wikimedia language code
+const RTL_LANGUAGES: &[&str] = &[
+    "aeb", "aeb-arab", "ar", "arc", "arq", "ary", "arz", "azb", "bcc", "bgn", "bqi", "ckb", "dv",
+    "fa", "glk", "he", "khw", "ks", "ks-arab", "lki", "lrc", "luz", "mzn", "nqo", "ota", "pnb",
+    "ps", "sd", "sdh", "skr", "skr-arab", "ug", "ug-arab", "ur", "yi",
+];
+
+/// Whether text in this language is written right-to-left
+pub fn is_rtl_language(language: &str) -> bool {
+    RTL_LANGUAGES.contains(&language.to_lowercase().as_str())
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -89,6 +281,36 @@ impl LatLon {
     pub fn new(lat: f64, lon: f64) -> Self {
         Self { lat, lon }
     }
+
+    /// Parses a WKT point like "Point(13.4 52.5)" (longitude first).
+    /// Allows a CRS prefix ("<http://www.wikidata.org/entity/Q2> Point(...)" or "SRID=4326;"),
+    /// Z/M dimensions, and any number format, e.g. "12" or "1.2e1".
+    pub fn new_from_wkt(wkt: &str) -> Option<Self> {
+        /// The rest of `s` after a case-insensitive keyword
+        fn strip_keyword<'a>(s: &'a str, keyword: &str) -> Option<&'a str> {
+            s.get(..keyword.len())
+                .filter(|start| start.eq_ignore_ascii_case(keyword))
+                .and_then(|_| s.get(keyword.len()..))
+        }
+        let mut s = wkt.trim();
+        if s.starts_with('<') {
+            s = s.split_once('>')?.1.trim_start();
+        }
+        if strip_keyword(s, "SRID=").is_some() {
+            s = s.split_once(';')?.1.trim_start();
+        }
+        let s = strip_keyword(s, "POINT")?.trim_start();
+        let s = s.trim_start_matches(['Z', 'M', 'z', 'm']).trim_start();
+        let coordinates = s.strip_prefix('(')?.strip_suffix(')')?;
+        let numbers: Vec<f64> = coordinates
+            .split_whitespace()
+            .map(|n| n.parse::<f64>().ok().filter(|n| n.is_finite()))
+            .collect::<Option<Vec<f64>>>()?;
+        match numbers.as_slice() {
+            [lon, lat, ..] if numbers.len() <= 4 => Some(Self::new(*lat, *lon)),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -104,12 +326,9 @@ pub enum SparqlValue {
 impl SparqlValue {
     pub fn new_from_json(j: &Value) -> Option<Self> {
         lazy_static! {
+            // Entities, lexeme forms and senses, and properties in their predicate forms (/prop/direct/P31 etc.)
             static ref RE_ENTITY: Regex =
-                Regex::new(r#"^https{0,1}://[^/]+/entity/([A-Z]\d+)$"#).expect("RE_ENTITY does not parse");
-            static ref RE_FILE: Regex =
-                Regex::new(r#"^https{0,1}://[^/]+/wiki/Special:FilePath/(.+?)$"#).expect("RE_FILE does not parse");
-            static ref RE_POINT: Regex =
-                Regex::new(r#"^Point\((-{0,1}\d+[\.0-9]+) (-{0,1}\d+[\.0-9]+)\)$"#).expect("RE_POINT does not parse");
+                Regex::new(r#"^https{0,1}://[^/]+/(?:entity/([A-Z]\d+|L\d+-[FS]\d+)|prop/(?:[a-z-]+/)?(P\d+))$"#).expect("RE_ENTITY does not parse");
             static ref RE_DATE: Regex =
                 Regex::new(r#"^([+-]{0,1}\d+-\d{2}-\d{2})T00:00:00Z$"#).expect("RE_DATE does not parse");
         }
@@ -119,33 +338,18 @@ impl SparqlValue {
         };
         match j["type"].as_str() {
             Some("uri") => match RE_ENTITY.captures(&value) {
-                Some(caps) => match caps.get(1) {
-                    Some(caps1) => Some(SparqlValue::Entity(caps1.as_str().to_string())),
-                    None => None,
-                },
-                None => match RE_FILE.captures(&value) {
-                    Some(caps) => match caps.get(1) {
-                        Some(caps1) => {
-                            let file = caps1.as_str().to_string();
-                            let file = urlencoding::decode(&file).ok()?;
-                            let file = file.replace("_", " ");
-                            Some(SparqlValue::File(file))
-                        }
-                        None => None,
-                    },
+                Some(caps) => caps
+                    .get(1)
+                    .or_else(|| caps.get(2))
+                    .map(|caps1| SparqlValue::Entity(caps1.as_str().to_string())),
+                None => match Self::file_from_url(value) {
+                    Some(file) => Some(SparqlValue::File(file)),
                     None => Some(SparqlValue::Uri(value.to_string())),
                 },
             },
             Some("literal") => match j["datatype"].as_str() {
                 Some("http://www.opengis.net/ont/geosparql#wktLiteral") => {
-                    match RE_POINT.captures(&value) {
-                        Some(caps) => {
-                            let lat: f64 = caps.get(2)?.as_str().parse().ok()?;
-                            let lon: f64 = caps.get(1)?.as_str().parse().ok()?;
-                            Some(SparqlValue::Location(LatLon::new(lat, lon)))
-                        }
-                        None => None,
-                    }
+                    LatLon::new_from_wkt(value).map(SparqlValue::Location)
                 }
                 Some("http://www.w3.org/2001/XMLSchema#dateTime") => {
                     let time = value.to_string();
@@ -167,8 +371,59 @@ impl SparqlValue {
             _ => None,
         }
     }
+
+    /// The file name in a Special:FilePath URL (with or without a query string like "?width=300"),
+    /// or in an upload.wikimedia.org URL of a file or one of its thumbnails
+    fn file_from_url(url: &str) -> Option<String> {
+        lazy_static! {
+            static ref RE_FILE: Regex =
+                Regex::new(r#"^https{0,1}://[^/]+/wiki/Special:FilePath/([^?#]+)"#).expect("RE_FILE does not parse");
+            static ref RE_UPLOAD_THUMB: Regex =
+                Regex::new(r#"^https{0,1}://upload\.wikimedia\.org/[^/]+/[^/]+/thumb/[0-9a-f]/[0-9a-f]{2}/([^/?#]+)/"#)
+                    .expect("RE_UPLOAD_THUMB does not parse");
+            static ref RE_UPLOAD: Regex =
+                Regex::new(r#"^https{0,1}://upload\.wikimedia\.org/[^/]+/[^/]+/[0-9a-f]/[0-9a-f]{2}/([^/?#]+)$"#)
+                    .expect("RE_UPLOAD does not parse");
+        }
+        let file = [&*RE_FILE, &*RE_UPLOAD_THUMB, &*RE_UPLOAD]
+            .iter()
+            .find_map(|re| re.captures(url))?
+            .get(1)?
+            .as_str()
+            .to_string();
+        let file = urlencoding::decode(&file).ok()?;
+        Some(file.replace('_', " "))
+    }
+
+    /// The raw value as text, for expression columns
+    pub fn as_plain_text(&self) -> String {
+        match self {
+            Self::Entity(s) | Self::File(s) | Self::Uri(s) | Self::Time(s) | Self::Literal(s) => s.to_owned(),
+            Self::Location(ll) => format!("{},{}", ll.lat, ll.lon),
+        }
+    }
 }
 
+lazy_static! {
+    static ref RE_NOWIKI: Regex = RegexBuilder::new(r"<nowiki\s*>(.*?)</nowiki\s*>|<nowiki\s*/>")
+        .case_insensitive(true)
+        .dot_matches_new_line(true)
+        .build()
+        .expect("RE_NOWIKI does not parse");
+}
+
+/// Parameters of the list template that Listeria reads; "freq" is for the update schedule on the wiki
+const KNOWN_TEMPLATE_PARAMETERS: &[&str] = &[
+    "api", "as_of", "autodesc", "autolist", "collapsible", "columns", "combine_separator",
+    "current_only", "data_module", "date_ranges", "empty_cell", "filter", "footer", "freq",
+    "header_links", "header_template", "hide_empty_columns", "items", "language", "links", "map",
+    "min_section", "one_row_per_item", "pagepile", "petscan", "point_in_time", "references",
+    "row_template", "sample", "sample_seed", "section", "section_sort", "section_sort_order",
+    "skip_table", "sort", "sort_empty", "sort_ignore_articles", "sort_order", "source", "sparql",
+    "sparql_page", "sparql_prefixes", "statement_links", "summary", "targets", "thumb", "timings",
+    "toc", "wdedit", "wikibase",
+];
+
 #[derive(Debug, Clone, Default)]
 pub struct Template {
     pub title: String,
@@ -176,13 +431,78 @@ pub struct Template {
 }
 
 impl Template {
+    /// Parameters that Listeria does not know, sorted, each with the known one it is probably a misspelling of
+    pub fn unknown_params(&self) -> Vec<(String, Option<&'static str>)> {
+        let mut ret: Vec<(String, Option<&'static str>)> = self
+            .params
+            .keys()
+            .filter(|key| !key.is_empty() && !key.chars().all(|c| c.is_ascii_digit())) // Positional
+            .filter(|key| !KNOWN_TEMPLATE_PARAMETERS.contains(&key.trim().to_lowercase().as_str()))
+            .map(|key| {
+                let lower = key.trim().to_lowercase();
+                let suggestion = KNOWN_TEMPLATE_PARAMETERS
+                    .iter()
+                    .map(|known| (Self::edit_distance(&lower, known), *known))
+                    .filter(|(distance, _)| *distance <= 2)
+                    .min()
+                    .map(|(_, known)| known);
+                (key.to_owned(), suggestion)
+            })
+            .collect();
+        ret.sort();
+        ret
+    }
+
+    /// Whether the source parameter is "wiki:page" of this list, so the list there may render into this one
+    pub fn names_source(&self, wiki: &str, page: &str) -> bool {
+        let normalize = |title: &str| {
+            let title = title.replace('_', " ");
+            let mut chars = title.trim().chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().collect::<String>() + chars.as_str(),
+                None => String::new(),
+            }
+        };
+        self.params
+            .iter()
+            .filter(|(key, _)| key.trim().eq_ignore_ascii_case("source"))
+            .flat_map(|(_, value)| TemplateParams::parse_targets(value))
+            .any(|(source_wiki, source_page)| source_wiki == wiki.to_lowercase() && normalize(&source_page) == normalize(page))
+    }
+
+    /// Levenshtein distance
+    fn edit_distance(a: &str, b: &str) -> usize {
+        let b: Vec<char> = b.chars().collect();
+        let mut row: Vec<usize> = (0..=b.len()).collect();
+        for (i, ca) in a.chars().enumerate() {
+            let mut previous = row[0];
+            row[0] = i + 1;
+            for (j, cb) in b.iter().enumerate() {
+                let substitution = previous + usize::from(ca != *cb);
+                previous = row[j + 1];
+                row[j + 1] = substitution.min(row[j] + 1).min(previous + 1);
+            }
+        }
+        row[b.len()]
+    }
+
     pub fn new_from_params(title: String, text: String) -> Result<Self> {
+        // Nothing inside <nowiki> can separate parameters; swap it out while splitting
+        let mut nowiki_parts: Vec<String> = vec![];
+        let text = RE_NOWIKI
+            .replace_all(&text, |caps: &regex::Captures| {
+                nowiki_parts.push(caps[0].to_string());
+                format!("\u{7f}UNIQ-nowiki-{}-QINU\u{7f}", nowiki_parts.len() - 1)
+            })
+            .to_string();
         let mut curly_braces = 0;
+        let mut links = 0; // Pipes in [[links]] do not separate parameters
         let mut parts: Vec<String> = vec![];
         let mut part: Vec<char> = vec![];
         let mut quoted = false ;
         let mut quote_char : char = ' ' ;
-        text.chars().for_each(|c| match c {
+        let chars: Vec<char> = text.chars().collect();
+        chars.iter().enumerate().for_each(|(pos, &c)| match c {
             '\''|'"' => {
                 if quoted {
                     if quote_char == c {
@@ -202,13 +522,17 @@ impl Template {
                 curly_braces -= 1;
                 part.push(c);
             }
-            '|' => {
-                if curly_braces == 0 && !quoted {
-                    parts.push(part.iter().collect());
-                    part.clear();
-                } else {
-                    part.push(c);
-                }
+            '[' if chars.get(pos + 1) == Some(&'[') => {
+                links += 1;
+                part.push(c);
+            }
+            ']' if links > 0 && pos > 0 && chars[pos - 1] == ']' => {
+                links -= 1;
+                part.push(c);
+            }
+            '|' if curly_braces == 0 && links == 0 && !quoted => {
+                parts.push(part.iter().collect());
+                part.clear();
             }
             _ => {
                 part.push(c);
@@ -219,13 +543,21 @@ impl Template {
             return Err ( anyhow!("Unclosed quote: {quote_char}") ) ;
         }
 
+        let restore_nowiki = |s: &str| {
+            nowiki_parts
+                .iter()
+                .enumerate()
+                .fold(s.to_string(), |s, (num, nowiki)| {
+                    s.replace(&format!("\u{7f}UNIQ-nowiki-{num}-QINU\u{7f}"), nowiki)
+                })
+        };
         let params: HashMap<String, String> = parts
             .iter()
             .filter_map(|part| {
                 let pos = part.find('=')?;
                 let k = part.get(0..pos)?.trim().to_string();
-                let v = part.get(pos + 1..)?.trim().to_string();
-                Some((k, v))
+                let v = part.get(pos + 1..)?.trim();
+                Some((k, restore_nowiki(v)))
             })
             .collect();
         Ok ( Self { title, params } )
@@ -235,10 +567,25 @@ impl Template {
         self.params = self
             .params
             .iter()
-            .map(|(k, v)| (k.to_owned(), v.replace("{{!}}", "|")))
+            .map(|(k, v)| (k.to_owned(), Self::expand_value(v)))
             .collect();
         // TODO proper template replacement
     }
+
+    /// Expands the {{!}} and {{=}} magic words, and unwraps <nowiki>, whose content is kept as it is
+    fn expand_value(value: &str) -> String {
+        let expand = |s: &str| s.replace("{{!}}", "|").replace("{{=}}", "=");
+        let mut ret = String::new();
+        let mut last = 0;
+        for caps in RE_NOWIKI.captures_iter(value) {
+            let m = caps.get(0).expect("RE_NOWIKI has no match");
+            ret += &expand(&value[last..m.start()]);
+            ret += caps.get(1).map(|c| c.as_str()).unwrap_or_default();
+            last = m.end();
+        }
+        ret += &expand(&value[last..]);
+        ret
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -270,6 +617,7 @@ pub enum SortMode {
     FamilyName,
     Property(String),
     SparqlVariable(String),
+    Metadata(ColumnType), // Modified, StatementCount, IdentifierCount
     None,
 }
 
@@ -284,6 +632,9 @@ impl SortMode {
             Some(s) => match s.as_str() {
                 "LABEL" => Self::Label,
                 "FAMILY_NAME" => Self::FamilyName,
+                "MODIFIED" => Self::Metadata(ColumnType::Modified),
+                "STATEMENTS" => Self::Metadata(ColumnType::StatementCount),
+                "IDENTIFIERS" => Self::Metadata(ColumnType::IdentifierCount),
                 other => {
                     if RE_PROP.is_match(other) {
                         Self::Property(other.to_string())
@@ -320,6 +671,215 @@ impl SortOrder {
     }
 }
 
+/// Which properties show only statements that are valid now (or at `as_of`), going by P580/P582
+#[derive(Debug, Clone, PartialEq)]
+pub enum CurrentOnly {
+    None,
+    All,
+    Properties(Vec<String>),
+}
+
+impl CurrentOnly {
+    pub fn new(os: Option<&String>) -> Self {
+        let s = match os {
+            Some(s) => s.trim().to_uppercase(),
+            None => return Self::None,
+        };
+        match s.as_str() {
+            "" | "NO" => Self::None,
+            "YES" | "ALL" => Self::All,
+            _ => Self::Properties(
+                s.split(',')
+                    .map(|p| p.trim().to_string())
+                    .filter(|p| !p.is_empty())
+                    .collect(),
+            ),
+        }
+    }
+
+    pub fn applies_to(&self, property: &str) -> bool {
+        match self {
+            Self::None => false,
+            Self::All => true,
+            Self::Properties(properties) => properties.iter().any(|p| p == property),
+        }
+    }
+
+    /// Parses a date like "2020-05-17", "+2020-05-00T00:00:00Z", or "-0044" into (year, month, day).
+    /// Missing or zero month/day become the start of the period, or its end if `end_of_period` is set.
+    pub fn parse_date(s: &str, end_of_period: bool) -> Option<(i64, u32, u32)> {
+        let s = s.trim();
+        let (sign, s) = match s.strip_prefix('-') {
+            Some(s) => (-1, s),
+            None => (1, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let s = s.split('T').next()?;
+        let mut parts = s.split('-');
+        let year = sign * parts.next()?.parse::<i64>().ok()?;
+        let month = parts.next().map(|m| m.parse::<u32>().ok()).unwrap_or(Some(0))?;
+        let day = parts.next().map(|d| d.parse::<u32>().ok()).unwrap_or(Some(0))?;
+        if month > 12 || day > 31 {
+            return None;
+        }
+        Some(match (month, day, end_of_period) {
+            (0, _, true) => (year, 12, 31),
+            (_, 0, true) => (year, month, 31),
+            _ => (year, month, day),
+        })
+    }
+}
+
+/// Where rows without a value to sort by go, regardless of sort order
+#[derive(Debug, Clone, PartialEq)]
+pub enum SortEmpty {
+    Default,
+    First,
+    Last,
+}
+
+impl SortEmpty {
+    pub fn new(os: Option<&String>) -> Self {
+        match os.map(|s| s.trim().to_uppercase()).as_deref() {
+            Some("FIRST") => Self::First,
+            Some("LAST") => Self::Last,
+            _ => Self::Default,
+        }
+    }
+}
+
+/// If a list with coordinates also goes to a Data:*.map page on Commons
+#[derive(Debug, Clone, PartialEq)]
+pub enum MapMode {
+    None,
+    Also, // Map, and the coordinates in the table
+    Only, // Map, instead of the coordinates in the table
+}
+
+impl MapMode {
+    pub fn new(os: Option<&String>) -> Self {
+        match os.map(|s| s.trim().to_uppercase()).as_deref() {
+            Some("YES") | Some("ALSO") => Self::Also,
+            Some("ONLY") => Self::Only,
+            _ => Self::None,
+        }
+    }
+}
+
+/// Where the summary line of the list goes, from the footer parameter
+#[derive(Debug, Clone, PartialEq)]
+pub enum FooterMode {
+    Bottom,   // Under the list
+    Top,      // Above the list
+    None,     // Left out
+    Template, // The footer template of the wiki instead, with the row count and the time of the edit
+}
+
+impl FooterMode {
+    pub fn new(os: Option<&String>) -> Self {
+        match os.map(|s| s.trim().to_uppercase()).as_deref() {
+            Some("TOP") | Some("ABOVE") => Self::Top,
+            Some("NO") | Some("NONE") => Self::None,
+            Some("TEMPLATE") => Self::Template,
+            _ => Self::Bottom,
+        }
+    }
+}
+
+/// The table of contents of a list with sections, from the toc parameter
+#[derive(Debug, Clone, PartialEq)]
+pub enum TocMode {
+    Default,    // As the wiki does it
+    Show,       // __TOC__ before the first section
+    Hide,       // __NOTOC__
+    Limit(u64), // Only headings up to this level, with the TOC limit template of the wiki
+}
+
+impl TocMode {
+    pub fn new(os: Option<&String>) -> Self {
+        let s = os.map(|s| s.trim().to_uppercase()).unwrap_or_default();
+        match s.as_str() {
+            "YES" | "SHOW" => Self::Show,
+            "NO" | "NONE" | "HIDE" => Self::Hide,
+            _ => match s.parse::<u64>() {
+                Ok(level) if (1..=6).contains(&level) => Self::Limit(level),
+                _ => Self::Default,
+            },
+        }
+    }
+}
+
+/// If the table of each section can be collapsed by the reader, from the collapsible parameter
+#[derive(Debug, Clone, PartialEq)]
+pub enum Collapsible {
+    None,
+    Expanded,  // Collapsible, shown at first
+    Collapsed, // Collapsible, hidden at first
+}
+
+impl Collapsible {
+    pub fn new(os: Option<&String>) -> Self {
+        match os.map(|s| s.trim().to_uppercase()).as_deref() {
+            Some("YES") | Some("EXPANDED") => Self::Expanded,
+            Some("COLLAPSED") => Self::Collapsed,
+            _ => Self::None,
+        }
+    }
+
+    /// CSS classes for the table or its wrapper, with a leading space
+    pub fn classes(&self) -> &str {
+        match self {
+            Self::None => "",
+            Self::Expanded => " mw-collapsible",
+            Self::Collapsed => " mw-collapsible mw-collapsed",
+        }
+    }
+}
+
+/// What the generated headers of property columns add to the property label, from the header_links parameter
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeaderLinks {
+    None,
+    Link,    // The label links to the property page
+    Tooltip, // The property ID and description show when hovering over the label
+}
+
+impl HeaderLinks {
+    pub fn new(os: Option<&String>) -> Self {
+        match os.map(|s| s.trim().to_uppercase()).as_deref() {
+            Some("YES") | Some("LINK") => Self::Link,
+            Some("TOOLTIP") => Self::Tooltip,
+            _ => Self::None,
+        }
+    }
+}
+
+/// A part of the line under the list, from the summary parameter, eg "itemnumber,querytime"
+#[derive(Debug, Clone, PartialEq)]
+pub enum SummaryToken {
+    ItemNumber,
+    QueryTime,  // Time the SPARQL query took
+    Sections,   // Number of sections
+    Endpoint,   // The SPARQL service
+    LastChange, // Time of the last revision of the page
+    Truncated,  // If sample left out rows
+}
+
+impl SummaryToken {
+    pub fn new_list(s: &str) -> Vec<Self> {
+        s.split([',', ';', ' '])
+            .filter_map(|token| match token.trim().to_uppercase().as_str() {
+                "ITEMNUMBER" => Some(Self::ItemNumber),
+                "QUERYTIME" => Some(Self::QueryTime),
+                "SECTIONS" => Some(Self::Sections),
+                "ENDPOINT" => Some(Self::Endpoint),
+                "LASTCHANGE" => Some(Self::LastChange),
+                "TRUNCATED" => Some(Self::Truncated),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum ReferencesParameter {
     None,
@@ -350,13 +910,37 @@ pub struct TemplateParams {
     row_template: Option<String>,
     header_template: Option<String>,
     autodesc: Option<String>,
-    summary: Option<String>,
+    summary: Vec<SummaryToken>,
     skip_table: bool,
     pub wdedit: bool,
+    pub timings: bool, // Adds how long the steps took as a comment after the table
     references: ReferencesParameter,
     one_row_per_item: bool,
     sort_order: SortOrder,
     wikibase: String,
+    current_only: CurrentOnly,
+    pub statement_links: CurrentOnly, // Properties whose values link to their statement; same syntax as current_only
+    pub point_in_time: CurrentOnly,   // Properties whose values get the year (or date) of their P585 qualifier
+    pub date_ranges: CurrentOnly,     // Properties whose values get the time span of their P580/P582 qualifiers
+    as_of: Option<String>,
+    filter: Option<String>,
+    combine_separator: String,
+    hide_empty_columns: bool,
+    empty_cell: Option<String>,
+    section_sort: SortMode,
+    section_sort_order: SortOrder,
+    sort_empty: SortEmpty,
+    sort_ignore_articles: bool, // Label sort skips leading articles like "The", see `Configuration::sort_articles`
+    sample: Option<usize>,
+    sample_seed: Option<u64>,
+    data_module: Option<String>,
+    map: MapMode,
+    header_links: HeaderLinks,
+    pub collapsible: Collapsible,
+    pub toc: TocMode,
+    pub footer: FooterMode,
+    targets: Vec<(String, String)>, // Wiki, page
+    sparql_prefixes: Option<String>,
 }
 
 impl Default for TemplateParams {
@@ -375,13 +959,37 @@ impl TemplateParams {
             row_template: None,
             header_template: None,
             autodesc: None,
-            summary: None,
+            summary: vec![],
             skip_table: false,
+            timings: false,
             wdedit: false,
             references: ReferencesParameter::None,
             one_row_per_item: false,
             sort_order: SortOrder::Ascending,
             wikibase: String::new(),
+            current_only: CurrentOnly::None,
+            statement_links: CurrentOnly::None,
+            point_in_time: CurrentOnly::None,
+            date_ranges: CurrentOnly::None,
+            as_of: None,
+            filter: None,
+            combine_separator: ", ".to_string(),
+            hide_empty_columns: false,
+            empty_cell: None,
+            section_sort: SortMode::None,
+            section_sort_order: SortOrder::Ascending,
+            sort_empty: SortEmpty::Default,
+            sort_ignore_articles: false,
+            sample: None,
+            sample_seed: None,
+            data_module: None,
+            map: MapMode::None,
+            header_links: HeaderLinks::None,
+            collapsible: Collapsible::None,
+            toc: TocMode::Default,
+            footer: FooterMode::Bottom,
+            targets: vec![],
+            sparql_prefixes: None,
         }
     }
 
@@ -416,7 +1024,8 @@ impl TemplateParams {
             summary: template
                 .params
                 .get("summary")
-                .map(|s| s.trim().to_uppercase()),
+                .map(|s| SummaryToken::new_list(s))
+                .unwrap_or_default(),
             skip_table: template.params.get("skip_table").is_some(),
             one_row_per_item: template
                 .params
@@ -428,15 +1037,95 @@ impl TemplateParams {
                 .get("wdedit")
                 .map(|s| s.trim().to_uppercase())
                 == Some("YES".to_string()),
+            timings: template
+                .params
+                .get("timings")
+                .map(|s| s.trim().to_uppercase())
+                == Some("YES".to_string()),
             references: ReferencesParameter::new(template.params.get("references")),
             sort_order: SortOrder::new(template.params.get("sort_order")),
+            // Empty for the default API of the configuration
             wikibase: template
                 .params
-                .get("wikibase")
+                .get("api")
+                .or_else(|| template.params.get("wikibase"))
+                .map(|s| s.trim().to_lowercase())
+                .unwrap_or_default(),
+            current_only: CurrentOnly::new(template.params.get("current_only")),
+            statement_links: CurrentOnly::new(template.params.get("statement_links")),
+            point_in_time: CurrentOnly::new(template.params.get("point_in_time")),
+            date_ranges: CurrentOnly::new(template.params.get("date_ranges")),
+            as_of: template
+                .params
+                .get("as_of")
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty()),
+            filter: template
+                .params
+                .get("filter")
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty()),
+            combine_separator: template
+                .params
+                .get("combine_separator")
+                .map(|s| s.to_string())
+                .unwrap_or_else(|| ", ".to_string()),
+            hide_empty_columns: template
+                .params
+                .get("hide_empty_columns")
                 .map(|s| s.trim().to_uppercase())
-                .unwrap_or_else(|| "wikidata".to_string()), // TODO config
+                == Some("YES".to_string()),
+            empty_cell: template
+                .params
+                .get("empty_cell")
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty()),
+            section_sort: SortMode::new(template.params.get("section_sort")),
+            section_sort_order: SortOrder::new(template.params.get("section_sort_order")),
+            sort_empty: SortEmpty::new(template.params.get("sort_empty")),
+            sort_ignore_articles: template
+                .params
+                .get("sort_ignore_articles")
+                .map(|s| s.trim().to_uppercase())
+                == Some("YES".to_string()),
+            sample: template
+                .params
+                .get("sample")
+                .and_then(|s| s.trim().parse::<usize>().ok()),
+            sample_seed: template
+                .params
+                .get("sample_seed")
+                .and_then(|s| s.trim().parse::<u64>().ok()),
+            data_module: template
+                .params
+                .get("data_module")
+                .map(|s| s.trim().replace('_', " "))
+                .filter(|s| !s.is_empty()),
+            map: MapMode::new(template.params.get("map")),
+            header_links: HeaderLinks::new(template.params.get("header_links")),
+            collapsible: Collapsible::new(template.params.get("collapsible")),
+            toc: TocMode::new(template.params.get("toc")),
+            footer: FooterMode::new(template.params.get("footer")),
+            targets: template
+                .params
+                .get("targets")
+                .map(|s| Self::parse_targets(s))
+                .unwrap_or_default(),
+            sparql_prefixes: template
+                .params
+                .get("sparql_prefixes")
+                .map(|s| s.trim().to_string()),
         }
     }
+
+    /// "dewiki:Liste der X; frwiki:Liste des X" to (wiki, page) pairs
+    fn parse_targets(s: &str) -> Vec<(String, String)> {
+        s.split(';')
+            .filter_map(|target| target.split_once(':'))
+            .map(|(wiki, page)| (wiki.trim().to_lowercase(), page.trim().replace('_', " ")))
+            .filter(|(wiki, page)| !wiki.is_empty() && !page.is_empty())
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -481,35 +1170,41 @@ pub trait Renderer {
     ) -> Result<Option<String>>;
 }
 
+/// Marks the HTML comment that replaces a list that could not be generated
+const LIST_ERROR_COMMENT_PREFIX: &str = "Listeria error: ";
+const LIST_WARNING_COMMENT_PREFIX: &str = "Listeria warning: ";
+
+/// Marks the hash of the generated table, to detect manual edits
+const LIST_HASH_COMMENT_PREFIX: &str = "Listeria hash: ";
+
+/// Marks the comment with how long the steps of making the list took, see the timings parameter
+const LIST_TIMINGS_COMMENT_PREFIX: &str = "Listeria timings: ";
+
 #[derive(Debug, Clone)]
 pub struct PageElement {
     before: String,
     template_start: String,
-    _inside: String,
+    inside: String,
     template_end: String,
     after: String,
     list: ListeriaList,
     is_just_text: bool,
+    added_end_template: bool,
+    error: Option<String>,
 }
 
 impl PageElement {
     pub fn new_from_text(text: &str, page: &ListeriaPage) -> Option<Self> {
-        let start_template = page
-            .config()
-            .get_local_template_title_start(&page.wiki())
-            .ok()?;
-        let end_template = page
-            .config()
-            .get_local_template_title_end(&page.wiki())
-            .ok()?;
-        let pattern_string_start = r#"\{\{(Wikidata[ _]list[^\|]*|"#.to_string()
-            + &start_template.replace(" ", "[ _]")
-            //+ r#")\s*\|"#; // New version
-            + r#"[^\|]*)"#; // Orig
-                            //+ r#")"#;
-        let pattern_string_end = r#"\{\{(Wikidata[ _]list[ _]end|"#.to_string()
-            + &end_template.replace(" ", "[ _]")
-            + r#")(\s*\}\})"#;
+        if page.start_template_names().is_empty() {
+            return None;
+        }
+        let name_pattern = |name: &String| regex::escape(&name.replace('_', " ")).replace(' ', "[ _]");
+        let mut start_names = page.start_template_names().to_owned();
+        start_names.sort_by_key(|name| std::cmp::Reverse(name.len())); // Longest first, "X list 2" before "X list"
+        let start_names: Vec<String> = start_names.iter().map(name_pattern).collect();
+        let end_names: Vec<String> = page.end_template_names().iter().map(name_pattern).collect();
+        let pattern_string_start = r#"\{\{\s*("#.to_string() + &start_names.join("|") + r#")\s*"#;
+        let pattern_string_end = r#"\{\{\s*("#.to_string() + &end_names.join("|") + r#")(\s*\}\})"#;
         let seperator_start: Regex = RegexBuilder::new(&pattern_string_start)
             .multi_line(true)
             .dot_matches_new_line(true)
@@ -523,29 +1218,25 @@ impl PageElement {
             .build()
             .ok()?;
 
-        let match_start = match seperator_start.find(&text) {
-            Some(m) => m,
-            None => return None,
-        };
+        let match_start = Self::find_start_template(&seperator_start, text, 0)?;
+        let template_start_end_bytes =
+            Self::get_template_end(text.get(match_start.end()..)?.to_string())? + match_start.end();
 
-        let (match_end, single_template) = match seperator_end.find_at(&text, match_start.start()) {
+        // The end template belongs to this list only if no other list starts before it
+        let next_start = Self::find_start_template(&seperator_start, text, template_start_end_bytes);
+        let match_end = seperator_end
+            .find_at(text, template_start_end_bytes)
+            .filter(|end| next_start.is_none_or(|next| end.start() < next.start()));
+        let (match_end, single_template) = match match_end {
             Some(m) => (m, false),
-            None => (match_start, true), // No end template, could be tabbed data
+            None => (match_start, true), // No end template; the table goes right after the start template
         };
-
-        let remaining = if single_template {
-            String::from_utf8(text.as_bytes()[match_start.end()..].to_vec()).ok()?
+        let after_start = if single_template {
+            template_start_end_bytes
         } else {
-            if match_end.start() < match_start.end() {
-                return None;
-            }
-            String::from_utf8(text.as_bytes()[match_start.end()..match_end.start()].to_vec())
-                .ok()?
-        };
-        let template_start_end_bytes = match Self::get_template_end(remaining) {
-            Some(pos) => pos + match_start.end(),
-            None => return None,
+            match_end.end()
         };
+
         let inside = if single_template {
             String::new()
         } else {
@@ -567,16 +1258,25 @@ impl PageElement {
                 text.as_bytes()[match_start.start()..template_start_end_bytes].to_vec(),
             )
             .ok()?,
-            _inside: inside,
+            inside,
             template_end: if single_template {
-                String::new()
+                // Close the list, so the next run replaces the table instead of adding another one
+                // The local name comes first
+                let end_template = page
+                    .end_template_names()
+                    .first()
+                    .cloned()
+                    .unwrap_or_else(|| "Wikidata list end".to_string());
+                format!("{{{{{end_template}}}}}")
             } else {
                 String::from_utf8(text.as_bytes()[match_end.start()..match_end.end()].to_vec())
                     .ok()?
             },
-            after: String::from_utf8(text.as_bytes()[match_end.end()..].to_vec()).ok()?,
+            after: String::from_utf8(text.as_bytes()[after_start..].to_vec()).ok()?,
             list: ListeriaList::new(template, page.page_params()),
             is_just_text: false,
+            added_end_template: single_template,
+            error: None,
         })
     }
 
@@ -588,11 +1288,13 @@ impl PageElement {
         Self {
             before: text.to_string(),
             template_start: String::new(),
-            _inside: String::new(),
+            inside: String::new(),
             template_end: String::new(),
             after: String::new(),
             list: ListeriaList::new(template, page.page_params()),
             is_just_text: true,
+            added_end_template: false,
+            error: None,
         }
     }
 
@@ -603,13 +1305,264 @@ impl PageElement {
     }
 
     pub fn new_inside(&self) -> Result<String> {
+        if self.is_just_text {
+            return Ok(String::new());
+        }
+        if let Some(error) = &self.error {
+            return Ok(self.error_inside(error));
+        }
+        if self.skip_manually_edited() {
+            return Ok(self.inside.trim().to_string());
+        }
+        // A list that fails to render shows its error, like one that failed to process
+        let render_start = chrono::Utc::now();
+        let list_wikitext = match self.list.as_wikitext() {
+            Ok(wikitext) => wikitext,
+            Err(e) => return Ok(self.error_inside(&e.to_string())),
+        };
+        // After the hash, so it does not count as a change of the table
+        let timings = match self.list.template_params().timings {
+            true => {
+                let mut timings = self.list.timings().to_vec();
+                let render_ms = (chrono::Utc::now() - render_start).num_milliseconds().max(0) as u64;
+                timings.push(("render".to_string(), render_ms));
+                let timings: Vec<String> =
+                    timings.iter().map(|(stage, ms)| format!("{stage} {ms} ms")).collect();
+                format!("\n<!-- {LIST_TIMINGS_COMMENT_PREFIX}{} -->", timings.join(", "))
+            }
+            false => String::new(),
+        };
+        let mut warnings: Vec<String> = self.warning_line().into_iter().collect();
+        if let Some(warning) = self.list.duplicate_items_warning() {
+            warnings.push(format!("<!-- {LIST_WARNING_COMMENT_PREFIX}{} -->", warning.replace("-->", "--&gt;")));
+        }
+        warnings.push(list_wikitext);
+        let wikitext = warnings.join("\n");
+        match self.list.config().manual_edit_policy() {
+            ManualEditPolicy::Ignore => Ok(format!("{wikitext}{timings}")),
+            _ => Ok(format!(
+                "{wikitext}\n<!-- {LIST_HASH_COMMENT_PREFIX}{} -->{timings}",
+                Self::table_hash(&wikitext)
+            )),
+        }
+    }
+
+    /// Lists unknown template parameters, with the configured warning template of the wiki, or as a comment
+    fn warning_line(&self) -> Option<String> {
+        let unknown = self.list.template().unknown_params();
+        if unknown.is_empty() {
+            return None;
+        }
+        let escape = |s: &str| s.replace('|', "{{!}}").replace('{', "&#123;").replace('}', "&#125;");
+        match self.list.config().get_list_warning_template(self.list.wiki()) {
+            Some(template) => {
+                let params: String = unknown
+                    .iter()
+                    .map(|(key, suggestion)| match suggestion {
+                        Some(known) => format!("|{}={known}", escape(key)),
+                        None => format!("|{}=", escape(key)),
+                    })
+                    .collect();
+                Some(format!("{{{{{template}{params}}}}}"))
+            }
+            None => {
+                let names: Vec<String> = unknown
+                    .iter()
+                    .map(|(key, suggestion)| match suggestion {
+                        Some(known) => format!("{key} (did you mean {known}?)"),
+                        None => key.to_owned(),
+                    })
+                    .collect();
+                let message = format!("Unknown parameter(s): {}", names.join(", ")).replace("-->", "--&gt;");
+                Some(format!("<!-- {LIST_WARNING_COMMENT_PREFIX}{message} -->"))
+            }
+        }
+    }
+
+    /// Hash of a generated table; ignores what MediaWiki may change on save (line endings, trailing whitespace, Unicode normalization)
+    fn table_hash(wikitext: &str) -> String {
+        let normalized: String = wikitext.nfc().collect();
+        let normalized: Vec<&str> = normalized.trim().lines().map(|line| line.trim_end()).collect();
+        format!("{:x}", md5::compute(normalized.join("\n")))
+    }
+
+    /// True if the table was changed by someone else since the bot wrote it.
+    /// Lists without a hash (older bot versions, or the hash was removed) count as not edited.
+    pub fn is_manually_edited(&self) -> bool {
+        if self.is_just_text {
+            return false;
+        }
+        let inside = self.strip_error_line(self.inside.trim());
+        let marker = format!("<!-- {LIST_HASH_COMMENT_PREFIX}");
+        let pos = match inside.rfind(&marker) {
+            Some(pos) => pos,
+            None => return false,
+        };
+        let stored_hash = inside[pos + marker.len()..]
+            .split("-->")
+            .next()
+            .unwrap_or_default()
+            .trim();
+        Self::table_hash(&inside[..pos]) != stored_hash
+    }
+
+    fn skip_manually_edited(&self) -> bool {
+        self.list.config().manual_edit_policy() == ManualEditPolicy::Skip && self.is_manually_edited()
+    }
+
+    pub fn error(&self) -> Option<&String> {
+        self.error.as_ref()
+    }
+
+    /// True if the list would change in a way that readers can see.
+    /// Ignores whitespace, and the order of rows if the list is not explicitly sorted.
+    pub fn has_meaningful_change(&self) -> Result<bool> {
+        if self.is_just_text {
+            return Ok(false);
+        }
+        if self.added_end_template {
+            return Ok(true);
+        }
+        let ignore_row_order = matches!(self.list.template_params().sort, SortMode::None);
+        let old_rows = Self::normalized_rows(&self.inside, ignore_row_order);
+        let new_rows = Self::normalized_rows(&self.new_inside()?, ignore_row_order);
+        Ok(old_rows != new_rows)
+    }
+
+    /// How the list was made, see `ListeriaList::explain`; with the error, if it failed
+    pub fn explain(&self) -> Option<Value> {
+        if self.is_just_text {
+            return None;
+        }
+        let mut ret = self.list.explain();
+        ret["error"] = json!(self.error);
+        Some(ret)
+    }
+
+    /// Problems with the list definition, see `ListeriaList::lint`
+    pub async fn lint(&mut self) -> Vec<String> {
         match self.is_just_text {
-            true => Ok(String::new()),
-            false => {
-                let mut renderer = RendererWikitext::new();
-                renderer.render(&self.list)
+            true => vec![],
+            false => self.list.lint().await,
+        }
+    }
+
+    /// Rows of the list added, removed and changed since the last run
+    pub fn row_changes(&self) -> Result<RowChanges> {
+        if self.is_just_text || self.error.is_some() || self.skip_manually_edited() {
+            return Ok(RowChanges::default());
+        }
+        let entity_ids: HashSet<String> = self.item_ids().iter().map(|id| id.to_uppercase()).collect();
+        let old_inside = self.strip_error_line(&self.inside);
+        Ok(ListeriaList::row_changes(old_inside, &self.new_inside()?, &entity_ids))
+    }
+
+    /// Table wikitext split into rows, with whitespace collapsed; optionally sorts the rows of each table.
+    /// Timings and the query time of the summary line change on every run, so they are left out.
+    fn normalized_rows(wikitext: &str, ignore_row_order: bool) -> Vec<String> {
+        lazy_static! {
+            static ref RE_QUERY_TIME: Regex =
+                Regex::new(r#"Query time: [\d.]+ s\."#).expect("RE_QUERY_TIME does not parse");
+        }
+        let mut parts: Vec<(bool, String)> = vec![(false, String::new())]; // (is_row, text)
+        for line in wikitext.lines() {
+            let line = line.split_whitespace().collect::<Vec<&str>>().join(" ");
+            let line = RE_QUERY_TIME.replace_all(&line, "Query time: s.");
+            if line.is_empty() || line.starts_with(&format!("<!-- {LIST_TIMINGS_COMMENT_PREFIX}")) {
+                continue;
+            }
+            if line.starts_with("|-") {
+                parts.push((true, String::new()));
+            } else if line.starts_with("|}") {
+                parts.push((false, String::new()));
+            }
+            if let Some(part) = parts.last_mut() {
+                part.1 += &line;
+                part.1 += "\n";
             }
         }
+        if ignore_row_order {
+            let mut start = 0;
+            while start < parts.len() {
+                let end = start + parts[start..].iter().take_while(|(is_row, _)| *is_row).count();
+                parts[start..end].sort();
+                start = end + 1;
+            }
+        }
+        parts.into_iter().map(|(_, text)| text).collect()
+    }
+
+    /// Wikidata items shown in this list
+    pub fn item_ids(&self) -> Vec<String> {
+        if self.is_just_text {
+            return vec![];
+        }
+        self.list.results().iter().map(|row| row.entity_id().to_owned()).collect()
+    }
+
+    /// The list itself, with its columns and result rows
+    pub fn list(&self) -> &ListeriaList {
+        &self.list
+    }
+
+    pub fn list_mut(&mut self) -> &mut ListeriaList {
+        &mut self.list
+    }
+
+    /// The raw SPARQL results of this list, once it has run
+    pub fn sparql_results(&self) -> Option<&Value> {
+        match self.is_just_text {
+            true => None,
+            false => self.list.sparql_results(),
+        }
+    }
+
+    pub fn set_error(&mut self, error: String) {
+        self.error = Some(error);
+    }
+
+    /// The error message for a failed list, followed by whatever the list showed before
+    fn error_inside(&self, error: &str) -> String {
+        let error_template = self
+            .list
+            .config()
+            .get_list_error_template(self.list.wiki())
+            .map(|t| t.to_string());
+        let mut error_wikitext = match &error_template {
+            Some(template) => format!(
+                "{{{{{}|{}}}}}",
+                template,
+                error.replace('|', "{{!}}").replace('{', "&#123;").replace('}', "&#125;")
+            ),
+            None => format!("<!-- {}{} -->", LIST_ERROR_COMMENT_PREFIX, error.replace("-->", "--&gt;")),
+        };
+        // Same line as the error, so it goes away with it
+        if let Some(category) = self.list.config().get_list_error_category(self.list.wiki()) {
+            error_wikitext += &format!("[[{category}]]");
+        }
+
+        // Remove the error from an earlier run, if any
+        let old_inside = self.strip_error_line(&self.inside);
+        format!("{}
+{}", error_wikitext, old_inside.trim())
+            .trim()
+            .to_string()
+    }
+
+    /// The text without the error line of an earlier failed run, if any
+    fn strip_error_line<'a>(&self, inside: &'a str) -> &'a str {
+        let error_template = self.list.config().get_list_error_template(self.list.wiki());
+        let inside = inside.trim_start();
+        let first_line = inside.lines().next().unwrap_or_default();
+        let had_error = first_line.starts_with(&format!("<!-- {LIST_ERROR_COMMENT_PREFIX}"))
+            || error_template
+                .map(|template| first_line.starts_with(&format!("{{{{{template}|")))
+                .unwrap_or(false);
+        if had_error {
+            inside.get(first_line.len()..).unwrap_or_default().trim_start()
+        } else {
+            inside
+        }
     }
 
     pub fn as_wikitext(&self) -> Result<String> {
@@ -626,9 +1579,40 @@ impl PageElement {
     }
 
     pub async fn process(&mut self) -> Result<()> {
-        match self.is_just_text {
-            true => Ok(()),
-            false => self.list.process().await,
+        if self.is_just_text || self.skip_manually_edited() {
+            return Ok(());
+        }
+        self.keep_row_order();
+        self.list.process().await?;
+        Ok(())
+    }
+
+    /// Replaces the list with one for this page made from `source`, a list processed on another wiki
+    pub async fn process_as_target(&mut self, source: &ListeriaList, page_params: Arc<PageParams>) -> Result<()> {
+        if self.is_just_text || self.skip_manually_edited() {
+            return Ok(());
+        }
+        self.list = source.retarget(page_params);
+        self.keep_row_order();
+        self.list.process_retargeted().await?;
+        Ok(())
+    }
+
+    fn keep_row_order(&mut self) {
+        if self.list.config().keep_row_order() {
+            let previous = self.strip_error_line(&self.inside).to_string();
+            self.list.keep_row_order_from(&previous);
+        }
+    }
+
+    /// Title and Lua source of the data module of this list, if it has one and was generated
+    pub fn data_module(&self) -> Result<Option<(String, String)>> {
+        if self.is_just_text || self.error.is_some() || self.skip_manually_edited() {
+            return Ok(None);
+        }
+        match self.list.data_module() {
+            Some(title) => Ok(Some((title.to_owned(), self.list.as_data_module()?))),
+            None => Ok(None),
         }
     }
 
@@ -636,6 +1620,18 @@ impl PageElement {
         self.is_just_text
     }
 
+    /// The first start template at or after `from`; the template name must be followed by parameters or the end of the template
+    fn find_start_template<'a>(re: &Regex, text: &'a str, from: usize) -> Option<regex::Match<'a>> {
+        let mut pos = from;
+        while let Some(m) = re.find_at(text, pos) {
+            match text[m.end()..].chars().next() {
+                Some('|') | Some('}') => return Some(m),
+                _ => pos = m.end(),
+            }
+        }
+        None
+    }
+
     fn get_template_end(text: String) -> Option<usize> {
         let mut pos: usize = 0;
         let mut curly_braces_open: usize = 2;
@@ -655,3 +1651,191 @@ impl PageElement {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_current_only() {
+        assert_eq!(CurrentOnly::new(None), CurrentOnly::None);
+        assert_eq!(CurrentOnly::new(Some(&"yes".to_string())), CurrentOnly::All);
+        let co = CurrentOnly::new(Some(&"p39, P54".to_string()));
+        assert!(co.applies_to("P39"));
+        assert!(co.applies_to("P54"));
+        assert!(!co.applies_to("P106"));
+        assert_eq!(CurrentOnly::parse_date("+2020-05-17T00:00:00Z", false), Some((2020, 5, 17)));
+        assert_eq!(CurrentOnly::parse_date("+2020-00-00T00:00:00Z", false), Some((2020, 0, 0)));
+        assert_eq!(CurrentOnly::parse_date("+2020-00-00T00:00:00Z", true), Some((2020, 12, 31)));
+        assert_eq!(CurrentOnly::parse_date("-0044-03-15", false), Some((-44, 3, 15)));
+        assert_eq!(CurrentOnly::parse_date("2020", false), Some((2020, 0, 0)));
+        assert_eq!(CurrentOnly::parse_date("yesterday", false), None);
+    }
+
+    #[test]
+    fn test_is_rtl_language() {
+        assert!(is_rtl_language("he"));
+        assert!(is_rtl_language("AR"));
+        assert!(!is_rtl_language("en"));
+        assert!(!is_rtl_language("arn"));
+    }
+
+    #[test]
+    fn test_latlon_new_from_wkt() {
+        assert_eq!(LatLon::new_from_wkt("Point(13.4 52.5)"), Some(LatLon::new(52.5, 13.4)));
+        assert_eq!(LatLon::new_from_wkt("Point(12 34)"), Some(LatLon::new(34.0, 12.0)));
+        assert_eq!(LatLon::new_from_wkt("Point(-1.5e1 +2E-1)"), Some(LatLon::new(0.2, -15.0)));
+        assert_eq!(
+            LatLon::new_from_wkt("<http://www.wikidata.org/entity/Q405> Point(1 2)"),
+            Some(LatLon::new(2.0, 1.0))
+        );
+        assert_eq!(LatLon::new_from_wkt("SRID=4326;POINT Z (1 2 3)"), Some(LatLon::new(2.0, 1.0)));
+        assert_eq!(LatLon::new_from_wkt("Point(1)"), None);
+        assert_eq!(LatLon::new_from_wkt("Point(NaN 1)"), None);
+        // Keywords are not sliced by bytes, which would split these characters
+        assert_eq!(LatLon::new_from_wkt("Poinö(1 2)"), None);
+        assert_eq!(LatLon::new_from_wkt("SRIDé=4326;Point(1 2)"), None);
+        assert_eq!(LatLon::new_from_wkt("Точка(1 2)"), None);
+        assert_eq!(LatLon::new_from_wkt("LineString(1 2, 3 4)"), None);
+    }
+
+    #[test]
+    fn test_template_new_from_params() {
+        let text = "\n|sparql=SELECT ?item { ?item wdt:P31 wd:Q5 }\n|columns=label,P569\n|section={{Section|a|b}}\n|summary=[[Help:Lists|help]]\n";
+        let template = Template::new_from_params(String::new(), text.to_string()).unwrap();
+        assert_eq!(template.params.len(), 4);
+        assert_eq!(template.params["sparql"], "SELECT ?item { ?item wdt:P31 wd:Q5 }");
+        assert_eq!(template.params["columns"], "label,P569");
+        assert_eq!(template.params["section"], "{{Section|a|b}}");
+        assert_eq!(template.params["summary"], "[[Help:Lists|help]]");
+    }
+
+    #[test]
+    fn test_template_unknown_params() {
+        let template = Template::new_from_params(
+            "".to_string(),
+            "|sparql=SELECT ?item {}\n|colums=label\n|Sort=label\n|xyzzy=1".to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            template.unknown_params(),
+            vec![("colums".to_string(), Some("columns")), ("xyzzy".to_string(), None)]
+        );
+        assert_eq!(Template::edit_distance("colums", "columns"), 1);
+    }
+
+    #[test]
+    fn test_template_fix_values() {
+        let text = "|sparql=SELECT ?item { ?item wdt:P31{{!}}wdt:P279 wd:Q5 . FILTER(?x {{=}} <nowiki>\"{{!}}|\"</nowiki>) }|columns=label";
+        let mut template = Template::new_from_params(String::new(), text.to_string()).unwrap();
+        assert_eq!(template.params.len(), 2);
+        template.fix_values();
+        assert_eq!(
+            template.params["sparql"],
+            "SELECT ?item { ?item wdt:P31|wdt:P279 wd:Q5 . FILTER(?x = \"{{!}}|\") }"
+        );
+        assert_eq!(template.params["columns"], "label");
+    }
+
+    #[test]
+    fn test_sparql_value_entity() {
+        let entity = |uri: &str| SparqlValue::new_from_json(&json!({"type":"uri","value":uri}));
+        assert_eq!(entity("http://www.wikidata.org/entity/Q42"), Some(SparqlValue::Entity("Q42".to_string())));
+        assert_eq!(entity("http://www.wikidata.org/entity/L7-F2"), Some(SparqlValue::Entity("L7-F2".to_string())));
+        assert_eq!(entity("https://commons.wikimedia.org/entity/M123"), Some(SparqlValue::Entity("M123".to_string())));
+        assert_eq!(entity("http://www.wikidata.org/prop/direct/P31"), Some(SparqlValue::Entity("P31".to_string())));
+        assert_eq!(entity("http://www.wikidata.org/prop/P31"), Some(SparqlValue::Entity("P31".to_string())));
+        assert_eq!(
+            entity("http://www.wikidata.org/entity/statement/Q42-abc"),
+            Some(SparqlValue::Uri("http://www.wikidata.org/entity/statement/Q42-abc".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_sparql_value_file() {
+        let file = |uri: &str| SparqlValue::new_from_json(&json!({"type":"uri","value":uri}));
+        let expected = Some(SparqlValue::File("Käthe Kollwitz.jpg".to_string()));
+        assert_eq!(file("http://commons.wikimedia.org/wiki/Special:FilePath/K%C3%A4the%20Kollwitz.jpg"), expected);
+        assert_eq!(file("http://commons.wikimedia.org/wiki/Special:FilePath/K%C3%A4the_Kollwitz.jpg?width=300"), expected);
+        assert_eq!(file("https://upload.wikimedia.org/wikipedia/commons/3/3a/K%C3%A4the_Kollwitz.jpg"), expected);
+        assert_eq!(
+            file("https://upload.wikimedia.org/wikipedia/commons/thumb/3/3a/K%C3%A4the_Kollwitz.jpg/300px-K%C3%A4the_Kollwitz.jpg"),
+            expected
+        );
+        assert_eq!(
+            file("https://upload.wikimedia.org/wikipedia/commons/"),
+            Some(SparqlValue::Uri("https://upload.wikimedia.org/wikipedia/commons/".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_summary_tokens() {
+        assert_eq!(SummaryToken::new_list("itemnumber"), vec![SummaryToken::ItemNumber]);
+        assert_eq!(
+            SummaryToken::new_list(" ITEMNUMBER, querytime,foo,lastchange "),
+            vec![SummaryToken::ItemNumber, SummaryToken::QueryTime, SummaryToken::LastChange]
+        );
+        assert!(SummaryToken::new_list("").is_empty());
+    }
+
+    #[test]
+    fn test_parse_targets() {
+        assert_eq!(
+            TemplateParams::parse_targets("dewiki:Liste der_Burgen; frwiki : Liste des châteaux;;nowiki"),
+            vec![
+                ("dewiki".to_string(), "Liste der Burgen".to_string()),
+                ("frwiki".to_string(), "Liste des châteaux".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_template_names_source() {
+        let template = |text: &str| Template::new_from_params("Wikidata list".to_string(), text.to_string()).unwrap();
+        let opted_in = template("|source=wikidatawiki:Wikidata:Lists of castles");
+        assert!(opted_in.names_source("wikidatawiki", "Wikidata:Lists of castles"));
+        assert!(opted_in.names_source("wikidatawiki", "Wikidata:Lists_of_castles"));
+        assert!(!opted_in.names_source("enwiki", "Wikidata:Lists of castles"));
+        assert!(!opted_in.names_source("wikidatawiki", "Wikidata:Lists of palaces"));
+        let not_opted_in = template("|sparql=SELECT ?item {}");
+        assert!(!not_opted_in.names_source("wikidatawiki", "Wikidata:Lists of castles"));
+    }
+
+    #[test]
+    fn test_toc_mode() {
+        let toc = |s: &str| TocMode::new(Some(&s.to_string()));
+        assert_eq!(toc("no"), TocMode::Hide);
+        assert_eq!(toc(" Show"), TocMode::Show);
+        assert_eq!(toc("2"), TocMode::Limit(2));
+        assert_eq!(toc("9"), TocMode::Default);
+        assert_eq!(TocMode::new(None), TocMode::Default);
+    }
+
+    #[test]
+    fn test_normalized_rows() {
+        let old = "{| class='wikitable sortable'\n! name\n|-\n| A  \n|-\n| B\n|}";
+        let new = "{| class='wikitable sortable'\n! name\n|-\n| B\n|-\n|   A\n|}\n";
+        assert_eq!(
+            PageElement::normalized_rows(old, true),
+            PageElement::normalized_rows(new, true)
+        );
+        assert_ne!(
+            PageElement::normalized_rows(old, false),
+            PageElement::normalized_rows(new, false)
+        );
+        let timed = format!("{old}\n<!-- {LIST_TIMINGS_COMMENT_PREFIX}sparql 12 ms, render 3 ms -->");
+        assert_eq!(
+            PageElement::normalized_rows(old, false),
+            PageElement::normalized_rows(&timed, false)
+        );
+        let summary = |seconds: &str| format!("{old}\n&sum; 2 items. Query time: {seconds} s.");
+        assert_eq!(
+            PageElement::normalized_rows(&summary("1.2"), false),
+            PageElement::normalized_rows(&summary("0.8"), false)
+        );
+        assert_ne!(
+            PageElement::normalized_rows(&summary("1.2"), false),
+            PageElement::normalized_rows(old, false)
+        );
+    }
+}
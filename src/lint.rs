@@ -0,0 +1,229 @@
+use regex::Regex;
+
+/// A local check of a SPARQL query, without running it: brackets, the SELECT clause, and its variables
+#[derive(Debug, Clone, Default)]
+pub struct SparqlCheck {
+    pub problems: Vec<String>,
+    pub variables: Vec<String>, // Selected variables, without "?"
+}
+
+impl SparqlCheck {
+    pub fn new(sparql: &str) -> Self {
+        lazy_static! {
+            static ref RE_SELECT: Regex = Regex::new(r"(?is)\bSELECT\s+(?:DISTINCT\s+|REDUCED\s+)?(.*?)(?:\bWHERE\b|\{)")
+                .expect("RE_SELECT does not parse");
+            static ref RE_VARIABLE: Regex = Regex::new(r"[?$](\w+)").expect("RE_VARIABLE does not parse");
+            static ref RE_AS: Regex = Regex::new(r"(?i)\bAS\s+[?$](\w+)").expect("RE_AS does not parse");
+        }
+        let mut ret = Self::default();
+        let code = ret.strip_literals(sparql);
+
+        let projection = match RE_SELECT.captures(&code) {
+            Some(caps) => caps.get(1).map(|m| m.as_str()).unwrap_or_default().to_string(),
+            None => {
+                ret.problems.push("No SELECT clause".to_string());
+                return ret;
+            }
+        };
+        if projection.trim() == "*" {
+            ret.variables = RE_VARIABLE.captures_iter(&code).map(|caps| caps[1].to_string()).collect();
+        } else {
+            // Variables at the top level, and the names of (expression AS ?name)
+            let mut depth = 0;
+            let mut top_level = String::new();
+            for c in projection.chars() {
+                match c {
+                    '(' => depth += 1,
+                    ')' => depth -= 1,
+                    c if depth == 0 => top_level.push(c),
+                    _ => {}
+                }
+            }
+            ret.variables = RE_VARIABLE.captures_iter(&top_level).map(|caps| caps[1].to_string()).collect();
+            ret.variables.extend(RE_AS.captures_iter(&projection).map(|caps| caps[1].to_string()));
+        }
+        ret.variables.sort();
+        ret.variables.dedup();
+        if !ret.has_variable("item") {
+            ret.problems.push("The query does not select ?item".to_string());
+        }
+        ret
+    }
+
+    /// True if the query selects this variable; case-insensitive, like column and sort names
+    pub fn has_variable(&self, name: &str) -> bool {
+        let name = name.trim_start_matches(['?', '@']);
+        self.variables.iter().any(|v| v.eq_ignore_ascii_case(name))
+    }
+
+    /// The query with strings, IRIs and comments blanked out; notes unbalanced brackets and unclosed strings
+    fn strip_literals(&mut self, sparql: &str) -> String {
+        let mut ret = String::with_capacity(sparql.len());
+        let mut stack: Vec<char> = vec![];
+        let mut chars = sparql.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' | '\'' => {
+                    let mut closed = false;
+                    while let Some(d) = chars.next() {
+                        if d == '\\' {
+                            chars.next();
+                        } else if d == c {
+                            closed = true;
+                            break;
+                        }
+                    }
+                    if !closed {
+                        self.problems.push(format!("Unclosed string ({c})"));
+                    }
+                    ret += "\"\"";
+                }
+                // An IRI; a "<" followed by whitespace or "=" is a comparison
+                '<' if chars.peek().is_some_and(|d| !d.is_whitespace() && *d != '=') => {
+                    for d in chars.by_ref() {
+                        if d == '>' || d.is_whitespace() {
+                            break;
+                        }
+                    }
+                    ret += "<>";
+                }
+                '#' => {
+                    for d in chars.by_ref() {
+                        if d == '\n' {
+                            break;
+                        }
+                    }
+                    ret.push('\n');
+                }
+                '{' | '(' | '[' => {
+                    stack.push(c);
+                    ret.push(c);
+                }
+                '}' | ')' | ']' => {
+                    let open = match c {
+                        '}' => '{',
+                        ')' => '(',
+                        _ => '[',
+                    };
+                    match stack.last() == Some(&open) {
+                        true => {
+                            stack.pop();
+                        }
+                        false => self.problems.push(format!("Unexpected '{c}'")),
+                    }
+                    ret.push(c);
+                }
+                c => ret.push(c),
+            }
+        }
+        if let Some(c) = stack.last() {
+            self.problems.push(format!("Unclosed '{c}'"));
+        }
+        ret
+    }
+}
+
+/// The query with strings, IRIs and comments replaced by spaces, so byte positions match the original query
+pub fn blank_literals(sparql: &str) -> String {
+    let mut ret = String::with_capacity(sparql.len());
+    let blank = |ret: &mut String, c: char| ret.push_str(&" ".repeat(c.len_utf8()));
+    let mut chars = sparql.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' | '\'' => {
+                blank(&mut ret, c);
+                while let Some(d) = chars.next() {
+                    blank(&mut ret, d);
+                    if d == '\\' {
+                        if let Some(e) = chars.next() {
+                            blank(&mut ret, e);
+                        }
+                    } else if d == c {
+                        break;
+                    }
+                }
+            }
+            '<' if chars.peek().is_some_and(|d| !d.is_whitespace() && *d != '=') => {
+                blank(&mut ret, c);
+                while let Some(d) = chars.next_if(|d| !d.is_whitespace()) {
+                    blank(&mut ret, d);
+                    if d == '>' {
+                        break;
+                    }
+                }
+            }
+            '#' => {
+                blank(&mut ret, c);
+                while let Some(d) = chars.next_if(|d| *d != '\n') {
+                    blank(&mut ret, d);
+                }
+            }
+            c => ret.push(c),
+        }
+    }
+    ret
+}
+
+/// Byte positions of the `{` and the matching `}` of the WHERE group, the first group of a SELECT query.
+/// Strings, IRIs and comments are skipped; None if the group is missing or not closed.
+pub fn where_group(sparql: &str) -> Option<(usize, usize)> {
+    let code = blank_literals(sparql);
+    let start = code.find('{')?;
+    let mut depth = 0;
+    for (pos, c) in code[start..].char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some((start, start + pos));
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sparql_check() {
+        let check = SparqlCheck::new("SELECT ?item ?itemLabel (COUNT(?x) AS ?count) { ?item wdt:P31 wd:Q5 ; rdfs:label \"a}\" . FILTER(?x < 3) } # }");
+        assert!(check.problems.is_empty(), "{:?}", check.problems);
+        assert_eq!(check.variables, vec!["count", "item", "itemLabel"]);
+        assert!(check.has_variable("?ITEMLABEL"));
+
+        let check = SparqlCheck::new("SELECT * WHERE { ?item <http://x.org/#a> ?value ");
+        assert_eq!(check.problems, vec!["Unclosed '{'".to_string()]);
+        assert!(check.has_variable("value"));
+
+        let check = SparqlCheck::new("SELECT ?human { ?human wdt:P31 wd:Q5 ) }");
+        assert_eq!(
+            check.problems,
+            vec!["Unexpected ')'".to_string(), "The query does not select ?item".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_blank_literals() {
+        let sparql = "SELECT ?item { ?item rdfs:label \"Zürich {\"@de . # where {\n?item <http://x.org/#a> ?v }";
+        let blanked = blank_literals(sparql);
+        assert_eq!(blanked.len(), sparql.len());
+        assert_eq!(blanked.matches('{').count(), 1);
+        assert!(!blanked.contains("where"));
+        assert!(blanked.ends_with(&format!("\n?item {} ?v }}", " ".repeat("<http://x.org/#a>".len()))));
+    }
+
+    #[test]
+    fn test_where_group() {
+        let sparql = "SELECT ?item { { SELECT ?item { ?item rdfs:label \"}\" } LIMIT 3 } # }\n} VALUES ?item { wd:Q1 }";
+        let (start, end) = where_group(sparql).unwrap();
+        assert_eq!(start, sparql.find('{').unwrap());
+        assert_eq!(&sparql[end..], "} VALUES ?item { wd:Q1 }");
+        assert_eq!(where_group("SELECT ?item { ?item wdt:P31 wd:Q5"), None);
+        assert_eq!(where_group("SELECT ?item"), None);
+    }
+}
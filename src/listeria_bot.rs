@@ -1,70 +1,19 @@
 use tokio::sync::Mutex;
-use chrono::{DateTime, Utc};
 use crate::configuration::Configuration;
+use crate::listeria_list::ListeriaList;
 use crate::listeria_page::ListeriaPage;
+use crate::page_queue::PageQueue;
+use crate::run_report::RunReport;
 use anyhow::{Result,anyhow};
-use mysql_async as my;
-use mysql_async::from_row;
-use mysql_async::prelude::*;
 use serde_json::Value;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::SystemTime;
 use tokio::sync::RwLock;
 use wikibase::mediawiki::api::Api;
 
-#[derive(Debug, Clone, Default)]
-pub struct PageToProcess {
-    pub id: u64,
-    pub title: String,
-    pub status: String,
-    pub wiki: String,
-}
-
-impl PageToProcess {
-    pub fn from_parts(parts: (u64,String,String,String)) -> Self {
-        Self {
-            id: parts.0,
-            title: parts.1,
-            status: parts.2,
-            wiki: parts.3,
-        }
-    }
-
-    pub fn from_row(row: mysql_async::Row) -> Self {
-        let parts = from_row::<(u64, String, String, String)>(row);
-        Self::from_parts(parts)
-    }
-}
-
-#[derive(Debug, Clone)]
-pub struct WikiPageResult {
-    pub wiki: String,
-    pub page: String,
-    pub result: String,
-    pub message: String,
-}
-
-unsafe impl Send for WikiPageResult {}
-
-impl WikiPageResult {
-    pub fn new(wiki: &str, page: &str, result: &str, message: String) -> Self {
-        Self {
-            wiki: wiki.to_string(),
-            page: page.to_string(),
-            result: result.to_string(),
-            message,
-        }
-    }
-
-    pub fn fail(wiki: &str, page: &str, message: &str) -> Self {
-        Self::new(
-            wiki,
-            page,
-            "FAIL",
-            message.to_string()
-        )
-    }
-}
+pub use crate::listeria_page::WikiPageResult;
+pub use crate::page_queue::PageToProcess;
 
 #[derive(Debug, Clone)]
 pub struct ListeriaBotWiki {
@@ -84,70 +33,100 @@ impl ListeriaBotWiki {
     }
 
     pub async fn process_page(&self, page: &str) -> WikiPageResult {
-        let mut listeria_page =
-            match ListeriaPage::new(self.config.clone(), self.api.clone(), page.to_owned()).await {
-                Ok(p) => p,
-                Err(e) => {
-                    return WikiPageResult::new(
-                        &self.wiki,
-                        page,
-                        "FAIL",
-                        format!("Could not open/parse page '{}': {}", page, e),
-                    )
-                }
-            };
+        self.process_page_with_items(page).await.0
+    }
+
+    /// Like `process_page`, but also returns the Wikidata items on the page
+    pub async fn process_page_with_items(&self, page: &str) -> (WikiPageResult, Vec<String>) {
+        let (wpr, listeria_page) = self.process_listeria_page(page).await;
+        let items = listeria_page.map(|p| p.item_ids()).unwrap_or_default();
+        (wpr, items)
+    }
+
+    /// Like `process_page`, but also returns the processed page, if it was updated
+    pub async fn process_listeria_page(&self, page: &str) -> (WikiPageResult, Option<ListeriaPage>) {
+        let mut listeria_page = match self.open_page(page).await {
+            Ok(p) => p,
+            Err(wpr) => return (wpr, None),
+        };
         if let Err(wpr) = listeria_page.run().await {
-            return wpr
+            return (wpr, None)
         }
-        let _did_edit = match listeria_page.update_source_page().await {
+        let _edit_result = match listeria_page.update_source_page().await {
             Ok(x) => x,
+            Err(wpr) => return (wpr, None),
+        };
+        let message = Self::page_message(&listeria_page);
+        (WikiPageResult::new(&self.wiki, page, "OK", message), Some(listeria_page))
+    }
+
+    /// Updates a page on this wiki with a list defined on another wiki
+    pub async fn process_target_page(&self, page: &str, source: &ListeriaList) -> WikiPageResult {
+        let mut listeria_page = match self.open_page(page).await {
+            Ok(p) => p,
             Err(wpr) => return wpr,
         };
-        WikiPageResult::new(&self.wiki, page, "OK", "".to_string())
+        if let Err(wpr) = listeria_page.run_as_target(source).await {
+            return wpr;
+        }
+        if let Err(wpr) = listeria_page.update_source_page().await {
+            return wpr;
+        }
+        let message = Self::page_message(&listeria_page);
+        WikiPageResult::new(&self.wiki, page, "OK", message)
+    }
+
+    /// Message for an updated page: the manual edit warning, errors of lists that failed, and list warnings
+    fn page_message(listeria_page: &ListeriaPage) -> String {
+        let mut messages: Vec<String> = listeria_page.manual_edit_warning().into_iter().collect();
+        messages.extend(listeria_page.list_errors());
+        messages.extend(listeria_page.list_warnings());
+        messages.join("; ")
+    }
+
+    async fn open_page(&self, page: &str) -> Result<ListeriaPage, WikiPageResult> {
+        let mut listeria_page = ListeriaPage::new(self.config.clone(), self.api.clone(), page.to_owned())
+            .await
+            .map_err(|e| {
+                WikiPageResult::new(
+                    &self.wiki,
+                    page,
+                    "FAIL",
+                    format!("Could not open/parse page '{}': {}", page, e),
+                )
+            })?;
+        if let Some(timeout) = self.config.page_timeout() {
+            listeria_page
+                .set_timeout(timeout)
+                .map_err(|e| WikiPageResult::new(&self.wiki, page, "FAIL", e.to_string()))?;
+        }
+        Ok(listeria_page)
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct ListeriaBot {
-    config: Arc<Configuration>,
-    wiki_apis: Arc<Mutex<HashMap<String, Arc<RwLock<Api>>>>>,
-    pool: mysql_async::Pool,
+    config: Arc<RwLock<Arc<Configuration>>>,
+    config_file: String,
+    config_overrides: Vec<String>,
+    config_modified: Arc<Mutex<Option<SystemTime>>>,
+    queue: PageQueue,
     site_matrix: Value,
     bot_per_wiki: Arc<Mutex<HashMap<String, ListeriaBotWiki>>>,
+    blocked_wikis: Arc<Mutex<HashMap<String, String>>>, // wiki => block reason
+    run_report: Arc<Mutex<RunReport>>,
 }
 
 impl ListeriaBot {
     pub async fn new(config_file: &str) -> Result<Self> {
-        let config = Configuration::new_from_file(config_file).await?;
-
-        let host = config
-            .mysql("host")
-            .as_str()
-            .ok_or(anyhow!("No host in config"))?
-            .to_string();
-        let schema = config
-            .mysql("schema")
-            .as_str()
-            .ok_or(anyhow!("No schema in config"))?
-            .to_string();
-        let port = config.mysql("port").as_u64().ok_or(anyhow!("No port in config"))? as u16;
-        let user = config
-            .mysql("user")
-            .as_str()
-            .ok_or(anyhow!("No user in config"))?
-            .to_string();
-        let password = config
-            .mysql("password")
-            .as_str()
-            .ok_or(anyhow!("No password in config"))?
-            .to_string();
-
-        let opts = my::OptsBuilder::default()
-            .ip_or_hostname(host.to_owned())
-            .db_name(Some(schema))
-            .user(Some(user))
-            .pass(Some(password))
-            .tcp_port(port);
+        Self::new_with_overrides(config_file, &[]).await
+    }
+
+    pub async fn new_with_overrides(config_file: &str, config_overrides: &[String]) -> Result<Self> {
+        let config = Configuration::new_from_file_with_overrides(config_file, config_overrides).await?;
+
+        let queue = PageQueue::new_from_config(&config)?;
+        queue.ensure_schema().await?;
 
         // Load site matrix
         let api = config.get_default_wbapi()?;
@@ -160,14 +139,46 @@ impl ListeriaBot {
             .get_query_api_json(&params)
             .await?;
         Ok(Self {
-            config: Arc::new(config),
-            wiki_apis: Arc::new(Mutex::new(HashMap::new())),
-            pool: mysql_async::Pool::new(opts),
+            config: Arc::new(RwLock::new(Arc::new(config))),
+            config_file: config_file.to_string(),
+            config_overrides: config_overrides.to_vec(),
+            config_modified: Arc::new(Mutex::new(Self::config_file_modified(config_file))),
+            queue,
             site_matrix,
             bot_per_wiki: Arc::new(Mutex::new(HashMap::new())),
+            blocked_wikis: Arc::new(Mutex::new(HashMap::new())),
+            run_report: Arc::new(Mutex::new(RunReport::new())),
         })
     }
 
+    pub async fn config(&self) -> Arc<Configuration> {
+        self.config.read().await.clone()
+    }
+
+    fn config_file_modified(config_file: &str) -> Option<SystemTime> {
+        std::fs::metadata(config_file).ok()?.modified().ok()
+    }
+
+    /// Reloads the config file if it has changed since it was last loaded.
+    /// Pages that are already running keep the configuration they started with.
+    /// Returns true if the configuration was replaced.
+    pub async fn reload_config_if_changed(&self) -> Result<bool> {
+        let modified = Self::config_file_modified(&self.config_file);
+        let mut config_modified = self.config_modified.lock().await;
+        if modified.is_none() || *config_modified == modified {
+            return Ok(false);
+        }
+        *config_modified = modified; // Don't retry a broken file until it changes again
+        let config =
+            Configuration::new_from_file_with_overrides(&self.config_file, &self.config_overrides)
+                .await?;
+        // The API clients of the new configuration use renewed tokens, unless the login has changed
+        config.wiki_login().keep_tokens_of(self.config().await.wiki_login())?;
+        *self.config.write().await = Arc::new(config);
+        self.bot_per_wiki.lock().await.clear(); // Recreated with the new configuration on demand
+        Ok(true)
+    }
+
     async fn create_bot_for_wiki(&self, wiki: &str) -> Option<ListeriaBotWiki> {
         if let Some(bot) = self.bot_per_wiki.lock().await.get(wiki) {
             return Some(bot.to_owned())
@@ -187,7 +198,7 @@ impl ListeriaBot {
             return Some(bot.to_owned())
         }
 
-        let bot = ListeriaBotWiki::new(&wiki, mw_api, self.config.clone());
+        let bot = ListeriaBotWiki::new(&wiki, mw_api, self.config().await);
         bpw.insert(wiki.to_string(), bot.clone());
         return Some(bot);
     }
@@ -254,47 +265,134 @@ impl ListeriaBot {
             .ok_or(anyhow!("AppState::get_server_url_for_wiki: Cannot find server for wiki '{wiki}'"))
     }
 
+    /// Releases pages this worker (or a crashed one) left RUNNING
     pub async fn reset_running(&self) -> Result<()> {
-        let sql = "UPDATE pagestatus SET status='OK' WHERE status='RUNNING'";
-        let _ = self.pool.get_conn().await?.exec_iter(sql, ()).await;
-        Ok(())
+        self.queue.reset_running().await
     }
-  
+
+    /// The shared page queue
+    pub fn queue(&self) -> &PageQueue {
+        &self.queue
+    }
+
+    /// Wikis where the bot account was found to be blocked during this run, with the block reason
+    pub async fn blocked_wikis(&self) -> HashMap<String, String> {
+        self.blocked_wikis.lock().await.clone()
+    }
+
     /// Returns a page to be processed. 
     pub async fn prepare_next_single_page(&self) -> Result<PageToProcess> {
-        // Gets the first 1000 pages (by timestamp), then randomly picks one
-        let sql = r#"SELECT * FROM (
-            SELECT pagestatus.id,pagestatus.page,pagestatus.status,wikis.name AS wiki 
-            FROM pagestatus,wikis 
-            WHERE pagestatus.wiki=wikis.id AND wikis.status='ACTIVE' AND pagestatus.status NOT IN ('RUNNING','DELETED')
-            ORDER BY pagestatus.timestamp
-            LIMIT 1000) ps
-            ORDER BY rand()
-            LIMIT 1"#;
-        let page = self.pool.get_conn().await?
-            .exec_iter(sql, ())
-            .await?
-            .map_and_drop(|row| PageToProcess::from_row(row))
-            .await?
-            .pop()
-            .ok_or(anyhow!("prepare_next_single_page:: no pop"))?;
-        self.update_page_status(&page.title,&page.wiki,"RUNNING","PREPARING").await?;
-        Ok(page)
+        // Skip wikis where the bot is blocked
+        let blocked_wikis: Vec<String> = self.blocked_wikis.lock().await.keys().cloned().collect();
+        self.queue.next_page(&blocked_wikis).await
     }
 
     pub async fn run_single_bot(&self, page: PageToProcess ) -> Result<()> {
-        let bot = match self.create_bot_for_wiki(&page.wiki).await {
+        self.update_page(&page.wiki, &page.title).await?;
+        Ok(())
+    }
+
+    /// Updates a single page, and records the result in the page queue and the run report
+    pub async fn update_page(&self, wiki: &str, page: &str) -> Result<WikiPageResult> {
+        let bot = match self.create_bot_for_wiki(wiki).await {
             Some(bot) => bot.to_owned(),
             None => {
-                self.update_page_status( &page.title, &page.wiki, "FAIL", &format!("No such wiki: {}",&page.wiki)).await?;
-                return Err(anyhow!("ListeriaBot::run_single_bot: No such wiki '{}'",page.wiki))
+                self.update_page_status(page, wiki, "FAIL", &format!("No such wiki: {wiki}")).await?;
+                return Err(anyhow!("ListeriaBot::update_page: No such wiki '{wiki}'"))
             }
         };
-        let wpr = bot.process_page(&page.title).await;
+        let blocked_reason = self.blocked_wikis.lock().await.get(wiki).cloned();
+        if let Some(reason) = blocked_reason {
+            self.update_page_status(page, wiki, "BLOCKED", &reason).await?;
+            return Ok(WikiPageResult::new(wiki, page, "BLOCKED", reason));
+        }
+        let (wpr, listeria_page) = bot.process_listeria_page(page).await;
+        let items = listeria_page.as_ref().map(|p| p.item_ids()).unwrap_or_default();
+        if let Some(listeria_page) = &listeria_page {
+            self.update_target_pages(listeria_page).await?;
+        }
+        if wpr.result == "OK" && self.config().await.event_stream_enabled() {
+            // For queueing the page when one of its items changes
+            if let Err(e) = self.queue.set_page_items(wiki, page, &items).await {
+                eprintln!("Could not store items for {wiki}:{page}: {e}");
+            }
+        }
+        if wpr.result == "BLOCKED" {
+            eprintln!("Bot is blocked on {}, skipping it for the rest of this run: {}", wpr.wiki, wpr.message);
+            self.blocked_wikis.lock().await.insert(wpr.wiki.to_owned(), wpr.message.to_owned());
+        }
         self.update_page_status(&wpr.page, &wpr.wiki, &wpr.result, &wpr.message).await?;
+        self.add_to_run_report(&wpr).await;
+        Ok(wpr)
+    }
+
+    /// Renders the lists of a page that have the targets parameter to the pages on other wikis
+    async fn update_target_pages(&self, listeria_page: &ListeriaPage) -> Result<()> {
+        for list in listeria_page.propagated_lists() {
+            for (wiki, page) in list.targets() {
+                let blocked_reason = self.blocked_wikis.lock().await.get(wiki).cloned();
+                let wpr = match (blocked_reason, self.create_bot_for_wiki(wiki).await) {
+                    (Some(reason), _) => WikiPageResult::new(wiki, page, "BLOCKED", reason),
+                    (None, Some(bot)) => bot.process_target_page(page, list).await,
+                    (None, None) => WikiPageResult::fail(wiki, page, &format!("No such wiki: {wiki}")),
+                };
+                if wpr.result == "BLOCKED" {
+                    self.blocked_wikis.lock().await.insert(wpr.wiki.to_owned(), wpr.message.to_owned());
+                }
+                self.update_page_status(&wpr.page, &wpr.wiki, &wpr.result, &wpr.message).await?;
+                self.add_to_run_report(&wpr).await;
+            }
+        }
         Ok(())
     }
 
+    /// Adds a page result to the current cycle; writes the report page when the cycle is complete
+    async fn add_to_run_report(&self, wpr: &WikiPageResult) {
+        let config = self.config().await;
+        if config.run_report_page().is_none() {
+            return;
+        }
+        let mut report = {
+            let mut run_report = self.run_report.lock().await;
+            run_report.add(wpr);
+            if run_report.len() < config.run_report_pages_per_cycle() {
+                return;
+            }
+            run_report.finish()
+        };
+        report.set_blocked_wikis(self.blocked_wikis().await);
+        if let Err(e) = self.write_run_report(&report).await {
+            eprintln!("Could not write run report: {e}");
+        }
+    }
+
+    pub async fn write_run_report(&self, report: &RunReport) -> Result<()> {
+        let config = self.config().await;
+        let (wiki, page) = config
+            .run_report_page()
+            .ok_or_else(|| anyhow!("No run_report page in config"))?;
+        let mw_api = self.get_or_create_wiki_api(wiki).await?;
+        let mut api = mw_api.write().await;
+        let token = api.get_edit_token().await?;
+        let wikitext = report.as_wikitext();
+        let params: HashMap<String, String> = [
+            ("action", "edit"),
+            ("title", page.as_str()),
+            ("text", wikitext.as_str()),
+            ("summary", "Listeria run report"),
+            ("token", token.as_str()),
+            ("bot", "1"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let j = api.post_query_api_json(&params).await?;
+        match j["error"]["info"].as_str() {
+            Some(msg) => Err(anyhow!("{msg}")),
+            None => Ok(()),
+        }
+    }
+
     async fn update_page_status(
         &self,
         page: &str,
@@ -302,47 +400,12 @@ impl ListeriaBot {
         status: &str,
         message: &str,
     ) -> Result<()> {
-        let now: DateTime<Utc> = Utc::now();
-        let timestamp = now.format("%Y%m%d%H%M%S").to_string();
-        println!("{timestamp} {wiki}:{page} : {status}: {message}");
-        let params = params! {
-            "wiki" => wiki,
-            "page" => page,
-            "timestamp" => timestamp,
-            "status" => status,
-            "message" => message.chars().take(63).collect::<String>(),
-        };
-        let sql = "UPDATE `pagestatus` SET `status`=:status,`message`=:message,`timestamp`=:timestamp,`bot_version`=2 WHERE `wiki`=(SELECT id FROM `wikis` WHERE `name`=:wiki) AND `page`=:page".to_string() ;
-        self.pool.get_conn().await?
-            .exec_iter(sql.as_str(), params)
-            .await?
-            .map_and_drop(|row| from_row::<String>(row))
-            .await?;
-        Ok(())
-    }
-
-    async fn create_wiki_api(&self, wiki: &str) -> Result<Arc<RwLock<Api>>> {
-        let api_url = format!("{}/w/api.php", self.get_server_url_for_wiki(wiki)?);
-        let mut mw_api = wikibase::mediawiki::api::Api::new(&api_url)
-            .await?;
-        mw_api.set_oauth2(self.config.oauth2_token());
-        let mw_api = Arc::new(RwLock::new(mw_api));
-        Ok(mw_api)
+        self.queue.set_page_status(page, wiki, status, message).await
     }
 
     async fn get_or_create_wiki_api(&self, wiki: &str) -> Result<Arc<RwLock<Api>>> {
-        if let Some(api) = &self.wiki_apis.lock().await.get(wiki) {
-            return Ok((*api).clone());
-        }
-
-        let mw_api = self.create_wiki_api(wiki).await?;
-        self.wiki_apis.lock().await.insert(wiki.to_owned(), mw_api);
-
-        self.wiki_apis
-            .lock().await
-            .get(wiki)
-            .ok_or(anyhow!("Wiki not found: {wiki}"))
-            .map(|api| api.clone())
+        let api_url = format!("{}/w/api.php", self.get_server_url_for_wiki(wiki)?);
+        self.config().await.client_manager().api(&api_url).await
     }
 
     pub async fn destruct(&mut self) {
@@ -1,14 +1,25 @@
+use crate::api_client::ApiClient;
+use crate::cell_formatter::CellFormatter;
+use crate::column::StatementFilter;
+use crate::configuration::Configuration;
 use crate::entity_container_wrapper::*;
+use crate::expression::Expression;
+use crate::item_source;
+use crate::lint::{self, SparqlCheck};
 use crate::result_cell::*;
 use crate::result_cell_part::ResultCellPart;
+use crate::render_scribunto::RendererScribunto;
+use crate::render_wikitext::RendererWikitext;
 use crate::result_row::ResultRow;
+use crate::row_filter::{FilterExpression, RowFilter};
 use crate::{
-    Column, ColumnType, LinksType, PageParams, ReferencesParameter, SectionType, SortMode,
-    SortOrder, SparqlValue, Template, TemplateParams,
+    Column, ColumnType, LinksType, PageParams, ReferencesParameter, Renderer, SectionType, SortMode,
+    SortEmpty, SortOrder, MapMode, HeaderLinks, SparqlValue, SummaryToken, Template, TemplateParams, is_rtl_language, CurrentOnly,
 };
 use anyhow::{Result,anyhow};
+use chrono::Datelike;
+use regex::Regex;
 use serde_json::Value;
-use tokio::time::{sleep,Duration};
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::sync::Arc;
@@ -18,6 +29,51 @@ use wikibase::mediawiki::api::Api;
 use wikibase::snak::SnakDataType;
 use futures::future::join_all;
 
+/// Row counts of the difference between two renderings of a list, for edit summaries
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RowChanges {
+    pub added: usize,
+    pub removed: usize,
+    pub changed: usize,
+    pub rows_before: usize,
+    pub rows_after: usize,
+}
+
+impl RowChanges {
+    pub fn add(&mut self, other: &Self) {
+        self.added += other.added;
+        self.removed += other.removed;
+        self.changed += other.changed;
+        self.rows_before += other.rows_before;
+        self.rows_after += other.rows_after;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added == 0 && self.removed == 0 && self.changed == 0
+    }
+
+    /// Like "+12 rows, −3 rows, 5 changed"; leaves out zeros
+    pub fn describe(&self) -> String {
+        let mut parts = vec![];
+        if self.added > 0 {
+            parts.push(format!("+{} rows", self.added));
+        }
+        if self.removed > 0 {
+            parts.push(format!("\u{2212}{} rows", self.removed));
+        }
+        if self.changed > 0 {
+            parts.push(format!("{} changed", self.changed));
+        }
+        parts.join(", ")
+    }
+}
+
+/// For items from external lists, in place of SPARQL results
+const ITEM_CONCEPT_BASE_URI: &str = "http://www.wikidata.org/entity/";
+
+/// Bounds for the thumb parameter, in pixels
+const THUMBNAIL_SIZE_RANGE: std::ops::RangeInclusive<u64> = 16..=1000;
+
 #[derive(Debug, Clone)]
 pub struct ListeriaList {
     page_params: Arc<PageParams>,
@@ -26,14 +82,26 @@ pub struct ListeriaList {
     params: TemplateParams,
     sparql_rows: Vec<HashMap<String, SparqlValue>>,
     sparql_main_variable: Option<String>,
+    sparql_results: Option<Value>,
     pub ecw: EntityContainerWrapper,
     results: Vec<ResultRow>,
     shadow_files: Vec<String>,
     local_page_cache: HashMap<String, bool>,
     section_id_to_name: HashMap<usize, String>,
-    wb_api: Arc<Api>,
+    wb_api: Arc<dyn ApiClient>,
     language: String,
     reference_ids: Arc<std::sync::RwLock<HashSet<String>>>,
+    cell_formatters: Vec<Arc<dyn CellFormatter>>,
+    row_filters: Vec<Arc<dyn RowFilter>>,
+    map_points: Vec<(String, f64, f64)>, // Entity ID, latitude, longitude
+    sparql_endpoint: Option<String>,
+    sparql: Option<String>, // The query as it was run, with prefixes and label service
+    query_time_ms: Option<i64>,
+    rows_before_sample: Option<usize>, // If sample left out rows
+    duplicate_items: Vec<String>,      // Items with more than one SPARQL row, with one_row_per_item
+    timings: Vec<(String, u64)>,       // Step of `process`, milliseconds
+    entity_apis: HashMap<String, String>, // Entity ID => API name, for entities of other wikibases in federated queries
+    previous_wikitext: Option<String>, // The list as the page has it, to keep the row order of; see `keep_row_order_from`
     profiling:bool,
 }
 
@@ -49,6 +117,7 @@ impl ListeriaList {
             params: TemplateParams::new(),
             sparql_rows: vec![],
             sparql_main_variable: None,
+            sparql_results: None,
             ecw: EntityContainerWrapper::new(),
             results: vec![],
             shadow_files: vec![],
@@ -57,32 +126,154 @@ impl ListeriaList {
             wb_api,
             language: page_params.language.to_string(),
             reference_ids: Arc::new(std::sync::RwLock::new(HashSet::new())),
+            cell_formatters: vec![],
+            row_filters: vec![],
+            map_points: vec![],
+            sparql_endpoint: None,
+            sparql: None,
+            query_time_ms: None,
+            rows_before_sample: None,
+            duplicate_items: vec![],
+            timings: vec![],
+            entity_apis: HashMap::new(),
+            previous_wikitext: None,
             profiling:false,
         }
     }
 
+    /// Wall clock time, as std::time::Instant is not available in the browser
+    fn add_timing(&mut self, stage: &str, start: chrono::DateTime<chrono::Utc>) {
+        let ms = (chrono::Utc::now() - start).num_milliseconds().max(0) as u64;
+        self.timings.push((stage.to_string(), ms));
+    }
+
+    /// How long the steps of `process` took, in milliseconds: "sparql", "entities" and "results"
+    pub fn timings(&self) -> &[(String, u64)] {
+        &self.timings
+    }
+
     fn profile(&self, msg:&str) {
         if self.profiling {
             println!("{}",msg);
         }
     }
 
+    /// Runs a list from template parameters alone, without loading or saving a page; e.g. for previews
+    pub async fn new_from_params(params: HashMap<String, String>, page_params: Arc<PageParams>) -> Result<Self> {
+        let template = Template {
+            title: String::new(),
+            params,
+        };
+        let mut ret = Self::new(template, page_params);
+        ret.process().await?;
+        Ok(ret)
+    }
+
+    /// Formatters are asked in the order they were added
+    pub fn add_cell_formatter(&mut self, formatter: Arc<dyn CellFormatter>) {
+        self.cell_formatters.push(formatter);
+    }
+
+    /// Custom rendering of a cell part by the first formatter that handles it
+    pub fn format_cell_part(
+        &self,
+        part: &ResultCellPart,
+        rownum: usize,
+        colnum: usize,
+        partnum: usize,
+    ) -> Option<String> {
+        self.cell_formatters
+            .iter()
+            .find_map(|f| f.format_part(self, part, rownum, colnum, partnum))
+    }
+
+    /// Rows must pass all filters, in addition to the filter template parameter.
+    /// Filters apply when the list is processed.
+    pub fn add_row_filter(&mut self, filter: Arc<dyn RowFilter>) {
+        self.row_filters.push(filter);
+    }
+
+    /// The list as a wikitext table, as the bot would put it on a page.
+    /// For lists with a data module, the invocation of the module instead.
+    pub fn as_wikitext(&self) -> Result<String> {
+        match self.params.data_module {
+            Some(_) => RendererScribunto::new().invocation(self),
+            None => RendererWikitext::new().render(self),
+        }
+    }
+
+    /// The list as a Lua data module, see `data_module`
+    pub fn as_data_module(&self) -> Result<String> {
+        RendererScribunto::new().render(self)
+    }
+
+    /// Title of the module page for the data of this list, from the data_module parameter
+    pub fn data_module(&self) -> Option<&String> {
+        self.params.data_module.as_ref()
+    }
+
     pub async fn process(&mut self) -> Result<()> {
         self.profile("START list::process");
+        self.timings.clear();
         self.process_template().await?;
         self.profile("AFTER list::process process_template");
+        let start = chrono::Utc::now();
         self.run_query().await?;
+        self.add_timing("sparql", start);
         self.profile("AFTER list::process run_query");
+        self.page_params.check_deadline()?;
+        let start = chrono::Utc::now();
         self.load_entities().await?;
+        self.add_timing("entities", start);
         self.profile("AFTER list::process load_entities");
+        self.page_params.check_deadline()?;
+        let start = chrono::Utc::now();
         self.generate_results().await?;
         self.profile("AFTER list::process generate_results");
+        self.page_params.check_deadline()?;
         self.process_results().await?;
+        self.add_timing("results", start);
         self.profile("AFTER list::process process_results");
         self.profile("END list::process");
         Ok(())
     }
 
+    /// A copy of this list for a page on another wiki, sharing the query results and loaded entities.
+    /// Run `process_retargeted` on it to generate the rows for that wiki.
+    pub fn retarget(&self, page_params: Arc<PageParams>) -> Self {
+        let mut ret = Self::new(Template::default(), page_params);
+        ret.template = self.template.clone(); // Values are fixed already
+        ret.sparql_rows = self.sparql_rows.clone();
+        ret.sparql_main_variable = self.sparql_main_variable.clone();
+        ret.sparql_results = self.sparql_results.clone();
+        ret.ecw = self.ecw.clone();
+        ret.cell_formatters = self.cell_formatters.clone();
+        ret.row_filters = self.row_filters.clone();
+        ret.sparql_endpoint = self.sparql_endpoint.clone();
+        ret.sparql = self.sparql.clone();
+        ret.entity_apis = self.entity_apis.clone();
+        ret.timings = self.timings.clone();
+        ret.query_time_ms = self.query_time_ms;
+        ret.profiling = self.profiling;
+        ret
+    }
+
+    /// Like `process`, for a list from `retarget`; labels and links are for the language and wiki of the new page
+    pub async fn process_retargeted(&mut self) -> Result<()> {
+        let language = self.language.clone();
+        self.process_template().await?;
+        self.language = language;
+        self.params.targets.clear(); // Only the source list propagates
+        self.label_columns();
+        self.generate_results().await?;
+        self.process_results().await
+    }
+
+    /// Wikis and pages this list is also rendered to, from the targets parameter
+    pub fn targets(&self) -> &Vec<(String, String)> {
+        &self.params.targets
+    }
+
     pub fn results(&self) -> &Vec<ResultRow> {
         &self.results
     }
@@ -115,9 +306,8 @@ impl ListeriaList {
         let template = self.template.clone();
         match self.get_template_value(&template, "columns") {
             Some(columns) => {
-                columns.split(',').for_each(|part| {
-                    let s = part.to_string();
-                    self.columns.push(Column::new(&s));
+                Column::split_columns(&columns).into_iter().for_each(|part| {
+                    self.columns.push(Column::new(part));
                 });
             }
             None => self.columns.push(Column::new(&"item".to_string())),
@@ -127,16 +317,31 @@ impl ListeriaList {
         if let Some(s) = self.get_template_value(&template, "links") {
             self.params.links = LinksType::new_from_string(s.to_string())
         }
+        // Overrides the language of the wiki, for labels and formatting
         if let Some(l) = self.get_template_value(&template, "language") {
-            self.language = l.to_lowercase()
+            let l = l.trim().to_lowercase();
+            if !l.is_empty() {
+                self.language = l
+            }
         }
 
+        // The api= (or wikibase=) parameter picks one of the configured APIs, and its SPARQL service
+        if self.params.wikibase.is_empty() {
+            self.params.wikibase = self.page_params.config.default_api().to_string();
+        }
         let wikibase = &self.params.wikibase;
-        self.wb_api = match self.page_params.config.get_wbapi(&wikibase.to_lowercase()) {
+        self.wb_api = match self.page_params.config.get_wbapi(wikibase) {
             Some(api) => api.clone(),
-            None => return Err(anyhow!("No wikibase setup configured for '{wikibase}'")),
+            None => {
+                let names: Vec<&str> = self.page_params.config.wbapi_names().into_iter().map(|s| s.as_str()).collect();
+                return Err(anyhow!("Unknown api '{wikibase}', configured are: {}", names.join(", ")));
+            }
         };
 
+        if let Some(title) = &self.params.data_module {
+            let site_info = self.page_params.site_info().map(|si| si.as_ref());
+            self.page_params.config.check_data_module_title(&self.page_params.wiki, title, site_info)?;
+        }
         Ok(())
     }
 
@@ -144,6 +349,79 @@ impl ListeriaList {
         &self.language
     }
 
+    /// Problems with the list definition: template parameters, the query, and the columns it uses.
+    /// Loads the query (eg from sparql_page), but does not run it.
+    pub async fn lint(&mut self) -> Vec<String> {
+        let mut ret: Vec<String> = self
+            .template
+            .unknown_params()
+            .iter()
+            .map(|(key, suggestion)| match suggestion {
+                Some(known) => format!("Unknown parameter '{key}' (did you mean '{known}'?)"),
+                None => format!("Unknown parameter '{key}'"),
+            })
+            .collect();
+        if let Err(e) = self.process_template().await {
+            ret.push(e.to_string());
+            return ret;
+        }
+        if self.columns.iter().any(|column| column.obj == ColumnType::Unknown) {
+            ret.push("Unrecognized column type in 'columns'".to_string());
+        }
+        if let Some(thumb) = self.get_template_value(&self.template, "thumb").filter(|s| !s.trim().is_empty()) {
+            match Self::parse_thumbnail_size(&thumb) {
+                Some(size) if THUMBNAIL_SIZE_RANGE.contains(&size) => {}
+                Some(_) => ret.push(format!(
+                    "'thumb' is outside {}-{} pixels, and will be clamped",
+                    THUMBNAIL_SIZE_RANGE.start(),
+                    THUMBNAIL_SIZE_RANGE.end()
+                )),
+                None => ret.push(format!("Invalid 'thumb' value '{thumb}', expected pixels like 128 or 128px")),
+            }
+        }
+        let sparql = match self.load_sparql().await {
+            Ok(Some(sparql)) => sparql,
+            Ok(None) => {
+                let sources = ["items", "pagepile", "petscan"];
+                if !sources.iter().any(|key| self.get_template_value(&self.template, key).is_some()) {
+                    ret.push("No 'sparql', 'sparql_page', 'items', 'pagepile' or 'petscan' parameter".to_string());
+                }
+                return ret;
+            }
+            Err(e) => {
+                ret.push(e.to_string());
+                return ret;
+            }
+        };
+        let check = SparqlCheck::new(&sparql);
+        ret.extend(check.problems.iter().map(|problem| format!("SPARQL: {problem}")));
+        for column in &self.columns {
+            if let ColumnType::Field(variable) = &column.obj {
+                if !check.has_variable(variable) {
+                    ret.push(format!("Column ?{} is not selected by the query", variable.to_lowercase()));
+                }
+            }
+            if let ColumnType::Expression(expression) = &column.obj {
+                for variable in Expression::new(expression).map(|e| e.variables()).unwrap_or_default() {
+                    if !check.has_variable(&variable) {
+                        ret.push(format!("Expression variable ?{variable} is not selected by the query"));
+                    }
+                }
+            }
+        }
+        if let SortMode::SparqlVariable(variable) = &self.params.sort {
+            if !check.has_variable(variable) {
+                ret.push(format!("Sort variable ?{variable} is not selected by the query"));
+            }
+        }
+        if let SectionType::SparqlVariable(variable) = &self.params.section {
+            if !check.has_variable(variable) {
+                ret.push(format!("Section variable {variable} is not selected by the query"));
+            }
+        }
+        ret
+    }
+
     async fn cache_local_pages_exist(&mut self, pages: &[String]) {
         let params: HashMap<String, String> = vec![
             ("action", "query"),
@@ -156,9 +434,7 @@ impl ListeriaList {
 
         let result = match self
             .page_params
-            .mw_api
-            .read()
-            .await
+            .api_client
             .get_query_api_json(&params)
             .await
         {
@@ -187,20 +463,24 @@ impl ListeriaList {
         if let Some(obj) = result["query"]["pages"].as_object() {
             for (_k, v) in obj.iter() {
                 if let Some(title) = v["title"].as_str() {
-                    if normalized.contains_key(title) {
+                    // Under the title as the wiki has it, and as it was asked for
+                    if let Some(from) = normalized.get(title) {
                         let page_exists = v["missing"].as_str().is_none();
                         self.local_page_cache.insert(title.to_string(), page_exists);
+                        self.local_page_cache.insert(from.to_string(), page_exists);
                     }
                 }
             }
         };
     }
 
+    /// From the cache filled by `process_redlinks`; the first letter is only case-insensitive if the wiki says so
     pub fn local_page_exists(&self, page: &str) -> bool {
-        *self
-            .local_page_cache
-            .get(&page.to_string())
-            .unwrap_or(&false)
+        self.local_page_cache
+            .get(page)
+            .or_else(|| self.local_page_cache.get(&self.normalize_page_title(&page.to_string())))
+            .copied()
+            .unwrap_or(false)
     }
 
     fn first_letter_to_upper_case(&self, s1: &String) -> String {
@@ -212,13 +492,30 @@ impl ListeriaList {
     }
 
     pub fn normalize_page_title(&self, s: &String) -> String {
-        // TODO use page to find out about first character capitalization on the current wiki
+        if let Some(site_info) = self.page_params.site_info() {
+            return site_info.normalize_title(s);
+        }
         if s.len() < 2 {
             return s.to_owned();
         }
         self.first_letter_to_upper_case(s)
     }
 
+    /// Wraps `text` in the wiki's bidi template if `language` is written in the other direction
+    pub fn bidi_wrap(&self, text: &str, language: &str) -> String {
+        let rtl = is_rtl_language(language);
+        if text.is_empty() || rtl == is_rtl_language(self.language()) {
+            return text.to_string();
+        }
+        match self.page_params.config.get_bidi_template(&self.page_params.wiki) {
+            Some(template) => template
+                .replace("$LANG$", language)
+                .replace("$DIR$", if rtl { "rtl" } else { "ltr" })
+                .replace("$TEXT$", text),
+            None => text.to_string(),
+        }
+    }
+
     pub fn get_location_template(
         &self,
         lat: f64,
@@ -235,51 +532,118 @@ impl ListeriaList {
             .replace("$REGION$", &region.unwrap_or_default())
     }
 
+    /// From the thumb parameter, or the default of the wiki
     pub fn thumbnail_size(&self) -> u64 {
-        let default = self.page_params.config.default_thumbnail_size();
-        match self.get_template_value(&self.template, "thumb") {
-            Some(s) => s.parse::<u64>().ok().or(Some(default)).unwrap_or(default),
-            None => default,
-        }
+        self.get_template_value(&self.template, "thumb")
+            .and_then(|s| Self::parse_thumbnail_size(&s))
+            .map(|size| size.clamp(*THUMBNAIL_SIZE_RANGE.start(), *THUMBNAIL_SIZE_RANGE.end()))
+            .unwrap_or_else(|| self.page_params.config.default_thumbnail_size(&self.page_params.wiki))
     }
 
-    pub async fn run_sparql_query(&self, sparql: &str) -> Result<Value> {
-        let endpoint = match self
-            .wb_api
-            .get_site_info_string("general", "wikibase-sparql")
-        {
-            Ok(endpoint) => {
-                // SPARQL service given by site
-                endpoint
-            }
-            _ => {
-                // Override SPARQL service (hardcoded for Commons)
-                "https://wcqs-beta.wmflabs.org/sparql"
-            }
+    /// Pixels from "128" or "128px"; None if empty or invalid
+    fn parse_thumbnail_size(s: &str) -> Option<u64> {
+        let s = s.trim().to_lowercase();
+        s.strip_suffix("px").unwrap_or(&s).trim().parse::<u64>().ok()
+    }
+
+    /// PREFIX declarations for the query, from the configuration for the wikibase, and the sparql_prefixes parameter.
+    /// The parameter adds "ex: <http://example.org/>" declarations separated by ";", or is "none" to add no prefixes at all.
+    fn sparql_prefixes(&self) -> Vec<(String, String)> {
+        lazy_static! {
+            static ref RE_PREFIX: Regex =
+                Regex::new(r"^\s*(?i:PREFIX\s+)?([\w.-]*):?\s*<([^>]*)>\s*$").expect("RE_PREFIX does not parse");
+        }
+        let mut ret = self.page_params.config.sparql_prefixes(&self.params.wikibase);
+        let param = match &self.params.sparql_prefixes {
+            Some(param) => param,
+            None => return ret,
         };
+        if param.eq_ignore_ascii_case("none") {
+            return vec![];
+        }
+        for caps in param.split(';').filter_map(|part| RE_PREFIX.captures(part)) {
+            let prefix = caps[1].to_string();
+            ret.retain(|(p, _)| *p != prefix);
+            ret.push((prefix, caps[2].to_string()));
+        }
+        ret
+    }
 
-        // SPARQL might need some retries sometimes, bad server or somesuch
-        let mut attempts_left = 10;
-        loop {
-            let ret = self.wb_api.sparql_query_endpoint(sparql, endpoint).await;//.map_err(|e|anyhow!("{e}"))
-            match ret {
-                Ok(ret) => return Ok(ret),
-                Err(e) => { 
-                    match &e {
-                        wikibase::mediawiki::media_wiki_error::MediaWikiError::String(s) => {
-                            if attempts_left>0 && s=="error decoding response body: expected value at line 1 column 1" {
-                                sleep(Duration::from_millis(500)).await;
-                                attempts_left -= 1;
-                                continue;
-                            } else {
-                                return Err(anyhow!("{e}"))
-                            }
-                        },
-                        e => return Err(anyhow!("{e}"))
-                    }
-                }
+    /// List language first, then the fallbacks
+    fn label_languages(&self) -> Vec<String> {
+        let mut ret = vec![self.language.to_owned()];
+        for language in ["mul", self.default_language(), "en"] {
+            if !ret.iter().any(|l| l == language) {
+                ret.push(language.to_string());
             }
         }
+        ret
+    }
+
+    /// Adds the label service to a query that selects ?xLabel, ?xDescription, or ?xAltLabel variables
+    /// without binding them, and has no label service of its own. None if nothing needs to change.
+    /// The service goes at the end of the WHERE group, before any VALUES, ORDER BY or LIMIT after it.
+    fn add_label_service(sparql: &str, languages: &str) -> Option<String> {
+        lazy_static! {
+            static ref RE_LABEL_VARIABLE: Regex =
+                Regex::new(r"[?$](\w+(?:AltLabel|Label|Description))\b").expect("RE_LABEL_VARIABLE does not parse");
+            static ref RE_LABEL_SERVICE: Regex =
+                Regex::new(r"(?i)wikibase:label\b").expect("RE_LABEL_SERVICE does not parse");
+            static ref RE_VARIABLE: Regex = Regex::new(r"[?$](\w+)").expect("RE_VARIABLE does not parse");
+            static ref RE_AS: Regex = Regex::new(r"(?i)\bAS\s+[?$](\w+)").expect("RE_AS does not parse");
+        }
+        // Strings, IRIs and comments are blanked out, at the same byte positions as in the query
+        let code = lint::blank_literals(sparql);
+        if RE_LABEL_SERVICE.is_match(&code) {
+            return None;
+        }
+        let (body_start, body_end) = lint::where_group(sparql)?;
+        let (projection, body) = (&code[..body_start], &code[body_start..body_end]);
+        let bound: HashSet<&str> = RE_VARIABLE
+            .captures_iter(body)
+            .chain(RE_AS.captures_iter(projection))
+            .filter_map(|caps| caps.get(1))
+            .map(|variable| variable.as_str())
+            .collect();
+        let unbound = RE_LABEL_VARIABLE
+            .captures_iter(projection)
+            .any(|caps| !bound.contains(&caps[1]));
+        if !unbound {
+            return None;
+        }
+        let service = format!(
+            "SERVICE wikibase:label {{ bd:serviceParam wikibase:language \"{languages}\" . }}\n"
+        );
+        Some(format!("{}\n{}{}", sparql[..body_end].trim_end(), service, &sparql[body_end..]))
+    }
+
+    /// Puts PREFIX declarations in front of the query, for prefixes it does not declare itself
+    fn add_sparql_prefixes(sparql: &str, prefixes: &[(String, String)]) -> String {
+        let declarations: String = prefixes
+            .iter()
+            .filter(|(prefix, _)| {
+                let declared = format!(r"(?i)\bPREFIX\s+{}:", regex::escape(prefix));
+                !Regex::new(&declared).map(|re| re.is_match(sparql)).unwrap_or(false)
+            })
+            .map(|(prefix, iri)| format!("PREFIX {prefix}: <{iri}>\n"))
+            .collect();
+        declarations + sparql
+    }
+
+    /// The SPARQL service of the wikibase; from the configuration, or its site info
+    pub async fn sparql_endpoint(&self) -> String {
+        if let Some(endpoint) = self.page_params.config.get_sparql_endpoint(&self.params.wikibase) {
+            return endpoint.to_owned();
+        }
+        match self.wb_api.sparql_endpoint().await {
+            Some(endpoint) => endpoint, // SPARQL service given by site
+            None => "https://wcqs-beta.wmflabs.org/sparql".to_string(), // Override SPARQL service (hardcoded for Commons)
+        }
+    }
+
+    pub async fn run_sparql_query(&self, sparql: &str) -> Result<Value> {
+        let endpoint = self.sparql_endpoint().await;
+        self.wb_api.sparql_query(&endpoint, sparql).await
     }
 
     async fn expand_sparql_templates(&self, sparql: &mut String) -> Result<()> {
@@ -287,7 +651,6 @@ impl ListeriaList {
             // No template
             return Ok(());
         }
-        let api = self.page_params.mw_api.read().await;
         let params: HashMap<String, String> = vec![
             ("action", "expandtemplates"),
             ("title", &self.page_params.page),
@@ -297,7 +660,9 @@ impl ListeriaList {
         .into_iter()
         .map(|(k, v)| (k.to_string(), v.to_string()))
         .collect();
-        let j = api
+        let j = self
+            .page_params
+            .api_client
             .get_query_api_json(&params)
             .await?;
         if let Some(s) = j["expandtemplates"]["wikitext"].as_str() {
@@ -314,28 +679,135 @@ impl ListeriaList {
             .next()
     }
 
-    pub async fn run_query(&mut self) -> Result<()> {
-        let mut sparql = match self.get_template_value(&self.template, "sparql") {
-            Some(s) => s,
-            None => return Err(anyhow!("No 'sparql' parameter in {:?}", &self.template)),
+    /// Wikitext of a page on this wiki
+    async fn load_local_page_wikitext(&self, title: &str) -> Result<String> {
+        let params: HashMap<String, String> = [
+            ("action", "query"),
+            ("prop", "revisions"),
+            ("rvprop", "content"),
+            ("rvslots", "main"),
+            ("titles", title),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let j = self
+            .page_params
+            .api_client
+            .get_query_api_json(&params)
+            .await?;
+        j["query"]["pages"]
+            .as_object()
+            .and_then(|pages| pages.values().next())
+            .and_then(|page| page["revisions"][0]["slots"]["main"]["*"].as_str())
+            .map(|s| s.to_string())
+            .ok_or_else(|| anyhow!("Page [[{title}]] does not exist"))
+    }
+
+    /// The query from `sparql` (with templates expanded), or from the page given in `sparql_page`
+    async fn load_sparql(&self) -> Result<Option<String>> {
+        let sparql = self.get_template_value(&self.template, "sparql");
+        let sparql_page = self.get_template_value(&self.template, "sparql_page");
+        match (sparql, sparql_page) {
+            (Some(_), Some(_)) => Err(anyhow!("Use either 'sparql' or 'sparql_page', not both")),
+            (Some(mut sparql), None) => {
+                self.expand_sparql_templates(&mut sparql).await.map_err(|e|anyhow!("{e}"))?;
+                Ok(Some(sparql))
+            }
+            (None, Some(sparql_page)) => {
+                let sparql_page = sparql_page.trim();
+                let title = match sparql_page.starts_with('/') {
+                    true => format!("{}{sparql_page}", self.page_params.page), // Subpage
+                    false => sparql_page.to_string(),
+                };
+                let wikitext = self.load_local_page_wikitext(&title).await?;
+                let sparql = Self::sparql_from_wikitext(&wikitext);
+                if sparql.is_empty() {
+                    return Err(anyhow!("No SPARQL query on [[{title}]]"));
+                }
+                Ok(Some(sparql))
+            }
+            (None, None) => Ok(None),
         }
-        .to_string();
+    }
 
-        self.expand_sparql_templates(&mut sparql).await.map_err(|e|anyhow!("{e}"))?;
+    /// The contents of the first <syntaxhighlight>, <source> or <pre> block, or the whole text
+    fn sparql_from_wikitext(wikitext: &str) -> String {
+        lazy_static! {
+            static ref RE_CODE_BLOCK: Regex =
+                Regex::new(r"(?is)<(syntaxhighlight|source|pre)\b[^>]*>(.*?)</(syntaxhighlight|source|pre)\s*>")
+                    .expect("RE_CODE_BLOCK does not parse");
+        }
+        match RE_CODE_BLOCK.captures(wikitext) {
+            Some(caps) => caps[2].trim().to_string(),
+            None => wikitext.trim().to_string(),
+        }
+    }
 
+    /// Items from `items`, either a list of QIDs or a page on this wiki that contains them
+    async fn load_manual_items(&self, items: &str) -> Result<Vec<String>> {
+        if item_source::is_item_list(items) {
+            return Ok(item_source::items_in_text(items));
+        }
+        let wikitext = self.load_local_page_wikitext(items.trim()).await?;
+        Ok(item_source::items_in_text(&wikitext))
+    }
+
+    /// Items from an external list (`items`, `pagepile` or `petscan`), if any
+    async fn load_external_items(&self) -> Result<Option<Vec<String>>> {
+        let items = self.get_template_value(&self.template, "items");
+        let pagepile = self.get_template_value(&self.template, "pagepile");
+        let petscan = self.get_template_value(&self.template, "petscan");
+        let sources = [&items, &pagepile, &petscan].iter().filter(|s| s.is_some()).count();
+        if sources > 1 {
+            return Err(anyhow!("Use only one of 'items', 'pagepile' and 'petscan'"));
+        }
+        if let Some(items) = items {
+            return Ok(Some(self.load_manual_items(&items).await?));
+        }
+        if let Some(pagepile_id) = pagepile {
+            return Ok(Some(item_source::pagepile_items(self.wb_api.as_ref(), &pagepile_id).await?));
+        }
+        if let Some(psid) = petscan {
+            return Ok(Some(item_source::petscan_items(self.wb_api.as_ref(), &psid).await?));
+        }
+        Ok(None)
+    }
+
+    pub async fn run_query(&mut self) -> Result<()> {
         // Return simulated results
         if self.page_params.simulate {
-            match &self.page_params.simulated_sparql_results {
-                Some(json_text) => {
-                    let j = serde_json::from_str(&json_text)?;
-                    return self.parse_sparql(j);
-                }
-                None => {}
+            if let Some(json_text) = &self.page_params.simulated_sparql_results {
+                let j = serde_json::from_str(json_text)?;
+                return self.parse_sparql(j);
+            }
+        }
+
+        let external_items = self.load_external_items().await?;
+        let mut sparql = match (self.load_sparql().await?, &external_items) {
+            (Some(s), _) => s,
+            (None, Some(items)) => {
+                let j = item_source::items_as_sparql_results(items, ITEM_CONCEPT_BASE_URI);
+                return self.parse_sparql(j);
             }
+            (None, None) => return Err(anyhow!("No 'sparql', 'sparql_page', 'items', 'pagepile' or 'petscan' parameter in {:?}", &self.template)),
+        };
+
+        if let Some(items) = &external_items {
+            sparql = item_source::restrict_sparql_to_items(&sparql, items)?;
         }
+        let languages = self.label_languages().join(",");
+        if let Some(with_labels) = Self::add_label_service(&sparql, &languages) {
+            sparql = with_labels;
+        }
+        sparql = Self::add_sparql_prefixes(&sparql, &self.sparql_prefixes());
 
+        self.sparql = Some(sparql.clone());
         self.profile("BEGIN run_query: run_sparql_query");
+        let start = chrono::Utc::now();
         let j = self.run_sparql_query(&sparql).await?;
+        self.query_time_ms = Some((chrono::Utc::now() - start).num_milliseconds());
+        self.sparql_endpoint = Some(self.sparql_endpoint().await);
         self.profile("END run_query: run_sparql_query");
         if self.page_params.simulate {
             println!("{}\n{}\n", &sparql, &j);
@@ -345,7 +817,9 @@ impl ListeriaList {
 
     fn parse_sparql(&mut self, j: Value) -> Result<()> {
         self.sparql_rows.clear();
+        self.entity_apis.clear();
         self.sparql_main_variable = None;
+        self.sparql_results = Some(j.to_owned());
 
         if false {
             // Use first variable
@@ -375,7 +849,11 @@ impl ListeriaList {
             let mut row: HashMap<String, SparqlValue> = HashMap::new();
             if let Some(bo) = b.as_object() {
                 for (k, v) in bo.iter() {
-                    match SparqlValue::new_from_json(&v) {
+                    let value = SparqlValue::new_from_json(v);
+                    if let (Some(SparqlValue::Entity(id)), Some(uri)) = (&value, v["value"].as_str()) {
+                        self.note_entity_api(id, uri);
+                    }
+                    match value {
                         Some(v2) => row.insert(k.to_owned(), v2),
                         None => {
                             return Err(anyhow!("Can't parse SPARQL value: {} => {:?}", &k, &v))
@@ -391,15 +869,53 @@ impl ListeriaList {
         Ok(())
     }
 
+    /// Remembers the API of an entity whose URI is in the concept base of another configured wikibase
+    fn note_entity_api(&mut self, id: &str, uri: &str) {
+        if let Some(api_name) = self.page_params.config.get_api_for_entity_uri(uri) {
+            if *api_name != self.params.wikibase {
+                self.entity_apis.insert(id.to_string(), api_name.to_owned());
+            }
+        }
+    }
+
+    /// Loads entities, each from the API of its wikibase; see `note_entity_api`.
+    /// Entities that are only referenced by other entities are loaded from the API of the list.
+    async fn load_entities_from_apis(&mut self, ids: &[String]) -> Result<()> {
+        let mut ids_per_api: HashMap<Option<&String>, Vec<String>> = HashMap::new();
+        for id in ids {
+            ids_per_api.entry(self.entity_apis.get(id)).or_default().push(id.to_owned());
+        }
+        for (api_name, ids) in ids_per_api {
+            let api = match api_name {
+                Some(api_name) => self
+                    .page_params
+                    .config
+                    .get_wbapi(api_name)
+                    .ok_or_else(|| anyhow!("No wikibase setup configured for '{api_name}'"))?
+                    .clone(),
+                None => self.wb_api.clone(),
+            };
+            self.ecw.load_entities(api.as_ref(), &ids).await.map_err(|e| anyhow!("{e}"))?;
+        }
+        Ok(())
+    }
+
     pub async fn load_entities(&mut self) -> Result<()> {
         // Any columns that require entities to be loaded?
         // TODO also force if self.links is redlinks etc.
         if self
             .columns
             .iter()
-            .filter(
-                |c| !matches!(c.obj, ColumnType::Number | ColumnType::Item | ColumnType::Field(_)),
-            )
+            .filter(|c| {
+                !matches!(
+                    c.obj,
+                    ColumnType::Number
+                        | ColumnType::Item
+                        | ColumnType::ItemTemplate
+                        | ColumnType::Field(_)
+                        | ColumnType::Expression(_)
+                )
+            })
             .count()
             == 0
         {
@@ -410,13 +926,41 @@ impl ListeriaList {
         if ids.is_empty() {
             return Err(anyhow!("No items to show"));
         }
-        self.ecw.load_entities(&self.wb_api, &ids).await.map_err(|e|anyhow!("{e}"))?;
+        self.load_entities_from_apis(&ids).await?;
+        self.load_property_path_entities(&ids).await?;
 
         self.label_columns();
 
         Ok(())
     }
 
+    /// Loads the items along the property path columns, one step at a time
+    async fn load_property_path_entities(&mut self, ids: &[String]) -> Result<()> {
+        let paths: Vec<Vec<String>> = self
+            .columns
+            .iter()
+            .filter_map(|c| match &c.obj {
+                ColumnType::PropertyPath(path) => Some(path.to_owned()),
+                _ => None,
+            })
+            .collect();
+        for path in paths {
+            let mut current = ids.to_vec();
+            for property in &path[..path.len() - 1] {
+                let mut next: Vec<String> = current
+                    .iter()
+                    .filter_map(|id| self.get_entity(id))
+                    .flat_map(|e| self.item_values(&e, property))
+                    .collect();
+                next.sort();
+                next.dedup();
+                self.load_entities_from_apis(&next).await?;
+                current = next;
+            }
+        }
+        Ok(())
+    }
+
     fn label_columns(&mut self) {
         self.columns = self
             .columns
@@ -453,9 +997,14 @@ impl ListeriaList {
 
         // Column headers
         self.columns.iter().for_each(|c| match &c.obj {
-            ColumnType::Property(prop) => {
+            ColumnType::Property(prop) | ColumnType::HasProperty(prop) => {
                 ids.push(prop.to_owned());
             }
+            ColumnType::PropertyFallback(props)
+            | ColumnType::PropertyCombination(props)
+            | ColumnType::PropertyPath(props) => {
+                ids.extend(props.iter().cloned());
+            }
             ColumnType::PropertyQualifier((prop, qual)) => {
                 ids.push(prop.to_owned());
                 ids.push(qual.to_owned());
@@ -498,10 +1047,7 @@ impl ListeriaList {
             e.id(),
             self.language
         );
-        let api = self.page_params.mw_api.read().await;
-        let body = api
-            .query_raw(&url, &api.no_params(), "GET")
-            .await?;
+        let body = self.page_params.api_client.get_url(&url).await?;
         let json: Value = serde_json::from_str(&body)?;
         match json["result"].as_str() {
             Some(result) => Ok(result.to_string()),
@@ -512,6 +1058,7 @@ impl ListeriaList {
     pub async fn generate_results(&mut self) -> Result<()> {
         let varname = self.get_var_name()?;
         let mut results: Vec<ResultRow> = vec![];
+        let mut duplicate_items: Vec<String> = vec![];
         match self.params.one_row_per_item {
             true => {
                 let tmp_rows : Vec<(String,Vec<&HashMap<String,SparqlValue>>)>
@@ -529,6 +1076,11 @@ impl ListeriaList {
                             (id.to_owned(),sparql_rows)
                         })
                     .collect();
+                duplicate_items = tmp_rows
+                    .iter()
+                    .filter(|(_id, sparql_rows)| sparql_rows.len() > 1)
+                    .map(|(id, _sparql_rows)| id.to_owned())
+                    .collect();
                 
                 let mut futures = vec!() ;
                 for (id,sparql_rows) in &tmp_rows {
@@ -555,6 +1107,7 @@ impl ListeriaList {
             }
         };
         self.results = results;
+        self.duplicate_items = duplicate_items;
         Ok(())
     }
 
@@ -621,15 +1174,11 @@ impl ListeriaList {
             })
             .collect();
 
-        let api_read = self
-        .page_params
-        .mw_api
-        .read()
-        .await;
+        let api = &self.page_params.api_client;
 
         let mut futures = vec![] ;
         for params in &param_list {
-            futures.push ( api_read.get_query_api_json(&params) ) ;
+            futures.push ( api.get_query_api_json(params) ) ;
         }
 
         let tmp_results = join_all(futures)
@@ -728,6 +1277,9 @@ impl ListeriaList {
         let mut labels = vec![];
         for id in ids {
             if let Some(e) = self.get_entity(id) {
+                if self.local_page(&e).is_some() {
+                    continue; // Linked by the sitelink, not the label
+                }
                 if let Some(l) = e.label_in_locale(self.language()) {
                     labels.push(l.to_string());
                 }
@@ -737,7 +1289,11 @@ impl ListeriaList {
         labels.sort();
         labels.dedup();
         // TODO in parallel
-        let labels_per_chunk = if self.page_params.mw_api.read().await.user().is_bot() {
+        let is_bot = match &self.page_params.mw_api {
+            Some(mw_api) => mw_api.read().await.user().is_bot(),
+            None => false,
+        };
+        let labels_per_chunk = if is_bot {
             500
         } else {
             50
@@ -750,9 +1306,72 @@ impl ListeriaList {
     }
 
     async fn process_sort_results(&mut self) -> Result<()> {
+        let sort = self.params.sort.to_owned();
+        let datatype = match self.apply_sortkeys(&sort).await? {
+            Some(datatype) => datatype,
+            None => return Ok(()),
+        };
+        // Stable in both directions, so rows with the same sort value keep their order
+        let descending = self.params.sort_order == SortOrder::Descending;
+        self.results.sort_by(|a, b| {
+            let ordering = a.compare_to(b, &datatype);
+            if descending {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        });
+        self.group_rows_without_sort_value(&datatype);
+
+        Ok(())
+    }
+
+    /// Moves rows without a sort value to the start or end of their section, keeping their order
+    fn group_rows_without_sort_value(&mut self, datatype: &SnakDataType) {
+        let empty_first = match self.params.sort_empty {
+            SortEmpty::Default => return,
+            SortEmpty::First => true,
+            SortEmpty::Last => false,
+        };
+        self.results.sort_by(|a, b| {
+            let a_empty = !a.has_sort_value(datatype);
+            let b_empty = !b.has_sort_value(datatype);
+            let ordering = if empty_first {
+                b_empty.cmp(&a_empty)
+            } else {
+                a_empty.cmp(&b_empty)
+            };
+            a.section().cmp(&b.section()).then(ordering)
+        });
+    }
+
+    /// Sorts the rows of each section by section_sort, keeping the order of the sections
+    async fn process_sort_within_sections(&mut self) -> Result<()> {
+        let sort = self.params.section_sort.to_owned();
+        let datatype = match self.apply_sortkeys(&sort).await? {
+            Some(datatype) => datatype,
+            None => return Ok(()),
+        };
+        let descending = self.params.section_sort_order == SortOrder::Descending;
+        self.results.sort_by(|a, b| {
+            a.section().cmp(&b.section()).then_with(|| {
+                let ordering = a.compare_to(b, &datatype);
+                if descending {
+                    ordering.reverse()
+                } else {
+                    ordering
+                }
+            })
+        });
+        self.group_rows_without_sort_value(&datatype);
+        Ok(())
+    }
+
+    /// Sets the sortkey of each row; returns how to compare the keys, or None if not sorting
+    async fn apply_sortkeys(&mut self, sort: &SortMode) -> Result<Option<SnakDataType>> {
         let sortkeys: Vec<String>;
         let mut datatype = SnakDataType::String; // Default
-        match &self.params.sort {
+        match sort {
             SortMode::Label => {
                 self.load_row_entities().await?;
                 sortkeys = self
@@ -762,6 +1381,7 @@ impl ListeriaList {
                     .collect();
             }
             SortMode::FamilyName => {
+                self.load_family_name_items().await?;
                 sortkeys = self
                     .results
                     .iter()
@@ -776,6 +1396,22 @@ impl ListeriaList {
                     .map(|row| row.get_sortkey_prop(&prop, &self, &datatype))
                     .collect();
             }
+            SortMode::Metadata(column_type) => {
+                let column_type = column_type.to_owned();
+                self.load_row_entities().await?;
+                if column_type != ColumnType::Modified {
+                    datatype = SnakDataType::Quantity; // Sort counts numerically
+                }
+                sortkeys = self
+                    .results
+                    .iter()
+                    .map(|row| {
+                        self.get_entity(row.entity_id())
+                            .and_then(|e| column_type.entity_metadata(&e))
+                            .unwrap_or_default()
+                    })
+                    .collect();
+            }
             SortMode::SparqlVariable(variable) => {
                 sortkeys = self
                     .results
@@ -783,35 +1419,59 @@ impl ListeriaList {
                     .map(|row| row.get_sortkey_sparql(&variable, &self))
                     .collect();
             }
-            SortMode::None => return Ok(()),
+            SortMode::None => return Ok(None),
         }
 
         // Apply sortkeys
         if self.results.len() != sortkeys.len() {
             // Paranoia
-            return Err(anyhow!("process_sort_results: sortkeys length mismatch"));
+            return Err(anyhow!("apply_sortkeys: sortkeys length mismatch"));
         }
         self.results
             .iter_mut()
             .enumerate()
             .for_each(|(rownum, row)| row.set_sortkey(sortkeys[rownum].to_owned()));
+        Ok(Some(datatype))
+    }
 
-        self.results.sort_by(|a, b| a.compare_to(b, &datatype));
-        if self.params.sort_order == SortOrder::Descending {
-            self.results.reverse()
+    /// Loads the row items and the items of their family name properties, for sort=family_name
+    async fn load_family_name_items(&mut self) -> Result<()> {
+        self.load_row_entities().await?;
+        let properties: Vec<String> = self
+            .config()
+            .family_name_sources()
+            .iter()
+            .filter(|source| !source.eq_ignore_ascii_case("label"))
+            .map(|source| source.to_uppercase())
+            .collect();
+        let mut items_to_load: Vec<String> = vec![];
+        for row in &self.results {
+            if let Some(entity) = self.get_entity(row.entity_id()) {
+                for property in &properties {
+                    for statement in entity.claims_with_property(property) {
+                        if let Some(dv) = statement.main_snak().data_value() {
+                            if let wikibase::Value::Entity(v) = dv.value() {
+                                items_to_load.push(v.id().to_string());
+                            }
+                        }
+                    }
+                }
+            }
         }
-
+        items_to_load.sort();
+        items_to_load.dedup();
+        self.load_entities_from_apis(&items_to_load).await?;
         Ok(())
     }
 
     async fn load_row_entities(&mut self) -> Result<()> {
-        let items_to_load = self
+        let items_to_load: Vec<String> = self
             .results
             .iter()
             .map(|row| row.entity_id())
             .cloned()
             .collect();
-        self.ecw.load_entities(&self.wb_api, &items_to_load).await.map_err(|e|anyhow!("{e}"))?;
+        self.load_entities_from_apis(&items_to_load).await?;
         Ok(())
     }
 
@@ -834,7 +1494,7 @@ impl ListeriaList {
             .collect::<Vec<String>>();
 
         // Make sure section name items are loaded
-        self.ecw.load_entities(&self.wb_api, &section_names).await.map_err(|e|anyhow!("{e}"))?;
+        self.load_entities_from_apis(&section_names).await?;
         let section_names = section_names
         .iter()
         .map(|q| self.get_label_with_fallback(q,None))
@@ -987,29 +1647,25 @@ impl ListeriaList {
         if !items_to_load.is_empty() {
             items_to_load.sort_unstable();
             items_to_load.dedup();
-            self.ecw.load_entities(&self.wb_api, &items_to_load).await.map_err(|e|anyhow!("{e}"))?;
+            self.load_entities_from_apis(&items_to_load).await?;
         }
         Ok(())
     }
 
     async fn fix_local_links(&mut self) -> Result<()> {
         // Set the is_category flag
-        let mw_api = self.mw_api();
-        let mw_api = mw_api.read().await;
+        let page_params = self.page_params.clone();
         for row in self.results.iter_mut() {
             for cell in row.cells_mut().iter_mut() {
                 for part in cell.parts_mut().iter_mut() {
                     if let ResultCellPart::LocalLink((page, _label, is_category)) = &mut part.part {
-                        let title = wikibase::mediawiki::title::Title::new_from_full(page, &mw_api);
-                        *is_category = title.namespace_id() == 14;
+                        *is_category = page_params.namespace_id(page).await == 14;
                     } else if let ResultCellPart::SnakList(v) = &mut part.part {
                         for subpart in v.iter_mut() {
                             if let ResultCellPart::LocalLink((page, _label, is_category)) =
                                 &mut subpart.part
                             {
-                                let title =
-                                    wikibase::mediawiki::title::Title::new_from_full(page, &mw_api);
-                                *is_category = title.namespace_id() == 14;
+                                *is_category = page_params.namespace_id(page).await == 14;
                             }
                         }
                     }
@@ -1021,6 +1677,10 @@ impl ListeriaList {
 
     pub async fn process_results(&mut self) -> Result<()> {
         self.profile("START list::process_results");
+        self.process_filter_results()?;
+        self.profile("AFTER list::process_results process_filter_results");
+        self.process_sample_results();
+        self.profile("AFTER list::process_results process_sample_results");
         self.gather_and_load_items().await?;
         self.profile("AFTER list::process_results gather_and_load_items");
         self.process_redlinks_only()?;
@@ -1035,18 +1695,270 @@ impl ListeriaList {
         self.profile("AFTER list::process_results process_excess_files");
         self.process_reference_items().await?;
         self.profile("AFTER list::process_results process_reference_items");
+        self.process_previous_row_order();
+        self.profile("AFTER list::process_results process_previous_row_order");
         self.process_sort_results().await?;
         self.profile("AFTER list::process_results process_sort_results");
         self.process_assign_sections().await?;
         self.profile("AFTER list::process_results process_assign_sections");
+        self.process_sort_within_sections().await?;
+        self.profile("AFTER list::process_results process_sort_within_sections");
         self.process_regions().await?;
         self.profile("AFTER list::process_results process_regions");
         self.fix_local_links().await?;
         self.profile("AFTER list::process_results fix_local_links");
+        self.process_map();
+        self.profile("AFTER list::process_results process_map");
+        self.process_hide_empty_columns();
+        self.profile("AFTER list::process_results process_hide_empty_columns");
         self.profile("END list::process_results");
         Ok(())
     }
 
+    /// Collects the first coordinates of each row for the map page.
+    /// With map=only, columns with coordinates are then dropped from the table.
+    fn process_map(&mut self) {
+        if self.params.map == MapMode::None {
+            return;
+        }
+        self.map_points = self
+            .results
+            .iter()
+            .filter_map(|row| {
+                row.cells()
+                    .iter()
+                    .flat_map(|cell| cell.parts())
+                    .find_map(|part| match &part.part {
+                        ResultCellPart::Location((lat, lon, _)) => Some((row.entity_id().to_owned(), *lat, *lon)),
+                        _ => None,
+                    })
+            })
+            .collect();
+        if self.params.map != MapMode::Only {
+            return;
+        }
+        let keep: Vec<bool> = (0..self.columns.len())
+            .map(|colnum| {
+                !self.results.iter().any(|row| match row.cells().get(colnum) {
+                    Some(cell) => cell
+                        .parts()
+                        .iter()
+                        .any(|part| matches!(part.part, ResultCellPart::Location(_))),
+                    None => false,
+                })
+            })
+            .collect();
+        for row in self.results.iter_mut() {
+            let mut keep_cell = keep.iter();
+            row.cells_mut()
+                .retain(|_| *keep_cell.next().unwrap_or(&true));
+        }
+        let mut keep_column = keep.iter();
+        self.columns.retain(|_| *keep_column.next().unwrap_or(&true));
+    }
+
+    /// Entity ID, latitude, and longitude of each row with coordinates, if the list has a map
+    pub fn map_points(&self) -> &Vec<(String, f64, f64)> {
+        &self.map_points
+    }
+
+    pub fn map_mode(&self) -> &MapMode {
+        &self.params.map
+    }
+
+    pub fn header_links(&self) -> &HeaderLinks {
+        &self.params.header_links
+    }
+
+    pub fn sort_ignore_articles(&self) -> bool {
+        self.params.sort_ignore_articles
+    }
+
+    /// Drops columns without a value in any row, if the template asks for it
+    fn process_hide_empty_columns(&mut self) {
+        if !self.params.hide_empty_columns || self.results.is_empty() {
+            return;
+        }
+        let keep: Vec<bool> = (0..self.columns.len())
+            .map(|colnum| {
+                self.results.iter().any(|row| match row.cells().get(colnum) {
+                    Some(cell) => !cell.is_empty(),
+                    None => false,
+                })
+            })
+            .collect();
+        for row in self.results.iter_mut() {
+            let mut keep_cell = keep.iter();
+            row.cells_mut()
+                .retain(|_| *keep_cell.next().unwrap_or(&true));
+        }
+        let mut keep_column = keep.iter();
+        self.columns.retain(|_| *keep_column.next().unwrap_or(&true));
+    }
+
+    /// Keeps the row order of `previous_wikitext`, the list as rendered before, when the list is processed.
+    /// This is the order of unsorted lists, and of rows of the same item in sorted ones;
+    /// sorting otherwise orders rows by their values and item IDs, so they only move if their values change.
+    pub fn keep_row_order_from(&mut self, previous_wikitext: &str) {
+        self.previous_wikitext = Some(previous_wikitext.to_string());
+    }
+
+    /// Puts the rows in the order of the previous rendering, keyed by item ID, before they are sorted.
+    /// Items new to the list go at the end, so unchanged rows render exactly as before.
+    fn process_previous_row_order(&mut self) {
+        let previous_wikitext = match &self.previous_wikitext {
+            Some(wikitext) => wikitext,
+            None => return,
+        };
+        let entity_ids: HashSet<String> = self
+            .results
+            .iter()
+            .map(|row| row.entity_id().to_uppercase())
+            .collect();
+        let mut previous_position: HashMap<String, usize> = HashMap::new();
+        for entity_id in Self::previous_row_ids(previous_wikitext, &entity_ids) {
+            let position = previous_position.len();
+            previous_position.entry(entity_id).or_insert(position);
+        }
+        if previous_position.is_empty() {
+            return;
+        }
+        // Stable, so new items keep their relative order
+        self.results.sort_by_key(|row| {
+            match previous_position.get(&row.entity_id().to_uppercase()) {
+                Some(position) => (false, *position),
+                None => (true, 0),
+            }
+        });
+    }
+
+    /// The item shown in each row of a previously generated list, in order.
+    /// A row is identified by its first mention of one of `entity_ids`.
+    fn previous_row_ids(wikitext: &str, entity_ids: &HashSet<String>) -> Vec<String> {
+        Self::row_chunks(wikitext)
+            .iter()
+            .filter_map(|chunk| Self::row_entity_ids(chunk).find(|entity_id| entity_ids.contains(entity_id)))
+            .collect()
+    }
+
+    /// The rows of generated list wikitext; table rows, or lines if there is no table
+    fn row_chunks(wikitext: &str) -> Vec<String> {
+        let mut chunks: Vec<String> = vec![];
+        if wikitext.lines().any(|line| line.trim_start().starts_with("|-")) {
+            let mut in_row = false;
+            for line in wikitext.lines() {
+                let line = line.trim_start();
+                if line.starts_with("|-") {
+                    in_row = true;
+                    chunks.push(String::new());
+                } else if line.starts_with("|}") {
+                    in_row = false;
+                }
+                if in_row {
+                    if let Some(chunk) = chunks.last_mut() {
+                        *chunk += line;
+                        *chunk += "\n";
+                    }
+                }
+            }
+        } else {
+            // No table; one row per line, eg from a row template
+            chunks = wikitext.lines().map(|line| line.to_string()).collect();
+        }
+        chunks
+    }
+
+    fn row_entity_ids(chunk: &str) -> impl Iterator<Item = String> + '_ {
+        lazy_static! {
+            static ref RE_ENTITY_ID: Regex =
+                Regex::new(r"\b(?:wd_)?([QqPpLlMm]\d+)\b").expect("RE_ENTITY_ID does not parse");
+        }
+        RE_ENTITY_ID.captures_iter(chunk).map(|caps| caps[1].to_uppercase())
+    }
+
+    /// Rows added, removed and changed between two renderings of a list, matched by item.
+    /// Rows of items in `entity_ids` are keyed by that item; others by their first item mentioned.
+    pub fn row_changes(old_wikitext: &str, new_wikitext: &str, entity_ids: &HashSet<String>) -> RowChanges {
+        let rows = |wikitext: &str| -> HashMap<String, String> {
+            Self::row_chunks(wikitext)
+                .iter()
+                .filter_map(|chunk| {
+                    let mut ids = Self::row_entity_ids(chunk).peekable();
+                    let first = ids.peek().cloned()?;
+                    let key = ids.find(|entity_id| entity_ids.contains(entity_id)).unwrap_or(first);
+                    let text = chunk.split_whitespace().collect::<Vec<&str>>().join(" ");
+                    Some((key, text))
+                })
+                .collect()
+        };
+        let old_rows = rows(old_wikitext);
+        let new_rows = rows(new_wikitext);
+        RowChanges {
+            added: new_rows.keys().filter(|key| !old_rows.contains_key(*key)).count(),
+            removed: old_rows.keys().filter(|key| !new_rows.contains_key(*key)).count(),
+            changed: new_rows
+                .iter()
+                .filter(|(key, text)| old_rows.get(*key).is_some_and(|old| old != *text))
+                .count(),
+            rows_before: old_rows.len(),
+            rows_after: new_rows.len(),
+        }
+    }
+
+    /// Keeps `sample` random rows, in their original order.
+    /// With `sample_seed`, the same results always give the same sample.
+    fn process_sample_results(&mut self) {
+        let sample = match self.params.sample {
+            Some(sample) if sample < self.results.len() => sample,
+            _ => return,
+        };
+        self.rows_before_sample = Some(self.results.len());
+        let mut rng = match self.params.sample_seed {
+            Some(seed) => fastrand::Rng::with_seed(seed),
+            None => {
+                let now = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default();
+                fastrand::Rng::with_seed(now as u64)
+            }
+        };
+        let mut rownums: Vec<usize> = (0..self.results.len()).collect();
+        rng.shuffle(&mut rownums);
+        let mut keep = vec![false; self.results.len()];
+        rownums.iter().take(sample).for_each(|rownum| keep[*rownum] = true);
+        let mut keep = keep.into_iter();
+        self.results.retain(|_| keep.next().unwrap_or(false));
+    }
+
+    fn process_filter_results(&mut self) -> Result<()> {
+        let expressions = match &self.params.filter {
+            Some(filter) => FilterExpression::new_list(filter)?,
+            None => vec![],
+        };
+        if expressions.is_empty() && self.row_filters.is_empty() {
+            return Ok(());
+        }
+        let keep: Vec<bool> = self
+            .results
+            .iter()
+            .map(|row| {
+                expressions.iter().all(|e| e.keep(self, row))
+                    && self.row_filters.iter().all(|f| f.keep(self, row))
+            })
+            .collect();
+        let mut keep = keep.into_iter();
+        self.results.retain(|_| keep.next().unwrap_or(true));
+        Ok(())
+    }
+
+    /// Shown in cells without a value, from the empty_cell parameter
+    pub fn empty_cell(&self) -> Option<&String> {
+        self.params.empty_cell.as_ref()
+    }
+
+    /// Between the values of a P1+P2 column
+    pub fn combine_separator(&self) -> &str {
+        &self.params.combine_separator
+    }
+
     pub fn get_links_type(&self) -> &LinksType {
         &self.params.links // TODO duplicate code
     }
@@ -1129,20 +2041,12 @@ impl ListeriaList {
             }
             SectionType::None => {}
         }
-        self.ecw
-            .load_entities(&self.wb_api, &entities_to_load)
-            .await
-            .map_err(|e|anyhow!("{e}"))?;
+        self.load_entities_from_apis(&entities_to_load).await?;
 
         entities_to_load = self.gather_items_sort()?;
         let mut v2 = self.gather_items_section()?;
         entities_to_load.append(&mut v2);
-        match self.ecw
-            .load_entities(&self.wb_api, &entities_to_load)
-            .await {
-                Ok(ret) => Ok(ret),
-                Err(e) => Err(anyhow!("{e}")),
-            }
+        self.load_entities_from_apis(&entities_to_load).await
     }
 
     pub fn column(&self, column_id: usize) -> Option<&Column> {
@@ -1168,15 +2072,61 @@ impl ListeriaList {
         &self.page_params.page
     }
 
-    pub fn summary(&self) -> &Option<String> {
+    pub fn summary(&self) -> &[SummaryToken] {
         &self.params.summary
     }
 
+    /// The SPARQL service that ran the query; None for simulated or external item lists
+    pub fn query_endpoint(&self) -> Option<&String> {
+        self.sparql_endpoint.as_ref()
+    }
+
+    /// The query in the web interface of its SPARQL service, like "https://query.wikidata.org/#SELECT..."
+    pub fn query_url(&self) -> Option<String> {
+        let endpoint = self.query_endpoint()?;
+        let sparql = self.sparql.as_ref()?;
+        let base = endpoint.strip_suffix("/sparql").unwrap_or(endpoint);
+        Some(format!("{base}/#{}", urlencoding::encode(sparql)))
+    }
+
+    /// How long the SPARQL query took, in milliseconds
+    pub fn query_time_ms(&self) -> Option<i64> {
+        self.query_time_ms
+    }
+
+    /// The number of rows before sample left some out
+    pub fn rows_before_sample(&self) -> Option<usize> {
+        self.rows_before_sample
+    }
+
+    /// A warning if items have more than one query result row, with one_row_per_item; usually from OPTIONALs
+    /// of multi-valued properties in the query
+    pub fn duplicate_items_warning(&self) -> Option<String> {
+        const MAX_SHOWN: usize = 20;
+        if self.duplicate_items.is_empty() {
+            return None;
+        }
+        let mut items = self.duplicate_items.iter().take(MAX_SHOWN).cloned().collect::<Vec<String>>().join(", ");
+        if self.duplicate_items.len() > MAX_SHOWN {
+            items += &format!(" and {} more", self.duplicate_items.len() - MAX_SHOWN);
+        }
+        Some(format!("Items with more than one query result row, check the OPTIONALs of the query: {items}"))
+    }
+
     pub fn header_template(&self) -> &Option<String> {
         &self.params.header_template
     }
 
     pub fn get_label_with_fallback(&self, entity_id: &str, use_language: Option<&str>) -> String {
+        self.get_label_and_language_with_fallback(entity_id, use_language).0
+    }
+
+    /// The label, and the language of the label that was found; no language if the entity ID is used instead
+    pub fn get_label_and_language_with_fallback(
+        &self,
+        entity_id: &str,
+        use_language: Option<&str>,
+    ) -> (String, Option<String>) {
         let use_language = match use_language {
             Some(l) => l,
             None => self.language(),
@@ -1184,28 +2134,34 @@ impl ListeriaList {
         match self.get_entity(entity_id) {
             Some(entity) => {
                 match entity.label_in_locale(use_language).map(|s| s.to_string()) {
-                    Some(s) => s,
+                    Some(s) => (s, Some(use_language.to_string())),
                     None => {
                         // Try the usual suspects
                         for language in ["en", "de", "fr", "es", "it", "el", "nl"].iter() {
                             if let Some(label) =
                                 entity.label_in_locale(language).map(|s| s.to_string())
                             {
-                                return label;
+                                return (label, Some(language.to_string()));
                             }
                         }
                         // Try any label, any language
-                        if let Some(entity) = self.get_entity(entity_id) {
-                            if let Some(label) = entity.labels().get(0) {
-                                return label.value().to_string();
-                            }
+                        if let Some(label) = entity.labels().first() {
+                            return (label.value().to_string(), Some(label.language().to_string()));
                         }
                         // Fallback to item ID as label
-                        entity_id.to_string()
+                        (entity_id.to_string(), None)
                     }
                 }
             }
-            None => entity_id.to_string(), // Fallback
+            None => (entity_id.to_string(), None), // Fallback
+        }
+    }
+
+    /// Wraps a label in the bidi template if it is in a language written in the other direction than the list
+    pub fn bidi_wrap_label(&self, label: &str, language: Option<&str>) -> String {
+        match language {
+            Some(language) => self.bidi_wrap(label, language),
+            None => label.to_string(),
         }
     }
 
@@ -1215,12 +2171,19 @@ impl ListeriaList {
 
     pub fn get_item_wiki_target(&self, entity_id: &str) -> String {
         let prefix = if self.is_wikidatawiki() { "" } else { ":d:" };
-        if let Some(first_char) = entity_id.chars().next() {
-            if first_char == 'p' || first_char == 'P' {
-                return format!("{}Property:{}", prefix, entity_id);
+        match entity_id.chars().next() {
+            Some('p') | Some('P') => format!("{}Property:{}", prefix, entity_id),
+            Some('l') | Some('L') => match entity_id.split_once('-') {
+                Some((lexeme, _)) => format!("{}Lexeme:{}#{}", prefix, lexeme, entity_id), // Form or sense
+                None => format!("{}Lexeme:{}", prefix, entity_id),
+            },
+            Some('m') | Some('M') => {
+                // MediaInfo entities live on Commons
+                let prefix = if self.page_params.wiki == "commonswiki" { "" } else { ":c:" };
+                format!("{}Special:EntityPage/{}", prefix, entity_id)
             }
+            _ => format!("{}{}", prefix, entity_id),
         }
-        format!("{}{}", prefix, entity_id)
     }
 
     pub fn get_item_link_with_fallback(&self, entity_id: &str) -> String {
@@ -1245,13 +2208,40 @@ impl ListeriaList {
         &self,
         e: &wikibase::entity::Entity,
         property: &str,
+    ) -> Vec<wikibase::statement::Statement> {
+        self.get_column_claims(e, property, &[])
+    }
+
+    /// The items that are values of a property, from the claims a column would show
+    pub fn item_values(&self, e: &wikibase::entity::Entity, property: &str) -> Vec<String> {
+        self.get_column_claims(e, property, &[])
+            .iter()
+            .filter_map(|statement| match statement.main_snak().data_value().as_ref()?.value() {
+                wikibase::Value::Entity(v) => Some(v.id().to_string()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Like get_filtered_claims, but only statements that match all of a column's filters
+    pub fn get_column_claims(
+        &self,
+        e: &wikibase::entity::Entity,
+        property: &str,
+        filters: &[StatementFilter],
     ) -> Vec<wikibase::statement::Statement> {
         let mut ret: Vec<wikibase::statement::Statement> = e
             .claims_with_property(property)
             .iter()
+            .filter(|x| filters.iter().all(|filter| filter.matches(x)))
             .map(|x| (*x).clone())
             .collect();
 
+        if self.params.current_only.applies_to(property) {
+            let as_of = self.as_of_date();
+            ret.retain(|statement| Self::is_current_statement(statement, as_of));
+        }
+
         if self.page_params.config.prefer_preferred() {
             let has_preferred = ret
                 .iter()
@@ -1265,20 +2255,629 @@ impl ListeriaList {
         }
     }
 
+    /// The date to check statement validity against; the as_of parameter, or today
+    fn as_of_date(&self) -> (i64, u32, u32) {
+        self.params
+            .as_of
+            .as_ref()
+            .and_then(|s| CurrentOnly::parse_date(s, false))
+            .unwrap_or_else(|| {
+                let today = chrono::Utc::now().date_naive();
+                (today.year() as i64, today.month(), today.day())
+            })
+    }
+
+    /// A statement is current if it has not started after, or ended before, `as_of`.
+    /// An unknown end time means it has ended.
+    fn is_current_statement(
+        statement: &wikibase::statement::Statement,
+        as_of: (i64, u32, u32),
+    ) -> bool {
+        statement.qualifiers().iter().all(|snak| {
+            let end_of_period = match snak.property() {
+                "P580" => false,
+                "P582" => true,
+                _ => return true,
+            };
+            if end_of_period && *snak.snak_type() == wikibase::snak::SnakType::UnknownValue {
+                return false;
+            }
+            let date = match snak.data_value().as_ref().map(|dv| dv.value()) {
+                Some(wikibase::Value::Time(t)) => CurrentOnly::parse_date(t.time(), end_of_period),
+                _ => None,
+            };
+            match date {
+                Some(date) if end_of_period => date >= as_of,
+                Some(date) => date <= as_of,
+                None => true,
+            }
+        })
+    }
+
     pub fn entity_to_local_link(&self, item: &str) -> Option<ResultCellPart> {
+        let sites = self.config().link_sites(self.wiki());
         self.ecw
-            .entity_to_local_link(item, self.wiki(), &self.language)
+            .entity_to_local_link(item, self.wiki(), &sites, &self.language)
+    }
+
+    /// The page for an entity on this wiki, or on another site configured in `link_sites`
+    pub fn local_page(&self, entity: &Entity) -> Option<String> {
+        let sites = self.config().link_sites(self.wiki());
+        EntityContainerWrapper::local_page(entity, self.wiki(), &sites)
     }
 
     pub fn default_language(&self) -> &str {
         &self.page_params.config.default_language()
     }
 
+    pub fn template(&self) -> &Template {
+        &self.template
+    }
+
     pub fn template_params(&self) -> &TemplateParams {
         &self.params
     }
 
-    pub fn mw_api(&self) -> Arc<RwLock<Api>> {
+    pub fn config(&self) -> &Configuration {
+        &self.page_params.config
+    }
+
+    /// The raw SPARQL results the list was built from
+    pub fn sparql_results(&self) -> Option<&Value> {
+        self.sparql_results.as_ref()
+    }
+
+    /// How the list was made, after `process`: the query as run, the main variable, columns,
+    /// sorting, sections and which statements are shown. For finding out why a list looks the way it does.
+    pub fn explain(&self) -> Value {
+        let columns: Vec<Value> = self
+            .columns
+            .iter()
+            .map(|column| json!({"type": format!("{:?}", column.obj), "label": column.label}))
+            .collect();
+        json!({
+            "wiki": self.wiki(),
+            "page": self.page_title(),
+            "language": self.language,
+            "wikibase": self.params.wikibase,
+            "sparql": self.sparql,
+            "sparql_endpoint": self.sparql_endpoint,
+            "duplicate_items": self.duplicate_items,
+            "timings": self.timings.iter().cloned().collect::<HashMap<String, u64>>(),
+            "main_variable": self.sparql_main_variable,
+            "sparql_rows": self.sparql_rows.len(),
+            "rows": self.results.len(),
+            "columns": columns,
+            "sort": format!("{:?}", self.params.sort),
+            "sort_order": format!("{:?}", self.params.sort_order),
+            "sort_empty": format!("{:?}", self.params.sort_empty),
+            "sort_ignore_articles": self.params.sort_ignore_articles,
+            "section": format!("{:?}", self.params.section),
+            "min_section": self.params.min_section,
+            "section_sort": format!("{:?}", self.params.section_sort),
+            "one_row_per_item": self.params.one_row_per_item,
+            "links": format!("{:?}", self.params.links),
+            "ranks": {
+                "prefer_preferred": self.page_params.config.prefer_preferred(),
+                "current_only": format!("{:?}", self.params.current_only),
+                "as_of": self.params.as_of,
+            },
+            "filter": self.params.filter,
+            "sample": self.params.sample,
+            "unknown_parameters": self.template.unknown_params().iter().map(|(key, _)| key).collect::<Vec<_>>(),
+        })
+    }
+
+    pub fn mw_api(&self) -> Option<Arc<RwLock<Api>>> {
         self.page_params.mw_api.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_client::MockApiClient;
+    use crate::client_manager::ClientManager;
+    use crate::wiki_login::WikiLogin;
+    use crate::PageParamsBuilder;
+
+    /// A list on an enwiki page, with template parameters and entities on mock wikibases
+    async fn test_list(params: &[(&str, &str)], entities: &[Value]) -> ListeriaList {
+        test_list_with_items(params, entities, &[]).await
+    }
+
+    /// Like `test_list`, with `items` as the result of any SPARQL query
+    async fn test_list_with_items(params: &[(&str, &str)], entities: &[Value], items: &[&str]) -> ListeriaList {
+        let j: Value = serde_json::from_str(&std::fs::read_to_string("config.json.template").unwrap()).unwrap();
+        let client_manager = ClientManager::new(&j["http"], WikiLogin::default());
+        let bindings: Vec<Value> = items
+            .iter()
+            .map(|q| json!({"item": {"type": "uri", "value": format!("http://www.wikidata.org/entity/{q}")}}))
+            .collect();
+        for api in j["apis"].as_object().unwrap().values() {
+            let mut mock = MockApiClient::default();
+            mock.add_entities_from_lines(&std::fs::read_to_string("test_data/common.entities").unwrap()).unwrap();
+            for entity in entities {
+                mock.add_entity(entity.to_owned()).unwrap();
+            }
+            mock.add_sparql_results("", json!({"head": {"vars": ["item"]}, "results": {"bindings": bindings}}));
+            client_manager.register_api_client(api["api"].as_str().unwrap(), Arc::new(mock));
+        }
+        let config = Configuration::new_from_json_with_client_manager(j, client_manager).await.unwrap();
+        let site_info: Value = serde_json::from_str(&std::fs::read_to_string("test_data/siteinfo.json").unwrap()).unwrap();
+        let mut mock = MockApiClient::default();
+        mock.add_response(&[("meta", "siteinfo")], json!({"query": site_info["enwiki"]}));
+        let page_params = PageParamsBuilder::new(Arc::new(config), "Test")
+            .wiki("enwiki")
+            .language("en")
+            .api_client(Arc::new(mock))
+            .build()
+            .await
+            .unwrap();
+        let template = Template {
+            title: String::new(),
+            params: params.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        };
+        ListeriaList::new(template, Arc::new(page_params))
+    }
+
+    /// An item with an English label, and statements with the given values: items, "+1990-05-06" dates, or strings
+    fn test_item(id: &str, label: &str, statements: &[(&str, &str)]) -> Value {
+        let mut claims = json!({});
+        for (num, (property, value)) in statements.iter().enumerate() {
+            let (datatype, datavalue) = if let Some(q) = value.strip_prefix('Q') {
+                let id = json!({"entity-type": "item", "numeric-id": q.parse::<u64>().unwrap(), "id": value});
+                ("wikibase-item", json!({"type": "wikibase-entityid", "value": id}))
+            } else if value.starts_with('+') {
+                let time = json!({"time": format!("{value}T00:00:00Z"), "timezone": 0, "before": 0, "after": 0,
+                    "precision": 11, "calendarmodel": "http://www.wikidata.org/entity/Q1985727"});
+                ("time", json!({"type": "time", "value": time}))
+            } else {
+                ("string", json!({"type": "string", "value": value}))
+            };
+            let statement = json!({"mainsnak": {"snaktype": "value", "property": property, "datavalue": datavalue,
+                "datatype": datatype}, "type": "statement", "rank": "normal", "id": format!("{id}${num}")});
+            match claims[*property].as_array_mut() {
+                Some(statements) => statements.push(statement),
+                None => claims[*property] = json!([statement]),
+            }
+        }
+        json!({"type": "item", "id": id, "labels": {"en": {"language": "en", "value": label}},
+            "descriptions": {}, "aliases": {}, "claims": claims, "sitelinks": {}, "modified": "2024-01-01T00:00:00Z"})
+    }
+
+    fn test_property(id: &str, datatype: &str) -> Value {
+        json!({"type": "property", "id": id, "datatype": datatype, "labels": {"en": {"language": "en", "value": id}},
+            "descriptions": {}, "aliases": {}, "claims": {}})
+    }
+
+    /// Item IDs of the rows, in order
+    fn row_ids(list: &ListeriaList) -> Vec<&str> {
+        list.results().iter().map(|row| row.entity_id().as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_process_sort_within_sections() {
+        let entities = [
+            test_item("Q11", "Carol", &[("P31", "Q5")]),
+            test_item("Q12", "Alice", &[("P31", "Q5")]),
+            test_item("Q13", "Bob", &[("P31", "Q146")]),
+            test_item("Q14", "Alf", &[("P31", "Q146")]),
+            test_item("Q5", "human", &[]),
+            test_item("Q146", "cat", &[]),
+            test_property("P31", "wikibase-item"),
+        ];
+        let items = ["Q11", "Q12", "Q13", "Q14"];
+        let params = [("sparql", "SELECT ?item {}"), ("columns", "item,label"), ("section", "P31"), ("section_sort", "label")];
+        let mut list = test_list_with_items(&params, &entities, &items).await;
+        list.process().await.unwrap();
+        // Sections in order of their names, cat before human; rows by label within each
+        assert_eq!(row_ids(&list), vec!["Q14", "Q13", "Q12", "Q11"]);
+
+        let mut params = params.to_vec();
+        params.push(("section_sort_order", "desc"));
+        let mut list = test_list_with_items(&params, &entities, &items).await;
+        list.process().await.unwrap();
+        assert_eq!(row_ids(&list), vec!["Q13", "Q14", "Q11", "Q12"]);
+    }
+
+    #[tokio::test]
+    async fn test_group_rows_without_sort_value() {
+        let entities = [
+            test_item("Q21", "A", &[("P569", "+1990-01-01")]),
+            test_item("Q22", "B", &[]),
+            test_item("Q23", "C", &[("P569", "+1980-01-01")]),
+            test_property("P569", "time"),
+        ];
+        let items = ["Q21", "Q22", "Q23"];
+        for (sort_empty, expected) in [("first", ["Q22", "Q23", "Q21"]), ("last", ["Q23", "Q21", "Q22"])] {
+            let params = [("sparql", "SELECT ?item {}"), ("columns", "item,P569"), ("sort", "P569"), ("sort_empty", sort_empty)];
+            let mut list = test_list_with_items(&params, &entities, &items).await;
+            list.process().await.unwrap();
+            assert_eq!(row_ids(&list), expected, "sort_empty={sort_empty}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_process_sample_results() {
+        let items = ["Q31", "Q32", "Q33", "Q34", "Q35"];
+        let entities: Vec<Value> = items.iter().map(|q| test_item(q, q, &[])).collect();
+        let params = [("sparql", "SELECT ?item {}"), ("columns", "item"), ("sample", "3"), ("sample_seed", "42")];
+        let mut list = test_list_with_items(&params, &entities, &items).await;
+        list.process().await.unwrap();
+        let sampled = row_ids(&list);
+        assert_eq!(sampled.len(), 3);
+        assert_eq!(list.rows_before_sample(), Some(5));
+        // Rows keep their order
+        let positions: Vec<usize> = sampled.iter().map(|id| items.iter().position(|q| q == id).unwrap()).collect();
+        assert!(positions.windows(2).all(|w| w[0] < w[1]), "{sampled:?}");
+
+        // The same seed picks the same rows
+        let mut again = test_list_with_items(&params, &entities, &items).await;
+        again.process().await.unwrap();
+        assert_eq!(row_ids(&again), sampled);
+
+        // A sample as large as the list keeps all rows
+        let params = [("sparql", "SELECT ?item {}"), ("columns", "item"), ("sample", "5")];
+        let mut list = test_list_with_items(&params, &entities, &items).await;
+        list.process().await.unwrap();
+        assert_eq!(row_ids(&list), items);
+        assert_eq!(list.rows_before_sample(), None);
+    }
+
+    #[tokio::test]
+    async fn test_load_family_name_items() {
+        let entities = [
+            test_item("Q41", "Anna Zorn", &[("P734", "Q52")]),
+            test_item("Q43", "Zed Adams", &[("P734", "Q51")]),
+            test_item("Q44", "Carl Miller", &[]),
+            test_item("Q51", "Adams", &[]),
+            test_item("Q52", "Abel", &[]), // Differs from the label, to tell where the sort key came from
+            test_property("P734", "wikibase-item"),
+        ];
+        let params = [("sparql", "SELECT ?item {}"), ("columns", "item"), ("sort", "family_name")];
+        let mut list = test_list_with_items(&params, &entities, &["Q41", "Q43", "Q44"]).await;
+        list.process().await.unwrap();
+        assert!(list.get_entity("Q51").is_some());
+        assert!(list.get_entity("Q52").is_some());
+        // Family name items first, then the last word of the label
+        assert_eq!(row_ids(&list), vec!["Q41", "Q43", "Q44"]);
+        assert_eq!(list.results()[0].sortkey(), "abel, anna zorn");
+        assert_eq!(list.results()[2].sortkey(), "miller, carl");
+    }
+
+    #[tokio::test]
+    async fn test_has_property_column() {
+        let entities = [
+            test_item("Q61", "With image", &[("P18", "Example.jpg")]),
+            test_item("Q62", "Without image", &[("P31", "Q5")]),
+            test_property("P18", "string"),
+        ];
+        let params = [("sparql", "SELECT ?item {}"), ("columns", "item,has/P18")];
+        let mut list = test_list_with_items(&params, &entities, &["Q61", "Q62"]).await;
+        list.process().await.unwrap();
+        let indicators: Vec<&ResultCellPart> = list.results().iter().map(|row| &row.cells()[1].parts()[0].part).collect();
+        assert_eq!(
+            indicators,
+            vec![&ResultCellPart::Text("✓".to_string()), &ResultCellPart::Text("✗".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_property_path_column() {
+        let entities = [
+            test_item("Q71", "Book", &[("P50", "Q72")]),
+            test_item("Q72", "Author", &[("P27", "Q73")]),
+            test_item("Q73", "Country", &[]),
+            test_item("Q74", "Anonymous book", &[]),
+            test_property("P50", "wikibase-item"),
+            test_property("P27", "wikibase-item"),
+        ];
+        let params = [("sparql", "SELECT ?item {}"), ("columns", "item,path/P50/P27")];
+        let mut list = test_list_with_items(&params, &entities, &["Q71", "Q74"]).await;
+        list.process().await.unwrap();
+        // The author was loaded in the entity phase, to follow the path to their country
+        assert!(list.get_entity("Q72").is_some());
+        let country: Vec<&ResultCellPart> = list.results()[0].cells()[1].parts().iter().map(|p| &p.part).collect();
+        assert!(
+            matches!(country.as_slice(), [ResultCellPart::Entity((id, _))] if id == "Q73"),
+            "{country:?}"
+        );
+        assert!(list.results()[1].cells()[1].is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_header_links() {
+        let mut p569 = test_property("P569", "time");
+        p569["descriptions"] = json!({"en": {"language": "en", "value": "date on which the \"subject\" was born"}});
+        let entities = [test_item("Q75", "Someone", &[("P569", "+1990-05-06")]), p569];
+        let headers = |list: &ListeriaList| -> Vec<String> {
+            let wikitext = list.as_wikitext().unwrap();
+            wikitext.lines().filter(|l| l.starts_with("! ")).map(|l| l.to_string()).collect()
+        };
+        let params = [("sparql", "SELECT ?item {}"), ("columns", "item,P569,P569:Born"), ("header_links", "link")];
+        let mut list = test_list_with_items(&params, &entities, &["Q75"]).await;
+        list.process().await.unwrap();
+        // Only generated labels of property columns change
+        assert_eq!(headers(&list), vec!["! item", "! [[:d:Property:P569|P569]]", "! Born"]);
+
+        let params = [("sparql", "SELECT ?item {}"), ("columns", "item,P569,P569:Born"), ("header_links", "tooltip")];
+        let mut list = test_list_with_items(&params, &entities, &["Q75"]).await;
+        list.process().await.unwrap();
+        assert_eq!(
+            headers(&list),
+            vec![
+                "! item",
+                "! <span title=\"P569: date on which the &quot;subject&quot; was born\">P569</span>",
+                "! Born"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_collapsible_wikitext() {
+        let entities = [test_item("Q76", "Someone", &[])];
+        let params = [("sparql", "SELECT ?item {}"), ("columns", "item"), ("collapsible", "collapsed")];
+        let mut list = test_list_with_items(&params, &entities, &["Q76"]).await;
+        list.process().await.unwrap();
+        assert_eq!(
+            list.as_wikitext().unwrap(),
+            "{| class='wikitable sortable mw-collapsible mw-collapsed'\n! item\n|-\n| [[:d:Q76|Q76]]\n|}"
+        );
+
+        // Without a table of its own, the rows need a wrapper to collapse
+        let params = [
+            ("sparql", "SELECT ?item {}"),
+            ("columns", "item"),
+            ("collapsible", "yes"),
+            ("skip_table", "yes"),
+        ];
+        let mut list = test_list_with_items(&params, &entities, &["Q76"]).await;
+        list.process().await.unwrap();
+        assert_eq!(list.as_wikitext().unwrap(), "<div class='mw-collapsible'>\n| [[:d:Q76|Q76]]\n</div>");
+    }
+
+    #[tokio::test]
+    async fn test_footer_wikitext() {
+        let entities = [test_item("Q77", "Someone", &[])];
+        let table = "{| class='wikitable sortable'\n! item\n|-\n| [[:d:Q77|Q77]]\n|}";
+        let summary = "&sum; 1 items.";
+        let cases = [
+            ("", format!("{table}\n----\n{summary}")),
+            ("top", format!("{summary}\n----\n{table}")),
+            ("no", table.to_string()),
+            // enwiki has no footer template, so the summary line stays
+            ("template", format!("{table}\n----\n{summary}")),
+        ];
+        for (footer, expected) in cases {
+            let params = [
+                ("sparql", "SELECT ?item {}"),
+                ("columns", "item"),
+                ("summary", "itemnumber"),
+                ("footer", footer),
+            ];
+            let mut list = test_list_with_items(&params, &entities, &["Q77"]).await;
+            list.process().await.unwrap();
+            assert_eq!(list.as_wikitext().unwrap(), expected, "footer={footer}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_items_warning() {
+        let entities = [test_item("Q81", "A", &[]), test_item("Q82", "B", &[])];
+        let items = ["Q81", "Q82", "Q81"];
+        let params = [("sparql", "SELECT ?item {}"), ("columns", "item")];
+        let mut list = test_list_with_items(&params, &entities, &items).await;
+        list.process().await.unwrap();
+        assert_eq!(list.results().len(), 2);
+        assert_eq!(
+            list.duplicate_items_warning().unwrap(),
+            "Items with more than one query result row, check the OPTIONALs of the query: Q81"
+        );
+
+        // One row per result with one_row_per_item=no, so nothing to warn about
+        let params = [("sparql", "SELECT ?item {}"), ("columns", "item"), ("one_row_per_item", "no")];
+        let mut list = test_list_with_items(&params, &entities, &items).await;
+        list.process().await.unwrap();
+        assert_eq!(list.results().len(), 3);
+        assert_eq!(list.duplicate_items_warning(), None);
+    }
+
+    #[tokio::test]
+    async fn test_process_hide_empty_columns() {
+        let entities = [
+            test_item("Q1", "Alice", &[("P31", "Q5")]),
+            test_item("Q2", "Bob", &[("P31", "Q5")]),
+            test_property("P31", "wikibase-item"),
+            test_property("P569", "time"),
+        ];
+        let params = [("sparql", "SELECT ?item {}"), ("columns", "item,P569,P31"), ("hide_empty_columns", "yes")];
+        let mut list = test_list_with_items(&params, &entities, &["Q1", "Q2"]).await;
+        list.process().await.unwrap();
+        let columns: Vec<&ColumnType> = list.columns.iter().map(|c| &c.obj).collect();
+        assert_eq!(columns, vec![&ColumnType::Item, &ColumnType::Property("P31".to_string())]);
+        assert!(list.results().iter().all(|row| row.cells().len() == 2));
+
+        let mut list = test_list_with_items(&params[..2], &entities, &["Q1", "Q2"]).await;
+        list.process().await.unwrap();
+        assert_eq!(list.columns.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_bidi_wrap() {
+        let entity = json!({"type": "item", "id": "Q1", "labels": {"he": {"language": "he", "value": "ירושלים"}},
+            "descriptions": {}, "aliases": {}, "claims": {}, "sitelinks": {}});
+        let mut list = test_list(&[], &[entity]).await;
+        list.process_template().await.unwrap();
+        let wb_api = list.wb_api.clone();
+        list.ecw.load_entities(wb_api.as_ref(), &["Q1".to_string()]).await.unwrap();
+
+        assert_eq!(list.bidi_wrap("שלום", "he"), "<bdi lang=\"he\" dir=\"rtl\">שלום</bdi>");
+        assert_eq!(list.bidi_wrap("hello", "en"), "hello");
+        assert_eq!(list.bidi_wrap("", "ar"), "");
+
+        // Without an English label, the Hebrew one is shown, and marked as such
+        assert_eq!(
+            list.get_label_and_language_with_fallback("Q1", None),
+            ("ירושלים".to_string(), Some("he".to_string()))
+        );
+        assert_eq!(list.get_label_and_language_with_fallback("Q2", None), ("Q2".to_string(), None));
+        assert_eq!(
+            ResultCellPart::Entity(("Q1".to_string(), true)).as_wikitext(&list, 0, 0, 0),
+            "''[[:d:Q1|<bdi lang=\"he\" dir=\"rtl\">ירושלים</bdi>]]''"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sitelink_wikitext() {
+        let mut alice = test_item("Q91", "Alice", &[]);
+        alice["sitelinks"] = json!({"enwiki": {"site": "enwiki", "title": "Alice Smith", "badges": []}});
+        let entities = [alice, test_item("Q92", "Bob", &[])];
+        let items = ["Q91", "Q92"];
+        let params = [("sparql", "SELECT ?item {}"), ("columns", "label")];
+        let mut list = test_list_with_items(&params, &entities, &items).await;
+        list.process().await.unwrap();
+        let wikitext = |list: &ListeriaList, id: &str| {
+            ResultCellPart::Entity((id.to_string(), true)).as_wikitext(list, 0, 0, 0)
+        };
+        assert_eq!(wikitext(&list, "Q91"), "[[Alice Smith|Alice]]");
+        assert_eq!(wikitext(&list, "Q92"), "''[[:d:Q92|Bob]]''");
+
+        // No local links at all with links=text
+        let params = [("sparql", "SELECT ?item {}"), ("columns", "label"), ("links", "text")];
+        let mut list = test_list_with_items(&params, &entities, &items).await;
+        list.process().await.unwrap();
+        assert_eq!(wikitext(&list, "Q91"), "Alice");
+    }
+
+    #[tokio::test]
+    async fn test_local_page_exists() {
+        let mut list = test_list(&[], &[]).await;
+        list.local_page_cache.insert("Water lily".to_string(), true);
+        list.local_page_cache.insert("Ant".to_string(), false);
+        assert!(list.local_page_exists("Water lily"));
+        assert!(list.local_page_exists("water_lily")); // enwiki only capitalizes the first letter
+        assert!(!list.local_page_exists("Water Lily"));
+        assert!(!list.local_page_exists("ant"));
+        assert!(!list.local_page_exists("Bee"));
+    }
+
+    #[tokio::test]
+    async fn test_math_and_score_wikitext() {
+        let list = test_list(&[], &[]).await;
+        let wikitext = |part: ResultCellPart| part.as_wikitext(&list, 0, 0, 0);
+        assert_eq!(wikitext(ResultCellPart::Math("E = mc^2".to_string())), "<math>E = mc^2</math>");
+        assert_eq!(
+            wikitext(ResultCellPart::MusicalNotation("\\relative c' { c d e }".to_string())),
+            "<score>\\relative c' { c d e }</score>"
+        );
+        assert_eq!(
+            wikitext(ResultCellPart::Math("x</math>[[Foo]]".to_string())),
+            "<math>x&lt;/math>[[Foo]]</math>"
+        );
+        assert_eq!(
+            wikitext(ResultCellPart::MusicalNotation("c</SCORE >{{Foo}}</math>".to_string())),
+            "<score>c&lt;/SCORE >{{Foo}}&lt;/math></score>"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_process_template_columns() {
+        let mut list = test_list(&[("columns", "label,P106[P31=Q5,P642=Q1]:Job,P569")], &[]).await;
+        list.process_template().await.unwrap();
+        let columns: Vec<&ColumnType> = list.columns.iter().map(|c| &c.obj).collect();
+        assert_eq!(
+            columns,
+            vec![
+                &ColumnType::Label,
+                &ColumnType::Property("P106".to_string()),
+                &ColumnType::Property("P569".to_string())
+            ]
+        );
+        assert_eq!(list.columns[1].label, "Job");
+        assert_eq!(list.columns[1].filters().len(), 2);
+    }
+
+    #[test]
+    fn test_add_label_service() {
+        let sparql = "SELECT ?item ?itemLabel { ?item wdt:P31 wd:Q5 } ORDER BY ?itemLabel LIMIT 5";
+        assert_eq!(
+            ListeriaList::add_label_service(sparql, "de,en").unwrap(),
+            "SELECT ?item ?itemLabel { ?item wdt:P31 wd:Q5\nSERVICE wikibase:label { bd:serviceParam wikibase:language \"de,en\" . }\n} ORDER BY ?itemLabel LIMIT 5"
+        );
+        assert!(ListeriaList::add_label_service("SELECT ?item ?itemLabel { ?item rdfs:label ?itemLabel }", "en").is_none());
+        assert!(ListeriaList::add_label_service("SELECT ?item ?itemLabel { ?item wdt:P31 wd:Q5 . SERVICE wikibase:label { bd:serviceParam wikibase:language \"en\" } }", "en").is_none());
+        assert!(ListeriaList::add_label_service("SELECT ?item { ?item wdt:P31 wd:Q5 }", "en").is_none());
+        assert!(ListeriaList::add_label_service("SELECT ?item (SAMPLE(?l) AS ?itemLabel) { ?item rdfs:label ?l } GROUP BY ?item", "en").is_none());
+
+        let service = "\nSERVICE wikibase:label { bd:serviceParam wikibase:language \"en\" . }\n";
+        let sparql = "SELECT ?item ?itemLabel { ?item wdt:P31 wd:Q5 } VALUES ?item { wd:Q1 wd:Q2 }";
+        assert_eq!(
+            ListeriaList::add_label_service(sparql, "en").unwrap(),
+            format!("SELECT ?item ?itemLabel {{ ?item wdt:P31 wd:Q5{service}}} VALUES ?item {{ wd:Q1 wd:Q2 }}")
+        );
+        let sparql = "SELECT ?item ?itemLabel WHERE { { SELECT ?item { ?item wdt:P31 wd:Q5 } LIMIT 10 } } ORDER BY ?itemLabel LIMIT 5";
+        assert_eq!(
+            ListeriaList::add_label_service(sparql, "en").unwrap(),
+            format!("SELECT ?item ?itemLabel WHERE {{ {{ SELECT ?item {{ ?item wdt:P31 wd:Q5 }} LIMIT 10 }}{service}}} ORDER BY ?itemLabel LIMIT 5")
+        );
+        let sparql = "SELECT ?item ?itemLabel { ?item rdfs:comment \"}\" } # wikibase:label }";
+        assert_eq!(
+            ListeriaList::add_label_service(sparql, "en").unwrap(),
+            format!("SELECT ?item ?itemLabel {{ ?item rdfs:comment \"}}\"{service}}} # wikibase:label }}")
+        );
+        // ?itemLabels does not bind ?itemLabel
+        assert!(ListeriaList::add_label_service("SELECT ?item ?itemLabel { ?item rdfs:label ?itemLabels }", "en").is_some());
+    }
+
+    #[test]
+    fn test_parse_thumbnail_size() {
+        assert_eq!(ListeriaList::parse_thumbnail_size("200"), Some(200));
+        assert_eq!(ListeriaList::parse_thumbnail_size(" 150 PX "), Some(150));
+        assert_eq!(ListeriaList::parse_thumbnail_size("5px"), Some(5));
+        assert_eq!(ListeriaList::parse_thumbnail_size("x100px"), None);
+        assert_eq!(ListeriaList::parse_thumbnail_size(""), None);
+    }
+
+    #[test]
+    fn test_add_sparql_prefixes() {
+        let prefixes = vec![
+            ("ex".to_string(), "http://example.org/".to_string()),
+            ("wd".to_string(), "http://www.wikidata.org/entity/".to_string()),
+        ];
+        let sparql = "prefix wd: <http://other.org/>\nSELECT ?item { ?item ex:p wd:Q1 }";
+        assert_eq!(
+            ListeriaList::add_sparql_prefixes(sparql, &prefixes),
+            format!("PREFIX ex: <http://example.org/>\n{sparql}")
+        );
+    }
+
+    #[test]
+    fn test_row_changes() {
+        let entity_ids: HashSet<String> = ["Q1", "Q2", "Q4"].iter().map(|s| s.to_string()).collect();
+        let old = "{|\n|-\n| [[:d:Q1|a]]\n|-\n| [[:d:Q2|b]] || 1\n|-\n| [[:d:Q3|c]]\n|}";
+        let new = "{|\n|-\n| [[:d:Q1|a]]\n|-\n| [[:d:Q2|b]] ||  2\n|-\n| [[:d:Q4|d]]\n|}";
+        let changes = ListeriaList::row_changes(old, new, &entity_ids);
+        assert_eq!(
+            changes,
+            RowChanges { added: 1, removed: 1, changed: 1, rows_before: 3, rows_after: 3 }
+        );
+        assert_eq!(changes.describe(), "+1 rows, \u{2212}1 rows, 1 changed");
+        assert!(ListeriaList::row_changes(old, old, &entity_ids).is_empty());
+    }
+
+    #[test]
+    fn test_previous_row_ids() {
+        let entity_ids: HashSet<String> = ["Q1", "Q2", "Q3"].iter().map(|s| s.to_string()).collect();
+        let wikitext = "{| class='wikitable'\n! name !! country\n|- class='wd_q3'\n| [[:d:Q3|c]] || [[:d:Q99|x]]\n|-\n| [[:d:Q99|x]] || [[:d:Q1|a]]\n|-\n| [[:d:Q7|gone]]\n|}";
+        assert_eq!(
+            ListeriaList::previous_row_ids(wikitext, &entity_ids),
+            vec!["Q3".to_string(), "Q1".to_string()]
+        );
+        let lines = "{{Row|item=Q2}}\n{{Row|item=Q1}}";
+        assert_eq!(
+            ListeriaList::previous_row_ids(lines, &entity_ids),
+            vec!["Q2".to_string(), "Q1".to_string()]
+        );
+    }
+}
@@ -1,9 +1,13 @@
-use crate::{*, listeria_bot::WikiPageResult};
-use futures::future::try_join_all;
+use crate::{*, api_client::ApiClient, render_map::RendererMap, cell_formatter::CellFormatter, row_filter::RowFilter, configuration::{ManualEditPolicy, TemplateNames}, wiki_login::WikiLogin};
+use futures::future::join_all;
 use std::collections::HashMap;
 use std::sync::Arc;
 use wikibase::mediawiki::api::Api;
 use anyhow::{Result,anyhow};
+use crate::listeria_list::RowChanges;
+
+/// Edit summary when no rows changed, and for data module pages
+const DEFAULT_EDIT_SUMMARY: &str = "Wikidata list updated [V2]";
 
 /* TODO
 - Sort by P/P, P/Q/P DOES NOT WORK IN LISTERIA-PHP
@@ -15,11 +19,79 @@ TEMPLATE PARAMETERS
 links IMPLEMENT fully?
 */
 
+#[derive(Debug, Clone)]
+pub struct WikiPageResult {
+    pub wiki: String,
+    pub page: String,
+    pub result: String,
+    pub message: String,
+}
+
+unsafe impl Send for WikiPageResult {}
+
+impl WikiPageResult {
+    pub fn new(wiki: &str, page: &str, result: &str, message: String) -> Self {
+        Self {
+            wiki: wiki.to_string(),
+            page: page.to_string(),
+            result: result.to_string(),
+            message,
+        }
+    }
+
+    pub fn fail(wiki: &str, page: &str, message: &str) -> Self {
+        Self::new(
+            wiki,
+            page,
+            "FAIL",
+            message.to_string()
+        )
+    }
+}
+
+/// What `update_source_page` did, for reporting
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct EditResult {
+    pub edited: bool,
+    pub revision_id: Option<u64>, // The new revision, if edited
+    pub bytes_changed: i64,       // Size difference of the page, if edited
+    pub rows_before: usize,
+    pub rows_after: usize,
+    pub skipped: Option<String>, // Why the page was not edited
+}
+
+impl EditResult {
+    fn skipped(reason: &str, changes: &RowChanges) -> Self {
+        Self {
+            rows_before: changes.rows_before,
+            rows_after: changes.rows_after,
+            skipped: Some(reason.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Like "edited (revision 123, +456 bytes, 10 → 12 rows)" or "not edited (no changes)"
+    pub fn describe(&self) -> String {
+        let rows = format!("{} \u{2192} {} rows", self.rows_before, self.rows_after);
+        match (&self.skipped, self.revision_id) {
+            (Some(reason), _) => format!("not edited ({reason})"),
+            (None, Some(revision_id)) => {
+                format!("edited (revision {revision_id}, {:+} bytes, {rows})", self.bytes_changed)
+            }
+            (None, None) => format!("edited ({:+} bytes, {rows})", self.bytes_changed),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ListeriaPage {
     page_params: Arc<PageParams>,
     data_has_changed: bool,
     elements: Vec<PageElement>,
+    start_template_names: Vec<String>,
+    end_template_names: Vec<String>,
+    cell_formatters: Vec<Arc<dyn CellFormatter>>,
+    row_filters: Vec<Arc<dyn RowFilter>>,
 }
 
 impl ListeriaPage {
@@ -29,18 +101,48 @@ impl ListeriaPage {
         page: String,
     ) -> Result<Self> {
         let page_params = PageParams::new(config, mw_api, page).await?;
-        let page_params = Arc::new(page_params);
-        Ok(Self {
-            page_params,
+        Ok(Self::new_from_params(page_params))
+    }
+
+    /// A page with parameters assembled elsewhere, e.g. by `PageParamsBuilder`
+    pub fn new_from_params(page_params: PageParams) -> Self {
+        Self {
+            page_params: Arc::new(page_params),
             data_has_changed: false,
             elements: vec![],
-        })
+            start_template_names: vec![],
+            end_template_names: vec![],
+            cell_formatters: vec![],
+            row_filters: vec![],
+        }
+    }
+
+    /// A page made from draft wikitext instead of the saved page, e.g. to preview an edit.
+    /// `run` parses the lists from `text`; `new_wikitext` gives the page as the bot would save it.
+    pub fn from_wikitext(text: &str, page_params: PageParams) -> Self {
+        let mut page_params = page_params;
+        page_params.simulated_text = Some(text.to_string());
+        Self::new_from_params(page_params)
     }
 
     pub fn config(&self) -> &Configuration {
         &self.page_params.config
     }
 
+    /// Custom cell rendering for all lists on the page; see `ListeriaList::add_cell_formatter`
+    pub fn add_cell_formatter(&mut self, formatter: Arc<dyn CellFormatter>) {
+        for element in &mut self.elements {
+            element.list_mut().add_cell_formatter(formatter.clone());
+        }
+        self.cell_formatters.push(formatter);
+    }
+
+    /// Row filter for all lists on the page; see `ListeriaList::add_row_filter`.
+    /// Needs to be added before `run`.
+    pub fn add_row_filter(&mut self, filter: Arc<dyn RowFilter>) {
+        self.row_filters.push(filter);
+    }
+
     pub fn wiki(&self) -> &String {
         &self.page_params.wiki
     }
@@ -64,6 +166,124 @@ impl ListeriaPage {
         }
     }
 
+    /// Replaces the live wiki for API calls, e.g. with recorded responses in tests
+    pub fn set_api_client(&mut self, api_client: Arc<dyn ApiClient>) -> Result<()> {
+        match Arc::get_mut(&mut self.page_params) {
+            Some(pp) => {
+                pp.api_client = api_client;
+                Ok(())
+            }
+            None => Err(anyhow!("Cannot set API client, page parameters are in use")),
+        }
+    }
+
+    /// Stops `run` and `update_source_page` once this much time has passed from now; the page is then left unchanged.
+    /// Lists check the time between steps, and unfinished queries and entity loading are dropped.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn set_timeout(&mut self, timeout: std::time::Duration) -> Result<()> {
+        match Arc::get_mut(&mut self.page_params) {
+            Some(pp) => {
+                pp.deadline = Some(tokio::time::Instant::now() + timeout);
+                Ok(())
+            }
+            None => Err(anyhow!("Cannot set timeout, page parameters are in use")),
+        }
+    }
+
+    fn timed_out(&self) -> WikiPageResult {
+        self.fail("Timed out; page not updated")
+    }
+
+    /// Names (without namespace) that the start template is used under on this wiki
+    pub fn start_template_names(&self) -> &Vec<String> {
+        &self.start_template_names
+    }
+
+    /// Names (without namespace) that the end template is used under on this wiki
+    pub fn end_template_names(&self) -> &Vec<String> {
+        &self.end_template_names
+    }
+
+    fn strip_namespace(title: &str) -> String {
+        match title.split_once(':') {
+            Some((_namespace, name)) => name.to_string(),
+            None => title.to_string(),
+        }
+    }
+
+    /// Collects the start/end template names: the sitelinked templates first, the English default,
+    /// configured aliases, and redirects to the sitelinked templates
+    async fn load_template_names(&mut self) {
+        let config = self.page_params.config.clone();
+        let wiki = &self.page_params.wiki;
+        let (local_start, local_end) = config.get_local_template_full_titles(wiki).await;
+        let mut start: Vec<String> = local_start.iter().map(|title| Self::strip_namespace(title)).collect();
+        let mut end: Vec<String> = local_end.iter().map(|title| Self::strip_namespace(title)).collect();
+        start.push("Wikidata list".to_string());
+        end.push("Wikidata list end".to_string());
+        let (alias_start, alias_end) = config.get_template_aliases(wiki);
+        start.extend(alias_start);
+        end.extend(alias_end);
+        if !self.page_params.simulate {
+            let (redirects_start, redirects_end) = self.load_template_redirects().await;
+            start.extend(redirects_start);
+            end.extend(redirects_end);
+        }
+        for names in [&mut start, &mut end] {
+            let mut seen = std::collections::HashSet::new();
+            names.retain(|name| seen.insert(name.replace('_', " ").to_lowercase()));
+        }
+        self.start_template_names = start;
+        self.end_template_names = end;
+    }
+
+    /// Redirects to the start and end templates; cached per wiki
+    async fn load_template_redirects(&self) -> TemplateNames {
+        let config = &self.page_params.config;
+        let wiki = &self.page_params.wiki;
+        if let Some(redirects) = config.cached_template_redirects(wiki) {
+            return redirects;
+        }
+        let (start, end) = match config.get_local_template_full_titles(wiki).await {
+            (Some(start), Some(end)) => (start, end),
+            _ => return (vec![], vec![]),
+        };
+        let titles = format!("{start}|{end}");
+        let params: HashMap<String, String> = [
+            ("action", "query"),
+            ("prop", "redirects"),
+            ("rdnamespace", "10"),
+            ("rdlimit", "max"),
+            ("titles", titles.as_str()),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let j = match self.page_params.api_client.get_query_api_json(&params).await {
+            Ok(j) => j,
+            Err(_) => return (vec![], vec![]), // Try again next time
+        };
+        let normalize = |t: &str| t.replace('_', " ");
+        let mut ret = (vec![], vec![]);
+        for page in j["query"]["pages"].as_object().into_iter().flat_map(|pages| pages.values()) {
+            let title = normalize(page["title"].as_str().unwrap_or_default());
+            let names: Vec<String> = page["redirects"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|r| r["title"].as_str())
+                .map(Self::strip_namespace)
+                .collect();
+            if title == normalize(&start) {
+                ret.0 = names;
+            } else if title == normalize(&end) {
+                ret.1 = names;
+            }
+        }
+        config.cache_template_redirects(wiki, ret.clone());
+        ret
+    }
+
     pub fn page_params(&self) -> Arc<PageParams> {
         self.page_params.clone()
     }
@@ -73,19 +293,17 @@ impl ListeriaPage {
     }
 
     pub async fn check_namespace(&self) -> Result<()> {
-        let api = self.page_params.mw_api.read().await;
-        let title = wikibase::mediawiki::title::Title::new_from_full(&self.page_params.page, &api);
-        drop(api);
+        let namespace_id = self.page_params.namespace_id(&self.page_params.page).await;
         if self
             .page_params
             .config
-            .can_edit_namespace(&self.page_params.wiki, title.namespace_id())
+            .can_edit_namespace(&self.page_params.wiki, namespace_id, self.page_params.site_info().map(|si| si.as_ref()))
         {
             Ok(())
         } else {
             Err(anyhow!(
                 "Namespace {} not allowed for edit on {}",
-                title.namespace_id(),
+                namespace_id,
                 &self.page_params.wiki
             ))
         }
@@ -93,16 +311,242 @@ impl ListeriaPage {
 
     pub async fn run(&mut self) -> Result<(),WikiPageResult> {
         self.check_namespace().await.map_err(|e| self.fail(&e.to_string()))?;
+        self.check_can_edit().await?;
+        self.load_template_names().await;
         self.elements = self.load_page().await?;
+        for element in &mut self.elements {
+            for formatter in &self.cell_formatters {
+                element.list_mut().add_cell_formatter(formatter.clone());
+            }
+            for filter in &self.row_filters {
+                element.list_mut().add_row_filter(filter.clone());
+            }
+        }
 
+        #[cfg(not(target_arch = "wasm32"))]
+        let deadline = self.page_params.deadline();
         let mut promises = Vec::new();
         for element in &mut self.elements {
             promises.push(element.process());
         }
-        try_join_all(promises).await.map_err(|e| self.fail(&e.to_string()))?;
+        #[cfg(target_arch = "wasm32")]
+        let results = join_all(promises).await;
+        #[cfg(not(target_arch = "wasm32"))]
+        let results = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, join_all(promises)).await {
+                Ok(results) => results,
+                Err(_) => return Err(self.timed_out()),
+            },
+            None => join_all(promises).await,
+        };
+        // Lists that stopped at the deadline are not shown as failed, the page is left as it is
+        if self.page_params.check_deadline().is_err() {
+            return Err(self.timed_out());
+        }
+
+        // A failed list shows its error in place, the other lists are still updated
+        for (element, result) in self.elements.iter_mut().zip(results) {
+            if let Err(e) = result {
+                element.set_error(e.to_string());
+            }
+        }
         Ok(())
     }
 
+    /// Errors of the lists that failed in `run`, as "List N: error"; the other lists are still updated
+    pub fn list_errors(&self) -> Vec<String> {
+        self.elements
+            .iter()
+            .filter(|element| !element.is_just_text())
+            .enumerate()
+            .filter_map(|(num, element)| Some(format!("List {}: {}", num + 1, element.error()?)))
+            .collect()
+    }
+
+    /// Warnings of the lists that were rendered, as "List N: warning"
+    pub fn list_warnings(&self) -> Vec<String> {
+        self.elements
+            .iter()
+            .filter(|element| !element.is_just_text())
+            .enumerate()
+            .filter_map(|(num, element)| {
+                Some(format!("List {}: {}", num + 1, element.list().duplicate_items_warning()?))
+            })
+            .collect()
+    }
+
+    /// How each list on the page was made, after `run`; see `ListeriaList::explain`
+    pub fn explain(&self) -> Value {
+        json!(self.elements.iter().filter_map(|element| element.explain()).collect::<Vec<Value>>())
+    }
+
+    /// Problems with the list definitions on the page, as "List N: problem"; renders and edits nothing
+    pub async fn lint(&mut self) -> Result<Vec<String>,WikiPageResult> {
+        self.load_template_names().await;
+        self.elements = self.load_page().await?;
+        let mut ret = vec![];
+        let lists = self.elements.iter_mut().filter(|element| !element.is_just_text());
+        for (num, element) in lists.enumerate() {
+            for problem in element.lint().await {
+                ret.push(format!("List {}: {problem}", num + 1));
+            }
+        }
+        if ret.is_empty() && self.elements.iter().all(|element| element.is_just_text()) {
+            ret.push("No list template on page".to_string());
+        }
+        Ok(ret)
+    }
+
+    /// Like `run`, but the lists on this page are rendered from `source`, a list on another wiki.
+    /// The list templates on this page only mark where the list goes.
+    pub async fn run_as_target(&mut self, source: &ListeriaList) -> Result<(),WikiPageResult> {
+        self.check_namespace().await.map_err(|e| self.fail(&e.to_string()))?;
+        self.check_can_edit().await?;
+        self.load_template_names().await;
+        self.elements = self.load_page().await?;
+        if self.elements.iter().all(|element| element.is_just_text()) {
+            return Err(self.fail("No list template on target page"));
+        }
+        // The target has to opt in, with source= on each list template; otherwise it is left alone
+        let (wiki, page) = (source.wiki(), source.page_title());
+        let not_opted_in = self
+            .elements
+            .iter()
+            .any(|element| !element.is_just_text() && !element.list().template().names_source(wiki, page));
+        if not_opted_in {
+            return Err(self.fail(&format!("List template on target page does not have source={wiki}:{page}")));
+        }
+        let page_params = self.page_params();
+        for element in &mut self.elements {
+            if let Err(e) = element.process_as_target(source, page_params.clone()).await {
+                element.set_error(e.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists on this page that are also rendered to other wikis
+    pub fn propagated_lists(&self) -> Vec<&ListeriaList> {
+        self.elements
+            .iter()
+            .filter(|element| !element.is_just_text() && element.error().is_none())
+            .map(|element| element.list())
+            .filter(|list| !list.targets().is_empty())
+            .collect()
+    }
+
+    /// Asks the wiki if the bot account may edit the page, so protected pages are skipped before any work is done
+    async fn check_can_edit(&self) -> Result<(),WikiPageResult> {
+        let can_edit = self
+            .can_edit_title(&self.page_params.page)
+            .await
+            .map_err(|e| self.fail(&e.to_string()))?;
+        match can_edit {
+            true => Ok(()),
+            false => Err(self.protected("Page is protected against edits by the bot").await),
+        }
+    }
+
+    /// Whether the bot account may edit a page on this wiki; true if the wiki does not say
+    async fn can_edit_title(&self, title: &str) -> Result<bool> {
+        if self.page_params.simulate {
+            return Ok(true);
+        }
+        let params: HashMap<String, String> = [
+            ("action", "query"),
+            ("prop", "info"),
+            ("intestactions", "edit"),
+            ("titles", title),
+        ]
+        .iter()
+        .map(|x| (x.0.to_string(), x.1.to_string()))
+        .collect();
+        let j = self.page_params.api_client.get_query_api_json(&params).await?;
+        let page = match j["query"]["pages"].as_object().and_then(|pages| pages.values().next()) {
+            Some(page) => page,
+            None => return Ok(true), // Let the usual page loading deal with it
+        };
+        let actions = &page["actions"];
+        // formatversion=1 has an empty string for "allowed", and omits the key otherwise
+        Ok(!actions.is_object() || actions["edit"].as_bool().unwrap_or(!actions["edit"].is_null()))
+    }
+
+    async fn protected(&self, message: &str) -> WikiPageResult {
+        if self.page_params.config.protected_page_talk_note() {
+            if let Err(e) = self.leave_protected_talk_note().await {
+                eprintln!("Could not leave note on talk page of [[{}]] on {}: {e}", &self.page_params.page, &self.page_params.wiki);
+            }
+        }
+        WikiPageResult::new(
+            &self.page_params.wiki,
+            &self.page_params.page,
+            "PROTECTED",
+            message.to_string(),
+        )
+    }
+
+    async fn leave_protected_talk_note(&self) -> Result<()> {
+        let section_title = "Listeria can not update this page";
+        let talk_page = {
+            let mw_api = self.page_params.mw_api.as_ref().ok_or_else(|| anyhow!("No wiki API"))?;
+            let api = mw_api.read().await;
+            let mut title = wikibase::mediawiki::title::Title::new_from_full(&self.page_params.page, &api);
+            title.toggle_talk();
+            title
+                .full_pretty(&api)
+                .ok_or_else(|| anyhow!("Can not determine talk page"))?
+        };
+        let api = &self.page_params.api_client;
+
+        // Only leave one note
+        let params: HashMap<String, String> = [
+            ("action", "query"),
+            ("prop", "revisions"),
+            ("rvprop", "content"),
+            ("rvslots", "main"),
+            ("titles", talk_page.as_str()),
+        ]
+        .iter()
+        .map(|x| (x.0.to_string(), x.1.to_string()))
+        .collect();
+        let j = api.get_query_api_json(&params).await?;
+        let has_note = j["query"]["pages"]
+            .as_object()
+            .map(|pages| {
+                pages.values().any(|page| {
+                    page["revisions"][0]["slots"]["main"]["*"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .contains(section_title)
+                })
+            })
+            .unwrap_or(false);
+        if has_note {
+            return Ok(());
+        }
+
+        let text = "The Wikidata list on this page could not be updated by the bot, because the page is protected. ~~~~";
+        let token = api.get_edit_token().await?;
+        let params: HashMap<String, String> = vec![
+            ("action", "edit"),
+            ("title", talk_page.as_str()),
+            ("section", "new"),
+            ("sectiontitle", section_title),
+            ("text", text),
+            ("summary", section_title),
+            ("token", &token),
+            ("bot", "1"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let j = api.post_query_api_json(&params).await?;
+        match j["error"]["info"].as_str() {
+            Some(msg) => Err(anyhow!("{msg}")),
+            None => Ok(()),
+        }
+    }
+
     async fn load_page(&mut self) -> Result<Vec<PageElement>,WikiPageResult> {
         let mut text = self.load_page_as("wikitext").await?;
         let mut ret = vec![];
@@ -132,6 +576,12 @@ impl ListeriaPage {
     }
 
     pub async fn load_page_as(&self, mode: &str) -> Result<String,WikiPageResult> {
+        if mode == "wikitext" {
+            // Parsing as wikitext gives back the text unchanged, no need to ask the wiki
+            if let Some(text) = &self.page_params.simulated_text {
+                return Ok(text.to_owned());
+            }
+        }
         let mut params: HashMap<String, String> = vec![("action", "parse"), ("prop", mode)]
             .iter()
             .map(|x| (x.0.to_string(), x.1.to_string()))
@@ -148,9 +598,7 @@ impl ListeriaPage {
         }
         let result = self
             .page_params
-            .mw_api
-            .read()
-            .await
+            .api_client
             .post_query_api_json(&params)
             .await
             .map_err(|e| self.fail(&e.to_string()))?;
@@ -198,61 +646,276 @@ impl ListeriaPage {
         Ok(ret)
     }
 
+    /// The whole page with all lists rendered
+    pub fn new_wikitext(&self) -> Result<String> {
+        let renderer = RendererWikitext::new();
+        Ok(renderer.get_new_wikitext("", self)?.unwrap_or_default())
+    }
+
     pub fn elements(&self) -> &Vec<PageElement> {
         &self.elements
     }
 
-    async fn save_wikitext_to_page(&self, title: &str, wikitext: &str) -> Result<()> {
-        let mut api = self.page_params.mw_api.write().await;
-        let token = api.get_edit_token().await?;
-        let params: HashMap<String, String> = vec![
-            ("action", "edit"),
-            ("title", title),
-            ("text", wikitext),
-            ("summary", "Wikidata list updated [V2]"),
-            ("token", &token),
-            ("bot","1"),
-        ]
-        .into_iter()
-        .map(|(k, v)| (k.to_string(), v.to_string()))
-        .collect();
-        let j = api
-            .post_query_api_json(&params)
-            .await?;
-        match j["error"].as_object() {
-            Some(o) => {
-                let msg = o["info"].as_str().unwrap_or("Error while saving");
-                Err(anyhow!("{msg}"))
+    /// False if the lists only changed in whitespace, or in row order where that is arbitrary
+    pub fn has_meaningful_changes(&self) -> Result<bool> {
+        for element in &self.elements {
+            if element.has_meaningful_change()? {
+                return Ok(true);
             }
-            None => Ok(()),
         }
+        Ok(false)
+    }
+
+    /// A warning if lists were edited by hand since the bot last wrote them
+    pub fn manual_edit_warning(&self) -> Option<String> {
+        let edited = self.elements.iter().filter(|e| e.is_manually_edited()).count();
+        if edited == 0 {
+            return None;
+        }
+        match self.page_params.config.manual_edit_policy() {
+            ManualEditPolicy::Ignore => None,
+            ManualEditPolicy::Overwrite => Some(format!("{edited} manually edited list(s) overwritten")),
+            ManualEditPolicy::Skip => Some(format!("{edited} manually edited list(s) left unchanged")),
+        }
+    }
+
+    /// Wikidata items shown in any list on this page
+    pub fn item_ids(&self) -> Vec<String> {
+        let mut ret: Vec<String> = self.elements.iter().flat_map(|e| e.item_ids()).collect();
+        ret.sort();
+        ret.dedup();
+        ret
+    }
+
+    /// Edit summary for the page, with the rows that changed, eg "Listeria update: +12 rows, −3 rows, 5 changed"
+    pub fn edit_summary(&self) -> String {
+        let changes = self.row_changes();
+        match changes.is_empty() {
+            true => DEFAULT_EDIT_SUMMARY.to_string(),
+            false => format!("Listeria update: {}", changes.describe()),
+        }
+    }
+
+    /// Row changes of all lists on the page, against their previous rendering
+    pub fn row_changes(&self) -> RowChanges {
+        let mut changes = RowChanges::default();
+        for element in &self.elements {
+            if let Ok(element_changes) = element.row_changes() {
+                changes.add(&element_changes);
+            }
+        }
+        changes
+    }
+
+    /// Saves the page; returns the new revision ID, if the API reports one
+    async fn save_wikitext_to_page(&self, title: &str, wikitext: &str, summary: &str) -> Result<Option<u64>,WikiPageResult> {
+        let wiki_login = self.page_params.config.wiki_login();
+        let mut oauth2_token = wiki_login.token();
+        // Without a wiki API, logging in is up to the API client
+        let mw_api = self.page_params.mw_api.as_ref();
+        if let Some(mw_api) = mw_api {
+            if wiki_login.is_expired() && wiki_login.can_refresh() {
+                let api = mw_api.read().await;
+                oauth2_token = wiki_login
+                    .refresh(&oauth2_token, &api)
+                    .await
+                    .map_err(|e| self.fail(&e.to_string()))?;
+            }
+            if !oauth2_token.is_empty() {
+                mw_api.write().await.set_oauth2(&oauth2_token);
+            }
+        }
+        let api = &self.page_params.api_client;
+        let mut token_refreshed = false;
+        loop {
+            let token = api.get_edit_token().await.map_err(|e| self.fail(&e.to_string()))?;
+            let params: HashMap<String, String> = vec![
+                ("action", "edit"),
+                ("title", title),
+                ("text", wikitext),
+                ("summary", summary),
+                ("token", &token),
+                ("bot","1"),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+            let j = api
+                .post_query_api_json(&params)
+                .await
+                .map_err(|e| self.fail(&e.to_string()))?;
+            let o = match j["error"].as_object() {
+                Some(o) => o,
+                None => return Ok(j["edit"]["newrevid"].as_u64()),
+            };
+            let code = o["code"].as_str().unwrap_or_default();
+            let refresh_api = mw_api.filter(|_| !token_refreshed && WikiLogin::is_token_error(code) && wiki_login.can_refresh());
+            if let Some(mw_api) = refresh_api {
+                // Access token has expired mid-run; renew it and try once more
+                let mut mw_api = mw_api.write().await;
+                oauth2_token = wiki_login
+                    .refresh(&oauth2_token, &mw_api)
+                    .await
+                    .map_err(|e| self.fail(&e.to_string()))?;
+                mw_api.set_oauth2(&oauth2_token);
+                token_refreshed = true;
+                continue;
+            }
+            let msg = o["info"].as_str().unwrap_or("Error while saving");
+            if Self::is_protection_error(code) {
+                return Err(self.protected(msg).await);
+            }
+            if Self::is_block_error(code) {
+                let reason = o["data"]["blockinfo"]["blockreason"]
+                    .as_str()
+                    .or_else(|| o["blockinfo"]["blockreason"].as_str())
+                    .unwrap_or(msg);
+                return Err(WikiPageResult::new(
+                    &self.page_params.wiki,
+                    &self.page_params.page,
+                    "BLOCKED",
+                    reason.to_string(),
+                ));
+            }
+            return Err(self.fail(msg));
+        }
+    }
+
+    fn is_protection_error(code: &str) -> bool {
+        matches!(
+            code,
+            "protectedpage" | "cascadeprotected" | "protectednamespace" | "protectednamespace-interface"
+        )
+    }
+
+    /// API error codes that mean the bot account can not edit on this wiki at all
+    fn is_block_error(code: &str) -> bool {
+        matches!(
+            code,
+            "blocked" | "autoblocked" | "globalblocking-blocked" | "globalblocking-blocked-iprange"
+        )
+    }
+
+    /// Saves the Lua data modules of lists with the data_module parameter.
+    /// Unchanged modules make no new revision; a list whose module the bot may not edit shows an error instead.
+    async fn update_data_modules(&mut self) -> Result<(),WikiPageResult> {
+        let mut modules = vec![];
+        for (num, element) in self.elements.iter().enumerate() {
+            if let Some((title, lua)) = element.data_module().map_err(|e| self.fail(&e.to_string()))? {
+                modules.push((num, title, lua));
+            }
+        }
+        for (num, title, lua) in modules {
+            let site_info = self.page_params.site_info().map(|si| si.as_ref());
+            let allowed = match self.page_params.config.check_data_module_title(&self.page_params.wiki, &title, site_info) {
+                Ok(()) => self.can_edit_title(&title).await.map_err(|e| self.fail(&e.to_string()))?,
+                Err(_) => false,
+            };
+            match allowed {
+                true => {
+                    self.save_wikitext_to_page(&title, &lua, DEFAULT_EDIT_SUMMARY).await?;
+                }
+                false => self.elements[num].set_error(format!("The bot may not edit data_module '{title}'")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Saves the Commons map pages of lists with the map parameter
+    async fn update_map_pages(&self) -> Result<(),WikiPageResult> {
+        let lists: Vec<&ListeriaList> = self
+            .elements
+            .iter()
+            .filter(|element| !element.is_just_text() && element.error().is_none())
+            .map(|element| element.list())
+            .filter(|list| *list.map_mode() != MapMode::None)
+            .collect();
+        if lists.is_empty() {
+            return Ok(());
+        }
+        let commons_client = self
+            .page_params
+            .config
+            .get_wbapi("commons")
+            .ok_or_else(|| self.fail("No Commons API configured for map pages"))?;
+        for list in lists {
+            RendererMap::new()
+                .write_map_data(list, commons_client.as_ref())
+                .await
+                .map_err(|e| self.fail(&e.to_string()))?;
+        }
+        Ok(())
     }
 
-    pub async fn update_source_page(&mut self) -> Result<bool,WikiPageResult> {
+    pub async fn update_source_page(&mut self) -> Result<EditResult,WikiPageResult> {
+        if self.page_params.check_deadline().is_err() {
+            return Err(self.timed_out());
+        }
+        self.update_data_modules().await?;
+        self.update_map_pages().await?;
         let renderer = RendererWikitext::new();
-        let mut edited = false;
+        let changes = self.row_changes();
         let old_wikitext = self.load_page_as("wikitext").await?;
         let new_wikitext = renderer.get_new_wikitext(&old_wikitext, self).map_err(|e| self.fail(&e.to_string()))?; // Safe
         match new_wikitext {
             Some(new_wikitext) => {
-                if old_wikitext != new_wikitext {
-                    self.save_wikitext_to_page(&self.page_params.page, &new_wikitext)
-                        .await
-                        .map_err(|e| self.fail(&e.to_string()))?;
-                    edited = true;
+                if old_wikitext == new_wikitext {
+                    return Ok(EditResult::skipped("no changes", &changes));
                 }
+                if !self.has_meaningful_changes().map_err(|e| self.fail(&e.to_string()))? {
+                    return Ok(EditResult::skipped("no meaningful changes", &changes));
+                }
+                let revision_id = self
+                    .save_wikitext_to_page(&self.page_params.page, &new_wikitext, &self.edit_summary())
+                    .await?;
+                Ok(EditResult {
+                    edited: true,
+                    revision_id,
+                    bytes_changed: new_wikitext.len() as i64 - old_wikitext.len() as i64,
+                    rows_before: changes.rows_before,
+                    rows_after: changes.rows_after,
+                    skipped: None,
+                })
             }
             None => {
                 if self.data_has_changed {
                     self.purge_page().await.map_err(|e| self.fail(&e.to_string()))?;
+                    return Ok(EditResult::skipped("no changes, purged", &changes));
                 }
+                Ok(EditResult::skipped("no changes", &changes))
             }
         }
+    }
 
-        Ok(edited)
+    /// Saves the page without changes, so MediaWiki re-renders it (eg after template changes)
+    pub async fn null_edit(&self) -> Result<()> {
+        if self.page_params.simulate {
+            println!(
+                "SIMULATING: null edit on [[{}]] on {}",
+                &self.page_params.page, self.page_params.wiki
+            );
+            return Ok(());
+        }
+        let api = &self.page_params.api_client;
+        let token = api.get_edit_token().await?;
+        let params: HashMap<String, String> = [
+            ("action", "edit"),
+            ("title", self.page_params.page.as_str()),
+            ("appendtext", ""),
+            ("nocreate", "1"),
+            ("token", token.as_str()),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let j = api.post_query_api_json(&params).await?;
+        match j["error"]["info"].as_str() {
+            Some(msg) => Err(anyhow!("{msg}")),
+            None => Ok(()),
+        }
     }
 
-    async fn purge_page(&self) -> Result<()> {
+    pub async fn purge_page(&self) -> Result<()> {
         if self.page_params.simulate {
             println!(
                 "SIMULATING: purging [[{}]] on {}",
@@ -268,21 +931,19 @@ impl ListeriaPage {
         .map(|x| (x.0.to_string(), x.1.to_string()))
         .collect();
 
-        let _ = self
-            .page_params
-            .mw_api
-            .write()
-            .await
-            .get_query_api_json(&params)
-            .await?;
+        let _ = self.page_params.api_client.get_query_api_json(&params).await?;
         Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::listeria_page::ListeriaPage;
+    use crate::api_client::MockApiClient;
+    use crate::client_manager::ClientManager;
+    use crate::listeria_list::RowChanges;
+    use crate::listeria_page::{EditResult, ListeriaPage};
     use crate::render_wikitext::RendererWikitext;
+    use crate::wiki_login::WikiLogin;
     use crate::*;
     use std::collections::HashMap;
     use std::fs;
@@ -316,32 +977,75 @@ mod tests {
         data
     }
 
-    async fn check_fixture_file(path: PathBuf) {
-        let data = read_fixture_from_file(path.clone());
-        let mw_api = wikibase::mediawiki::api::Api::new(&data["API"])
+    /// Wiki and language of a fixture, from the API URL of its wiki
+    fn fixture_wiki(api_url: &str) -> (String, String) {
+        let host = api_url.split('/').nth(2).unwrap();
+        match host.split_once('.').unwrap() {
+            ("commons", "wikimedia.org") => ("commonswiki".to_string(), "en".to_string()),
+            ("www", "wikidata.org") => ("wikidatawiki".to_string(), "en".to_string()),
+            (language, "wikipedia.org") => (format!("{language}wiki"), language.to_string()),
+            _ => panic!("Unknown fixture wiki {host}"),
+        }
+    }
+
+    /// The config file template, and mock wikibases with the entities and queries of a fixture; nothing is loaded from live wikis
+    async fn fixture_config(data: &HashMap<String, String>, mut j: Value) -> Arc<Configuration> {
+        j["namespace_blocks"] = json!({}); // Allow all namespaces, everywhere
+        let client_manager = ClientManager::new(&j["http"], WikiLogin::default());
+        let apis: Vec<String> = j["apis"]
+            .as_object()
+            .unwrap()
+            .values()
+            .map(|v| v["api"].as_str().unwrap().to_string())
+            .collect();
+        for api_url in apis {
+            let mut mock = MockApiClient::default();
+            mock.add_entities_from_lines(&fs::read_to_string("test_data/common.entities").unwrap()).unwrap();
+            if let Some(lines) = data.get("MOCK_ENTITIES") {
+                mock.add_entities_from_lines(lines).unwrap();
+            }
+            if let Some(lines) = data.get("MOCK_SPARQL") {
+                mock.add_sparql_results_from_lines(lines).unwrap();
+            }
+            client_manager.register_api_client(&api_url, Arc::new(mock));
+        }
+        let config = Configuration::new_from_json_with_client_manager(j, client_manager).await.unwrap();
+        Arc::new(config)
+    }
+
+    /// A page of a fixture, with API calls answered from its MOCK_API section and the site info of its wiki
+    async fn fixture_page(data: &HashMap<String, String>, config: Arc<Configuration>) -> ListeriaPage {
+        let (wiki, language) = fixture_wiki(&data["API"]);
+        let site_info: Value = serde_json::from_str(&fs::read_to_string("test_data/siteinfo.json").unwrap()).unwrap();
+        let mut mock = MockApiClient::new_from_lines(data.get("MOCK_API").map(|s| s.as_str()).unwrap_or_default()).unwrap();
+        mock.add_response(&[("meta", "siteinfo")], json!({"query": site_info[&wiki]}));
+        let page_params = PageParamsBuilder::new(config, &data["PAGETITLE"])
+            .wiki(&wiki)
+            .language(&language)
+            .api_client(Arc::new(mock))
+            .build()
             .await
             .unwrap();
-        let mw_api = Arc::new(RwLock::new(mw_api));
+        ListeriaPage::new_from_params(page_params)
+    }
 
-        let file = std::fs::File::open("config.json").unwrap();
-        let reader = BufReader::new(file);
-        let mut j: Value = serde_json::from_reader(reader).unwrap();
-        j["namespace_blocks"] = json!({}); // Allow all namespaces, everywhere
+    fn config_template() -> Value {
+        serde_json::from_str(&fs::read_to_string("config.json.template").unwrap()).unwrap()
+    }
+
+    async fn check_fixture_file(path: PathBuf) {
+        let data = read_fixture_from_file(path.clone());
+        let mut j = config_template();
         if path.to_str().unwrap() == "test_data/shadow_images.fixture" {
             // HACKISH
             j["prefer_preferred"] = json!(false);
         }
-        let mut config = Configuration::new_from_json(j).await.unwrap();
-        if path.to_str().unwrap() == "test_data/commons_sparql.fixture" {
-            // HACKISH
-            let _ = config.wbapi_login("commons").await;
-            // println!("LOGIN TO COMMONS: {}", result);
+        if path.to_str().unwrap() == "test_data/keep_row_order.fixture" {
+            // The page has the previous revision of the list
+            j["keep_row_order"] = json!(true);
         }
-        let config = Arc::new(config);
-
-        let mut page = ListeriaPage::new(config, mw_api, data["PAGETITLE"].clone())
-            .await
-            .unwrap();
+        let config = fixture_config(&data, j).await;
+        let mut page = fixture_page(&data, config).await;
         page.do_simulate(
             data.get("WIKITEXT").map(|s| s.to_string()),
             data.get("SPARQL_RESULTS").map(|s| s.to_string()),
@@ -358,6 +1062,35 @@ mod tests {
         if data.contains_key("EXPECTED_PART") {
             assert!(wt.contains(&data["EXPECTED_PART"]));
         }
+        if data.contains_key("EXPECTED_PAGE") {
+            let page_wt = page.new_wikitext().unwrap();
+            assert_eq!(page_wt.trim(), data["EXPECTED_PAGE"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn timings_comment() {
+        let data = read_fixture_from_file(PathBuf::from("test_data/qid.fixture"));
+        let config = fixture_config(&data, config_template()).await;
+        let mut page = fixture_page(&data, config).await;
+        let wikitext = data["WIKITEXT"].replace("|links=all", "|links=all\n|timings=yes");
+        page.do_simulate(Some(wikitext), data.get("SPARQL_RESULTS").map(|s| s.to_string()), None);
+        page.run().await.unwrap();
+        let wt = page.new_wikitext().unwrap();
+        let re = regex::Regex::new(r"\|\}\n<!-- Listeria timings: sparql \d+ ms, entities \d+ ms, results \d+ ms, render \d+ ms -->\n").unwrap();
+        assert!(re.is_match(&wt), "{wt}");
+    }
+
+    #[test]
+    fn edit_result_describe() {
+        let changes = RowChanges { rows_before: 10, rows_after: 12, ..Default::default() };
+        let mut edit_result = EditResult::skipped("no changes", &changes);
+        assert_eq!(edit_result.describe(), "not edited (no changes)");
+        edit_result.skipped = None;
+        edit_result.edited = true;
+        edit_result.revision_id = Some(123);
+        edit_result.bytes_changed = 456;
+        assert_eq!(edit_result.describe(), "edited (revision 123, +456 bytes, 10 \u{2192} 12 rows)");
     }
 
     #[tokio::test]
@@ -565,21 +1298,36 @@ mod tests {
         check_fixture_file(PathBuf::from("test_data/image_pipe.fixture")).await;
     }
 
+    #[tokio::test]
+    async fn stray_end_templates() {
+        check_fixture_file(PathBuf::from("test_data/stray_end_templates.fixture")).await;
+    }
+
+    #[tokio::test]
+    async fn unpaired_start_template() {
+        check_fixture_file(PathBuf::from("test_data/unpaired_start_template.fixture")).await;
+    }
+
+    #[tokio::test]
+    async fn keep_row_order() {
+        check_fixture_file(PathBuf::from("test_data/keep_row_order.fixture")).await;
+    }
+
+    #[tokio::test]
+    async fn property_fallback() {
+        check_fixture_file(PathBuf::from("test_data/property_fallback.fixture")).await;
+    }
+
+    #[tokio::test]
+    async fn missing_end_template() {
+        check_fixture_file(PathBuf::from("test_data/missing_end_template.fixture")).await;
+    }
+
     #[tokio::test]
     async fn edit_wikitext() {
         let data = read_fixture_from_file(PathBuf::from("test_data/edit_wikitext.fixture"));
-        let mw_api = wikibase::mediawiki::api::Api::new("https://en.wikipedia.org/w/api.php")
-            .await
-            .unwrap();
-        let mw_api = Arc::new(RwLock::new(mw_api));
-        let config = Arc::new(Configuration::new_from_file("config.json").await.unwrap());
-        let mut page = ListeriaPage::new(
-            config,
-            mw_api,
-            "User:Magnus Manske/listeria test5".to_string(),
-        )
-        .await
-        .unwrap();
+        let config = fixture_config(&data, config_template()).await;
+        let mut page = fixture_page(&data, config).await;
         page.do_simulate(
             data.get("WIKITEXT").map(|s| s.to_string()),
             data.get("SPARQL_RESULTS").map(|s| s.to_string()),
@@ -0,0 +1,322 @@
+use crate::configuration::Configuration;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use mysql_async as my;
+use mysql_async::from_row;
+use mysql_async::prelude::*;
+
+/// Pages claimed by a worker for longer than this are considered abandoned
+const DEFAULT_STALE_AFTER_MINUTES: u64 = 120;
+
+/// Failed pages are retried after 2^failures hours, but at least once a week
+const MAX_FAILURE_BACKOFF_HOURS: u64 = 168;
+
+/// Pages (by timestamp) to pick a random page from, so concurrent workers rarely collide
+const CANDIDATE_POOL_SIZE: usize = 1000;
+
+/// Picks a new candidate if another worker claimed the page first
+const MAX_CLAIM_ATTEMPTS: usize = 5;
+
+/// Rows per INSERT when storing the items of a page
+const ITEMS_PER_INSERT: usize = 500;
+
+#[derive(Debug, Clone, Default)]
+pub struct PageToProcess {
+    pub id: u64,
+    pub title: String,
+    pub status: String,
+    pub wiki: String,
+}
+
+impl PageToProcess {
+    pub fn from_parts(parts: (u64,String,String,String)) -> Self {
+        Self {
+            id: parts.0,
+            title: parts.1,
+            status: parts.2,
+            wiki: parts.3,
+        }
+    }
+
+    pub fn from_row(row: mysql_async::Row) -> Self {
+        let parts = from_row::<(u64, String, String, String)>(row);
+        Self::from_parts(parts)
+    }
+}
+
+/// The page queue in ToolsDB, shared by all bot processes.
+/// `pagestatus` holds the pages and their last status; `page_queue` adds per-page
+/// priorities, failure counts, last-run timestamps, and which worker currently has the page.
+#[derive(Debug, Clone)]
+pub struct PageQueue {
+    pool: my::Pool,
+    worker_id: String,
+    stale_after_minutes: u64,
+}
+
+impl PageQueue {
+    pub fn new_from_config(config: &Configuration) -> Result<Self> {
+        let host = config
+            .mysql("host")
+            .as_str()
+            .ok_or(anyhow!("No host in config"))?
+            .to_string();
+        let schema = config
+            .mysql("schema")
+            .as_str()
+            .ok_or(anyhow!("No schema in config"))?
+            .to_string();
+        let port = config.mysql("port").as_u64().ok_or(anyhow!("No port in config"))? as u16;
+        let user = config
+            .mysql("user")
+            .as_str()
+            .ok_or(anyhow!("No user in config"))?
+            .to_string();
+        let password = config
+            .mysql("password")
+            .as_str()
+            .ok_or(anyhow!("No password in config"))?
+            .to_string();
+
+        let opts = my::OptsBuilder::default()
+            .ip_or_hostname(host.to_owned())
+            .db_name(Some(schema))
+            .user(Some(user))
+            .pass(Some(password))
+            .tcp_port(port);
+
+        let worker_id = match config.job_queue("worker_id").as_str() {
+            Some(id) => id.to_string(),
+            None => Self::default_worker_id(),
+        };
+        let stale_after_minutes = config
+            .job_queue("stale_after_minutes")
+            .as_u64()
+            .unwrap_or(DEFAULT_STALE_AFTER_MINUTES);
+
+        Ok(Self {
+            pool: my::Pool::new(opts),
+            worker_id,
+            stale_after_minutes,
+        })
+    }
+
+    fn default_worker_id() -> String {
+        let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "listeria".to_string());
+        format!("{host}-{}", std::process::id())
+    }
+
+    pub fn worker_id(&self) -> &str {
+        &self.worker_id
+    }
+
+    fn timestamp() -> String {
+        let now: DateTime<Utc> = Utc::now();
+        now.format("%Y%m%d%H%M%S").to_string()
+    }
+
+    /// Creates the `page_queue` table if it does not exist yet
+    pub async fn ensure_schema(&self) -> Result<()> {
+        let sql = r#"CREATE TABLE IF NOT EXISTS `page_queue` (
+            `pagestatus_id` INT UNSIGNED NOT NULL PRIMARY KEY,
+            `priority` INT NOT NULL DEFAULT 0,
+            `failures` INT UNSIGNED NOT NULL DEFAULT 0,
+            `last_run` DATETIME NULL,
+            `worker` VARCHAR(64) NULL,
+            `claimed` DATETIME NULL,
+            KEY `priority` (`priority`),
+            KEY `worker` (`worker`)
+            )"#;
+        self.pool.get_conn().await?.query_drop(sql).await?;
+        let sql = r#"CREATE TABLE IF NOT EXISTS `page_items` (
+            `pagestatus_id` INT UNSIGNED NOT NULL,
+            `item` VARCHAR(32) NOT NULL,
+            PRIMARY KEY (`pagestatus_id`,`item`),
+            KEY `item` (`item`)
+            )"#;
+        self.pool.get_conn().await?.query_drop(sql).await?;
+        Ok(())
+    }
+
+    /// Releases pages left RUNNING by this worker, or abandoned by any worker
+    pub async fn reset_running(&self) -> Result<()> {
+        let sql = r#"UPDATE `pagestatus`,`page_queue`
+            SET `pagestatus`.`status`='OK',`page_queue`.`worker`=NULL,`page_queue`.`claimed`=NULL
+            WHERE `pagestatus`.`id`=`page_queue`.`pagestatus_id` AND `pagestatus`.`status`='RUNNING'
+            AND (`page_queue`.`worker`=:worker OR `page_queue`.`claimed` < NOW() - INTERVAL :minutes MINUTE)"#;
+        let params = params! {
+            "worker" => &self.worker_id,
+            "minutes" => self.stale_after_minutes,
+        };
+        self.pool.get_conn().await?.exec_drop(sql, params).await?;
+
+        // Pages that were RUNNING before the queue table existed
+        let sql = r#"UPDATE `pagestatus` SET `status`='OK' WHERE `status`='RUNNING'
+            AND NOT EXISTS (SELECT * FROM `page_queue` WHERE `pagestatus_id`=`pagestatus`.`id` AND `worker` IS NOT NULL)"#;
+        self.pool.get_conn().await?.exec_drop(sql, ()).await?;
+        Ok(())
+    }
+
+    /// Claims the next page for this worker.
+    /// Higher priorities go first, then the least recently updated pages; failing pages back off exponentially.
+    pub async fn next_page(&self, excluded_wikis: &[String]) -> Result<PageToProcess> {
+        let not_excluded = if excluded_wikis.is_empty() {
+            String::new()
+        } else {
+            let placeholders = vec!["?"; excluded_wikis.len()].join(",");
+            format!("AND wikis.name NOT IN ({placeholders})")
+        };
+        let sql = format!(r#"SELECT id,page,status,wiki FROM (
+            SELECT pagestatus.id,pagestatus.page,pagestatus.status,wikis.name AS wiki,IFNULL(page_queue.priority,0) AS priority
+            FROM pagestatus
+            INNER JOIN wikis ON pagestatus.wiki=wikis.id
+            LEFT JOIN page_queue ON page_queue.pagestatus_id=pagestatus.id
+            WHERE wikis.status='ACTIVE' AND pagestatus.status NOT IN ('RUNNING','DELETED') {not_excluded}
+            AND (page_queue.failures IS NULL OR page_queue.failures=0 OR page_queue.last_run IS NULL
+                OR page_queue.last_run < NOW() - INTERVAL LEAST(POW(2,page_queue.failures),{MAX_FAILURE_BACKOFF_HOURS}) HOUR)
+            ORDER BY priority DESC,pagestatus.timestamp
+            LIMIT {CANDIDATE_POOL_SIZE}) ps
+            ORDER BY priority DESC,rand()
+            LIMIT 1"#);
+        for _attempt in 0..MAX_CLAIM_ATTEMPTS {
+            let page = self.pool.get_conn().await?
+                .exec_iter(sql.as_str(), excluded_wikis.to_vec())
+                .await?
+                .map_and_drop(PageToProcess::from_row)
+                .await?
+                .pop()
+                .ok_or(anyhow!("PageQueue::next_page: no page available"))?;
+            if self.claim(&page).await? {
+                return Ok(page);
+            }
+        }
+        Err(anyhow!("PageQueue::next_page: could not claim a page, other workers were faster"))
+    }
+
+    /// Marks a page as RUNNING for this worker; false if another worker got it first
+    async fn claim(&self, page: &PageToProcess) -> Result<bool> {
+        let mut conn = self.pool.get_conn().await?;
+        let sql = "UPDATE `pagestatus` SET `status`='RUNNING',`message`='PREPARING',`timestamp`=:timestamp WHERE `id`=:id AND `status`<>'RUNNING'";
+        conn.exec_drop(sql, params! {"id" => page.id, "timestamp" => Self::timestamp()}).await?;
+        if conn.affected_rows() == 0 {
+            return Ok(false);
+        }
+        let sql = r#"INSERT INTO `page_queue` (`pagestatus_id`,`worker`,`claimed`) VALUES (:id,:worker,NOW())
+            ON DUPLICATE KEY UPDATE `worker`=:worker,`claimed`=NOW()"#;
+        conn.exec_drop(sql, params! {"id" => page.id, "worker" => &self.worker_id}).await?;
+        Ok(true)
+    }
+
+    /// Stores the result for a page, and releases it.
+    /// Failures are counted for backoff; any other status resets the count. The priority is used up.
+    pub async fn set_page_status(
+        &self,
+        page: &str,
+        wiki: &str,
+        status: &str,
+        message: &str,
+    ) -> Result<()> {
+        let timestamp = Self::timestamp();
+        println!("{timestamp} {wiki}:{page} : {status}: {message}");
+        let params = params! {
+            "wiki" => wiki,
+            "page" => page,
+            "timestamp" => timestamp,
+            "status" => status,
+            "message" => message.chars().take(63).collect::<String>(),
+        };
+        let sql = "UPDATE `pagestatus` SET `status`=:status,`message`=:message,`timestamp`=:timestamp,`bot_version`=2 WHERE `wiki`=(SELECT id FROM `wikis` WHERE `name`=:wiki) AND `page`=:page";
+        self.pool.get_conn().await?.exec_drop(sql, params).await?;
+
+        if status == "RUNNING" {
+            return Ok(());
+        }
+        let failed = if status == "FAIL" { 1 } else { 0 };
+        let params = params! {
+            "wiki" => wiki,
+            "page" => page,
+            "failed" => failed,
+        };
+        let sql = r#"INSERT INTO `page_queue` (`pagestatus_id`,`failures`,`last_run`)
+            SELECT `pagestatus`.`id`,:failed,NOW() FROM `pagestatus`,`wikis`
+            WHERE `pagestatus`.`wiki`=`wikis`.`id` AND `wikis`.`name`=:wiki AND `pagestatus`.`page`=:page
+            ON DUPLICATE KEY UPDATE `failures`=IF(:failed=1,`failures`+1,0),`last_run`=NOW(),`priority`=0,`worker`=NULL,`claimed`=NULL"#;
+        self.pool.get_conn().await?.exec_drop(sql, params).await?;
+        Ok(())
+    }
+
+    /// Names of the wikis the bot is active on
+    pub async fn active_wikis(&self) -> Result<Vec<String>> {
+        let sql = "SELECT `name` FROM `wikis` WHERE `status`='ACTIVE'";
+        let ret = self.pool.get_conn().await?
+            .exec_iter(sql, ())
+            .await?
+            .map_and_drop(from_row::<String>)
+            .await?;
+        Ok(ret)
+    }
+
+    /// Replaces the list of Wikidata items shown on a page
+    pub async fn set_page_items(&self, wiki: &str, page: &str, items: &[String]) -> Result<()> {
+        let mut conn = self.pool.get_conn().await?;
+        let sql = "SELECT `pagestatus`.`id` FROM `pagestatus`,`wikis` WHERE `pagestatus`.`wiki`=`wikis`.`id` AND `wikis`.`name`=:wiki AND `pagestatus`.`page`=:page";
+        let pagestatus_id = match conn.exec_first::<u64, _, _>(sql, params! {"wiki" => wiki, "page" => page}).await? {
+            Some(id) => id,
+            None => return Ok(()), // Page is not in the queue
+        };
+        conn.exec_drop("DELETE FROM `page_items` WHERE `pagestatus_id`=?", (pagestatus_id,)).await?;
+        for chunk in items.chunks(ITEMS_PER_INSERT) {
+            let placeholders = vec!["(?,?)"; chunk.len()].join(",");
+            let sql = format!("INSERT IGNORE INTO `page_items` (`pagestatus_id`,`item`) VALUES {placeholders}");
+            let values: Vec<my::Value> = chunk
+                .iter()
+                .flat_map(|item| [pagestatus_id.into(), item.into()])
+                .collect();
+            conn.exec_drop(sql, values).await?;
+        }
+        Ok(())
+    }
+
+    /// Raises the priority of a page, unless it is already higher
+    pub async fn raise_priority(&self, wiki: &str, page: &str, priority: i32) -> Result<()> {
+        let params = params! {
+            "wiki" => wiki,
+            "page" => page,
+            "priority" => priority,
+        };
+        let sql = r#"INSERT INTO `page_queue` (`pagestatus_id`,`priority`)
+            SELECT `pagestatus`.`id`,:priority FROM `pagestatus`,`wikis`
+            WHERE `pagestatus`.`wiki`=`wikis`.`id` AND `wikis`.`name`=:wiki AND `pagestatus`.`page`=:page
+            ON DUPLICATE KEY UPDATE `priority`=GREATEST(`priority`,:priority)"#;
+        self.pool.get_conn().await?.exec_drop(sql, params).await?;
+        Ok(())
+    }
+
+    /// Raises the priority of all pages showing a Wikidata item
+    pub async fn raise_priority_for_item(&self, item: &str, priority: i32) -> Result<()> {
+        let params = params! {
+            "item" => item,
+            "priority" => priority,
+        };
+        let sql = r#"INSERT INTO `page_queue` (`pagestatus_id`,`priority`)
+            SELECT `pagestatus_id`,:priority FROM `page_items` WHERE `item`=:item
+            ON DUPLICATE KEY UPDATE `priority`=GREATEST(`priority`,:priority)"#;
+        self.pool.get_conn().await?.exec_drop(sql, params).await?;
+        Ok(())
+    }
+
+    /// Sets the priority of a page; pages with higher priority are processed first
+    pub async fn set_priority(&self, wiki: &str, page: &str, priority: i32) -> Result<()> {
+        let params = params! {
+            "wiki" => wiki,
+            "page" => page,
+            "priority" => priority,
+        };
+        let sql = r#"INSERT INTO `page_queue` (`pagestatus_id`,`priority`)
+            SELECT `pagestatus`.`id`,:priority FROM `pagestatus`,`wikis`
+            WHERE `pagestatus`.`wiki`=`wikis`.`id` AND `wikis`.`name`=:wiki AND `pagestatus`.`page`=:page
+            ON DUPLICATE KEY UPDATE `priority`=:priority"#;
+        self.pool.get_conn().await?.exec_drop(sql, params).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+//! Python bindings; build with maturin, e.g. `maturin develop --features python`
+use crate::configuration::Configuration;
+use crate::listeria_list::ListeriaList;
+use crate::listeria_page::ListeriaPage;
+use crate::PageParams;
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::prelude::*;
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+use tokio::runtime::Runtime;
+use tokio::sync::RwLock;
+use wikibase::mediawiki::api::Api;
+
+fn runtime() -> PyResult<&'static Runtime> {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    if let Some(runtime) = RUNTIME.get() {
+        return Ok(runtime);
+    }
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(to_py_err)?;
+    Ok(RUNTIME.get_or_init(|| runtime))
+}
+
+fn to_py_err<E: std::fmt::Display>(e: E) -> PyErr {
+    PyRuntimeError::new_err(e.to_string())
+}
+
+async fn load_context(api_url: &str, config_path: &str) -> anyhow::Result<(Arc<Configuration>, Arc<RwLock<Api>>)> {
+    let config = Arc::new(Configuration::new_from_file(config_path).await?);
+    let mut mw_api = Api::new(api_url).await?;
+    mw_api.set_oauth2(&config.oauth2_token());
+    Ok((config, Arc::new(RwLock::new(mw_api))))
+}
+
+/// A wiki page with lists
+#[pyclass(name = "Page", unsendable)]
+struct PyPage {
+    page: ListeriaPage,
+}
+
+#[pymethods]
+impl PyPage {
+    /// Loads a page; with `wikitext`, that draft is used instead of the saved page
+    #[new]
+    #[pyo3(signature = (api_url, title, config_path = "config.json", wikitext = None))]
+    fn new(api_url: &str, title: &str, config_path: &str, wikitext: Option<&str>) -> PyResult<Self> {
+        let page = runtime()?
+            .block_on(async {
+                let (config, mw_api) = load_context(api_url, config_path).await?;
+                let page_params = PageParams::new(config, mw_api, title.to_string()).await?;
+                Ok::<_, anyhow::Error>(match wikitext {
+                    Some(text) => ListeriaPage::from_wikitext(text, page_params),
+                    None => ListeriaPage::new_from_params(page_params),
+                })
+            })
+            .map_err(to_py_err)?;
+        Ok(Self { page })
+    }
+
+    /// Generates all lists on the page
+    fn run(&mut self) -> PyResult<()> {
+        runtime()?
+            .block_on(self.page.run())
+            .map_err(|e| to_py_err(e.message))
+    }
+
+    /// The lists as wikitext tables
+    fn lists_wikitext(&self) -> PyResult<Vec<String>> {
+        self.page.as_wikitext().map_err(to_py_err)
+    }
+
+    /// The whole page as the bot would save it
+    fn page_wikitext(&self) -> PyResult<String> {
+        self.page.new_wikitext().map_err(to_py_err)
+    }
+
+    /// The result rows of each list, as JSON
+    fn results_json(&self) -> PyResult<String> {
+        let results: Vec<_> = self
+            .page
+            .elements()
+            .iter()
+            .filter(|element| !element.is_just_text())
+            .map(|element| element.list().results())
+            .collect();
+        serde_json::to_string(&results).map_err(to_py_err)
+    }
+
+    /// Saves the page if its lists changed; true if it was edited
+    fn save(&mut self) -> PyResult<bool> {
+        runtime()?
+            .block_on(self.page.update_source_page())
+            .map(|edit_result| edit_result.edited)
+            .map_err(|e| to_py_err(e.message))
+    }
+}
+
+/// Renders a single list from template parameters, without a page
+#[pyfunction]
+#[pyo3(signature = (api_url, params, config_path = "config.json", title = "Listeria preview"))]
+fn render_list(api_url: &str, params: HashMap<String, String>, config_path: &str, title: &str) -> PyResult<String> {
+    runtime()?
+        .block_on(async {
+            let (config, mw_api) = load_context(api_url, config_path).await?;
+            let page_params = PageParams::new(config, mw_api, title.to_string()).await?;
+            ListeriaList::new_from_params(params, Arc::new(page_params))
+                .await?
+                .as_wikitext()
+        })
+        .map_err(to_py_err)
+}
+
+#[pymodule]
+fn listeria(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyPage>()?;
+    m.add_function(wrap_pyfunction!(render_list, m)?)?;
+    Ok(())
+}
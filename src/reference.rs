@@ -1,14 +1,18 @@
 use crate::listeria_list::ListeriaList;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use std::sync::RwLock;
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Reference {
     pub url: Option<String>,
     pub title: Option<String>,
     pub date: Option<String>,
     pub stated_in: Option<String>, // Item
+    #[serde(skip)]
     md5: Arc<RwLock<String>>,
+    #[serde(skip)]
     wikitext_cache: Arc<RwLock<Option<String>>>,
 }
 impl PartialEq for Reference {
@@ -121,16 +125,83 @@ impl Reference {
             }
             _ => return String::new(), // No error
         }
+        let s = match self.as_citation(list) {
+            Some(s) => s,
+            None => self.as_plain_wikitext(list),
+        };
+
+        match self.md5.write() {
+            Ok(mut md5) => {
+                *md5 = format!("{:x}", md5::compute(s.clone()));
+            }
+            _ => return String::new(), // No error
+        }
+
+        match self.wikitext_cache.write() {
+            Ok(mut cache) => {
+                *cache = Some(s);
+            }
+            _ => return String::new(), // No error
+        }
+        self.as_wikitext(list)
+    }
+
+    /// Renders the reference with the wiki's citation template pattern, if configured
+    fn as_citation(&self, list: &ListeriaList) -> Option<String> {
+        let kind = if self.url.is_some() { "web" } else { "item" };
+        let pattern = list.config().get_reference_template(list.wiki(), kind)?;
+        let stated_in = self.stated_in.as_ref().map(|q| list.get_item_link_with_fallback(q));
+        let values = [
+            ("URL", self.url.to_owned()),
+            ("TITLE", self.title.to_owned().or_else(|| self.url.to_owned())),
+            ("STATED_IN", stated_in),
+            ("STATED_IN_ITEM", self.stated_in.to_owned()),
+            ("DATE", self.date.to_owned()),
+        ];
+        Some(Self::fill_pattern(pattern, &values))
+    }
+
+    /// A value that can be used as a template parameter, e.g. a title with "|" in it
+    fn escape_template_value(value: &str) -> String {
+        value
+            .replace("{{", "&#123;&#123;")
+            .replace("}}", "&#125;&#125;")
+            .replace('=', "{{=}}")
+            .replace('|', "{{!}}")
+    }
+
+    /// Replaces $KEY$ placeholders in a template pattern.
+    /// Template parameters without a value are removed.
+    fn fill_pattern(pattern: &str, values: &[(&str, Option<String>)]) -> String {
+        let mut ret = pattern.to_string();
+        for (key, value) in values {
+            let placeholder = format!("${}$", key);
+            match value {
+                Some(value) => ret = ret.replace(&placeholder, &Self::escape_template_value(value)),
+                None => {
+                    let re = format!(r"\|[^|{{}}=]*=?\s*{}\s*", regex::escape(&placeholder));
+                    if let Ok(re) = Regex::new(&re) {
+                        ret = re.replace_all(&ret, "").to_string();
+                    }
+                    ret = ret.replace(&placeholder, "");
+                }
+            }
+        }
+        ret
+    }
+
+    fn as_plain_wikitext(&self, list: &ListeriaList) -> String {
         let mut s = String::new();
 
         if self.title.is_some() && self.url.is_some() {
             s += &format!(
                 "{{{{cite web|url={}|title={}",
-                self.url.as_ref().unwrap_or(&String::new()),
-                self.title.as_ref().unwrap_or(&String::new())
+                Self::escape_template_value(self.url.as_deref().unwrap_or_default()),
+                Self::escape_template_value(self.title.as_deref().unwrap_or_default())
             );
             if let Some(stated_in) = &self.stated_in {
-                s += &format!("|website={}", list.get_item_link_with_fallback(stated_in));
+                let website = list.get_item_link_with_fallback(stated_in);
+                s += &format!("|website={}", Self::escape_template_value(&website));
             }
             if let Some(date) = &self.date {
                 s += &format!("|access-date={}", &date);
@@ -148,20 +219,41 @@ impl Reference {
                 None => {}
             }
         }
+        s
+    }
+}
 
-        match self.md5.write() {
-            Ok(mut md5) => {
-                *md5 = format!("{:x}", md5::compute(s.clone()));
-            }
-            _ => return String::new(), // No error
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        match self.wikitext_cache.write() {
-            Ok(mut cache) => {
-                *cache = Some(s);
-            }
-            _ => return String::new(), // No error
-        }
-        self.as_wikitext(list)
+    #[test]
+    fn test_fill_pattern() {
+        let pattern = "{{cite web|url=$URL$|title=$TITLE$|website=$STATED_IN$|access-date=$DATE$}}";
+        let values = [
+            ("URL", Some("https://example.org".to_string())),
+            ("TITLE", Some("Example".to_string())),
+            ("STATED_IN", None),
+            ("DATE", Some("2020-01-02".to_string())),
+        ];
+        assert_eq!(
+            Reference::fill_pattern(pattern, &values),
+            "{{cite web|url=https://example.org|title=Example|access-date=2020-01-02}}"
+        );
+        let values = [("STATED_IN_ITEM", Some("Q42".to_string())), ("DATE", None)];
+        assert_eq!(
+            Reference::fill_pattern("{{Cite Q|$STATED_IN_ITEM$|$DATE$}}", &values),
+            "{{Cite Q|Q42}}"
+        );
+
+        // Values must not end the template or add parameters to it
+        let values = [
+            ("URL", Some("https://example.org/?a=1|b".to_string())),
+            ("TITLE", Some("{{A}} | B = C".to_string())),
+        ];
+        assert_eq!(
+            Reference::fill_pattern("{{cite web|url=$URL$|title=$TITLE$}}", &values),
+            "{{cite web|url=https://example.org/?a{{=}}1{{!}}b|title=&#123;&#123;A&#125;&#125; {{!}} B {{=}} C}}"
+        );
     }
 }
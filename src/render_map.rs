@@ -0,0 +1,119 @@
+use crate::api_client::ApiClient;
+use crate::render_wikitext::RendererWikitext;
+use crate::{ListeriaList, ListeriaPage, Renderer};
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Renders the coordinates of a list as a Data:*.map page on Commons, for lists with the map parameter
+pub struct RendererMap {}
+
+impl Renderer for RendererMap {
+    fn new() -> Self {
+        Self {}
+    }
+
+    fn render(&mut self, list: &ListeriaList) -> Result<String> {
+        let features: Vec<Value> = list
+            .map_points()
+            .iter()
+            .map(|(entity_id, lat, lon)| {
+                json!({
+                    "type": "Feature",
+                    "properties": {
+                        "title": list.get_label_with_fallback(entity_id, None),
+                        "description": format!("[[:d:{entity_id}|{entity_id}]]"),
+                    },
+                    "geometry": {"type": "Point", "coordinates": [lon, lat]}
+                })
+            })
+            .collect();
+        let (latitude, longitude, zoom) = Self::view(list.map_points());
+        let ret = json!({
+            "license": "CC0-1.0",
+            "description": {"en": format!("Listeria map for {} on {}", list.page_title(), list.wiki())},
+            "sources": "https://github.com/magnusmanske/listeria_rs",
+            "latitude": latitude,
+            "longitude": longitude,
+            "zoom": zoom,
+            "data": {"type": "FeatureCollection", "features": features}
+        });
+        Ok(format!("{}", ret))
+    }
+
+    /// The page text is the same as for wikitext lists; the map is on its own page
+    fn get_new_wikitext(
+        &self,
+        wikitext: &str,
+        page: &ListeriaPage,
+    ) -> Result<Option<String>> {
+        RendererWikitext::new().get_new_wikitext(wikitext, page)
+    }
+}
+
+impl RendererMap {
+    pub fn map_page_name(&self, list: &ListeriaList) -> Option<String> {
+        let ret = "Data:Listeria/".to_string() + list.wiki() + "/" + list.page_title() + ".map";
+        if ret.len() > 250 {
+            return None; // Page title too long
+        }
+        Some(ret)
+    }
+
+    /// Center and zoom level that show all points
+    fn view(points: &[(String, f64, f64)]) -> (f64, f64, u8) {
+        if points.is_empty() {
+            return (0.0, 0.0, 1);
+        }
+        let lats = points.iter().map(|(_, lat, _)| *lat);
+        let lons = points.iter().map(|(_, _, lon)| *lon);
+        let (min_lat, max_lat) = lats.fold((f64::MAX, f64::MIN), |(a, b), x| (a.min(x), b.max(x)));
+        let (min_lon, max_lon) = lons.fold((f64::MAX, f64::MIN), |(a, b), x| (a.min(x), b.max(x)));
+        let span = (max_lat - min_lat).max(max_lon - min_lon);
+        let zoom = match span > 0.0 {
+            true => (360.0 / span).log2().floor().clamp(1.0, 14.0) as u8,
+            false => 12, // Single place
+        };
+        ((min_lat + max_lat) / 2.0, (min_lon + max_lon) / 2.0, zoom)
+    }
+
+    /// Writes the map page; the API does not make a new revision if nothing changed
+    pub async fn write_map_data(&mut self, list: &ListeriaList, commons_api: &dyn ApiClient) -> Result<()> {
+        let map_page = self
+            .map_page_name(list)
+            .ok_or(anyhow!("Map page name too long"))?;
+        let text = self.render(list)?;
+        let token = commons_api.get_edit_token().await?;
+        let params: HashMap<String, String> = [
+            ("action", "edit"),
+            ("title", map_page.as_str()),
+            ("summary", "Wikidata list map updated"),
+            ("text", text.as_str()),
+            ("bot", "1"),
+            ("token", token.as_str()),
+        ]
+        .iter()
+        .map(|x| (x.0.to_string(), x.1.to_string()))
+        .collect();
+        let j = commons_api.post_query_api_json(&params).await?;
+        match j["error"]["info"].as_str() {
+            Some(info) => Err(anyhow!("Could not save {map_page}: {info}")),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_view() {
+        assert_eq!(RendererMap::view(&[]), (0.0, 0.0, 1));
+        let points = vec![
+            ("Q1".to_string(), 50.0, 10.0),
+            ("Q2".to_string(), 52.0, 12.0),
+        ];
+        assert_eq!(RendererMap::view(&points), (51.0, 11.0, 7));
+    }
+}
@@ -0,0 +1,105 @@
+use crate::render_wikitext::RendererWikitext;
+use crate::{ListeriaList, ListeriaPage, Renderer};
+use anyhow::{anyhow, Result};
+
+/// Renders a list as a Lua data module, for lists with the data_module parameter.
+/// The module can be read with mw.loadData; the page itself only gets the invocation.
+pub struct RendererScribunto {}
+
+impl Renderer for RendererScribunto {
+    fn new() -> Self {
+        Self {}
+    }
+
+    fn render(&mut self, list: &ListeriaList) -> Result<String> {
+        let mut lua = String::from("-- Generated by Listeria; manual changes will be overwritten\nreturn {\n");
+
+        let columns: Vec<String> = list
+            .columns()
+            .iter()
+            .map(|col| Self::lua_string(&col.label))
+            .collect();
+        lua += &format!("\tcolumns = {{ {} }},\n", columns.join(", "));
+
+        lua += "\tsections = {\n";
+        for section_id in list.get_section_ids() {
+            if let Some(name) = list.section_name(section_id) {
+                lua += &format!("\t\t[{}] = {},\n", section_id, Self::lua_string(name));
+            }
+        }
+        lua += "\t},\n";
+
+        lua += "\trows = {\n";
+        for (rownum, row) in list.results().iter().enumerate() {
+            let cells: Vec<String> = row
+                .cells()
+                .iter()
+                .enumerate()
+                .map(|(colnum, cell)| Self::lua_string(&cell.as_wikitext(list, rownum, colnum)))
+                .collect();
+            lua += &format!(
+                "\t\t{{ item = {}, section = {}, cells = {{ {} }} }},\n",
+                Self::lua_string(row.entity_id()),
+                row.section(),
+                cells.join(", ")
+            );
+        }
+        lua += "\t},\n}\n";
+        Ok(lua)
+    }
+
+    /// The page text is the same as for wikitext lists; the lists themselves render the invocation
+    fn get_new_wikitext(
+        &self,
+        wikitext: &str,
+        page: &ListeriaPage,
+    ) -> Result<Option<String>> {
+        RendererWikitext::new().get_new_wikitext(wikitext, page)
+    }
+}
+
+impl RendererScribunto {
+    /// What goes on the page in place of the table
+    pub fn invocation(&self, list: &ListeriaList) -> Result<String> {
+        let data_module = list
+            .data_module()
+            .ok_or_else(|| anyhow!("List has no data module"))?;
+        let pattern = list
+            .config()
+            .get_data_module_invocation(list.wiki())
+            .ok_or_else(|| anyhow!("No data module invocation configured for {}", list.wiki()))?;
+        Ok(pattern.replace("$DATA$", data_module))
+    }
+
+    /// A double-quoted Lua string literal
+    fn lua_string(s: &str) -> String {
+        let mut ret = String::with_capacity(s.len() + 2);
+        ret.push('"');
+        for c in s.chars() {
+            match c {
+                '\\' => ret += "\\\\",
+                '"' => ret += "\\\"",
+                '\n' => ret += "\\n",
+                '\r' => ret += "\\r",
+                '\0' => ret += "\\0",
+                c => ret.push(c),
+            }
+        }
+        ret.push('"');
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lua_string() {
+        assert_eq!(RendererScribunto::lua_string("abc"), "\"abc\"");
+        assert_eq!(
+            RendererScribunto::lua_string("a \"b\"\n[[c\\d]]"),
+            "\"a \\\"b\\\"\\n[[c\\\\d]]\""
+        );
+    }
+}
@@ -10,11 +10,36 @@ impl Renderer for RendererTabbedData {
     }
 
     fn render(&mut self, list: &ListeriaList) -> Result<String> {
-        let mut ret = json!({"license": "CC0-1.0","description": {"en":"Listeria output"},"sources":"https://github.com/magnusmanske/listeria_rs","schema":{"fields":[{ "name": "section", "type": "number", "title": { list.language().to_owned(): "Section"}}]},"data":[]});
-        list.columns().iter().enumerate().for_each(|(colnum,col)| {
-            if let Some(x) = ret["schema"]["fields"].as_array_mut() {
-                x.push(json!({"name":"col_".to_string()+&colnum.to_string(),"type":"string","title":{list.language().to_owned():col.label}}));
+        let mut languages = vec![list.language().to_string()];
+        for language in list.config().tabbed_data_languages() {
+            if !languages.contains(language) {
+                languages.push(language.to_owned());
             }
+        }
+        let mut fields = vec![json!({"name": "section", "type": "number", "title": {"en": "Section"}})];
+        list.columns().iter().enumerate().for_each(|(colnum, col)| {
+            // A label from the column definition is only known in the list language
+            let title: serde_json::Map<String, Value> = match col.has_label() {
+                true => [(list.language().to_string(), json!(col.label))].into_iter().collect(),
+                false => languages
+                    .iter()
+                    .map(|language| (language.to_owned(), json!(col.label_in_language(list, Some(language)))))
+                    .collect(),
+            };
+            fields.push(json!({"name": format!("col_{colnum}"), "type": "string", "title": title}));
+        });
+        let page = format!("{}:{}", list.wiki(), list.page_title());
+        let listeria = "[https://github.com/magnusmanske/listeria_rs Listeria]";
+        let sources = match list.query_url() {
+            Some(url) => format!("Generated by {listeria} for {page}, from [{url} this SPARQL query]"),
+            None => format!("Generated by {listeria} for {page}"),
+        };
+        let mut ret = json!({
+            "license": "CC0-1.0",
+            "description": {"en": format!("Wikidata list for {page}")},
+            "sources": sources,
+            "schema": {"fields": fields},
+            "data": [],
         });
         ret["data"] = list
             .results()
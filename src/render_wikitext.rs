@@ -1,5 +1,8 @@
-use crate::{ListeriaList, ListeriaPage, Renderer};
+use crate::result_row::ResultRow;
+use crate::column::Column;
+use crate::{Collapsible, FooterMode, HeaderLinks, ListeriaList, ListeriaPage, Renderer, SummaryToken, TocMode};
 use anyhow::Result;
+use wikibase::entity::EntityTrait;
 
 pub struct RendererWikitext {}
 
@@ -9,11 +12,12 @@ impl Renderer for RendererWikitext {
     }
 
     fn render(&mut self, list: &ListeriaList) -> Result<String> {
-        let mut wt: String = list
+        let mut wt = self.as_wikitext_toc(list);
+        wt += &list
             .get_section_ids()
             .iter()
             .map(|section_id| self.as_wikitext_section(list, *section_id))
-            .collect();
+            .collect::<String>();
 
         if !list.shadow_files().is_empty() {
             wt += "\n----\nThe following local image(s) are not shown in the above list, because they shadow a Commons image of the same name, and might be non-free:";
@@ -22,8 +26,18 @@ impl Renderer for RendererWikitext {
             }
         }
 
-        if let Some("ITEMNUMBER") = list.summary().as_deref() {
-            wt += format!("\n----\n&sum; {} items.", list.results().len()).as_str();
+        let footer_template = list.config().get_footer_template(list.wiki());
+        let summary = self.as_wikitext_summary(list);
+        match (&list.template_params().footer, footer_template) {
+            (FooterMode::None, _) => {}
+            (FooterMode::Template, Some(template)) => {
+                // A magic word for the time, so the footer does not change on every run
+                let rows = list.results().len();
+                wt += &format!("\n{{{{{template}|rows={rows}|timestamp={{{{REVISIONTIMESTAMP}}}}}}}}");
+            }
+            _ if summary.is_empty() => {}
+            (FooterMode::Top, _) => wt = format!("{summary}\n----\n{wt}"),
+            _ => wt += format!("\n----\n{summary}").as_str(),
         }
 
         Ok(wt)
@@ -44,6 +58,56 @@ impl Renderer for RendererWikitext {
 }
 
 impl RendererWikitext {
+    /// The line under the list, from the summary tokens. Querytime changes on every run, so
+    /// `PageElement::has_meaningful_change` ignores it; lastchange uses magic words for that reason.
+    fn as_wikitext_summary(&self, list: &ListeriaList) -> String {
+        let parts: Vec<String> = list
+            .summary()
+            .iter()
+            .filter_map(|token| match token {
+                SummaryToken::ItemNumber => Some(format!("&sum; {} items.", list.results().len())),
+                SummaryToken::Sections => {
+                    let sections = list
+                        .get_section_ids()
+                        .iter()
+                        .filter(|section_id| list.section_name(**section_id).is_some())
+                        .count();
+                    Some(format!("{sections} sections."))
+                }
+                SummaryToken::QueryTime => list
+                    .query_time_ms()
+                    .map(|ms| format!("Query time: {:.1} s.", ms as f64 / 1000.0)),
+                SummaryToken::Endpoint => list
+                    .query_endpoint()
+                    .map(|endpoint| format!("Query service: {endpoint}.")),
+                SummaryToken::LastChange => Some(
+                    "Last change: {{REVISIONYEAR}}-{{REVISIONMONTH}}-{{REVISIONDAY2}}.".to_string(),
+                ),
+                SummaryToken::Truncated => list
+                    .rows_before_sample()
+                    .map(|total| format!("Showing {} of {total} items.", list.results().len())),
+            })
+            .collect();
+        parts.join(" ")
+    }
+
+    /// The magic word or template of the toc parameter, if the list has sections
+    fn as_wikitext_toc(&self, list: &ListeriaList) -> String {
+        let has_sections = list.get_section_ids().iter().any(|id| list.section_name(*id).is_some());
+        if !has_sections {
+            return String::new();
+        }
+        match &list.template_params().toc {
+            TocMode::Default => String::new(),
+            TocMode::Show => "__TOC__".to_string(),
+            TocMode::Hide => "__NOTOC__".to_string(),
+            TocMode::Limit(level) => match list.config().get_toc_limit_template(list.wiki()) {
+                Some(template) => format!("{{{{{template}|{level}}}}}\n__TOC__"),
+                None => "__TOC__".to_string(),
+            },
+        }
+    }
+
     fn as_wikitext_section(&self, list: &ListeriaList, section_id: usize) -> String {
         let mut wt = String::new();
 
@@ -52,24 +116,35 @@ impl RendererWikitext {
             wt += &header;
         }
 
+        // A table of its own can collapse; rows of a header template or without a table need a wrapper
+        let collapsible = &list.template_params().collapsible;
+        let wrap = *collapsible != Collapsible::None && (list.skip_table() || list.header_template().is_some());
+        if wrap {
+            wt += &format!("<div class='{}'>\n", collapsible.classes().trim());
+        }
+
         wt += &self.as_wikitext_table_header(list);
 
+        let section_rows: Vec<&ResultRow> = list
+            .results()
+            .iter()
+            .filter(|row| row.section() == section_id)
+            .collect();
+        let row_attributes: Vec<String> = section_rows
+            .iter()
+            .map(|row| row.row_attributes(None))
+            .collect();
+
         if list.get_row_template().is_none()
             && !list.skip_table()
             && !list.results().is_empty()
             && !list.template_params().wdedit
         {
-            wt += "|-\n";
+            wt += "|-";
+            wt += row_attributes.first().map(|s| s.as_str()).unwrap_or_default();
+            wt += "\n";
         }
 
-        let row_entity_ids: Vec<String> = list
-            .results()
-            .iter()
-            .filter(|row| row.section() == section_id)
-            .map(|row| row.entity_id())
-            .cloned()
-            .collect();
-
         // Rows
         let rows = list
             .results()
@@ -81,25 +156,41 @@ impl RendererWikitext {
         if list.skip_table() {
             wt += &rows.join("\n");
         } else if list.template_params().wdedit {
-            let x: Vec<String> = row_entity_ids
+            let x: Vec<String> = section_rows
                 .iter()
                 .zip(rows.iter())
-                .map(|(entity_id, row)| {
+                .map(|(result_row, row)| {
                     match &list.header_template() {
                         Some(_) => row.to_string(),
-                        None => format!("\n|- class='wd_{}'\n{}", &entity_id.to_lowercase(), &row)
+                        None => {
+                            let class = format!("wd_{}", result_row.entity_id().to_lowercase());
+                            format!("\n|-{}\n{}", result_row.row_attributes(Some(&class)), &row)
+                        }
                     }
                 })
                 .collect();
             wt += &x.join("").trim();
-        } else {
+        } else if list.get_row_template().is_some() {
             wt += &rows.join("\n|-\n");
+        } else {
+            let x: Vec<String> = rows
+                .iter()
+                .enumerate()
+                .map(|(num, row)| match num {
+                    0 => row.to_string(),
+                    _ => format!("\n|-{}\n{}", row_attributes[num], row),
+                })
+                .collect();
+            wt += &x.join("");
         }
 
         // End
         if !list.skip_table() {
             wt += "\n|}";
         }
+        if wrap {
+            wt += "\n</div>";
+        }
 
         wt
     }
@@ -118,13 +209,14 @@ impl RendererWikitext {
                     if list.template_params().wdedit {
                         wt += " wd_can_edit";
                     }
+                    wt += list.template_params().collapsible.classes();
                     wt += "'\n";
                     list.columns()
                         .iter()
                         .enumerate()
                         .for_each(|(_colnum, col)| {
                             wt += "! ";
-                            wt += &col.label;
+                            wt += &self.header_label(list, col);
                             wt += "\n";
                         });
                 }
@@ -132,4 +224,25 @@ impl RendererWikitext {
         }
         wt
     }
+
+    /// The column label, with a link to its property or a tooltip if the header_links parameter asks for it
+    fn header_label(&self, list: &ListeriaList, col: &Column) -> String {
+        let property = match (list.header_links(), col.header_property()) {
+            (HeaderLinks::None, _) | (_, None) => return col.label.to_owned(),
+            (_, Some(property)) => property,
+        };
+        match list.header_links() {
+            HeaderLinks::Link => format!("[[{}|{}]]", list.get_item_wiki_target(property), col.label),
+            _ => {
+                let description = list
+                    .get_entity(property)
+                    .and_then(|e| e.description_in_locale(list.language()).map(|s| s.to_string()));
+                let title = match description {
+                    Some(description) => format!("{property}: {description}"),
+                    None => property.to_owned(),
+                };
+                format!("<span title=\"{}\">{}</span>", title.replace('"', "&quot;"), col.label)
+            }
+        }
+    }
 }
@@ -1,18 +1,27 @@
 use crate::column::*;
+use crate::entity_container_wrapper::interproject_prefix;
+use crate::expression::Expression;
 use crate::listeria_list::ListeriaList;
 use crate::reference::Reference;
 use crate::result_cell_part::PartWithReference;
 use crate::result_cell_part::ResultCellPart;
-use crate::{ReferencesParameter, SparqlValue};
+use crate::{CurrentOnly, ReferencesParameter, SparqlValue};
+use chrono::Datelike;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
 use wikibase::entity::EntityTrait;
 
-#[derive(Debug, Clone, PartialEq)]
+/// Year, month, day, as from `CurrentOnly::parse_date`
+type Day = (i64, u32, u32);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ResultCell {
     parts: Vec<PartWithReference>,
     wdedit_class: Option<String>,
     deduplicate_parts: bool,
+    #[serde(default)]
+    separator: Option<String>, // Between parts; line breaks otherwise
 }
 
 impl ResultCell {
@@ -26,6 +35,7 @@ impl ResultCell {
             parts: vec![],
             wdedit_class: None,
             deduplicate_parts: true,
+            separator: None,
         };
 
         let entity = list.get_entity(entity_id);
@@ -42,6 +52,23 @@ impl ResultCell {
                     None,
                 ));
             }
+            ColumnType::ItemLabelQid => {
+                let (label, language) = list.get_label_and_language_with_fallback(entity_id, None);
+                let text = format!(
+                    "{} ([[{}|{}]])",
+                    list.bidi_wrap_label(&label, language.as_deref()),
+                    list.get_item_wiki_target(entity_id),
+                    entity_id
+                );
+                ret.parts.push(PartWithReference::new(ResultCellPart::Text(text), None));
+            }
+            ColumnType::ItemTemplate => {
+                let part = match list.config().get_item_template(list.wiki()) {
+                    Some(template) => ResultCellPart::Text(format!("{{{{{template}|{entity_id}}}}}")),
+                    None => ResultCellPart::Entity((entity_id.to_owned(), false)), // Like "item"
+                };
+                ret.parts.push(PartWithReference::new(part, None));
+            }
             ColumnType::Description => {
                 if let Some(e) = entity {
                     match e.description_in_locale(list.language()) {
@@ -86,31 +113,104 @@ impl ResultCell {
                     }
                 }
             }
+            ColumnType::Expression(expression) => {
+                if let Ok(expression) = Expression::new(expression) {
+                    for row in sparql_rows.iter() {
+                        let values = row
+                            .iter()
+                            .map(|(k, v)| (k.to_lowercase(), v.as_plain_text()))
+                            .collect();
+                        let text = expression.evaluate(&values).as_text();
+                        if !text.is_empty() {
+                            ret.parts.push(PartWithReference::new(ResultCellPart::Text(text), None));
+                        }
+                    }
+                }
+            }
+            ColumnType::Modified | ColumnType::StatementCount | ColumnType::IdentifierCount => {
+                if let Some(s) = entity.and_then(|e| col.obj.entity_metadata(&e)) {
+                    ret.parts.push(PartWithReference::new(ResultCellPart::Text(s), None));
+                }
+            }
+            ColumnType::HasProperty(property) => {
+                let has_value = match entity {
+                    Some(e) => list
+                        .get_filtered_claims(&e, property)
+                        .iter()
+                        .any(|statement| statement.main_snak().data_value().is_some()),
+                    None => false,
+                };
+                let indicator = list.config().get_boolean_indicator(list.wiki(), has_value);
+                ret.parts.push(PartWithReference::new(ResultCellPart::Text(indicator), None));
+            }
+            ColumnType::Sitelink(site) => {
+                let title = entity.and_then(|e| {
+                    let sitelinks = e.sitelinks().as_ref()?;
+                    Some(sitelinks.iter().find(|s| s.site() == site)?.title().to_string())
+                });
+                if let Some(title) = title {
+                    let prefix = match site == list.wiki() {
+                        true => Some(String::new()),
+                        false => interproject_prefix(list.wiki(), site),
+                    };
+                    let part = match prefix {
+                        Some(prefix) => ResultCellPart::LocalLink((prefix + &title, title, false)),
+                        None => ResultCellPart::Text(title),
+                    };
+                    ret.parts.push(PartWithReference::new(part, None));
+                }
+            }
+            ColumnType::Age((start, end)) => {
+                if let Some(s) = entity.and_then(|e| Self::age_text(list, &e, start, end.as_deref())) {
+                    ret.parts.push(PartWithReference::new(ResultCellPart::Text(s), None));
+                }
+            }
             ColumnType::Property(property) => {
                 if let Some(e) = entity {
                     ret.wdedit_class = match &list.header_template() {
                         Some(_) => None,
                         None => Some(format!("wd_{}", property.to_lowercase()))
                     } ;
-                    list.get_filtered_claims(&e, property)
+                    ret.parts = Self::get_parts_property(list, &e, property, col);
+                }
+            }
+            ColumnType::PropertyFallback(properties) => {
+                if let Some(e) = entity {
+                    ret.parts = properties
                         .iter()
-                        .for_each(|statement| {
-                            let references = match list.get_reference_parameter() {
-                                ReferencesParameter::All => {
-                                    Self::get_references_for_statement(&statement, list.language())
-                                }
-                                _ => None,
-                            };
-                            ret.parts.push(PartWithReference::new(
-                                ResultCellPart::from_snak(statement.main_snak()),
-                                references,
-                            ));
-                        });
+                        .map(|property| Self::get_parts_property(list, &e, property, col))
+                        .find(|parts| !parts.is_empty())
+                        .unwrap_or_default();
+                }
+            }
+            ColumnType::PropertyCombination(properties) => {
+                if let Some(e) = entity {
+                    ret.parts = properties
+                        .iter()
+                        .flat_map(|property| Self::get_parts_property(list, &e, property, col))
+                        .collect();
+                    ret.separator = Some(list.combine_separator().to_string());
+                }
+            }
+            ColumnType::PropertyPath(path) => {
+                if let (Some(e), Some((last, steps))) = (entity, path.split_last()) {
+                    let mut entities = vec![e];
+                    for property in steps {
+                        let mut ids: Vec<String> =
+                            entities.iter().flat_map(|e| list.item_values(e, property)).collect();
+                        ids.sort();
+                        ids.dedup();
+                        entities = ids.iter().filter_map(|id| list.get_entity(id)).collect();
+                    }
+                    ret.parts = entities
+                        .iter()
+                        .flat_map(|e| Self::get_parts_property(list, e, last, col))
+                        .collect();
                 }
             }
             ColumnType::PropertyQualifier((p1, p2)) => {
                 if let Some(e) = entity {
-                    list.get_filtered_claims(&e, p1)
+                    list.get_column_claims(&e, p1, col.filters())
                         .iter()
                         .for_each(|statement| {
                             ret.get_parts_p_p(statement, p2).iter().for_each(|part| {
@@ -122,7 +222,7 @@ impl ResultCell {
             }
             ColumnType::PropertyQualifierValue((p1, q1, p2)) => {
                 if let Some(e) = entity {
-                    list.get_filtered_claims(&e, p1)
+                    list.get_column_claims(&e, p1, col.filters())
                         .iter()
                         .for_each(|statement| {
                             ret.get_parts_p_q_p(statement, q1, p2)
@@ -139,7 +239,7 @@ impl ResultCell {
                     match e.label_in_locale(language) {
                         Some(s) => {
                             ret.parts.push(PartWithReference::new(
-                                ResultCellPart::Text(s.to_string()),
+                                ResultCellPart::LanguageText((language.to_owned(), s.to_string())),
                                 None,
                             ));
                         }
@@ -165,7 +265,7 @@ impl ResultCell {
                     aliases.sort();
                     aliases.iter().for_each(|alias| {
                         ret.parts.push(PartWithReference::new(
-                            ResultCellPart::Text(alias.to_owned()),
+                            ResultCellPart::LanguageText((language.to_owned(), alias.to_owned())),
                             None,
                         ));
                     });
@@ -181,15 +281,7 @@ impl ResultCell {
                         Some(s) => s.to_string(),
                         None => entity_id.to_string(),
                     };
-                    let local_page = match e.sitelinks() {
-                        Some(sl) => sl
-                            .iter()
-                            .filter(|s| *s.site() == *list.wiki())
-                            .map(|s| s.title().to_string())
-                            .next(),
-                        None => None,
-                    };
-                    match local_page {
+                    match list.local_page(&e) {
                         Some(page) => {
                             ret.parts.push(PartWithReference::new(
                                 ResultCellPart::LocalLink((page, label, false)),
@@ -237,6 +329,50 @@ impl ResultCell {
             .collect()
     }
 
+    /// The first date of a property, as the earliest and latest day it can mean; None if less precise than a year
+    fn date_bounds(list: &ListeriaList, e: &wikibase::entity::Entity, property: &str) -> Option<(Day, Day)> {
+        list.get_column_claims(e, property, &[])
+            .iter()
+            .find_map(|statement| match statement.main_snak().data_value().as_ref()?.value() {
+                wikibase::Value::Time(t) if *t.precision() >= 9 => {
+                    let earliest = CurrentOnly::parse_date(t.time(), false)?;
+                    let latest = CurrentOnly::parse_date(t.time(), true)?;
+                    Some((earliest, latest))
+                }
+                _ => None,
+            })
+    }
+
+    /// Years from `start` to `end`, or to today if the item has no `end` date (eg still alive).
+    /// "41–42" if the precision of the dates does not tell.
+    fn age_text(list: &ListeriaList, e: &wikibase::entity::Entity, start: &str, end: Option<&str>) -> Option<String> {
+        let (start_earliest, start_latest) = Self::date_bounds(list, e, start)?;
+        let (end_earliest, end_latest) = match end.and_then(|end| Self::date_bounds(list, e, end)) {
+            Some(bounds) => bounds,
+            None => {
+                let today = chrono::Utc::now().date_naive();
+                let today = (today.year() as i64, today.month(), today.day());
+                (today, today)
+            }
+        };
+        let min = Self::years_between(start_latest, end_earliest);
+        let max = Self::years_between(start_earliest, end_latest);
+        match (min, max) {
+            (min, _) if min < 0 => None, // Dates the wrong way around
+            (min, max) if min == max => Some(min.to_string()),
+            (min, max) => Some(format!("{min}\u{2013}{max}")),
+        }
+    }
+
+    /// Full years from one date to another; month or day 0 is the start of the year or month
+    fn years_between(from: Day, to: Day) -> i64 {
+        let years = to.0 - from.0;
+        match (to.1, to.2) < (from.1, from.2) {
+            true => years - 1,
+            false => years,
+        }
+    }
+
     fn get_references_for_statement(
         statement: &wikibase::statement::Statement,
         language: &str,
@@ -255,6 +391,26 @@ impl ResultCell {
         }
     }
 
+    fn get_parts_property(
+        list: &ListeriaList,
+        e: &wikibase::entity::Entity,
+        property: &str,
+        col: &Column,
+    ) -> Vec<PartWithReference> {
+        list.get_column_claims(e, property, col.filters())
+            .iter()
+            .map(|statement| {
+                let references = match list.get_reference_parameter() {
+                    ReferencesParameter::All => {
+                        Self::get_references_for_statement(statement, list.language())
+                    }
+                    _ => None,
+                };
+                PartWithReference::new_for_statement(list, statement, property, references)
+            })
+            .collect()
+    }
+
     fn get_parts_p_q_p(
         &self,
         statement: &wikibase::statement::Statement,
@@ -300,17 +456,30 @@ impl ResultCell {
                 ResultCellPart::File(s) => s.to_owned(),
                 ResultCellPart::Uri(s) => s.to_owned(),
                 ResultCellPart::Text(s) => s.to_owned(),
+                ResultCellPart::LanguageText((_language, s)) => s.to_owned(),
+                ResultCellPart::MonoLingual((language, s)) => format!("{}:{}", language, s),
                 ResultCellPart::ExternalId((_prop, id)) => id.to_owned(),
+                ResultCellPart::Math(s) => s.to_owned(),
+                ResultCellPart::MusicalNotation(s) => s.to_owned(),
                 _ => String::new(),
             },
             None => String::new(),
         }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.parts.is_empty()
+    }
+
     pub fn parts(&self) -> &Vec<PartWithReference> {
         &self.parts
     }
 
+    /// The CSS class for the wdedit gadget, if any
+    pub fn wdedit_class(&self) -> Option<&String> {
+        self.wdedit_class.as_ref()
+    }
+
     pub fn parts_mut(&mut self) -> &mut Vec<PartWithReference> {
         &mut self.parts
     }
@@ -336,7 +505,14 @@ impl ResultCell {
                     .as_tabbed_data(list, rownum, colnum, partnum)
             })
             .collect();
-        json!(ret.join("<br/>"))
+        let max_length = list.config().tabbed_data_max_cell_length();
+        if ret.is_empty() {
+            if let Some(placeholder) = list.empty_cell() {
+                return json!(ResultCellPart::truncate(placeholder, max_length));
+            }
+        }
+        let separator = self.separator.as_deref().unwrap_or("<br/>");
+        json!(ResultCellPart::truncate(&ret.join(separator), max_length))
     }
 
     pub fn as_wikitext(&self, list: &ListeriaList, rownum: usize, colnum: usize) -> String {
@@ -366,7 +542,24 @@ impl ResultCell {
             }
             parts = parts2;
         }
-        ret += &parts.join("<br/>");
+        match list.empty_cell() {
+            Some(placeholder) if parts.is_empty() => ret += placeholder,
+            _ => ret += &parts.join(self.separator.as_deref().unwrap_or("<br/>")),
+        }
         ret
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_years_between() {
+        assert_eq!(ResultCell::years_between((1879, 3, 14), (1955, 4, 18)), 76);
+        assert_eq!(ResultCell::years_between((1879, 3, 14), (1955, 3, 13)), 75);
+        // Year precision: latest start to earliest end, and the other way around
+        assert_eq!(ResultCell::years_between((1900, 12, 31), (1950, 0, 0)), 49);
+        assert_eq!(ResultCell::years_between((1900, 0, 0), (1950, 12, 31)), 50);
+    }
+}
@@ -3,17 +3,84 @@ use crate::listeria_list::ListeriaList;
 use crate::reference::Reference;
 use crate::{LinksType, SparqlValue};
 use regex::Regex;
+use unicode_segmentation::UnicodeSegmentation;
+use serde::{Deserialize, Serialize};
 use wikibase::entity::EntityTrait;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PartWithReference {
     pub part: ResultCellPart,
     pub references: Option<Vec<Reference>>,
+    #[serde(default)]
+    pub statement_id: Option<String>, // For a link to the statement on Wikidata, eg "Q42$F078E5B3-..."
+    #[serde(default)]
+    pub point_in_time: Option<String>, // From the P585 qualifier, shown after the value
+    #[serde(default)]
+    pub date_range: Option<(Option<String>, Option<String>)>, // From the P580/P582 qualifiers, shown after the value
 }
 
 impl PartWithReference {
     pub fn new(part: ResultCellPart, references: Option<Vec<Reference>>) -> Self {
-        Self { part, references }
+        Self { part, references, statement_id: None, point_in_time: None, date_range: None }
+    }
+
+    /// The main value of a statement of `property`; with a link to the statement, its point in time,
+    /// and its start and end time, if the statement_links, point_in_time and date_ranges parameters ask for them
+    pub fn new_for_statement(
+        list: &ListeriaList,
+        statement: &wikibase::statement::Statement,
+        property: &str,
+        references: Option<Vec<Reference>>,
+    ) -> Self {
+        let params = list.template_params();
+        Self {
+            part: ResultCellPart::from_snak(statement.main_snak()),
+            references,
+            statement_id: match params.statement_links.applies_to(property) {
+                true => statement.id(),
+                false => None,
+            },
+            point_in_time: match params.point_in_time.applies_to(property) {
+                true => ResultCellPart::qualifier_time(statement, "P585"),
+                false => None,
+            },
+            date_range: match params.date_ranges.applies_to(property) {
+                true => {
+                    let start = ResultCellPart::qualifier_time(statement, "P580");
+                    let end = ResultCellPart::qualifier_time(statement, "P582");
+                    Some((start, end)).filter(|(start, end)| start.is_some() || end.is_some())
+                }
+                false => None,
+            },
+        }
+    }
+
+    fn format_date_range(list: &ListeriaList, start: &Option<String>, end: &Option<String>) -> String {
+        let kind = match (start, end) {
+            (Some(_), None) => "since",
+            (None, Some(_)) => "until",
+            _ => "range",
+        };
+        list.config()
+            .get_date_range_format(list.wiki(), kind)
+            .replace("$START$", start.as_deref().unwrap_or_default())
+            .replace("$END$", end.as_deref().unwrap_or_default())
+    }
+
+    /// A small link to the statement, next to the value; the value itself may already be a link
+    fn statement_link(&self, list: &ListeriaList) -> String {
+        let statement_id = match &self.statement_id {
+            Some(statement_id) => statement_id,
+            None => return String::new(),
+        };
+        match statement_id.split_once('$') {
+            Some((entity_id, _)) if !entity_id.is_empty() => format!(
+                "<sup>[[{}#{}|&#x2197;]]</sup>",
+                list.get_item_wiki_target(&entity_id.to_uppercase()),
+                statement_id
+            ),
+            _ => String::new(),
+        }
     }
 
     pub fn as_wikitext(
@@ -35,11 +102,19 @@ impl PartWithReference {
             }
             None => String::new(),
         };
-        wikitext_part + &wikitext_reference
+        let point_in_time = match &self.point_in_time {
+            Some(time) => format!(" ({time})"),
+            None => String::new(),
+        };
+        let date_range = match &self.date_range {
+            Some((start, end)) => format!(" ({})", Self::format_date_range(list, start, end)),
+            None => String::new(),
+        };
+        wikitext_part + &point_in_time + &date_range + &self.statement_link(list) + &wikitext_reference
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ResultCellPart {
     Number,
     Entity((String, bool)),            // ID, try_localize
@@ -50,16 +125,21 @@ pub enum ResultCellPart {
     Uri(String),
     ExternalId((String, String)), // Property, ID
     Text(String),
+    LanguageText((String, String)), // Language, text
+    MonoLingual((String, String)),  // Language, text
+    Math(String),            // TeX
+    MusicalNotation(String), // LilyPond
     SnakList(Vec<PartWithReference>), // PP and PQP
 }
 
 impl ResultCellPart {
     pub fn from_sparql_value(v: &SparqlValue) -> Self {
         match v {
-            SparqlValue::Entity(x) => ResultCellPart::Entity((x.to_owned(), true)),
+            // Lexeme forms and senses are not loaded as entities, so they have no label
+            SparqlValue::Entity(x) => ResultCellPart::Entity((x.to_owned(), !x.contains('-'))),
             SparqlValue::File(x) => ResultCellPart::File(x.to_owned()),
             SparqlValue::Uri(x) => ResultCellPart::Uri(x.to_owned()),
-            SparqlValue::Time(x) => ResultCellPart::Text(x.to_owned()),
+            SparqlValue::Time(x) => ResultCellPart::Time(Self::reduce_sparql_time(x)),
             SparqlValue::Location(x) => ResultCellPart::Location((x.lat, x.lon, None)),
             SparqlValue::Literal(x) => ResultCellPart::Text(x.to_owned()),
         }
@@ -90,6 +170,10 @@ impl ResultCellPart {
                     wikibase::SnakDataType::ExternalId => {
                         ResultCellPart::ExternalId((snak.property().to_string(), v.to_string()))
                     }
+                    wikibase::SnakDataType::Math => ResultCellPart::Math(v.to_string()),
+                    wikibase::SnakDataType::MusicalNotation => {
+                        ResultCellPart::MusicalNotation(v.to_string())
+                    }
                     _ => ResultCellPart::Text(v.to_string()),
                 },
                 wikibase::Value::Quantity(v) => ResultCellPart::Text(v.amount().to_string()),
@@ -98,13 +182,38 @@ impl ResultCellPart {
                     ResultCellPart::Location((*v.latitude(), *v.longitude(), None))
                 }
                 wikibase::Value::MonoLingual(v) => {
-                    ResultCellPart::Text(v.language().to_string() + ":" + v.text())
+                    ResultCellPart::MonoLingual((v.language().to_string(), v.text().to_string()))
                 }
             },
             _ => ResultCellPart::Text("No/unknown value".to_string()),
         }
     }
 
+    /// The first time qualifier of a property (eg P585, point in time) of a statement, as shown for its precision
+    pub fn qualifier_time(statement: &wikibase::statement::Statement, property: &str) -> Option<String> {
+        statement
+            .qualifiers()
+            .iter()
+            .filter(|snak| snak.property() == property)
+            .find_map(|snak| match snak.data_value().as_ref()?.value() {
+                wikibase::Value::Time(v) => Some(Self::reduce_time(v)),
+                _ => None,
+            })
+    }
+
+    /// The date of a SPARQL xsd:dateTime, like the day precision of `reduce_time`
+    pub fn reduce_sparql_time(time: &str) -> String {
+        lazy_static! {
+            static ref RE_SPARQL_DATE: Regex =
+                Regex::new(r#"^\+{0,1}(-{0,1}\d+-\d{2}-\d{2})(?:T[0-9:.]*(?:Z|[+-]\d{2}:\d{2})?)?$"#)
+                    .expect("RE_SPARQL_DATE does not parse");
+        }
+        match RE_SPARQL_DATE.captures(time) {
+            Some(caps) => caps[1].to_string(),
+            None => time.to_string(),
+        }
+    }
+
     pub fn reduce_time(v: &wikibase::TimeValue) -> String {
         lazy_static! {
             static ref RE_DATE: Regex =
@@ -132,13 +241,37 @@ impl ResultCellPart {
         }
     }
 
-    fn tabbed_string_safe(&self, s: String) -> String {
+    /// `<tag>content</tag>`; a closing math or score tag in the content is escaped, so it can not end the tag early
+    fn wrap_in_tag(tag: &str, content: &str) -> String {
+        lazy_static! {
+            static ref RE_CLOSING_TAG: Regex =
+                Regex::new(r"(?i)</(math|score)\b").expect("RE_CLOSING_TAG does not parse");
+        }
+        format!("<{tag}>{}</{tag}>", RE_CLOSING_TAG.replace_all(content, "&lt;/$1"))
+    }
+
+    fn tabbed_string_safe(&self, s: String, max_length: usize) -> String {
         let ret = s.replace("\n", " ").replace("\t", " ");
-        // 400 chars Max
-        if ret.len() >= 380 {
-            ret[0..380].to_string();
+        Self::truncate(&ret, max_length)
+    }
+
+    /// Cuts `s` to at most `max_length` characters, ending in an ellipsis if it was cut.
+    /// Only cuts between graphemes, so accents, emoji sequences and flags stay whole.
+    pub fn truncate(s: &str, max_length: usize) -> String {
+        if s.chars().count() <= max_length {
+            return s.to_string();
+        }
+        let mut budget = max_length.saturating_sub(1); // Room for the ellipsis
+        let mut ret = String::new();
+        for grapheme in s.graphemes(true) {
+            let length = grapheme.chars().count();
+            if length > budget {
+                break;
+            }
+            budget -= length;
+            ret += grapheme;
         }
-        ret
+        ret + "…"
     }
 
     pub fn as_wikitext(
@@ -149,12 +282,15 @@ impl ResultCellPart {
         partnum: usize,
     ) -> String {
         //format!("CELL ROW {} COL {} PART {}", rownum, colnum, partnum)
+        if let Some(s) = list.format_cell_part(self, rownum, colnum, partnum) {
+            return s;
+        }
         match self {
             ResultCellPart::Number => format!("style='text-align:right'| {}", rownum + 1),
             ResultCellPart::Entity((id, try_localize)) => {
                 if !try_localize {
                     let is_item_column = match list.column(colnum) {
-                        Some(col) => col.obj == ColumnType::Item,
+                        Some(col) => matches!(col.obj, ColumnType::Item | ColumnType::ItemTemplate),
                         None => false,
                     };
                     if list.is_wikidatawiki() || is_item_column {
@@ -170,15 +306,27 @@ impl ResultCellPart {
                             Some(_) => list.language(),
                             None => list.default_language(),
                         };
-                        let use_label = list.get_label_with_fallback(id, Some(use_language));
+                        let (use_label, label_language) =
+                            list.get_label_and_language_with_fallback(id, Some(use_language));
+                        let display_label = list.bidi_wrap_label(&use_label, label_language.as_deref());
                         let labeled_entity_link = if list.is_wikidatawiki() {
-                            format!("[[{}|{}]]", list.get_item_wiki_target(id), use_label)
+                            format!("[[{}|{}]]", list.get_item_wiki_target(id), display_label)
                         } else {
-                            format!("''[[{}|{}]]''", list.get_item_wiki_target(id), use_label)
+                            format!("''[[{}|{}]]''", list.get_item_wiki_target(id), display_label)
                         };
 
+                        // A sitelink gives the page even if its title differs from the label
+                        let local_page = match list.get_links_type() {
+                            LinksType::Text | LinksType::Reasonator => None,
+                            _ => list.local_page(&e),
+                        };
+                        if let Some(page) = local_page {
+                            return ResultCellPart::LocalLink((page, use_label, false))
+                                .as_wikitext(list, rownum, colnum, partnum);
+                        }
+
                         match list.get_links_type() {
-                            LinksType::Text => use_label,
+                            LinksType::Text => display_label,
                             LinksType::Red | LinksType::RedOnly => {
                                 let contains_colon = use_label.contains(':');
                                 if list.local_page_exists(&use_label) {
@@ -229,12 +377,14 @@ impl ResultCellPart {
             }
             ResultCellPart::Uri(url) => url.to_owned(),
             ResultCellPart::ExternalId((property, id)) => {
+                let label = Self::transform_text(list, colnum, id);
                 match list.ecw.external_id_url(property, id) {
-                    Some(url) => "[".to_string() + &url + " " + &id + "]",
-                    None => id.to_owned(),
+                    Some(url) => "[".to_string() + &url + " " + &label + "]",
+                    None => label,
                 }
             }
             ResultCellPart::Text(text) => {
+                let text = &Self::transform_text(list, colnum, text);
                 match list.column(colnum) {
                     Some(col) => {
                         match &col.obj {
@@ -252,6 +402,15 @@ impl ResultCellPart {
                     None => text.to_owned(),
                 }
             }
+            ResultCellPart::LanguageText((language, text)) => {
+                list.bidi_wrap(&Self::transform_text(list, colnum, text), language)
+            }
+            ResultCellPart::MonoLingual((language, text)) => {
+                let text = Self::transform_text(list, colnum, text);
+                format!("{}:{}", language, list.bidi_wrap(&text, language))
+            }
+            ResultCellPart::Math(tex) => Self::wrap_in_tag("math", tex),
+            ResultCellPart::MusicalNotation(score) => Self::wrap_in_tag("score", score),
             ResultCellPart::SnakList(v) => v
                 .iter()
                 .map(|rcp| rcp.part.as_wikitext(list, rownum, colnum, partnum))
@@ -260,6 +419,13 @@ impl ResultCellPart {
         }
     }
 
+    fn transform_text(list: &ListeriaList, colnum: usize, text: &str) -> String {
+        match list.column(colnum) {
+            Some(col) => col.transform_text(text),
+            None => text.to_string(),
+        }
+    }
+
     pub fn as_tabbed_data(
         &self,
         list: &ListeriaList,
@@ -267,6 +433,72 @@ impl ResultCellPart {
         colnum: usize,
         partnum: usize,
     ) -> String {
-        self.tabbed_string_safe(self.as_wikitext(list, rownum, colnum, partnum))
+        let max_length = list.config().tabbed_data_max_cell_length();
+        let s = match self {
+            // Tabbed data is not parsed as wikitext, so keep the source
+            ResultCellPart::Math(s) | ResultCellPart::MusicalNotation(s) => s.to_owned(),
+            ResultCellPart::LanguageText((_language, text)) => {
+                Self::transform_text(list, colnum, text)
+            }
+            ResultCellPart::MonoLingual((language, text)) => {
+                format!("{}:{}", language, Self::transform_text(list, colnum, text))
+            }
+            _ => self.as_wikitext(list, rownum, colnum, partnum),
+        };
+        self.tabbed_string_safe(s, max_length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(ResultCellPart::truncate("abc", 3), "abc");
+        assert_eq!(ResultCellPart::truncate("abcd", 3), "ab…");
+        assert_eq!(ResultCellPart::truncate("äöüß", 3), "äö…");
+        // "e" with a combining acute accent stays together
+        assert_eq!(ResultCellPart::truncate("abe\u{301}cd", 4), "ab…");
+        assert_eq!(ResultCellPart::truncate("abe\u{301}cd", 5), "abe\u{301}…");
+        // Neither a ZWJ family nor a flag is cut in two
+        assert_eq!(ResultCellPart::truncate("a\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}bc", 5), "a…");
+        assert_eq!(ResultCellPart::truncate("a\u{1F1E9}\u{1F1EA}bc", 4), "a\u{1F1E9}\u{1F1EA}…");
+        assert_eq!(ResultCellPart::truncate("a\u{1F1E9}\u{1F1EA}bc", 3), "a…");
+        // Nor a Hangul syllable of conjoining jamo
+        assert_eq!(ResultCellPart::truncate("a\u{1100}\u{1161}bc", 3), "a…");
+    }
+
+    #[test]
+    fn test_from_sparql_value() {
+        assert_eq!(
+            ResultCellPart::from_sparql_value(&SparqlValue::Time("2021-03-04T12:30:00Z".to_string())),
+            ResultCellPart::Time("2021-03-04".to_string())
+        );
+        assert_eq!(
+            ResultCellPart::from_sparql_value(&SparqlValue::Time("-0044-03-15".to_string())),
+            ResultCellPart::Time("-0044-03-15".to_string())
+        );
+        assert_eq!(
+            ResultCellPart::from_sparql_value(&SparqlValue::Entity("Q42".to_string())),
+            ResultCellPart::Entity(("Q42".to_string(), true))
+        );
+    }
+
+    #[test]
+    fn test_serialize_part() {
+        let mut reference = Reference::default();
+        reference.url = Some("https://example.org".to_string());
+        let part = PartWithReference::new(
+            ResultCellPart::SnakList(vec![PartWithReference::new(
+                ResultCellPart::LocalLink(("Berlin".to_string(), "Berlin".to_string(), false)),
+                Some(vec![reference]),
+            )]),
+            None,
+        );
+        let j = serde_json::to_value(&part).unwrap();
+        assert_eq!(j["part"]["SnakList"][0]["part"]["LocalLink"][0], json!("Berlin"));
+        let part2: PartWithReference = serde_json::from_value(j).unwrap();
+        assert_eq!(part, part2);
     }
 }
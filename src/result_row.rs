@@ -4,18 +4,28 @@ use crate::result_cell::ResultCell;
 use crate::result_cell_part::ResultCellPart;
 use crate::{serde_json, HashMap, SparqlValue};
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::cmp::Ordering;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
 use wikibase::entity::EntityTrait;
 use wikibase::SnakDataType;
 
-#[derive(Debug, Clone, Default)]
+/// Starts the comment that names the item of a row, see `Configuration::row_markers`
+const ROW_MARKER_COMMENT_PREFIX: &str = "Listeria row: ";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ResultRow {
     entity_id: String,
     cells: Vec<ResultCell>,
     section: usize,
     sortkey: String,
     keep: bool,
+    #[serde(default)]
+    class: Option<String>,
+    #[serde(default)]
+    style: Option<String>,
 }
 
 impl ResultRow {
@@ -100,6 +110,44 @@ impl ResultRow {
             let x = ResultCell::new(list, &self.entity_id, sparql_rows, column).await;
             self.cells.push(x);
         }
+        self.class = Self::get_row_attribute(sparql_rows, "rowclass");
+        self.style = Self::get_row_attribute(sparql_rows, "rowstyle");
+    }
+
+    /// Value of a ?rowclass or ?rowstyle SPARQL variable, made safe for a table row attribute
+    fn get_row_attribute(
+        sparql_rows: &[&HashMap<String, SparqlValue>],
+        varname: &str,
+    ) -> Option<String> {
+        let value = sparql_rows.iter().find_map(|row| match row.get(varname)? {
+            SparqlValue::Literal(s) | SparqlValue::Uri(s) | SparqlValue::Entity(s) => Some(s),
+            _ => None,
+        })?;
+        let value: String = value
+            .chars()
+            .filter(|c| !matches!(c, '"' | '\'' | '|' | '\n' | '<' | '>' | '{' | '}' | '[' | ']'))
+            .collect();
+        match value.trim() {
+            "" => None,
+            value => Some(value.to_string()),
+        }
+    }
+
+    /// Attributes for the table row line (`|-`), with a leading space; wdedit adds its own class
+    pub fn row_attributes(&self, wdedit_class: Option<&str>) -> String {
+        let class = match (wdedit_class, &self.class) {
+            (Some(a), Some(b)) => Some(format!("{} {}", a, b)),
+            (Some(a), None) => Some(a.to_string()),
+            (None, b) => b.to_owned(),
+        };
+        let mut ret = String::new();
+        if let Some(class) = class {
+            ret += &format!(" class='{}'", class);
+        }
+        if let Some(style) = &self.style {
+            ret += &format!(" style='{}'", style);
+        }
+        ret
     }
 
     pub fn set_sortkey(&mut self, sortkey: String) {
@@ -108,29 +156,161 @@ impl ResultRow {
 
     pub fn get_sortkey_label(&self, list: &ListeriaList) -> String {
         match list.get_entity(self.entity_id()) {
-            Some(_entity) => list.get_label_with_fallback(self.entity_id(), None),
+            Some(_entity) => {
+                let label = list.get_label_with_fallback(self.entity_id(), None);
+                if !list.sort_ignore_articles() {
+                    return label;
+                }
+                let articles = list.config().sort_articles(list.language());
+                Self::strip_leading_article(&label, &articles).to_string()
+            }
             None => "".to_string(),
         }
     }
 
+    /// Drops a leading article ("The Hobbit" => "Hobbit", "L'Étranger" => "Étranger"), ignoring case;
+    /// a label that is nothing but the article is kept
+    pub fn strip_leading_article<'a>(label: &'a str, articles: &[String]) -> &'a str {
+        for article in articles {
+            let prefix = match label.get(..article.len()) {
+                Some(prefix) if prefix.to_lowercase() == article.to_lowercase() => prefix,
+                _ => continue,
+            };
+            let rest = &label[prefix.len()..];
+            let rest = if article.ends_with('\'') || article.ends_with('’') {
+                rest
+            } else if rest.starts_with(char::is_whitespace) {
+                rest.trim_start()
+            } else {
+                continue;
+            };
+            if !rest.is_empty() {
+                return rest;
+            }
+        }
+        label
+    }
+
+    /// "family name, full label", folded for sorting; the family name comes from the first
+    /// configured source that has one, see `Configuration::family_name_sources`
     pub fn get_sortkey_family_name(&self, page: &ListeriaList) -> String {
         lazy_static! {
             static ref RE_SR_JR: Regex = Regex::new(r", [JS]r\.$").expect("RE_SR_JR does not parse");
             static ref RE_BRACES: Regex = Regex::new(r"\s+\(.+\)$").expect("RE_BRACES does not parse");
             static ref RE_LAST_FIRST: Regex = Regex::new(r"^(?P<f>.+) (?P<l>\S+)$").expect("RE_LAST_FIRST does not parse");
         }
-        match page.get_entity(&self.entity_id) {
-            Some(entity) => match entity.label_in_locale(&page.language()) {
-                Some(label) => {
-                    let ret = RE_SR_JR.replace_all(label, "");
-                    let ret = RE_BRACES.replace_all(&ret, "");
-                    let ret = RE_LAST_FIRST.replace_all(&ret, "$l, $f");
-                    ret.to_string()
-                }
-                None => entity.id().to_string(),
-            },
-            None => "".to_string(),
-        }
+        let entity = match page.get_entity(&self.entity_id) {
+            Some(entity) => entity,
+            None => return "".to_string(),
+        };
+        let label = match entity.label_in_locale(page.language()) {
+            Some(label) => label.to_string(),
+            None => page.get_label_with_fallback(&self.entity_id, None),
+        };
+        let label = RE_SR_JR.replace_all(&label, "");
+        let label = RE_BRACES.replace_all(&label, "").to_string();
+        let family_name = page.config().family_name_sources().iter().find_map(|source| {
+            if source.eq_ignore_ascii_case("label") {
+                let last_first = RE_LAST_FIRST.replace_all(&label, "$l, $f");
+                return Some(last_first.to_string());
+            }
+            page.get_filtered_claims(&entity, &source.to_uppercase())
+                .iter()
+                .filter_map(|statement| statement.main_snak().data_value().to_owned())
+                .find_map(|dv| match dv.value() {
+                    wikibase::Value::Entity(v) => {
+                        Some(page.get_label_with_fallback(v.id(), None))
+                    }
+                    wikibase::Value::StringValue(s) => Some(s.to_owned()),
+                    wikibase::Value::MonoLingual(m) => Some(m.text().to_string()),
+                    _ => None,
+                })
+                .map(|family_name| format!("{}, {}", family_name, label))
+        });
+        Self::fold_for_sorting(&family_name.unwrap_or(label))
+    }
+
+    /// Case-folds and strips accents; Cyrillic and Greek are transliterated, so all names sort together
+    pub fn fold_for_sorting(s: &str) -> String {
+        s.nfd()
+            .filter(|c| !is_combining_mark(*c))
+            .flat_map(|c| c.to_lowercase())
+            .map(|c| match Self::transliterate(c) {
+                Some(s) => s.to_string(),
+                None => c.to_string(),
+            })
+            .collect()
+    }
+
+    fn transliterate(c: char) -> Option<&'static str> {
+        Some(match c {
+            'ß' => "ss",
+            'æ' => "ae",
+            'œ' => "oe",
+            'ø' => "o",
+            'đ' | 'ð' => "d",
+            'ł' => "l",
+            'þ' => "th",
+            'а' => "a",
+            'б' => "b",
+            'в' => "v",
+            'г' => "g",
+            'ґ' => "g",
+            'д' => "d",
+            'е' | 'є' | 'э' => "e",
+            'ж' => "zh",
+            'з' => "z",
+            'и' | 'і' | 'ї' | 'й' | 'ы' => "i",
+            'к' => "k",
+            'л' => "l",
+            'м' => "m",
+            'н' => "n",
+            'о' => "o",
+            'п' => "p",
+            'р' => "r",
+            'с' => "s",
+            'т' => "t",
+            'у' | 'ў' => "u",
+            'ф' => "f",
+            'х' => "kh",
+            'ц' => "ts",
+            'ч' => "ch",
+            'ш' => "sh",
+            'щ' => "shch",
+            'ъ' | 'ь' => "",
+            'ю' => "yu",
+            'я' => "ya",
+            'α' => "a",
+            'β' => "v",
+            'γ' => "g",
+            'δ' => "d",
+            'ε' => "e",
+            'ζ' => "z",
+            'η' => "i",
+            'θ' => "th",
+            'ι' => "i",
+            'κ' => "k",
+            'λ' => "l",
+            'μ' => "m",
+            'ν' => "n",
+            'ξ' => "x",
+            'ο' => "o",
+            'π' => "p",
+            'ρ' => "r",
+            'σ' | 'ς' => "s",
+            'τ' => "t",
+            'υ' => "y",
+            'φ' => "f",
+            'χ' => "ch",
+            'ψ' => "ps",
+            'ω' => "o",
+            _ => return None,
+        })
+    }
+
+    /// False if the row had no value for the sort key
+    pub fn has_sort_value(&self, datatype: &SnakDataType) -> bool {
+        !self.sortkey.is_empty() && self.sortkey != self.no_value(datatype)
     }
 
     fn no_value(&self, datatype: &SnakDataType) -> String {
@@ -166,13 +346,12 @@ impl ResultRow {
     }
 
     pub fn get_sortkey_sparql(&self, variable: &str, list: &ListeriaList) -> String {
-        let obj = ColumnType::Field(variable.to_lowercase());
         // TODO sort by actual sparql values instead?
         match list
             .columns()
             .iter()
             .enumerate()
-            .find(|(_colnum, col)| col.obj == obj)
+            .find(|(_colnum, col)| matches!(&col.obj, ColumnType::Field(f) if f.eq_ignore_ascii_case(variable)))
         {
             Some((colnum, _col)) => match self.cells.get(colnum) {
                 Some(cell) => cell.get_sortkey(),
@@ -278,13 +457,40 @@ impl ResultRow {
             .enumerate()
             .map(|(colnum, cell)| cell.as_wikitext(list, rownum, colnum))
             .collect::<Vec<String>>();
-        match list.get_row_template() {
+        let row = match list.get_row_template() {
             Some(t) => format!(
                 "{{{{{}\n| {}\n}}}}",
                 t,
                 self.cells_as_wikitext(list, &cells)
             ),
             None => "|".to_string() + &cells.join("\n|"),
+        };
+        match list.config().row_markers(list.wiki()) {
+            true => format!("<!-- {ROW_MARKER_COMMENT_PREFIX}{} -->\n{row}", self.entity_id),
+            false => row,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fold_for_sorting() {
+        assert_eq!(ResultRow::fold_for_sorting("Ångström, Anders"), "angstrom, anders");
+        assert_eq!(ResultRow::fold_for_sorting("Straße"), "strasse");
+        assert_eq!(ResultRow::fold_for_sorting("Чехов"), "chekhov");
+        assert_eq!(ResultRow::fold_for_sorting("Σεφέρης"), "seferis");
+    }
+
+    #[test]
+    fn test_strip_leading_article() {
+        let articles: Vec<String> = ["The", "L'"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(ResultRow::strip_leading_article("The Hobbit", &articles), "Hobbit");
+        assert_eq!(ResultRow::strip_leading_article("the  Hobbit", &articles), "Hobbit");
+        assert_eq!(ResultRow::strip_leading_article("L'Étranger", &articles), "Étranger");
+        assert_eq!(ResultRow::strip_leading_article("Theodor", &articles), "Theodor");
+        assert_eq!(ResultRow::strip_leading_article("The", &articles), "The");
+    }
+}
@@ -0,0 +1,167 @@
+use crate::listeria_list::ListeriaList;
+use crate::result_row::ResultRow;
+use crate::CurrentOnly;
+use anyhow::{anyhow, Result};
+use std::cmp::Ordering;
+
+/// Decides which rows stay in a list, once its results are generated.
+/// Register with `ListeriaList::add_row_filter` or `ListeriaPage::add_row_filter`.
+pub trait RowFilter: std::fmt::Debug + Send + Sync {
+    fn keep(&self, list: &ListeriaList, row: &ResultRow) -> bool;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterOperator {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl FilterOperator {
+    fn accepts(&self, ordering: Ordering) -> bool {
+        match self {
+            Self::Equal => ordering == Ordering::Equal,
+            Self::NotEqual => ordering != Ordering::Equal,
+            Self::Less => ordering == Ordering::Less,
+            Self::LessOrEqual => ordering != Ordering::Greater,
+            Self::Greater => ordering == Ordering::Greater,
+            Self::GreaterOrEqual => ordering != Ordering::Less,
+        }
+    }
+}
+
+/// One condition of the `filter` template parameter, eg "P569>1900", "P31=Q5", "P18", or "!P18".
+/// A row is kept if any statement of the property matches; dates compare at the precision given.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterExpression {
+    HasValue(String),
+    HasNoValue(String),
+    Compare((String, FilterOperator, String)), // Property, operator, value
+}
+
+impl FilterExpression {
+    /// Parses conditions separated by ";", all of which must hold
+    pub fn new_list(s: &str) -> Result<Vec<Self>> {
+        s.split(';')
+            .map(|part| part.trim())
+            .filter(|part| !part.is_empty())
+            .map(Self::new)
+            .collect()
+    }
+
+    pub fn new(s: &str) -> Result<Self> {
+        lazy_static! {
+            static ref RE_EXPRESSION: regex::Regex =
+                regex::Regex::new(r"^(!?)\s*([Pp]\d+)\s*(?:(!=|<=|>=|=|<|>)\s*(.+?))?\s*$")
+                    .expect("RE_EXPRESSION does not parse");
+        }
+        let caps = RE_EXPRESSION
+            .captures(s.trim())
+            .ok_or_else(|| anyhow!("Bad filter expression '{s}'"))?;
+        let negated = &caps[1] == "!";
+        let property = caps[2].to_uppercase();
+        let operator = match caps.get(3).map(|m| m.as_str()) {
+            None if negated => return Ok(Self::HasNoValue(property)),
+            None => return Ok(Self::HasValue(property)),
+            Some(_) if negated => return Err(anyhow!("Bad filter expression '{s}'")),
+            Some("=") => FilterOperator::Equal,
+            Some("!=") => FilterOperator::NotEqual,
+            Some("<") => FilterOperator::Less,
+            Some("<=") => FilterOperator::LessOrEqual,
+            Some(">") => FilterOperator::Greater,
+            Some(_) => FilterOperator::GreaterOrEqual,
+        };
+        Ok(Self::Compare((property, operator, caps[4].to_string())))
+    }
+
+    /// Compares a statement value with the expression value, if they are comparable
+    fn compare_value(value: &wikibase::Value, expected: &str) -> Option<Ordering> {
+        match value {
+            wikibase::Value::Entity(e) => {
+                Some(e.id().to_uppercase().cmp(&expected.trim().to_uppercase()))
+            }
+            wikibase::Value::StringValue(s) => Some(s.as_str().cmp(expected)),
+            wikibase::Value::MonoLingual(m) => Some(m.text().cmp(expected)),
+            wikibase::Value::Quantity(q) => {
+                q.amount().partial_cmp(&expected.trim().parse::<f64>().ok()?)
+            }
+            wikibase::Value::Time(t) => {
+                let precision = expected.trim().trim_start_matches(['+', '-']).split('-').count();
+                let expected = CurrentOnly::parse_date(expected, false)?;
+                let time = CurrentOnly::parse_date(t.time(), false)?;
+                let (time, expected) = match precision {
+                    1 => ((time.0, 0, 0), (expected.0, 0, 0)),
+                    2 => ((time.0, time.1, 0), (expected.0, expected.1, 0)),
+                    _ => (time, expected),
+                };
+                Some(time.cmp(&expected))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl RowFilter for FilterExpression {
+    fn keep(&self, list: &ListeriaList, row: &ResultRow) -> bool {
+        let entity = match list.get_entity(row.entity_id()) {
+            Some(entity) => entity,
+            None => return false,
+        };
+        let property = match self {
+            Self::HasValue(p) | Self::HasNoValue(p) | Self::Compare((p, _, _)) => p,
+        };
+        let mut values = list
+            .get_filtered_claims(&entity, property)
+            .into_iter()
+            .filter_map(|statement| statement.main_snak().data_value().to_owned())
+            .map(|dv| dv.value().to_owned());
+        match self {
+            Self::HasValue(_) => values.next().is_some(),
+            Self::HasNoValue(_) => values.next().is_none(),
+            Self::Compare((_, operator, expected)) => values.any(|value| {
+                Self::compare_value(&value, expected)
+                    .map(|ordering| operator.accepts(ordering))
+                    .unwrap_or(false)
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_expression_new() {
+        assert_eq!(
+            FilterExpression::new("p569 > 1900").unwrap(),
+            FilterExpression::Compare((
+                "P569".to_string(),
+                FilterOperator::Greater,
+                "1900".to_string()
+            ))
+        );
+        assert_eq!(
+            FilterExpression::new("P31!=Q5").unwrap(),
+            FilterExpression::Compare((
+                "P31".to_string(),
+                FilterOperator::NotEqual,
+                "Q5".to_string()
+            ))
+        );
+        assert_eq!(
+            FilterExpression::new("P18").unwrap(),
+            FilterExpression::HasValue("P18".to_string())
+        );
+        assert_eq!(
+            FilterExpression::new("!P18").unwrap(),
+            FilterExpression::HasNoValue("P18".to_string())
+        );
+        assert!(FilterExpression::new("!P18=Q5").is_err());
+        assert!(FilterExpression::new("label>A").is_err());
+        assert_eq!(FilterExpression::new_list("P18; P569>=1900-05").unwrap().len(), 2);
+    }
+}
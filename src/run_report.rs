@@ -0,0 +1,178 @@
+use crate::listeria_page::WikiPageResult;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+
+/// Page statuses that mean "not done, but not an error either"
+const SKIPPED_STATUSES: &[&str] = &["PROTECTED", "BLOCKED", "DELETED", "INVALID"];
+
+/// Collects page results over one bot cycle, for the on-wiki status page
+#[derive(Debug, Clone)]
+pub struct RunReport {
+    started: DateTime<Utc>,
+    ended: Option<DateTime<Utc>>, // Set by `finish`
+    results: Vec<WikiPageResult>,
+    blocked_wikis: HashMap<String, String>,
+}
+
+impl Default for RunReport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RunReport {
+    pub fn new() -> Self {
+        Self::new_started_at(Utc::now())
+    }
+
+    fn new_started_at(started: DateTime<Utc>) -> Self {
+        Self {
+            started,
+            ended: None,
+            results: vec![],
+            blocked_wikis: HashMap::new(),
+        }
+    }
+
+    /// Ends the cycle now, and returns its report; the next cycle starts from here, with no results yet
+    pub fn finish(&mut self) -> Self {
+        let now = Utc::now();
+        let mut ret = std::mem::replace(self, Self::new_started_at(now));
+        ret.ended = Some(now);
+        ret
+    }
+
+    pub fn add(&mut self, wpr: &WikiPageResult) {
+        self.results.push(wpr.to_owned());
+    }
+
+    pub fn set_blocked_wikis(&mut self, blocked_wikis: HashMap<String, String>) {
+        self.blocked_wikis = blocked_wikis;
+    }
+
+    pub fn len(&self) -> usize {
+        self.results.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.results.is_empty()
+    }
+
+    fn count_by_status(&self) -> Vec<(String, usize)> {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for wpr in &self.results {
+            *counts.entry(wpr.result.to_owned()).or_insert(0) += 1;
+        }
+        let mut ret: Vec<(String, usize)> = counts.into_iter().collect();
+        ret.sort();
+        ret
+    }
+
+    fn nowiki(s: &str) -> String {
+        if s.is_empty() {
+            String::new()
+        } else {
+            format!("<nowiki>{}</nowiki>", s.replace("</nowiki>", ""))
+        }
+    }
+
+    pub fn as_wikitext(&self) -> String {
+        let timestamp_format = "%Y-%m-%d %H:%M:%S";
+        let mut wt = format!(
+            "Listeria bot run from {} to {} (UTC): {} pages.\n\n",
+            self.started.format(timestamp_format),
+            self.ended.unwrap_or_else(Utc::now).format(timestamp_format),
+            self.results.len()
+        );
+
+        wt += "{| class='wikitable sortable'\n! Status\n! Pages\n";
+        for (status, count) in self.count_by_status() {
+            wt += &format!("|-\n| {status}\n| style='text-align:right'| {count}\n");
+        }
+        wt += "|}\n";
+
+        if !self.blocked_wikis.is_empty() {
+            let mut blocked: Vec<(&String, &String)> = self.blocked_wikis.iter().collect();
+            blocked.sort();
+            wt += "\n== Blocked ==\n";
+            for (wiki, reason) in blocked {
+                wt += &format!("* {wiki}: {}\n", Self::nowiki(reason));
+            }
+        }
+
+        let problems: Vec<&WikiPageResult> =
+            self.results.iter().filter(|wpr| wpr.result != "OK").collect();
+        if !problems.is_empty() {
+            wt += "\n== Skipped and failed pages ==\n";
+            wt += "{| class='wikitable sortable'\n! Wiki\n! Page\n! Status\n! Message\n";
+            for wpr in problems {
+                let kind = if SKIPPED_STATUSES.contains(&wpr.result.as_str()) {
+                    "skipped"
+                } else {
+                    "error"
+                };
+                wt += &format!(
+                    "|-\n| {}\n| {}\n| {} ({kind})\n| {}\n",
+                    wpr.wiki,
+                    Self::nowiki(&wpr.page),
+                    wpr.result,
+                    Self::nowiki(&wpr.message)
+                );
+            }
+            wt += "|}\n";
+        }
+
+        let warnings: Vec<&WikiPageResult> =
+            self.results.iter().filter(|wpr| wpr.result == "OK" && !wpr.message.is_empty()).collect();
+        if !warnings.is_empty() {
+            wt += "\n== Updated pages with warnings ==\n";
+            wt += "{| class='wikitable sortable'\n! Wiki\n! Page\n! Message\n";
+            for wpr in warnings {
+                wt += &format!(
+                    "|-\n| {}\n| {}\n| {}\n",
+                    wpr.wiki,
+                    Self::nowiki(&wpr.page),
+                    Self::nowiki(&wpr.message)
+                );
+            }
+            wt += "|}\n";
+        }
+
+        wt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_as_wikitext() {
+        let mut report = RunReport::new();
+        report.add(&WikiPageResult::new("enwiki", "Foo", "OK", String::new()));
+        report.add(&WikiPageResult::new("enwiki", "Bar", "OK", "List 1: Items with more than one query result row".to_string()));
+        report.add(&WikiPageResult::fail("dewiki", "Baz", "No items to show"));
+        report.add(&WikiPageResult::new("frwiki", "Qux", "PROTECTED", "Page is protected".to_string()));
+        let wt = report.as_wikitext();
+        assert!(wt.contains(": 4 pages."));
+        assert!(wt.contains("|-\n| OK\n| style='text-align:right'| 2\n"));
+        assert!(wt.contains("| FAIL (error)\n| <nowiki>No items to show</nowiki>"));
+        assert!(wt.contains("| PROTECTED (skipped)\n"));
+        assert!(!wt.contains("<nowiki>Foo</nowiki>"));
+        assert!(wt.contains("| <nowiki>Bar</nowiki>\n| <nowiki>List 1: Items with more than one query result row</nowiki>"));
+    }
+
+    #[test]
+    fn test_finish() {
+        let mut report = RunReport::new();
+        report.add(&WikiPageResult::new("enwiki", "Foo", "OK", String::new()));
+        let finished = report.finish();
+        let ended = finished.ended.unwrap();
+        assert_eq!(finished.len(), 1);
+        assert!(finished.started <= ended);
+        assert!(report.is_empty());
+        assert_eq!(report.started, ended);
+        assert_eq!(report.ended, None);
+        assert_eq!(finished.as_wikitext(), finished.as_wikitext()); // The end does not move on
+    }
+}
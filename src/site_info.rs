@@ -0,0 +1,158 @@
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Namespaces of a wiki, from its site info: names, aliases (including gender forms), and title case.
+/// `ClientManager` keeps one per wiki, shared by all pages and lists.
+#[derive(Debug, Clone, Default)]
+pub struct SiteInfo {
+    local_names: HashMap<i64, String>,
+    names: HashMap<String, i64>, // Lower case local and canonical names, and aliases
+    first_letter_case: HashMap<i64, bool>,
+    case_sensitive: bool, // $wgCapitalLinks is off, for namespaces without a case of their own
+}
+
+impl SiteInfo {
+    /// From the "query" part of a siteinfo API result, with namespaces and namespacealiases.
+    /// Works for both formatversion 1 and 2.
+    pub fn new(j: &Value) -> Self {
+        let mut ret = Self {
+            case_sensitive: j["general"]["case"].as_str() == Some("case-sensitive"),
+            ..Default::default()
+        };
+        let default_case = match ret.case_sensitive {
+            true => "case-sensitive",
+            false => "first-letter",
+        };
+        let namespaces = j["namespaces"].as_object().into_iter().flatten().map(|(_, ns)| ns);
+        for ns in namespaces {
+            let id = match ns["id"].as_i64() {
+                Some(id) => id,
+                None => continue,
+            };
+            let local_name = ns["*"].as_str().or_else(|| ns["name"].as_str()).unwrap_or_default();
+            ret.local_names.insert(id, local_name.to_string());
+            ret.add_name(local_name, id);
+            if let Some(canonical) = ns["canonical"].as_str() {
+                ret.add_name(canonical, id);
+            }
+            let first_letter = ns["case"].as_str().unwrap_or(default_case) == "first-letter";
+            ret.first_letter_case.insert(id, first_letter);
+        }
+        for alias in j["namespacealiases"].as_array().into_iter().flatten() {
+            let name = alias["*"].as_str().or_else(|| alias["alias"].as_str());
+            if let (Some(id), Some(name)) = (alias["id"].as_i64(), name) {
+                ret.add_name(name, id);
+            }
+        }
+        ret
+    }
+
+    fn add_name(&mut self, name: &str, id: i64) {
+        if !name.is_empty() {
+            self.names.insert(Self::key(name), id);
+        }
+    }
+
+    fn key(name: &str) -> String {
+        name.replace('_', " ").trim().to_lowercase()
+    }
+
+    /// The local name of a namespace, eg "Datei" for 6 on dewiki
+    pub fn local_namespace_name(&self, namespace_id: i64) -> Option<&String> {
+        self.local_names.get(&namespace_id)
+    }
+
+    /// The namespace with this local or canonical name, or alias
+    pub fn namespace_by_name(&self, name: &str) -> Option<i64> {
+        self.names.get(&Self::key(name)).copied()
+    }
+
+    /// Namespace ID and the title without the namespace prefix
+    fn split_title<'a>(&self, title: &'a str) -> (i64, &'a str) {
+        let title = title.trim_start_matches(':');
+        if let Some((prefix, rest)) = title.split_once(':') {
+            if let Some(id) = self.names.get(&Self::key(prefix)) {
+                return (*id, rest);
+            }
+        }
+        (0, title)
+    }
+
+    /// The namespace of a title; 0 for the main namespace, or an unknown prefix
+    pub fn namespace_id(&self, title: &str) -> i64 {
+        self.split_title(title).0
+    }
+
+    /// A title as MediaWiki stores it: spaces instead of underscores, the local namespace name,
+    /// and the first letter in upper case, unless the namespace is case-sensitive
+    pub fn normalize_title(&self, title: &str) -> String {
+        let (id, rest) = self.split_title(title);
+        let rest = rest.replace('_', " ");
+        let rest = rest.split_whitespace().collect::<Vec<&str>>().join(" ");
+        let rest = match self.first_letter_case.get(&id).copied().unwrap_or(!self.case_sensitive) {
+            true => {
+                let mut chars = rest.chars();
+                match chars.next() {
+                    Some(first) => first.to_uppercase().collect::<String>() + chars.as_str(),
+                    None => String::new(),
+                }
+            }
+            false => rest,
+        };
+        match self.local_names.get(&id) {
+            Some(name) if id != 0 => format!("{name}:{rest}"),
+            _ => rest,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dewiki() -> SiteInfo {
+        SiteInfo::new(&json!({
+            "namespaces": {
+                "0": {"id": 0, "case": "first-letter", "*": ""},
+                "2": {"id": 2, "case": "first-letter", "canonical": "User", "*": "Benutzer"},
+                "6": {"id": 6, "case": "first-letter", "canonical": "File", "*": "Datei"},
+                "14": {"id": 14, "case": "first-letter", "canonical": "Category", "*": "Kategorie"},
+                "100": {"id": 100, "case": "case-sensitive", "canonical": "Portal", "*": "Portal"}
+            },
+            "namespacealiases": [
+                {"id": 2, "*": "Benutzerin"},
+                {"id": 6, "*": "Bild"}
+            ]
+        }))
+    }
+
+    #[test]
+    fn test_namespace_id() {
+        let site_info = dewiki();
+        assert_eq!(site_info.namespace_id("Kategorie:Burg"), 14);
+        assert_eq!(site_info.namespace_id("category:Burg"), 14);
+        assert_eq!(site_info.namespace_id("Benutzerin:Beispiel"), 2);
+        assert_eq!(site_info.namespace_id("Bild:X.jpg"), 6);
+        assert_eq!(site_info.namespace_id("Star Wars: Episode I"), 0);
+    }
+
+    #[test]
+    fn test_normalize_title() {
+        let site_info = dewiki();
+        assert_eq!(site_info.normalize_title("category:burg_Eltz"), "Kategorie:Burg Eltz");
+        assert_eq!(site_info.normalize_title("bild:x.jpg"), "Datei:X.jpg");
+        assert_eq!(site_info.normalize_title("portal:kunst"), "Portal:kunst");
+        assert_eq!(site_info.normalize_title("eltz  castle"), "Eltz castle");
+        assert_eq!(site_info.local_namespace_name(6), Some(&"Datei".to_string()));
+
+        let wiktionary = SiteInfo::new(&json!({
+            "general": {"case": "case-sensitive"},
+            "namespaces": {
+                "0": {"id": 0, "*": ""},
+                "14": {"id": 14, "case": "first-letter", "canonical": "Category", "*": "Category"}
+            }
+        }));
+        assert_eq!(wiktionary.normalize_title("water_lily"), "water lily");
+        assert_eq!(wiktionary.normalize_title("category:nouns"), "Category:Nouns");
+    }
+}
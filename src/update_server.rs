@@ -0,0 +1,319 @@
+use crate::listeria_bot::{ListeriaBot, WikiPageResult};
+use anyhow::{anyhow, Result};
+use axum::extract::{ConnectInfo, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use futures::FutureExt;
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+const DEFAULT_PORT: u16 = 8000;
+const DEFAULT_REQUESTS_PER_MINUTE: usize = 10;
+
+/// Results of the most recent on-demand updates, for the status endpoint
+const MAX_RECENT_RESULTS: usize = 50;
+
+/// Allows at most `max_requests` per IP within a sliding window
+#[derive(Debug)]
+pub struct RateLimiter {
+    max_requests: usize,
+    window: Duration,
+    requests: HashMap<IpAddr, VecDeque<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(max_requests: usize, window: Duration) -> Self {
+        Self {
+            max_requests,
+            window,
+            requests: HashMap::new(),
+        }
+    }
+
+    /// Records a request from `ip` at `now`; false if the IP is over its limit
+    pub fn check(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let window = self.window;
+        self.requests.retain(|_, times| {
+            while times.front().is_some_and(|t| now.duration_since(*t) >= window) {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+        let times = self.requests.entry(ip).or_default();
+        if times.len() >= self.max_requests {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+}
+
+/// The tokens that allow updates, as a bearer token or `token` parameter
+#[derive(Debug)]
+pub struct TokenAuth {
+    tokens: Vec<String>,
+}
+
+impl TokenAuth {
+    /// Without tokens, anyone could trigger updates; that is refused
+    pub fn new(tokens: Vec<String>) -> Result<Self> {
+        let tokens: Vec<String> = tokens.into_iter().filter(|t| !t.is_empty()).collect();
+        if tokens.is_empty() {
+            return Err(anyhow!("No server.tokens configured, refusing to serve updates"));
+        }
+        Ok(Self { tokens })
+    }
+
+    pub fn is_authorized(&self, headers: &HeaderMap, params: &HashMap<String, String>) -> bool {
+        let bearer = headers
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        let token = match bearer.or(params.get("token").map(|t| t.as_str())) {
+            Some(token) => token,
+            None => return false,
+        };
+        // Every token is compared, in full, so the time taken does not tell how much of one matched
+        self.tokens
+            .iter()
+            .fold(false, |found, t| Self::constant_time_eq(t.as_bytes(), token.as_bytes()) | found)
+    }
+
+    fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+        if a.len() != b.len() {
+            return false;
+        }
+        a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+    }
+}
+
+#[derive(Debug)]
+struct ServerState {
+    bot: Arc<ListeriaBot>,
+    auth: TokenAuth,
+    trust_forwarded_for: bool,
+    started: DateTime<Utc>,
+    rate_limiter: Mutex<RateLimiter>,
+    running: Mutex<Vec<(String, String)>>, // wiki, page
+    recent: Mutex<VecDeque<WikiPageResult>>,
+}
+
+/// HTTP server for on-demand page updates, e.g. from the "update now" link on wikis.
+/// `POST /update?wiki=...&page=...` updates a page right away, `GET /status` reports on the server.
+/// Updates require one of `server.tokens` as a bearer token or `token` parameter; the server does not start without tokens.
+#[derive(Debug, Clone)]
+pub struct UpdateServer {
+    state: Arc<ServerState>,
+    port: u16,
+}
+
+impl UpdateServer {
+    pub async fn new(bot: Arc<ListeriaBot>) -> Result<Self> {
+        let config = bot.config().await;
+        let tokens = config
+            .server("tokens")
+            .as_array()
+            .map(|a| a.iter().filter_map(|t| t.as_str()).map(|t| t.to_string()).collect())
+            .unwrap_or_default();
+        let requests_per_minute = config
+            .server("requests_per_minute")
+            .as_u64()
+            .map(|n| n as usize)
+            .unwrap_or(DEFAULT_REQUESTS_PER_MINUTE);
+        let port = config
+            .server("port")
+            .as_u64()
+            .map(|p| p as u16)
+            .unwrap_or(DEFAULT_PORT);
+        let state = ServerState {
+            bot,
+            auth: TokenAuth::new(tokens)?,
+            trust_forwarded_for: config.server("trust_forwarded_for").as_bool().unwrap_or(false),
+            started: Utc::now(),
+            rate_limiter: Mutex::new(RateLimiter::new(requests_per_minute, Duration::from_secs(60))),
+            running: Mutex::new(vec![]),
+            recent: Mutex::new(VecDeque::new()),
+        };
+        Ok(Self {
+            state: Arc::new(state),
+            port,
+        })
+    }
+
+    pub fn router(&self) -> Router {
+        Router::new()
+            .route("/update", post(update))
+            .route("/status", get(status))
+            .with_state(self.state.clone())
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        let listener = tokio::net::TcpListener::bind(("0.0.0.0", self.port)).await?;
+        println!("Listening on port {}", self.port);
+        axum::serve(
+            listener,
+            self.router().into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response {
+    (status, Json(json!({"status": "error", "message": message}))).into_response()
+}
+
+/// The client IP; behind a proxy, the last X-Forwarded-For address if configured to trust it.
+/// Proxies append to that header, so earlier addresses are whatever the client sent.
+fn client_ip(headers: &HeaderMap, addr: SocketAddr, trust_forwarded_for: bool) -> IpAddr {
+    if trust_forwarded_for {
+        let forwarded = headers
+            .get("x-forwarded-for")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(',').next_back())
+            .and_then(|ip| ip.trim().parse().ok());
+        if let Some(ip) = forwarded {
+            return ip;
+        }
+    }
+    addr.ip()
+}
+
+async fn update(
+    State(state): State<Arc<ServerState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    if !state.auth.is_authorized(&headers, &params) {
+        return error_response(StatusCode::UNAUTHORIZED, "Missing or invalid token");
+    }
+    let ip = client_ip(&headers, addr, state.trust_forwarded_for);
+    if !state.rate_limiter.lock().await.check(ip, Instant::now()) {
+        return error_response(StatusCode::TOO_MANY_REQUESTS, "Too many requests, try again later");
+    }
+    let (wiki, page) = match (params.get("wiki"), params.get("page")) {
+        (Some(wiki), Some(page)) if !wiki.is_empty() && !page.is_empty() => (wiki.to_owned(), page.replace('_', " ")),
+        _ => return error_response(StatusCode::BAD_REQUEST, "wiki and page parameters are required"),
+    };
+
+    let key = (wiki.to_owned(), page.to_owned());
+    {
+        let mut running = state.running.lock().await;
+        if running.contains(&key) {
+            return error_response(StatusCode::CONFLICT, "This page is already being updated");
+        }
+        running.push(key.to_owned());
+    }
+
+    // A task of its own frees the page even if the client goes away; a panic frees it too
+    let task_state = state.clone();
+    let task = tokio::spawn(async move {
+        let state = task_state;
+        let result = AssertUnwindSafe(state.bot.update_page(&wiki, &page))
+            .catch_unwind()
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Update of {wiki}/{page} panicked")));
+        state.running.lock().await.retain(|k| *k != key);
+        let wpr = result?;
+        let mut recent = state.recent.lock().await;
+        recent.push_front(wpr.clone());
+        recent.truncate(MAX_RECENT_RESULTS);
+        Ok(wpr)
+    });
+
+    match task.await.map_err(|e| anyhow!(e)).and_then(|result| result) {
+        Ok(wpr) => {
+            let ret = json!({"status": "ok", "wiki": wpr.wiki, "page": wpr.page, "result": wpr.result, "message": wpr.message});
+            Json(ret).into_response()
+        }
+        Err(e) => error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    }
+}
+
+async fn status(State(state): State<Arc<ServerState>>) -> Response {
+    let running: Vec<_> = state
+        .running
+        .lock()
+        .await
+        .iter()
+        .map(|(wiki, page)| json!({"wiki": wiki, "page": page}))
+        .collect();
+    let recent: Vec<_> = state
+        .recent
+        .lock()
+        .await
+        .iter()
+        .map(|wpr| json!({"wiki": wpr.wiki, "page": wpr.page, "result": wpr.result, "message": wpr.message}))
+        .collect();
+    Json(json!({
+        "status": "ok",
+        "started": state.started.to_rfc3339(),
+        "worker": state.bot.queue().worker_id(),
+        "blocked_wikis": state.bot.blocked_wikis().await,
+        "running": running,
+        "recent": recent,
+    }))
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(2, Duration::from_secs(60));
+        let ip1: IpAddr = "127.0.0.1".parse().unwrap();
+        let ip2: IpAddr = "127.0.0.2".parse().unwrap();
+        let now = Instant::now();
+        assert!(limiter.check(ip1, now));
+        assert!(limiter.check(ip1, now + Duration::from_secs(1)));
+        assert!(!limiter.check(ip1, now + Duration::from_secs(2)));
+        assert!(limiter.check(ip2, now + Duration::from_secs(2)));
+        assert!(limiter.check(ip1, now + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let addr: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "1.2.3.4, 192.0.2.7".parse().unwrap());
+        assert_eq!(client_ip(&headers, addr, false), addr.ip());
+
+        // The proxy appended the real client address after the one the client made up
+        assert_eq!(client_ip(&headers, addr, true), "192.0.2.7".parse::<IpAddr>().unwrap());
+        headers.insert("x-forwarded-for", "garbage".parse().unwrap());
+        assert_eq!(client_ip(&headers, addr, true), addr.ip());
+        assert_eq!(client_ip(&HeaderMap::new(), addr, true), addr.ip());
+    }
+
+    #[test]
+    fn test_token_auth() {
+        assert!(TokenAuth::new(vec![]).is_err());
+        assert!(TokenAuth::new(vec![String::new()]).is_err());
+
+        let auth = TokenAuth::new(vec!["secret".to_string(), "other".to_string()]).unwrap();
+        let no_params = HashMap::new();
+        assert!(!auth.is_authorized(&HeaderMap::new(), &no_params));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", "Bearer other".parse().unwrap());
+        assert!(auth.is_authorized(&headers, &no_params));
+        headers.insert("authorization", "Bearer secre".parse().unwrap());
+        assert!(!auth.is_authorized(&headers, &no_params));
+
+        let params: HashMap<String, String> = [("token".to_string(), "secret".to_string())].into();
+        assert!(auth.is_authorized(&HeaderMap::new(), &params));
+        let params: HashMap<String, String> = [("token".to_string(), "secret2".to_string())].into();
+        assert!(!auth.is_authorized(&HeaderMap::new(), &params));
+    }
+}
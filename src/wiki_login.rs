@@ -0,0 +1,151 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use tokio::sync::Mutex;
+use wikibase::mediawiki::api::Api;
+
+const DEFAULT_TOKEN_URL: &str = "https://meta.wikimedia.org/w/rest.php/oauth2/access_token";
+
+/// Renew this long before the access token actually expires
+const EXPIRY_MARGIN_SECONDS: i64 = 60;
+
+/// OAuth2 credentials from the `wiki_login` config section.
+/// The access token can be renewed at runtime if a refresh token and client credentials are configured.
+#[derive(Debug, Clone, Default)]
+pub struct WikiLogin {
+    token: Arc<RwLock<String>>,
+    refresh_token: Arc<RwLock<Option<String>>>,
+    expires: Arc<RwLock<Option<DateTime<Utc>>>>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    token_url: String,
+    refresh_lock: Arc<Mutex<()>>,
+    config: Value, // The config section, to tell if a reloaded login is the same
+}
+
+impl WikiLogin {
+    pub fn new_from_json(j: &Value) -> Self {
+        let s = |key: &str| j[key].as_str().map(|s| s.to_string());
+        let expires = j["expires_in"]
+            .as_i64()
+            .map(|seconds| Utc::now() + Duration::seconds(seconds));
+        Self {
+            token: Arc::new(RwLock::new(s("token").unwrap_or_default())),
+            refresh_token: Arc::new(RwLock::new(s("refresh_token"))),
+            expires: Arc::new(RwLock::new(expires)),
+            client_id: s("client_id"),
+            client_secret: s("client_secret"),
+            token_url: s("token_url").unwrap_or_else(|| DEFAULT_TOKEN_URL.to_string()),
+            refresh_lock: Arc::new(Mutex::new(())),
+            config: j.to_owned(),
+        }
+    }
+
+    /// Takes over the tokens of `previous`, loaded from the same config section, which may have renewed them since.
+    /// A changed config section keeps its own tokens.
+    pub fn keep_tokens_of(&self, previous: &WikiLogin) -> Result<()> {
+        if self.config != previous.config {
+            return Ok(());
+        }
+        *self.token.write().map_err(|e| anyhow!("{e}"))? = previous.token();
+        *self.refresh_token.write().map_err(|e| anyhow!("{e}"))? =
+            previous.refresh_token.read().map_err(|e| anyhow!("{e}"))?.to_owned();
+        *self.expires.write().map_err(|e| anyhow!("{e}"))? = previous.expires.read().map_err(|e| anyhow!("{e}"))?.to_owned();
+        Ok(())
+    }
+
+    pub fn token(&self) -> String {
+        match self.token.read() {
+            Ok(token) => token.to_owned(),
+            Err(_) => String::new(),
+        }
+    }
+
+    pub fn can_refresh(&self) -> bool {
+        let has_refresh_token = matches!(self.refresh_token.read().as_deref(), Ok(Some(_)));
+        has_refresh_token && self.client_id.is_some() && self.client_secret.is_some()
+    }
+
+    /// True if the token is known to expire (or have expired) soon
+    pub fn is_expired(&self) -> bool {
+        match self.expires.read().as_deref() {
+            Ok(Some(expires)) => *expires - Duration::seconds(EXPIRY_MARGIN_SECONDS) <= Utc::now(),
+            _ => false,
+        }
+    }
+
+    /// API error codes that mean the access token is no longer valid
+    pub fn is_token_error(code: &str) -> bool {
+        code.starts_with("mwoauth-invalid-authorization") || code == "mwoauth-token-expired"
+    }
+
+    /// Gets a new access token, unless another task already replaced `stale_token`.
+    /// Returns the access token to use from now on.
+    pub async fn refresh(&self, stale_token: &str, api: &Api) -> Result<String> {
+        let _lock = self.refresh_lock.lock().await;
+        let current = self.token();
+        if current != stale_token {
+            return Ok(current);
+        }
+        if !self.can_refresh() {
+            return Err(anyhow!("OAuth2 token expired and no refresh credentials are configured"));
+        }
+        let refresh_token = self
+            .refresh_token
+            .read()
+            .map_err(|e| anyhow!("{e}"))?
+            .to_owned()
+            .unwrap_or_default();
+        let params: HashMap<String, String> = vec![
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token.as_str()),
+            ("client_id", self.client_id.as_deref().unwrap_or_default()),
+            ("client_secret", self.client_secret.as_deref().unwrap_or_default()),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let body = api.query_raw(&self.token_url, &params, "POST").await?;
+        let j: Value = serde_json::from_str(&body)?;
+        let token = j["access_token"]
+            .as_str()
+            .ok_or_else(|| anyhow!("OAuth2 refresh failed: {body}"))?
+            .to_string();
+
+        *self.token.write().map_err(|e| anyhow!("{e}"))? = token.to_owned();
+        if let Some(rt) = j["refresh_token"].as_str() {
+            // Refresh tokens may be rotated
+            *self.refresh_token.write().map_err(|e| anyhow!("{e}"))? = Some(rt.to_string());
+        }
+        *self.expires.write().map_err(|e| anyhow!("{e}"))? = j["expires_in"]
+            .as_i64()
+            .map(|seconds| Utc::now() + Duration::seconds(seconds));
+        Ok(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_keep_tokens_of() {
+        let j = json!({"token":"old","refresh_token":"r1","client_id":"id","client_secret":"secret"});
+        let previous = WikiLogin::new_from_json(&j);
+        *previous.token.write().unwrap() = "renewed".to_string();
+        *previous.refresh_token.write().unwrap() = Some("r2".to_string());
+
+        let reloaded = WikiLogin::new_from_json(&j);
+        let shared = reloaded.clone(); // Eg the one of the client manager
+        reloaded.keep_tokens_of(&previous).unwrap();
+        assert_eq!(shared.token(), "renewed");
+        assert_eq!(shared.refresh_token.read().unwrap().as_deref(), Some("r2"));
+
+        let changed = WikiLogin::new_from_json(&json!({"token":"new"}));
+        changed.keep_tokens_of(&previous).unwrap();
+        assert_eq!(changed.token(), "new");
+    }
+}